@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS "session" (
+    "id" SERIAL NOT NULL,
+    "session_id" text NOT NULL,
+    "room_id"  text NOT NULL,
+    "domain" text NOT NULL,
+    "redirect_url" text NOT NULL,
+    "purpose" text NOT NULL,
+    "name" text NOT NULL,
+    "instance" text NOT NULL,
+    "attr_id" text NOT NULL,
+    "auth_result" text,
+    "last_activity" timestamp NOT NULL,
+    PRIMARY KEY ("id")
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS "session_attr_id_idx" ON "session" ("attr_id");
+CREATE UNIQUE INDEX IF NOT EXISTS "session_session_id_idx" ON "session" ("session_id");
@@ -0,0 +1,6 @@
+ALTER TABLE "session"
+    ADD COLUMN IF NOT EXISTS "join_code" text,
+    ADD COLUMN IF NOT EXISTS "join_code_used" boolean NOT NULL DEFAULT false;
+
+CREATE UNIQUE INDEX IF NOT EXISTS "session_join_code_idx" ON "session" ("join_code");
+CREATE INDEX IF NOT EXISTS "session_room_id_idx" ON "session" ("room_id");
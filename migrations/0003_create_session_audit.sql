@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS "session_audit" (
+    "id" SERIAL NOT NULL,
+    "session_id" text NOT NULL,
+    "actor" text NOT NULL,
+    "event" text NOT NULL,
+    "created_at" timestamp NOT NULL,
+    PRIMARY KEY ("id")
+);
+
+CREATE INDEX IF NOT EXISTS "session_audit_session_id_idx" ON "session_audit" ("session_id");
@@ -0,0 +1,4 @@
+ALTER TABLE "session"
+    ADD COLUMN IF NOT EXISTS "state" text NOT NULL DEFAULT 'created';
+
+UPDATE "session" SET "state" = 'auth_completed' WHERE "auth_result" IS NOT NULL;
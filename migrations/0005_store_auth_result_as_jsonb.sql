@@ -0,0 +1,6 @@
+-- Authentication results are now stored decrypted as JSON. Previously stored
+-- results, the JWEs received from the core, are kept as JSON strings until
+-- session::migrate_legacy_auth_results converts them with the decryption keys
+-- of the plugin.
+ALTER TABLE "session"
+    ALTER COLUMN "auth_result" TYPE jsonb USING to_jsonb("auth_result");
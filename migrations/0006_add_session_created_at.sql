@@ -0,0 +1,10 @@
+-- Sessions record when they were created, so that the sessions in a room can
+-- be listed in a stable order. Existing sessions are assumed to have been
+-- created at their last activity.
+ALTER TABLE "session" ADD COLUMN "created_at" timestamp;
+UPDATE "session" SET "created_at" = "last_activity";
+ALTER TABLE "session"
+    ALTER COLUMN "created_at" SET NOT NULL,
+    ALTER COLUMN "created_at" SET DEFAULT now();
+
+CREATE INDEX ON "session" ("room_id", "created_at", "id");
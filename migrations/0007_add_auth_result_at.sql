@@ -0,0 +1,6 @@
+-- Sessions record when their authentication result was registered, so that
+-- results can be removed after a retention period regardless of activity.
+-- Existing results are assumed to have been registered at their session's
+-- last activity.
+ALTER TABLE "session" ADD COLUMN "auth_result_at" timestamp;
+UPDATE "session" SET "auth_result_at" = "last_activity" WHERE "auth_result" IS NOT NULL;
@@ -0,0 +1,15 @@
+-- Security-relevant events such as refused tokens and hosts viewing
+-- authentication results, written by the audit module
+CREATE TABLE IF NOT EXISTS "audit_log" (
+    "id" BIGSERIAL NOT NULL,
+    "kind" text NOT NULL,
+    "room_id" text,
+    "session_id" text,
+    "actor" text,
+    "detail" text,
+    "occurred_at" timestamp NOT NULL,
+    PRIMARY KEY ("id")
+);
+
+CREATE INDEX IF NOT EXISTS "audit_log_occurred_at_idx" ON "audit_log" ("occurred_at");
+CREATE INDEX IF NOT EXISTS "audit_log_session_id_idx" ON "audit_log" ("session_id");
@@ -0,0 +1,10 @@
+-- Token buckets of the rate limiter, when configured to be shared between
+-- instances through the session database
+CREATE TABLE IF NOT EXISTS "rate_limit" (
+    "key" text NOT NULL,
+    "tokens" double precision NOT NULL,
+    "updated_at" timestamp NOT NULL,
+    PRIMARY KEY ("key")
+);
+
+CREATE INDEX IF NOT EXISTS "rate_limit_updated_at_idx" ON "rate_limit" ("updated_at");
@@ -0,0 +1,9 @@
+-- Platform tokens that were already used, when replay protection is shared
+-- between instances through the session database
+CREATE TABLE IF NOT EXISTS "used_token" (
+    "key" text NOT NULL,
+    "expires_at" timestamp NOT NULL,
+    PRIMARY KEY ("key")
+);
+
+CREATE INDEX IF NOT EXISTS "used_token_expires_at_idx" ON "used_token" ("expires_at");
@@ -0,0 +1,4 @@
+-- Sessions are removed by their last activity or, with absolute expiry, by
+-- their creation time, so both are indexed to keep cleanups cheap.
+CREATE INDEX ON "session" ("last_activity");
+CREATE INDEX ON "session" ("created_at");
@@ -0,0 +1,4 @@
+-- Sessions remember the authentication session the core started for them,
+-- so that failures reported by the core can be traced back to a session.
+ALTER TABLE "session" ADD COLUMN "core_session_id" text;
+CREATE INDEX ON "session" ("core_session_id");
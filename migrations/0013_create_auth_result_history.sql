@@ -0,0 +1,14 @@
+-- Authentication results replaced when a host asks a guest to authenticate
+-- again are kept, and removed together with their session.
+CREATE TABLE "auth_result_history" (
+    "id" SERIAL NOT NULL,
+    "session_id" text NOT NULL REFERENCES "session" ("session_id") ON DELETE CASCADE,
+    "auth_result" jsonb NOT NULL,
+    "auth_result_at" timestamp,
+    "reset_at" timestamp NOT NULL DEFAULT now(),
+    "reset_by" text,
+    PRIMARY KEY ("id")
+);
+
+CREATE INDEX ON "auth_result_history" ("session_id");
+CREATE INDEX ON "auth_result_history" ("reset_at");
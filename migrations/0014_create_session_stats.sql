@@ -0,0 +1,10 @@
+-- Daily counts of the sessions created, completed and expired per purpose,
+-- kept after the sessions themselves are removed.
+CREATE TABLE "session_stats" (
+    "day" date NOT NULL,
+    "purpose" text NOT NULL,
+    "created" bigint NOT NULL DEFAULT 0,
+    "completed" bigint NOT NULL DEFAULT 0,
+    "expired" bigint NOT NULL DEFAULT 0,
+    PRIMARY KEY ("day", "purpose")
+);
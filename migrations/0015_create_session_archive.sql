@@ -0,0 +1,13 @@
+-- Metadata of sessions removed by the cleanup, archived if the retention
+-- policy asks for it. Holds no identifiers, names or attributes.
+CREATE TABLE "session_archive" (
+    "purpose" text NOT NULL,
+    "domain" text NOT NULL,
+    "instance" text NOT NULL,
+    "state" text NOT NULL,
+    "created_at" timestamp NOT NULL,
+    "auth_result_at" timestamp,
+    "archived_at" timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX ON "session_archive" ("created_at");
@@ -0,0 +1,4 @@
+-- Sessions created through a SessionTransaction are left out of listings
+-- until committed, so that a session whose creation is rolled back or never
+-- finished is not seen by hosts.
+ALTER TABLE "session" ADD COLUMN "committed" boolean NOT NULL DEFAULT true;
@@ -1,4 +1,15 @@
+-- Resulting schema of all migrations in migrations/. Note that this drops
+-- existing session data; use session::run_migrations to upgrade instead.
+
+DROP TABLE IF EXISTS "auth_result_history";
 DROP TABLE IF EXISTS "session";
+DROP TABLE IF EXISTS "session_audit";
+DROP TABLE IF EXISTS "audit_log";
+DROP TABLE IF EXISTS "rate_limit";
+DROP TABLE IF EXISTS "used_token";
+DROP TABLE IF EXISTS "session_stats";
+DROP TABLE IF EXISTS "session_archive";
+DROP TABLE IF EXISTS "schema_migrations";
 
 CREATE TABLE "session" (
     "id" SERIAL NOT NULL,
@@ -10,10 +21,110 @@ CREATE TABLE "session" (
     "name" text NOT NULL,
     "instance" text NOT NULL,
     "attr_id" text NOT NULL,
-    "auth_result" text,
+    "auth_result" jsonb,
+    "auth_result_at" timestamp,
+    "join_code" text,
+    "join_code_used" boolean NOT NULL DEFAULT false,
+    "state" text NOT NULL DEFAULT 'created',
     "last_activity" timestamp NOT NULL,
+    "created_at" timestamp NOT NULL DEFAULT now(),
+    "core_session_id" text,
+    "committed" boolean NOT NULL DEFAULT true,
     PRIMARY KEY ("id")
 );
 
 CREATE UNIQUE INDEX ON "session" ("attr_id");
 CREATE UNIQUE INDEX ON "session" ("session_id");
+CREATE UNIQUE INDEX ON "session" ("join_code");
+CREATE INDEX ON "session" ("room_id");
+CREATE INDEX ON "session" ("room_id", "created_at", "id");
+CREATE INDEX ON "session" ("last_activity");
+CREATE INDEX ON "session" ("created_at");
+CREATE INDEX ON "session" ("core_session_id");
+
+CREATE TABLE "auth_result_history" (
+    "id" SERIAL NOT NULL,
+    "session_id" text NOT NULL REFERENCES "session" ("session_id") ON DELETE CASCADE,
+    "auth_result" jsonb NOT NULL,
+    "auth_result_at" timestamp,
+    "reset_at" timestamp NOT NULL DEFAULT now(),
+    "reset_by" text,
+    PRIMARY KEY ("id")
+);
+
+CREATE INDEX ON "auth_result_history" ("session_id");
+CREATE INDEX ON "auth_result_history" ("reset_at");
+
+CREATE TABLE "session_audit" (
+    "id" SERIAL NOT NULL,
+    "session_id" text NOT NULL,
+    "actor" text NOT NULL,
+    "event" text NOT NULL,
+    "created_at" timestamp NOT NULL,
+    PRIMARY KEY ("id")
+);
+
+CREATE INDEX ON "session_audit" ("session_id");
+
+CREATE TABLE "audit_log" (
+    "id" BIGSERIAL NOT NULL,
+    "kind" text NOT NULL,
+    "room_id" text,
+    "session_id" text,
+    "actor" text,
+    "detail" text,
+    "occurred_at" timestamp NOT NULL,
+    PRIMARY KEY ("id")
+);
+
+CREATE INDEX ON "audit_log" ("occurred_at");
+CREATE INDEX ON "audit_log" ("session_id");
+
+CREATE TABLE "rate_limit" (
+    "key" text NOT NULL,
+    "tokens" double precision NOT NULL,
+    "updated_at" timestamp NOT NULL,
+    PRIMARY KEY ("key")
+);
+
+CREATE INDEX ON "rate_limit" ("updated_at");
+
+CREATE TABLE "used_token" (
+    "key" text NOT NULL,
+    "expires_at" timestamp NOT NULL,
+    PRIMARY KEY ("key")
+);
+
+CREATE INDEX ON "used_token" ("expires_at");
+
+CREATE TABLE "session_stats" (
+    "day" date NOT NULL,
+    "purpose" text NOT NULL,
+    "created" bigint NOT NULL DEFAULT 0,
+    "completed" bigint NOT NULL DEFAULT 0,
+    "expired" bigint NOT NULL DEFAULT 0,
+    PRIMARY KEY ("day", "purpose")
+);
+
+CREATE TABLE "session_archive" (
+    "purpose" text NOT NULL,
+    "domain" text NOT NULL,
+    "instance" text NOT NULL,
+    "state" text NOT NULL,
+    "created_at" timestamp NOT NULL,
+    "auth_result_at" timestamp,
+    "archived_at" timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX ON "session_archive" ("created_at");
+
+-- All migrations are applied, so session::run_migrations only applies the ones
+-- added later. Keep the version at the number of the latest migration.
+CREATE TABLE "schema_migrations" (
+    "version" integer NOT NULL,
+    "applied_at" timestamp NOT NULL,
+    PRIMARY KEY ("version")
+);
+
+INSERT INTO "schema_migrations" ("version", "applied_at")
+SELECT generate_series(1, 16), now();
@@ -0,0 +1,282 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+#[cfg(feature = "rocket")]
+use rocket::fairing::{AdHoc, Fairing};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+#[cfg(feature = "rocket")]
+use crate::config::CurrentConfig;
+#[cfg(all(feature = "sessions", feature = "rocket"))]
+use crate::session::SessionDBConn;
+#[cfg(feature = "sessions")]
+use crate::session::SessionDb;
+use crate::{error::Error, shutdown::spawn_tracked};
+
+lazy_static! {
+    static ref AUDIT_LOG: Mutex<Option<mpsc::UnboundedSender<AuditEvent>>> = Mutex::new(None);
+}
+
+/// Kind of security-relevant event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEventKind {
+    /// A session was created for a guest
+    SessionCreated,
+    /// A platform token was refused
+    TokenVerificationFailed,
+    /// An authentication result was stored with a session
+    AuthResultStored,
+    /// A host discarded the authentication result of a session, so that the
+    /// guest authenticates again
+    AuthResultReset,
+    /// A host retrieved the authentication result of a session
+    ResultViewed,
+}
+
+impl AuditEventKind {
+    /// Name of the event, as stored in the audit log
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuditEventKind::SessionCreated => "session_created",
+            AuditEventKind::TokenVerificationFailed => "token_verification_failed",
+            AuditEventKind::AuthResultStored => "auth_result_stored",
+            AuditEventKind::AuthResultReset => "auth_result_reset",
+            AuditEventKind::ResultViewed => "result_viewed",
+        }
+    }
+}
+
+/// Entry in the audit log. Never holds tokens or attribute values, only
+/// identifiers and a short description.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditEvent {
+    pub kind: AuditEventKind,
+    /// Seconds since the Unix epoch at which the event occurred
+    pub timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    /// Who caused the event, such as a host by its ID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl AuditEvent {
+    /// Event of the given kind, occurring now
+    pub fn new(kind: AuditEventKind) -> Self {
+        AuditEvent {
+            kind,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since_epoch| since_epoch.as_secs()),
+            room_id: None,
+            session_id: None,
+            actor: None,
+            detail: None,
+        }
+    }
+
+    /// Attribute the event to the session `session_id` in room `room_id`
+    pub fn session(mut self, room_id: impl Into<String>, session_id: impl Into<String>) -> Self {
+        self.room_id = Some(room_id.into());
+        self.session_id = Some(session_id.into());
+        self
+    }
+
+    pub fn actor(mut self, actor: impl Into<String>) -> Self {
+        self.actor = Some(actor.into());
+        self
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+/// Record an event in the audit log. Events are written in the background by
+/// the sink installed through [`audit_fairing`] or [`custom_audit_fairing`],
+/// and dropped if no sink is installed.
+pub fn record(event: AuditEvent) {
+    if let Some(sender) = AUDIT_LOG.lock().unwrap().as_ref() {
+        // Sending only fails once the writer stopped during shutdown
+        let _ = sender.send(event);
+    }
+}
+
+/// Destination of the audit log
+#[async_trait]
+pub trait AuditSink: Send + Sync {
+    async fn write(&self, event: &AuditEvent) -> Result<(), Error>;
+}
+
+/// Audit sink writing every event as a line of JSON to stderr
+pub struct LogAuditSink;
+
+#[async_trait]
+impl AuditSink for LogAuditSink {
+    async fn write(&self, event: &AuditEvent) -> Result<(), Error> {
+        eprintln!("{}", serde_json::json!({ "audit": event }));
+        Ok(())
+    }
+}
+
+/// Audit sink writing events to the `audit_log` table of the session database
+#[cfg(feature = "sessions")]
+pub struct DatabaseAuditSink<D> {
+    db: D,
+}
+
+#[cfg(feature = "sessions")]
+impl<D: SessionDb> DatabaseAuditSink<D> {
+    pub fn new(db: D) -> Self {
+        DatabaseAuditSink { db }
+    }
+}
+
+#[cfg(feature = "sessions")]
+#[async_trait]
+impl<D: SessionDb> AuditSink for DatabaseAuditSink<D> {
+    async fn write(&self, event: &AuditEvent) -> Result<(), Error> {
+        let event = event.clone();
+        self.db
+            .run(move |c| {
+                c.execute(
+                    "INSERT INTO audit_log (
+                        kind,
+                        room_id,
+                        session_id,
+                        actor,
+                        detail,
+                        occurred_at
+                    ) VALUES ($1, $2, $3, $4, $5, to_timestamp($6));",
+                    &[
+                        &event.kind.name(),
+                        &event.room_id,
+                        &event.session_id,
+                        &event.actor,
+                        &event.detail,
+                        &(event.timestamp as f64),
+                    ],
+                )
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+/// Where to write the audit log, as configured through `audit_log`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditLogTarget {
+    /// Write events as JSON lines to stderr, see [`LogAuditSink`]
+    Log,
+    /// Write events to the session database, see [`DatabaseAuditSink`]
+    #[cfg(feature = "sessions")]
+    Database,
+}
+
+async fn write_or_report(sink: &dyn AuditSink, event: &AuditEvent) {
+    if let Err(e) = sink.write(event).await {
+        eprintln!(
+            "Could not write {} to the audit log: {}",
+            event.kind.name(),
+            e
+        );
+    }
+}
+
+/// Install `sink` as the destination of all events recorded from now on, and
+/// spawn a task writing them in order until `shutdown` completes. Events
+/// recorded before shutdown are still written, which
+/// [`crate::shutdown::drain`] waits for. With Rocket, this is done by attaching
+/// [`audit_fairing`] or [`custom_audit_fairing`].
+pub fn start_writer(sink: Arc<dyn AuditSink>, shutdown: impl Future<Output = ()> + Send + 'static) {
+    let (sender, mut events) = mpsc::unbounded_channel();
+    *AUDIT_LOG.lock().unwrap() = Some(sender);
+
+    spawn_tracked(async move {
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Some(event) => write_or_report(sink.as_ref(), &event).await,
+                    None => break,
+                },
+                _ = &mut shutdown => {
+                    events.close();
+                    while let Some(event) = events.recv().await {
+                        write_or_report(sink.as_ref(), &event).await;
+                    }
+                    break;
+                }
+            }
+        }
+    });
+}
+
+/// Fairing writing the audit log to the target configured through
+/// `audit_log`. Does nothing if no target is configured. Requires the
+/// configuration to be managed, and for the `database` target the
+/// [`SessionDBConn`] fairing to be attached.
+#[cfg(feature = "rocket")]
+pub fn audit_fairing() -> impl Fairing {
+    AdHoc::on_liftoff("Audit log", |rocket| {
+        Box::pin(async move {
+            let config = CurrentConfig::from_rocket(rocket).expect("No configuration found");
+            let sink: Arc<dyn AuditSink> = match config.audit_log() {
+                None => return,
+                Some(AuditLogTarget::Log) => Arc::new(LogAuditSink),
+                #[cfg(feature = "sessions")]
+                Some(AuditLogTarget::Database) => match SessionDBConn::get_one(rocket).await {
+                    Some(db) => Arc::new(DatabaseAuditSink::new(db)),
+                    None => {
+                        eprintln!("Audit log disabled: no session database available");
+                        return;
+                    }
+                },
+            };
+            start_writer(sink, rocket.shutdown());
+        })
+    })
+}
+
+/// Fairing writing the audit log to `sink`, regardless of the configuration
+#[cfg(feature = "rocket")]
+pub fn custom_audit_fairing(sink: Arc<dyn AuditSink>) -> impl Fairing {
+    AdHoc::on_liftoff("Audit log", move |rocket| {
+        Box::pin(async move {
+            start_writer(sink, rocket.shutdown());
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuditEvent, AuditEventKind, AuditLogTarget};
+
+    #[test]
+    fn test_audit_event() {
+        let event = AuditEvent::new(AuditEventKind::ResultViewed)
+            .session("room", "session")
+            .actor("host");
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["kind"], "result_viewed");
+        assert_eq!(json["session_id"], "session");
+        assert_eq!(json["actor"], "host");
+        assert!(json.get("detail").is_none());
+        assert!(event.timestamp > 0);
+
+        let target: AuditLogTarget = serde_yaml::from_str("log").unwrap();
+        assert_eq!(target, AuditLogTarget::Log);
+    }
+}
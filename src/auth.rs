@@ -2,18 +2,21 @@ use core::convert::Infallible;
 use std::{convert::TryFrom, str::FromStr};
 
 use reqwest::header::AUTHORIZATION;
+#[cfg(feature = "rocket")]
 use rocket::{
     fairing::{AdHoc, Fairing},
     http::{Cookie, CookieJar, SameSite, Status},
     outcome::Outcome,
     request::{self, FromRequest, Request},
     response::Redirect,
-    State,
 };
+#[cfg(feature = "rocket")]
 use rocket_oauth2::{OAuth2, TokenResponse};
 use serde::{Deserialize, Serialize};
 use tera::Context;
 
+#[cfg(feature = "rocket")]
+use crate::config::CurrentConfig;
 use crate::{
     config::Config,
     error::Error,
@@ -33,6 +36,7 @@ pub enum AuthProvider {
 }
 
 impl AuthProvider {
+    #[cfg(feature = "rocket")]
     pub fn fairing(&self) -> impl Fairing {
         match self {
             AuthProvider::Google => AdHoc::on_ignite("Auth", |rocket| async {
@@ -88,12 +92,14 @@ impl From<Authorized> for bool {
     }
 }
 
+#[cfg(feature = "rocket")]
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for Authorized {
     type Error = Error;
 
     async fn from_request(request: &'r Request<'_>) -> request::Outcome<Authorized, Error> {
-        let config = request.rocket().state::<Config>().unwrap(); // if we don't have a config, panic
+        // if we don't have a config, panic
+        let config = CurrentConfig::from_rocket(request.rocket()).unwrap();
 
         match config.auth_provider() {
             Some(auth_provider) => match request.cookies().get_private("token") {
@@ -117,21 +123,24 @@ impl<'r> FromRequest<'r> for Authorized {
     }
 }
 
+#[cfg(feature = "rocket")]
 struct Google;
 
+#[cfg(feature = "rocket")]
 #[rocket::get("/auth/login")]
 fn login_google(cookies: &CookieJar<'_>, oauth2: OAuth2<Google>) -> Redirect {
     oauth2.get_redirect(cookies, &["profile"]).unwrap()
 }
 
+#[cfg(feature = "rocket")]
 #[rocket::get("/auth/redirect")]
 async fn redirect_google(
-    config: &State<Config>,
+    config: CurrentConfig<'_>,
     cookies: &CookieJar<'_>,
     token: TokenResponse<Google>,
     translations: Translations,
 ) -> Result<String, Error> {
-    redirect_generic(config, cookies, token, translations).await
+    redirect_generic(&config, cookies, token, translations).await
 }
 
 #[derive(serde::Deserialize)]
@@ -154,21 +163,24 @@ async fn check_token_google(token: TokenCookie) -> Result<bool, Error> {
     Ok(!user_info.sub.is_empty())
 }
 
+#[cfg(feature = "rocket")]
 struct Microsoft;
 
+#[cfg(feature = "rocket")]
 #[rocket::get("/auth/login")]
 fn login_microsoft(cookies: &CookieJar<'_>, oauth2: OAuth2<Microsoft>) -> Redirect {
     oauth2.get_redirect(cookies, &["user.read"]).unwrap()
 }
 
+#[cfg(feature = "rocket")]
 #[rocket::get("/auth/redirect")]
 async fn redirect_microsoft(
-    config: &State<Config>,
+    config: CurrentConfig<'_>,
     cookies: &CookieJar<'_>,
     token: TokenResponse<Microsoft>,
     translations: Translations,
 ) -> Result<String, Error> {
-    redirect_generic(config, cookies, token, translations).await
+    redirect_generic(&config, cookies, token, translations).await
 }
 
 #[derive(serde::Deserialize)]
@@ -191,8 +203,9 @@ async fn check_token_microsoft(token: TokenCookie) -> Result<bool, Error> {
     Ok(!user_info.display_name.is_empty())
 }
 
+#[cfg(feature = "rocket")]
 async fn redirect_generic<T>(
-    config: &State<Config>,
+    config: &Config,
     cookies: &CookieJar<'_>,
     token: TokenResponse<T>,
     translations: Translations,
@@ -228,6 +241,7 @@ async fn redirect_generic<T>(
     )))
 }
 
+#[cfg(feature = "rocket")]
 #[rocket::post("/auth/logout")]
 async fn logout_generic(
     cookies: &CookieJar<'_>,
@@ -0,0 +1,115 @@
+use crate::{
+    config::AuthDuringCommConfig,
+    error::Error,
+    jwt::sign_auth_select_params,
+    translations::Translations,
+    types::{AuthSelectParams, GuestToken},
+};
+
+/// URL of the auth-select widget for a guest, with widget parameters signed for
+/// the platform instance of the guest.
+/// After selecting an authentication method, the guest is sent to `start_url`;
+/// when cancelling, the guest returns to the redirect URL of its token. The
+/// widget shows the display name for the guest's session domain, in the
+/// guest's language.
+///
+/// Besides the guest token and purpose, this needs the configuration for the
+/// widget URL and signing key of the instance, the translations of the
+/// request for the display name, and the start URL, which usually contains
+/// the attribute ID of the session rather than anything in the token.
+pub fn widget_url_for(
+    config: &AuthDuringCommConfig,
+    translations: &Translations,
+    guest_token: &GuestToken,
+    purpose: &str,
+    start_url: &str,
+) -> Result<String, Error> {
+    let params = AuthSelectParams {
+        purpose: purpose.to_owned(),
+        start_url: start_url.to_owned(),
+        cancel_url: guest_token.redirect_url.clone(),
+        display_name: translations.display_name(config, &guest_token.instance, &guest_token.domain),
+    };
+    let signed = sign_auth_select_params(params, config.widget_signer_for(&guest_token.instance))?;
+
+    Ok(format!(
+        "{}/{}",
+        config.widget_url().trim_end_matches('/'),
+        signed
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use josekit::jws::alg::hmac::HmacJwsAlgorithm;
+
+    use super::widget_url_for;
+    use crate::{
+        config::AuthDuringCommConfig,
+        translations::Translations,
+        types::{GuestToken, RoomId, SessionDomain, SessionId},
+    };
+
+    const WIDGET_SECRET: &str = "widget-secret-widget-secret-widget";
+
+    #[test]
+    fn test_widget_url_for() {
+        let widget_signer = HmacJwsAlgorithm::Hs256
+            .signer_from_bytes(WIDGET_SECRET)
+            .unwrap();
+        let widget_verifier = HmacJwsAlgorithm::Hs256
+            .verifier_from_bytes(WIDGET_SECRET)
+            .unwrap();
+        let config = AuthDuringCommConfig::new_for_test(
+            Box::new(widget_signer.clone()),
+            Box::new(widget_signer),
+            Box::new(widget_verifier.clone()),
+            Box::new(widget_verifier.clone()),
+        );
+
+        let guest_token = GuestToken {
+            id: SessionId::new("1").unwrap(),
+            domain: SessionDomain::Guest,
+            redirect_url: "https://example.com/cancel".to_owned(),
+            name: "Guest".to_owned(),
+            room_id: RoomId::new("16").unwrap(),
+            instance: "example.com".to_owned(),
+            purpose: "test".to_owned(),
+        };
+
+        let translations = Translations {
+            language: "nl".to_owned(),
+            translations: HashMap::from([(
+                "display_name_guest".to_owned(),
+                "comm-common voor gasten".to_owned(),
+            )]),
+        };
+
+        let url = widget_url_for(
+            &config,
+            &translations,
+            &guest_token,
+            "test",
+            "https://example.com/start",
+        )
+        .unwrap();
+        let signed = url.strip_prefix("https://example.com/").unwrap();
+
+        let (payload, _) = josekit::jwt::decode_with_verifier(signed, &widget_verifier).unwrap();
+        assert_eq!(payload.claim("purpose").unwrap(), "test");
+        assert_eq!(
+            payload.claim("start_url").unwrap(),
+            "https://example.com/start"
+        );
+        assert_eq!(
+            payload.claim("cancel_url").unwrap(),
+            "https://example.com/cancel"
+        );
+        assert_eq!(
+            payload.claim("display_name").unwrap(),
+            "comm-common voor gasten"
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use std::{collections::HashMap, convert::Infallible, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts, Query},
+    http::{header, request::Parts, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+#[cfg(feature = "auth_during_comm")]
+use crate::{
+    config::AuthDuringCommConfig,
+    guards::{
+        verify_guest_token, verify_host_token, ReplayCache, ValidatedGuestToken,
+        ValidatedHostToken, GUEST_TOKEN_PARAM, HOST_TOKEN_PARAM,
+    },
+    types::VerifiedToken,
+};
+use crate::{
+    config::Config,
+    error::{Error, TRACE_ID_HEADER, TRACE_ID_LENGTH},
+    templates::{RenderType, RenderedContent},
+    translations::{Translations, LANGUAGE_PARAM},
+    util::random_string,
+};
+
+/// State the extractors in this module take the configuration from. Use it
+/// as the state of the router, or implement [`FromRef`] for it on the state
+/// of the plugin.
+#[derive(Clone)]
+pub struct CommState {
+    pub config: Arc<Config>,
+    /// Platform tokens that were already used
+    #[cfg(feature = "auth_during_comm")]
+    pub replay_cache: Arc<ReplayCache>,
+}
+
+impl CommState {
+    pub fn new(config: Config) -> Self {
+        CommState {
+            config: Arc::new(config),
+            #[cfg(feature = "auth_during_comm")]
+            replay_cache: Arc::new(ReplayCache::new()),
+        }
+    }
+}
+
+impl IntoResponse for Error {
+    /// Respond with problem details, see [`Error::problem`]. Unlike with
+    /// Rocket, the request is not available, so a trace ID is always
+    /// generated.
+    fn into_response(self) -> Response {
+        let trace_id = random_string(TRACE_ID_LENGTH);
+        let code = self.status_code();
+        // Log the full error to stderr, clients only get the public message
+        eprintln!("Error {} [trace {}]: {}", code, trace_id, self);
+
+        let status = StatusCode::from_u16(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let mut response = (
+            status,
+            [(header::CONTENT_TYPE, "application/problem+json")],
+            self.problem(&trace_id).to_string(),
+        )
+            .into_response();
+        if let Ok(trace_id) = HeaderValue::from_str(&trace_id) {
+            response.headers_mut().insert(TRACE_ID_HEADER, trace_id);
+        }
+        response
+    }
+}
+
+impl IntoResponse for RenderedContent {
+    fn into_response(self) -> Response {
+        let content_type = match self.render_type {
+            RenderType::Json => "application/json",
+            RenderType::Text => "text/plain; charset=utf-8",
+            RenderType::Html | RenderType::HtmlPage => "text/html; charset=utf-8",
+        };
+        ([(header::CONTENT_TYPE, content_type)], self.content).into_response()
+    }
+}
+
+fn query_params(parts: &Parts) -> HashMap<String, String> {
+    Query::<HashMap<String, String>>::try_from_uri(&parts.uri)
+        .map_or_else(|_| HashMap::new(), |Query(params)| params)
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Translations
+where
+    CommState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Infallible> {
+        let state = CommState::from_ref(state);
+        let query_language = query_params(parts).remove(LANGUAGE_PARAM);
+        let accept_language = parts
+            .headers
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok());
+
+        Ok(Translations::for_request(
+            &state.config,
+            query_language.as_deref(),
+            accept_language,
+        ))
+    }
+}
+
+/// Get a platform token from the `Authorization: Bearer` header, falling back
+/// to the query parameter `param`
+#[cfg(feature = "auth_during_comm")]
+fn platform_jwt(parts: &Parts, param: &str) -> Option<String> {
+    parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_owned)
+        .or_else(|| query_params(parts).remove(param))
+}
+
+/// Record the use of `token`, verified from `jwt`, in the replay cache of
+/// `state`. Used tokens can only be remembered in the session database when
+/// using Rocket, so a `shared` replay policy is refused.
+#[cfg(feature = "auth_during_comm")]
+fn record_use<T>(
+    state: &CommState,
+    config: &AuthDuringCommConfig,
+    jwt: &str,
+    token: &VerifiedToken<T>,
+) -> Result<bool, Error> {
+    if config.token_replay_policy().shared {
+        return Err(Error::InternalServer(
+            "Replay protection through the session database requires Rocket".to_owned(),
+        ));
+    }
+    Ok(state.replay_cache.record_token(jwt, token))
+}
+
+/// Verifies the host token like the Rocket request guard does, remembering
+/// used tokens in the [`ReplayCache`] of the [`CommState`]
+#[cfg(feature = "auth_during_comm")]
+#[async_trait]
+impl<S> FromRequestParts<S> for ValidatedHostToken
+where
+    CommState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Error> {
+        let state = CommState::from_ref(state);
+        let jwt = platform_jwt(parts, HOST_TOKEN_PARAM)
+            .ok_or_else(|| Error::Unauthorized("Missing host token".to_owned()))?;
+
+        let config = state.config.auth_during_comm_config();
+        let token = verify_host_token(config, &jwt)?;
+        if config.token_replay_policy().reject_reused_host_tokens
+            && !record_use(&state, config, &jwt, &token)?
+        {
+            return Err(Error::Unauthorized(
+                "Host token was already used".to_owned(),
+            ));
+        }
+
+        Ok(ValidatedHostToken(token.claims))
+    }
+}
+
+/// Verifies the guest token like the Rocket request guard does, remembering
+/// used tokens in the [`ReplayCache`] of the [`CommState`]
+#[cfg(feature = "auth_during_comm")]
+#[async_trait]
+impl<S> FromRequestParts<S> for ValidatedGuestToken
+where
+    CommState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Error> {
+        let state = CommState::from_ref(state);
+        let jwt = platform_jwt(parts, GUEST_TOKEN_PARAM)
+            .ok_or_else(|| Error::Unauthorized("Missing guest token".to_owned()))?;
+
+        let config = state.config.auth_during_comm_config();
+        let token = verify_guest_token(config, &jwt)?;
+        if config.token_replay_policy().reject_reused_guest_tokens
+            && !record_use(&state, config, &jwt, &token)?
+        {
+            return Err(Error::Unauthorized(
+                "Guest token was already used".to_owned(),
+            ));
+        }
+
+        Ok(ValidatedGuestToken(token.claims))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        http::{header, StatusCode},
+        response::IntoResponse,
+    };
+
+    use crate::error::{Error, TRACE_ID_HEADER};
+
+    #[test]
+    fn test_error_response() {
+        let response = Error::Forbidden("Not your room".to_owned()).into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+        assert!(response.headers().contains_key(TRACE_ID_HEADER));
+    }
+}
@@ -0,0 +1,219 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use verder_helpen_proto::{ClientUrlResponse, StartRequestAuthOnly};
+
+use crate::{
+    config::Config,
+    error::{CoreError, Error, TRACE_ID_HEADER},
+    jwt::sign_start_auth_request,
+    request_id::RequestId,
+};
+
+/// Timeout and retry settings for requests to the core, configured through
+/// `core_requests` in the auth during comm configuration. Connection failures,
+/// timeouts and server errors are retried with exponential backoff; other
+/// errors are not.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CoreRequestPolicy {
+    /// Timeout for a single request in milliseconds
+    pub timeout_ms: u64,
+    /// Number of times a failed request is retried
+    pub max_retries: u32,
+    /// Delay before the first retry in milliseconds, doubled for every next
+    /// retry
+    pub initial_backoff_ms: u64,
+    /// Whether the readiness route checks that the core is reachable
+    pub readiness_check: bool,
+}
+
+impl Default for CoreRequestPolicy {
+    fn default() -> Self {
+        CoreRequestPolicy {
+            timeout_ms: 10_000,
+            max_retries: 3,
+            initial_backoff_ms: 200,
+            readiness_check: false,
+        }
+    }
+}
+
+impl CoreRequestPolicy {
+    /// Delay before retry number `retry`, counting from zero
+    fn backoff(&self, retry: u32) -> Duration {
+        Duration::from_millis(self.initial_backoff_ms.saturating_mul(1 << retry.min(16)))
+    }
+}
+
+/// Send the request built by `build` according to `policy`, retrying transient
+/// failures. Fails with [`CoreError::Unreachable`] if the core could not be
+/// reached, and with [`CoreError::Rejected`] if it did not accept the request.
+async fn send_with_retries(
+    policy: &CoreRequestPolicy,
+    build: impl Fn() -> reqwest::RequestBuilder,
+) -> Result<reqwest::Response, Error> {
+    let mut retry = 0;
+    loop {
+        let result = build()
+            .timeout(Duration::from_millis(policy.timeout_ms))
+            .send()
+            .await;
+        let error: Error = match result {
+            Ok(response) if response.status().is_success() => return Ok(response),
+            Ok(response) if !response.status().is_server_error() => {
+                return Err(CoreError::Rejected(response.status().as_u16()).into())
+            }
+            Ok(response) => CoreError::Rejected(response.status().as_u16()).into(),
+            Err(e) => CoreError::Unreachable(e).into(),
+        };
+
+        if retry >= policy.max_retries {
+            return Err(error);
+        }
+        eprintln!("Request to core failed, retrying: {}", error);
+        tokio::time::sleep(policy.backoff(retry)).await;
+        retry += 1;
+    }
+}
+
+/// Response of the core to a start authentication request
+#[derive(Deserialize)]
+struct StartResponse {
+    #[serde(flatten)]
+    client_url: ClientUrlResponse,
+    /// ID of the started authentication session, for cores that return it
+    session_id: Option<String>,
+}
+
+/// Authentication session started at the core, see
+/// [`start_authentication_session`]
+#[derive(Debug)]
+pub struct CoreAuthSession {
+    /// URL to which the guest must be sent to authenticate
+    pub client_url: ClientUrlResponse,
+    /// Handle identifying the authentication session at the core: the session
+    /// ID returned by the core, or the client URL if it returned none
+    pub core_session_id: String,
+}
+
+/// Ask the Verder Helpen core to start authentication for `purpose`, through
+/// `auth_method`, the method chosen by the guest to provide the attributes of
+/// the purpose. The core sends the attributes to `callback_url`, and the guest
+/// back to the external guest URL. Returns the URL to which the guest must be
+/// sent to authenticate.
+pub async fn start_authentication(
+    config: &Config,
+    purpose: &str,
+    auth_method: &str,
+    callback_url: &str,
+) -> Result<ClientUrlResponse, Error> {
+    let request = StartRequestAuthOnly {
+        purpose: purpose.to_owned(),
+        auth_method: auth_method.to_owned(),
+        comm_url: config.external_guest_url().to_owned(),
+        attr_url: Some(callback_url.to_owned()),
+    };
+    start_authentication_request(config, request).await
+}
+
+/// Ask the Verder Helpen core to start authentication for `request`, signed
+/// with the configured start authentication key. Returns the URL to which the
+/// guest must be sent to authenticate.
+pub async fn start_authentication_request(
+    config: &Config,
+    request: StartRequestAuthOnly,
+) -> Result<ClientUrlResponse, Error> {
+    Ok(start_authentication_session(config, request)
+        .await?
+        .client_url)
+}
+
+/// Start authentication like [`start_authentication_request`], additionally
+/// returning the handle of the authentication session at the core, to be
+/// stored with the comm session, e.g. through `Session::mark_auth_started_with`
+pub async fn start_authentication_session(
+    config: &Config,
+    request: StartRequestAuthOnly,
+) -> Result<CoreAuthSession, Error> {
+    start_authentication_session_with(config, request, None).await
+}
+
+/// Start authentication like [`start_authentication_session`], forwarding
+/// `request_id` to the core in the `X-Request-Id` header, so that the request
+/// can be traced across the plugin and the core
+pub async fn start_authentication_session_with(
+    config: &Config,
+    request: StartRequestAuthOnly,
+    request_id: Option<&RequestId>,
+) -> Result<CoreAuthSession, Error> {
+    let auth_during_comm_config = config.auth_during_comm_config();
+    let signed = sign_start_auth_request(
+        request,
+        auth_during_comm_config.start_auth_key_id(),
+        auth_during_comm_config.start_auth_signer(),
+    )?;
+
+    let client = reqwest::Client::new();
+    let url = format!(
+        "{}/start",
+        auth_during_comm_config.core_url().trim_end_matches('/')
+    );
+    #[cfg(feature = "metrics")]
+    let _timer = crate::metrics::core_request_timer("start");
+    let response = send_with_retries(auth_during_comm_config.core_request_policy(), || {
+        let builder = client
+            .post(&url)
+            .header(reqwest::header::CONTENT_TYPE, "application/jwt")
+            .body(signed.clone());
+        match request_id {
+            Some(request_id) => builder.header(TRACE_ID_HEADER, request_id.as_str()),
+            None => builder,
+        }
+    })
+    .await?;
+
+    let response: StartResponse = response.json().await.map_err(CoreError::InvalidResponse)?;
+    let core_session_id = response
+        .session_id
+        .unwrap_or_else(|| response.client_url.client_url.clone());
+    Ok(CoreAuthSession {
+        client_url: response.client_url,
+        core_session_id,
+    })
+}
+
+/// Check that the core can be reached within the configured timeout. Any
+/// response counts, as the core need not serve its base URL.
+pub async fn check_reachable(config: &Config) -> Result<(), Error> {
+    let auth_during_comm_config = config.auth_during_comm_config();
+    let policy = auth_during_comm_config.core_request_policy();
+    reqwest::Client::new()
+        .get(auth_during_comm_config.core_url())
+        .timeout(Duration::from_millis(policy.timeout_ms))
+        .send()
+        .await
+        .map_err(CoreError::Unreachable)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::CoreRequestPolicy;
+
+    #[test]
+    fn test_backoff() {
+        let policy = CoreRequestPolicy::default();
+        assert_eq!(policy.backoff(0), Duration::from_millis(200));
+        assert_eq!(policy.backoff(1), Duration::from_millis(400));
+        assert_eq!(policy.backoff(3), Duration::from_millis(1600));
+
+        let policy = CoreRequestPolicy {
+            initial_backoff_ms: u64::MAX,
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(40), Duration::from_millis(u64::MAX));
+    }
+}
@@ -1,15 +1,18 @@
 use serde::Serialize;
 use serde_json;
-use tera::Context;
 
-#[cfg(feature = "session_db")]
-use crate::session::{Session, SessionDBConn};
-#[cfg(feature = "session_db")]
-use crate::types::platform_token::{FromPlatformJwt, HostToken};
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
 use crate::{
+    audit::{self, AuditEvent, AuditEventKind},
+    session::{ActivityUpdate, Session, SessionDb},
+    types::platform_token::{unverified_instance, FromPlatformJwt, HostToken},
+};
+use crate::{
+    auth_result::decrypt_stored,
     config::Config,
     error::Error,
-    templates::{RenderType, RenderedContent, TEMPLATES},
+    render::render_attributes,
+    templates::{RenderType, RenderedContent},
     translations::Translations,
     types::{Credentials, GuestAuthResult},
 };
@@ -23,14 +26,7 @@ pub fn collect_credentials(
 
     for guest_auth_result in guest_auth_results.iter() {
         if let Some(result) = &guest_auth_result.auth_result {
-            if let Some(attributes) =
-                verder_helpen_jwt::dangerous_decrypt_auth_result_without_verifying_expiration(
-                    result,
-                    config.verifier(),
-                    config.decrypter(),
-                )?
-                .attributes
-            {
+            if let Some(attributes) = decrypt_stored(result, config)?.attributes {
                 credentials.push(Credentials {
                     name: guest_auth_result.name.clone(),
                     purpose: guest_auth_result.purpose.clone(),
@@ -68,11 +64,13 @@ impl From<Credentials> for SortedCredentials {
     }
 }
 
-/// render a list of users and credentials to html or json
+/// render a list of users and credentials to html or json, ordering and
+/// labelling attributes as configured in `[global.attribute_display]`
 pub fn render_credentials(
     credentials: Vec<Credentials>,
     render_type: RenderType,
     translations: Translations,
+    config: &Config,
 ) -> Result<RenderedContent, Error> {
     if render_type == RenderType::Json {
         let content = serde_json::to_string(&credentials)?;
@@ -82,67 +80,94 @@ pub fn render_credentials(
         });
     }
 
-    let mut context = Context::new();
-
-    let sorted_credentials: Vec<SortedCredentials> = credentials
-        .into_iter()
-        .map(SortedCredentials::from)
-        .collect();
-
-    context.insert("translations", translations.all());
-    context.insert("credentials", &sorted_credentials);
-
-    let content = if render_type == RenderType::HtmlPage {
-        TEMPLATES.render("base.html", &context)?
-    } else {
-        TEMPLATES.render("credentials.html", &context)?
-    };
-
-    Ok(RenderedContent {
-        content,
+    render_attributes(
+        credentials,
         render_type,
-    })
+        translations,
+        config.attribute_display(),
+    )
 }
 
 /// retrieve sessions for all users in a room
 /// the id of the room is provided by a host jwt
-#[cfg(feature = "session_db")]
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
 pub async fn get_sessions_for_host(
     host_token: String,
     config: &Config,
-    db: &SessionDBConn,
+    db: &impl SessionDb,
 ) -> Result<Vec<Session>, Error> {
-    let host_token = HostToken::from_platform_jwt(
-        &host_token,
-        config.auth_during_comm_config().host_verifier(),
-    )?;
+    let (_, sessions) = verified_host_sessions(host_token, config, db).await?;
+    Ok(sessions)
+}
 
-    Session::find_by_room_id(host_token.room_id, db).await
+/// Verify a host jwt, and retrieve the sessions in the room it grants access to
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+async fn verified_host_sessions(
+    host_token: String,
+    config: &Config,
+    db: &impl SessionDb,
+) -> Result<(HostToken, Vec<Session>), Error> {
+    let verifier = config
+        .auth_during_comm_config()
+        .host_verifier_for(unverified_instance(&host_token).as_deref());
+    let host_token = HostToken::from_platform_jwt(&host_token, verifier)?;
+
+    let sessions =
+        Session::find_by_room_id_with(host_token.room_id.clone(), ActivityUpdate::Preserve, db)
+            .await?;
+    Ok((host_token, sessions))
 }
 
 /// retrieve authentication results for all users in a room
 /// the id of the room is provided by a host jwt
-#[cfg(feature = "session_db")]
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
 pub async fn get_credentials_for_host(
     host_token: String,
     config: &Config,
-    db: &SessionDBConn,
+    db: &impl SessionDb,
 ) -> Result<Vec<Credentials>, Error> {
-    let sessions = get_sessions_for_host(host_token, config, db).await?;
+    let (host_token, sessions) = verified_host_sessions(host_token, config, db).await?;
+    Ok(credentials_for_host(&host_token, sessions))
+}
+
+/// Authentication results in `sessions`, as viewed by the host of
+/// `host_token`. Sessions of other platform instances than that of the host
+/// are left out, as room IDs are only unique within an instance. Every viewed
+/// result is recorded in the audit log. Viewing does not mark the sessions as
+/// active.
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+pub(crate) fn credentials_for_host(
+    host_token: &HostToken,
+    sessions: Vec<Session>,
+) -> Vec<Credentials> {
+    let sessions: Vec<Session> = sessions
+        .into_iter()
+        .filter(|session| session.guest_token.instance == host_token.instance)
+        .collect();
     for session in &sessions {
-        session.mark_active(db).await?;
+        if session.auth_result.is_some() {
+            audit::record(
+                AuditEvent::new(AuditEventKind::ResultViewed)
+                    .session(
+                        session.guest_token.room_id.as_str(),
+                        session.guest_token.id.as_str(),
+                    )
+                    .actor(&host_token.id),
+            );
+        }
     }
 
-    let guest_auth_results = sessions
+    sessions
         .into_iter()
-        .map(|session: Session| GuestAuthResult {
-            purpose: Some(session.guest_token.purpose),
-            name: Some(session.guest_token.name),
-            auth_result: session.auth_result,
+        .filter_map(|session: Session| {
+            let attributes = session.auth_result?.attributes?;
+            Some(Credentials {
+                purpose: Some(session.guest_token.purpose),
+                name: Some(session.guest_token.name),
+                attributes,
+            })
         })
-        .collect::<Vec<GuestAuthResult>>();
-
-    collect_credentials(&guest_auth_results, config)
+        .collect()
 }
 
 #[cfg(test)]
@@ -157,7 +182,7 @@ mod tests {
     use verder_helpen_proto::{AuthResult, AuthStatus};
 
     use super::*;
-    use crate::config::AuthDuringCommConfig;
+    use crate::{config::AuthDuringCommConfig, render::AttributeDisplay};
 
     const EC_PUBKEY: &str = r"
     type: EC
@@ -227,29 +252,14 @@ mod tests {
             auth_result: Some(jwe),
         }];
 
-        let auth_during_comm_config = AuthDuringCommConfig {
-            core_url: "https://example.com".to_string(),
-            widget_url: "https://example.com".to_string(),
-            display_name: "comm-common".to_string(),
+        let auth_during_comm_config = AuthDuringCommConfig::new_for_test(
             widget_signer,
             start_auth_signer,
-            start_auth_key_id: "not-needed".into(),
-            guest_verifier: Box::new(guest_verifier),
-            host_verifier: Box::new(host_verifier),
-        };
+            Box::new(guest_verifier),
+            Box::new(host_verifier),
+        );
 
-        let config: Config = Config {
-            internal_url: "https://example.com".to_string(),
-            external_host_url: None,
-            external_guest_url: None,
-            sentry_dsn: None,
-            default_locale: String::from("nl"),
-            translations: HashMap::new(),
-            decrypter,
-            auth_provider: None,
-            verifier,
-            auth_during_comm_config,
-        };
+        let config = Config::new_for_test(decrypter, verifier, auth_during_comm_config);
 
         let translations = Translations {
             translations: HashMap::from([
@@ -263,7 +273,8 @@ mod tests {
 
         let credentials = collect_credentials(&guest_auth_results, &config).unwrap();
         let out_result =
-            render_credentials(credentials, RenderType::Html, translations.clone()).unwrap();
+            render_credentials(credentials, RenderType::Html, translations.clone(), &config)
+                .unwrap();
         let result: &str = "<sectionclass=\"credentials\"><h4>HenkDieter</\
                             h4><dl><dt><span>Leeftijd</span></dt><dd><span>42</span></\
                             dd><dt><span>E-mailadres</span></dt><dd><span>hd@example.com</span></\
@@ -275,8 +286,13 @@ mod tests {
         );
 
         let credentials = collect_credentials(&guest_auth_results, &config).unwrap();
-        let out_result =
-            render_credentials(credentials, RenderType::HtmlPage, translations.clone()).unwrap();
+        let out_result = render_credentials(
+            credentials,
+            RenderType::HtmlPage,
+            translations.clone(),
+            &config,
+        )
+        .unwrap();
         let result: &str = "<!doctypehtml><htmllang=\"en\"><head><metacharset=\"utf-8\"\
                             ><metaname=\"viewport\"content=\"width=device-width,initial-scale=1\"\
                             ><title>Gegevens</title></head><body><main><divclass=\"attributes\"\
@@ -292,7 +308,8 @@ mod tests {
 
         let credentials = collect_credentials(&guest_auth_results, &config).unwrap();
         let rendered =
-            render_credentials(credentials, RenderType::Json, translations.clone()).unwrap();
+            render_credentials(credentials, RenderType::Json, translations.clone(), &config)
+                .unwrap();
         let result: serde_json::Value = serde_json::from_str(rendered.content()).unwrap();
         let expected = serde_json::json! {
             [{
@@ -303,5 +320,24 @@ mod tests {
         };
 
         assert_eq!(result, expected);
+
+        // Attributes are ordered and labelled as configured
+        let mut config = config;
+        config.attribute_display = AttributeDisplay {
+            order: vec!["email".to_string(), "age".to_string()],
+            labels: HashMap::from([("age".to_string(), "Leeftijd in jaren".to_string())]),
+        };
+        let credentials = collect_credentials(&guest_auth_results, &config).unwrap();
+        let out_result =
+            render_credentials(credentials, RenderType::Html, translations, &config).unwrap();
+        let result: &str = "<sectionclass=\"credentials\"><h4>HenkDieter</\
+                            h4><dl><dt><span>E-mailadres</span></dt><dd><span>hd@example.com</\
+                            span></dd><dt><span>Leeftijdinjaren</span></dt><dd><span>42</span></\
+                            dd></dl></section>";
+
+        assert_eq!(
+            remove_whitespace(result),
+            remove_whitespace(out_result.content())
+        );
     }
 }
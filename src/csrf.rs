@@ -0,0 +1,242 @@
+use std::{
+    fmt::Debug,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use josekit::jws::{alg::hmac::HmacJwsAlgorithm, JwsSigner};
+#[cfg(feature = "rocket")]
+use rocket::{
+    data::{self, Data, FromData},
+    fairing::{AdHoc, Fairing},
+    form::{Form, FromForm},
+    http::Status,
+    outcome::Outcome,
+    request::Request,
+};
+
+#[cfg(feature = "rocket")]
+use crate::config::CurrentConfig;
+use crate::{error::Error, jwt::JwtError};
+
+/// Time for which minted CSRF tokens are accepted, unless configured otherwise
+pub const DEFAULT_CSRF_TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Mints and verifies CSRF tokens bound to a session. A token holds its
+/// expiry time and a signature over the session ID and that expiry time.
+/// Must be managed by Rocket, e.g. through [`csrf_fairing`], for [`CsrfForm`]
+/// to work.
+pub struct CsrfProtection {
+    signer: Box<dyn JwsSigner>,
+    lifetime: Duration,
+}
+
+impl Clone for CsrfProtection {
+    fn clone(&self) -> Self {
+        CsrfProtection {
+            signer: self.signer.box_clone(),
+            lifetime: self.lifetime,
+        }
+    }
+}
+
+impl Debug for CsrfProtection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CsrfProtection")
+            .field("algorithm", &self.signer.algorithm().name())
+            .field("lifetime", &self.lifetime)
+            .finish()
+    }
+}
+
+/// Signed message of a token for `session_id`, expiring at `expires_at`
+fn message(session_id: &str, expires_at: u64) -> String {
+    format!("{}.{}", session_id, expires_at)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Compare in time independent of where the first difference is
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs())
+}
+
+impl CsrfProtection {
+    /// CSRF protection signing with `signer`. Tokens are verified by signing
+    /// again, so the signer must be deterministic, such as HS256 or RS256.
+    pub fn new(signer: Box<dyn JwsSigner>) -> Result<Self, Error> {
+        let probe = b"csrf";
+        if signer.sign(probe).map_err(JwtError::from)?
+            != signer.sign(probe).map_err(JwtError::from)?
+        {
+            return Err(Error::Config(format!(
+                "CSRF tokens can't be signed with {}, as its signatures are not deterministic",
+                signer.algorithm().name()
+            )));
+        }
+
+        Ok(CsrfProtection {
+            signer,
+            lifetime: DEFAULT_CSRF_TOKEN_LIFETIME,
+        })
+    }
+
+    /// CSRF protection using HMAC with `secret`
+    pub fn from_secret(secret: &[u8]) -> Result<Self, Error> {
+        let signer = HmacJwsAlgorithm::Hs256
+            .signer_from_bytes(secret)
+            .map_err(JwtError::from)?;
+        Self::new(Box::new(signer))
+    }
+
+    pub fn with_lifetime(self, lifetime: Duration) -> Self {
+        CsrfProtection { lifetime, ..self }
+    }
+
+    fn signature(&self, session_id: &str, expires_at: u64) -> Result<Vec<u8>, Error> {
+        Ok(self
+            .signer
+            .sign(message(session_id, expires_at).as_bytes())
+            .map_err(JwtError::from)?)
+    }
+
+    /// Token for a form rendered for the session `session_id`, to be included
+    /// as a hidden `csrf_token` field
+    pub fn mint(&self, session_id: &str) -> Result<String, Error> {
+        self.mint_at(session_id, SystemTime::now())
+    }
+
+    fn mint_at(&self, session_id: &str, now: SystemTime) -> Result<String, Error> {
+        let expires_at = unix_time(now + self.lifetime);
+        let signature = self.signature(session_id, expires_at)?;
+        Ok(format!("{}.{}", expires_at, to_hex(&signature)))
+    }
+
+    /// Check that `token` was minted for the session `session_id` and has not
+    /// expired
+    pub fn verify(&self, token: &str, session_id: &str) -> Result<(), Error> {
+        self.verify_at(token, session_id, SystemTime::now())
+    }
+
+    fn verify_at(&self, token: &str, session_id: &str, now: SystemTime) -> Result<(), Error> {
+        let invalid = || Error::Forbidden("Invalid CSRF token".to_owned());
+        let (expires_at, signature) = token.split_once('.').ok_or_else(invalid)?;
+        let expires_at: u64 = expires_at.parse().map_err(|_| invalid())?;
+
+        let expected = to_hex(&self.signature(session_id, expires_at)?);
+        if !constant_time_eq(expected.as_bytes(), signature.as_bytes()) {
+            return Err(invalid());
+        }
+        if expires_at <= unix_time(now) {
+            return Err(Error::Forbidden("CSRF token expired".to_owned()));
+        }
+        Ok(())
+    }
+}
+
+/// Form carrying a CSRF token
+pub trait CsrfProtected {
+    /// The token submitted with the form, usually from a hidden `csrf_token`
+    /// field
+    fn csrf_token(&self) -> &str;
+
+    /// ID of the session the form acts on, which the token must be minted
+    /// for. This should identify the session of the authenticated user, such
+    /// as the ID in a verified host token.
+    fn csrf_session_id(&self) -> &str;
+}
+
+/// Form data guard accepting a form only if it carries a valid CSRF token.
+/// Requests with a missing, invalid or expired token are refused with 403
+/// Forbidden. Requires a [`CsrfProtection`] to be managed.
+#[derive(Debug)]
+pub struct CsrfForm<T>(pub T);
+
+impl<T> CsrfForm<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+#[cfg(feature = "rocket")]
+#[rocket::async_trait]
+impl<'r, T: FromForm<'r> + CsrfProtected> FromData<'r> for CsrfForm<T> {
+    type Error = Error;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let csrf = match request.rocket().state::<CsrfProtection>() {
+            Some(csrf) => csrf,
+            None => {
+                return Outcome::Error((
+                    Status::InternalServerError,
+                    Error::InternalServer("No CSRF protection configured".to_owned()),
+                ))
+            }
+        };
+
+        let form = match Form::<T>::from_data(request, data).await {
+            Outcome::Success(form) => form.into_inner(),
+            Outcome::Error((status, _)) => {
+                return Outcome::Error((status, Error::BadRequest("Invalid form")))
+            }
+            Outcome::Forward(forward) => return Outcome::Forward(forward),
+        };
+
+        match csrf.verify(form.csrf_token(), form.csrf_session_id()) {
+            Ok(()) => Outcome::Success(CsrfForm(form)),
+            Err(e) => Outcome::Error((Status::Forbidden, e)),
+        }
+    }
+}
+
+/// Fairing managing a [`CsrfProtection`] using the configured `csrf_secret`.
+/// Does nothing if no secret is configured. Requires the configuration to be
+/// managed.
+#[cfg(feature = "rocket")]
+pub fn csrf_fairing() -> impl Fairing {
+    AdHoc::on_ignite("CSRF protection", |rocket| {
+        Box::pin(async move {
+            let csrf = CurrentConfig::from_rocket(&rocket)
+                .expect("No configuration found")
+                .csrf()
+                .cloned();
+            match csrf {
+                Some(csrf) => rocket.manage(csrf),
+                None => rocket,
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::CsrfProtection;
+
+    #[test]
+    fn test_csrf_tokens() {
+        let csrf = CsrfProtection::from_secret(b"fliepfliepfliepfliepfliepfliepfliepfliep")
+            .unwrap()
+            .with_lifetime(Duration::from_secs(60));
+        let now = SystemTime::now();
+
+        let token = csrf.mint_at("session", now).unwrap();
+        assert!(csrf.verify_at(&token, "session", now).is_ok());
+        assert!(csrf.verify_at(&token, "other session", now).is_err());
+        assert!(csrf
+            .verify_at(&token, "session", now + Duration::from_secs(61))
+            .is_err());
+
+        let (expires_at, signature) = token.split_once('.').unwrap();
+        let extended = format!("{}.{}", expires_at.parse::<u64>().unwrap() + 60, signature);
+        assert!(csrf.verify_at(&extended, "session", now).is_err());
+        assert!(csrf.verify_at("garbage", "session", now).is_err());
+    }
+}
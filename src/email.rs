@@ -0,0 +1,254 @@
+use std::fmt::Debug;
+#[cfg(feature = "rocket")]
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+#[cfg(feature = "rocket")]
+use rocket::fairing::Fairing;
+use serde::Deserialize;
+
+#[cfg(feature = "rocket")]
+use crate::sinks::sink_fairing;
+use crate::{
+    auth_result::StoredAuthResult,
+    config::{Config, ConfigValidation},
+    error::Error,
+    render::{render_auth_result, AttributeDisplay},
+    secrets::Secret,
+    session::Session,
+    sinks::ResultSink,
+    templates::RenderType,
+    translations::Translations,
+};
+
+/// Port of the SMTP relay, unless configured otherwise
+const DEFAULT_SMTP_PORT: u16 = 587;
+
+/// Subject of mailed authentication results, unless configured otherwise
+const DEFAULT_SUBJECT: &str = "Verder Helpen authentication result";
+
+/// SMTP settings for mailing authentication results, configured through
+/// `[global.email]`
+#[derive(Deserialize, Debug)]
+pub struct RawEmailConfig {
+    /// Host name of the SMTP relay, reached over STARTTLS
+    smtp_host: String,
+    smtp_port: Option<u16>,
+    smtp_username: Option<String>,
+    smtp_password: Option<Secret>,
+    /// Sender of the mails, e.g. "Verder Helpen <noreply@example.com>"
+    from: String,
+    /// Address of the host receiving the authentication results
+    to: String,
+    subject: Option<String>,
+}
+
+/// Checked SMTP settings for mailing authentication results
+#[derive(Clone)]
+pub struct EmailConfig {
+    smtp_host: String,
+    smtp_port: u16,
+    smtp_credentials: Option<Credentials>,
+    from: Mailbox,
+    to: Mailbox,
+    subject: String,
+}
+
+impl Debug for EmailConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EmailConfig")
+            .field("smtp_host", &self.smtp_host)
+            .field("smtp_port", &self.smtp_port)
+            .field("from", &self.from)
+            .field("to", &self.to)
+            .field("subject", &self.subject)
+            .finish()
+    }
+}
+
+/// Parse the mail address configured at `email.<key>`
+fn mailbox(key: &str, address: &str, validation: &mut ConfigValidation) -> Option<Mailbox> {
+    let result = address
+        .parse()
+        .map_err(|e| Error::Config(format!("invalid address {:?}: {}", address, e)));
+    validation.check(&format!("email.{}", key), result)
+}
+
+impl EmailConfig {
+    /// Check the raw configuration, recording all problems in `validation`
+    pub(crate) fn validate(
+        raw_config: RawEmailConfig,
+        validation: &mut ConfigValidation,
+    ) -> Option<EmailConfig> {
+        let from = mailbox("from", &raw_config.from, validation);
+        let to = mailbox("to", &raw_config.to, validation);
+
+        let smtp_credentials = match (raw_config.smtp_username, raw_config.smtp_password) {
+            (Some(username), Some(password)) => validation
+                .check("email.smtp_password", password.resolve())
+                .map(|password| Some(Credentials::new(username, password))),
+            (None, None) => Some(None),
+            _ => {
+                validation.problem(
+                    "email: smtp_username and smtp_password must be set together".to_string(),
+                );
+                None
+            }
+        };
+
+        Some(EmailConfig {
+            smtp_host: raw_config.smtp_host,
+            smtp_port: raw_config.smtp_port.unwrap_or(DEFAULT_SMTP_PORT),
+            smtp_credentials: smtp_credentials?,
+            from: from?,
+            to: to?,
+            subject: raw_config
+                .subject
+                .unwrap_or_else(|| DEFAULT_SUBJECT.to_string()),
+        })
+    }
+
+    pub fn to(&self) -> &Mailbox {
+        &self.to
+    }
+
+    /// Mail holding `body` as plain text, from and to the configured addresses
+    fn message(&self, body: String) -> Result<Message, Error> {
+        Message::builder()
+            .from(self.from.clone())
+            .to(self.to.clone())
+            .subject(&self.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+            .map_err(|e| Error::Email(e.to_string()))
+    }
+}
+
+/// Result sink mailing the rendered attributes of authentication results to
+/// the configured host address
+pub struct AuthResultMailer {
+    config: EmailConfig,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    translations: Translations,
+    display: AttributeDisplay,
+}
+
+impl AuthResultMailer {
+    /// Mailer for the email settings `email`, rendering attributes in the
+    /// default locale of `config`
+    pub fn new(email: EmailConfig, config: &Config) -> Result<Self, Error> {
+        let mut transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&email.smtp_host)
+            .map_err(|e| Error::Email(e.to_string()))?
+            .port(email.smtp_port);
+        if let Some(credentials) = &email.smtp_credentials {
+            transport = transport.credentials(credentials.clone());
+        }
+
+        Ok(AuthResultMailer {
+            config: email,
+            transport: transport.build(),
+            translations: Translations::for_default_locale(config),
+            display: config.attribute_display().clone(),
+        })
+    }
+
+    /// Plain text mail with the attributes of `auth_result`, registered with
+    /// `session`
+    fn message_for(
+        &self,
+        session: &Session,
+        auth_result: &StoredAuthResult,
+    ) -> Result<Message, Error> {
+        let rendered = render_auth_result(
+            auth_result,
+            Some(session.guest_token.name.clone()),
+            Some(session.guest_token.purpose.clone()),
+            RenderType::Text,
+            self.translations.clone(),
+            &self.display,
+        )?;
+        self.config.message(rendered.content)
+    }
+}
+
+#[async_trait]
+impl ResultSink for AuthResultMailer {
+    async fn deliver(
+        &self,
+        session: &Session,
+        auth_result: &StoredAuthResult,
+    ) -> Result<(), Error> {
+        let message = self.message_for(session, auth_result)?;
+        self.transport
+            .send(message)
+            .await
+            .map_err(|e| Error::Email(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Fairing mailing the rendered attributes of every authentication result to
+/// the address configured through `[global.email]`. Does nothing if no email
+/// settings are configured. Requires the [`Config`] to be managed and the
+/// [`crate::session::SessionDBConn`] fairing to be attached.
+#[cfg(feature = "rocket")]
+pub fn auth_result_mailer() -> impl Fairing {
+    sink_fairing("Auth result mailer", |config| {
+        let mut sinks: Vec<Arc<dyn ResultSink>> = vec![];
+        if let Some(email) = config.email() {
+            sinks.push(Arc::new(AuthResultMailer::new(email.clone(), config)?));
+        }
+        Ok(sinks)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EmailConfig, RawEmailConfig};
+    use crate::config::ConfigValidation;
+
+    fn validate(raw: &str) -> (Option<EmailConfig>, Result<(), crate::error::Error>) {
+        let raw_config: RawEmailConfig = serde_yaml::from_str(raw).unwrap();
+        let mut validation = ConfigValidation::default();
+        let config = EmailConfig::validate(raw_config, &mut validation);
+        (config, validation.finish())
+    }
+
+    #[test]
+    fn test_email_config() {
+        let (config, result) = validate(
+            r#"
+            smtp_host: smtp.example.com
+            from: "Verder Helpen <noreply@example.com>"
+            to: helpdesk@example.com
+            "#,
+        );
+        assert!(result.is_ok());
+        let config = config.unwrap();
+        assert_eq!(config.to().email.to_string(), "helpdesk@example.com");
+
+        let message = config.message("name: Henk Dieter\n".to_string()).unwrap();
+        let formatted = String::from_utf8(message.formatted()).unwrap();
+        assert!(formatted.contains("Henk Dieter"));
+        assert!(formatted.contains("Subject: Verder Helpen authentication result"));
+
+        let (config, result) = validate(
+            r#"
+            smtp_host: smtp.example.com
+            smtp_username: helpdesk
+            from: noreply@example.com
+            to: not an address
+            "#,
+        );
+        assert!(config.is_none());
+        match result {
+            Err(crate::error::Error::Validation(problems)) => assert_eq!(problems.len(), 2),
+            _ => panic!("Expected validation problems"),
+        }
+    }
+}
@@ -1,17 +1,24 @@
+#[cfg(feature = "rocket")]
+use std::io::Cursor;
+
+#[cfg(feature = "rocket")]
 use rocket::{
     http::{ContentType, Status},
     Response,
 };
-use rocket_sync_db_pools::postgres;
 use serde_json::json;
 use tera;
 use thiserror::Error;
 
-use crate::jwt::JwtError;
+use crate::{jwt::JwtError, templates::TEMPLATES};
 
 #[derive(Debug, Error)]
+#[non_exhaustive]
 /// General Error type, used to capture all kinds of common errors. Can be used
-/// to respond to requests
+/// to respond to requests. Errors from other libraries are kept as the source
+/// of their category, so matching on the category is stable while its causes
+/// may change. New variants may be added, so matches outside this crate need a
+/// wildcard arm.
 pub enum Error {
     #[error("Not found")]
     NotFound,
@@ -19,61 +26,269 @@ pub enum Error {
     BadRequest(&'static str),
     #[error("Forbidden: {0}")]
     Forbidden(String),
+    #[error("Conflict: {0}")]
+    Conflict(&'static str),
     #[error("Unauthorized: {0}")]
     Unauthorized(String),
+    #[error("Too many requests")]
+    TooManyRequests,
     #[error("Internal Server: {0}")]
     InternalServer(String),
-    #[error("JWE Error: {0}")]
-    Jwe(#[from] JwtError),
-    #[error("Postgres Error: {0}")]
-    Postgres(#[from] postgres::Error),
-    #[error("Reqwest Error: {0}")]
-    Reqwest(#[from] reqwest::Error),
+    #[error("Configuration Error: {0}")]
+    Config(String),
+    #[error("Invalid configuration: {}", .0.join("; "))]
+    Validation(Vec<String>),
+    #[error("Core Error: {0}")]
+    Core(#[from] CoreError),
+    #[error("JWT Error: {0}")]
+    Jwt(#[from] JwtError),
+    #[error("Database Error: {0}")]
+    Database(#[from] DatabaseError),
+    #[error("HTTP Error: {0}")]
+    Http(#[from] reqwest::Error),
     #[error("JSON Error: {0}")]
     Json(#[from] serde_json::Error),
     #[error("Parse Error: {0}")]
     Parse(#[from] strum::ParseError),
     #[error("Template Error: {0}")]
     Template(#[from] tera::Error),
+    #[cfg(feature = "email")]
+    #[error("Email Error: {0}")]
+    Email(String),
+}
+
+/// Failure to use the session database
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum DatabaseError {
+    #[cfg(feature = "sessions")]
+    #[error("{0}")]
+    Query(#[from] postgres::Error),
+    #[error("no connection available: {0}")]
+    Unavailable(String),
+    /// The database lacks columns or indexes the queries rely on
+    #[error("schema out of date: {0}")]
+    Schema(String),
+}
+
+impl DatabaseError {
+    /// Whether the database could not be reached, e.g. during a failover, so
+    /// that retrying later may succeed
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            #[cfg(feature = "sessions")]
+            DatabaseError::Query(e) => e.is_closed(),
+            DatabaseError::Unavailable(_) => true,
+            DatabaseError::Schema(_) => false,
+        }
+    }
+}
+
+/// Failure of a request to the Verder Helpen core
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum CoreError {
+    #[error("core unreachable: {0}")]
+    Unreachable(#[source] reqwest::Error),
+    #[error("core rejected request with status {0}")]
+    Rejected(u16),
+    #[error("invalid response from core: {0}")]
+    InvalidResponse(#[source] reqwest::Error),
+}
+
+/// Header from which the trace ID of a request is taken, if present
+pub(crate) const TRACE_ID_HEADER: &str = "X-Request-Id";
+
+/// Length of generated trace IDs
+pub(crate) const TRACE_ID_LENGTH: usize = 16;
+
+/// Reason phrase of the status codes errors are responded with
+fn reason_phrase(code: u16) -> &'static str {
+    match code {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        409 => "Conflict",
+        429 => "Too Many Requests",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+impl Error {
+    /// HTTP status code responded with for this error
+    pub fn status_code(&self) -> u16 {
+        use Error::*;
+        match self {
+            NotFound => 404,
+            BadRequest(_) | Jwt(_) => 400,
+            Forbidden(_) => 403,
+            Conflict(_) => 409,
+            Unauthorized(_) => 401,
+            TooManyRequests => 429,
+            Core(CoreError::Unreachable(_)) => 503,
+            Core(_) => 502,
+            Database(e) if e.is_unavailable() => 503,
+            _ => 500,
+        }
+    }
+
+    /// Status responded with for this error
+    #[cfg(feature = "rocket")]
+    pub fn status(&self) -> Status {
+        Status::new(self.status_code())
+    }
+
+    /// Description of this error that is safe to show to clients. Internal
+    /// errors are described generically, so that no details about keys,
+    /// queries or the infrastructure leak; they are only logged.
+    pub fn public_message(&self) -> String {
+        use Error::*;
+        match self {
+            NotFound | TooManyRequests => self.to_string(),
+            BadRequest(m) | Conflict(m) => m.to_string(),
+            Forbidden(m) | Unauthorized(m) => m.to_string(),
+            Jwt(_) => "The token could not be verified or decrypted".to_string(),
+            Core(CoreError::Unreachable(_)) => "The Verder Helpen core is unreachable".to_string(),
+            Core(_) => "The Verder Helpen core rejected the request".to_string(),
+            Database(e) if e.is_unavailable() => {
+                "The session database is temporarily unavailable".to_string()
+            }
+            _ => "An internal error occurred".to_string(),
+        }
+    }
+
+    /// Problem details (RFC 7807) describing this error
+    pub fn problem(&self, trace_id: &str) -> serde_json::Value {
+        let code = self.status_code();
+        json!({
+            "type": "about:blank",
+            "title": reason_phrase(code),
+            "status": code,
+            "detail": self.public_message(),
+            "trace_id": trace_id,
+        })
+    }
+
+    /// HTML error page describing this error, rendered from `error.html`
+    pub fn html_page(&self, trace_id: &str) -> Result<String, tera::Error> {
+        let code = self.status_code();
+        let mut context = tera::Context::new();
+        context.insert("status", &code);
+        context.insert("title", reason_phrase(code));
+        context.insert("detail", &self.public_message());
+        context.insert("trace_id", trace_id);
+        TEMPLATES.render("error.html", &context)
+    }
 }
 
+#[cfg(feature = "rocket")]
 impl<'r, 'o: 'r> rocket::response::Responder<'r, 'o> for Error {
     fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
-        use Error::*;
-        let (message, status) = match &self {
-            NotFound => ("Not found".to_string(), Status::NotFound),
-            BadRequest(m) => (m.to_string(), Status::BadRequest),
-            Forbidden(m) => (m.to_string(), Status::Forbidden),
-            Unauthorized(m) => (m.to_string(), Status::Unauthorized),
-            InternalServer(m) => (m.to_string(), Status::InternalServerError),
-            Jwe(m) => (m.to_string(), Status::BadRequest),
-            Template(m) => (m.to_string(), Status::InternalServerError),
-            _ => return rocket::response::Debug::from(self).respond_to(request),
-        };
+        #[cfg(feature = "sentry")]
+        crate::reporting::report_error(&self, request);
 
-        // Log the error to stderr
-        eprintln!("Error {}", message);
+        let status = self.status();
+        let trace_id = crate::request_id::request_id(request).as_str().to_owned();
 
-        if request.headers().get_one("Accept") == Some("application/json") {
-            let body = json!({ "error": status.to_string() });
+        // Log the full error to stderr, clients only get the public message
+        eprintln!("Error {} [trace {}]: {}", status.code, trace_id, self);
 
-            return Ok(Response::build_from(body.respond_to(request).unwrap())
-                .status(status)
-                .header(ContentType::JSON)
-                .finalize());
-        }
+        let preferred = request
+            .accept()
+            .map(|accept| accept.preferred().media_type());
+        let (body, content_type) = match preferred {
+            Some(media_type) if media_type.is_json() || media_type.sub() == "problem+json" => (
+                self.problem(&trace_id).to_string(),
+                ContentType::new("application", "problem+json"),
+            ),
+            Some(media_type) if media_type.is_html() => match self.html_page(&trace_id) {
+                Ok(page) => (page, ContentType::HTML),
+                Err(e) => {
+                    eprintln!("Could not render error page: {}", e);
+                    (self.public_message(), ContentType::Text)
+                }
+            },
+            _ => (self.public_message(), ContentType::Text),
+        };
 
-        Ok(
-            Response::build_from(status.to_string().respond_to(request).unwrap())
-                .status(status)
-                .header(ContentType::Text)
-                .finalize(),
-        )
+        Ok(Response::build()
+            .status(status)
+            .header(content_type)
+            .raw_header(TRACE_ID_HEADER, trace_id)
+            .sized_body(body.len(), Cursor::new(body))
+            .finalize())
     }
 }
 
 impl From<verder_helpen_jwt::Error> for Error {
     fn from(e: verder_helpen_jwt::Error) -> Self {
-        Error::Jwe(JwtError::Jwe(e))
+        Error::Jwt(JwtError::Jwe(e))
+    }
+}
+
+#[cfg(feature = "sessions")]
+impl From<postgres::Error> for Error {
+    fn from(e: postgres::Error) -> Self {
+        Error::Database(DatabaseError::Query(e))
+    }
+}
+
+#[cfg(feature = "async-db")]
+impl From<deadpool_postgres::PoolError> for Error {
+    fn from(e: deadpool_postgres::PoolError) -> Self {
+        match e {
+            deadpool_postgres::PoolError::Backend(e) => DatabaseError::Query(e).into(),
+            e => DatabaseError::Unavailable(e.to_string()).into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CoreError, DatabaseError, Error};
+
+    #[test]
+    fn test_problem_details() {
+        let problem = Error::Forbidden("Not your room".to_owned()).problem("trace");
+        assert_eq!(problem["status"], 403);
+        assert_eq!(problem["title"], "Forbidden");
+        assert_eq!(problem["detail"], "Not your room");
+        assert_eq!(problem["trace_id"], "trace");
+
+        let internal = Error::Config("secret key file /etc/key.pem unreadable".to_owned());
+        assert_eq!(internal.status_code(), 500);
+        assert!(!internal.problem("trace")["detail"]
+            .as_str()
+            .unwrap()
+            .contains("key.pem"));
+
+        let page = internal.html_page("trace").unwrap();
+        assert!(page.contains("trace"));
+        assert!(!page.contains("key.pem"));
+    }
+
+    #[test]
+    fn test_error_categories() {
+        let rejected = Error::from(CoreError::Rejected(418));
+        assert_eq!(rejected.status_code(), 502);
+        assert!(matches!(rejected, Error::Core(CoreError::Rejected(418))));
+        assert_eq!(
+            rejected.to_string(),
+            "Core Error: core rejected request with status 418"
+        );
+
+        let unavailable = Error::from(DatabaseError::Unavailable("timed out".to_owned()));
+        assert_eq!(unavailable.status_code(), 503);
+        assert!(!unavailable.public_message().contains("timed out"));
+        assert_eq!(
+            Error::from(DatabaseError::Schema("session".to_owned())).status_code(),
+            500
+        );
+
+        let invalid = Error::Validation(vec!["a".to_owned(), "b".to_owned()]);
+        assert_eq!(invalid.to_string(), "Invalid configuration: a; b");
     }
 }
@@ -0,0 +1,139 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Number of events kept for subscribers that fall behind. Subscribers missing
+/// more events skip ahead to the most recent ones.
+const EVENT_CAPACITY: usize = 1024;
+
+lazy_static! {
+    static ref ROOM_EVENTS: broadcast::Sender<RoomEvent> = broadcast::channel(EVENT_CAPACITY).0;
+}
+
+/// Kind of change to a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomEventKind {
+    /// The session was created
+    SessionCreated,
+    /// An authentication result was registered with the session
+    AuthResult,
+    /// The authentication result of the session was reset, and the guest
+    /// will authenticate again
+    AuthReset,
+    /// The session expired, and was or will be removed
+    SessionExpired,
+}
+
+impl RoomEventKind {
+    /// Name of the event, as used in event streams
+    pub fn name(&self) -> &'static str {
+        match self {
+            RoomEventKind::SessionCreated => "session_created",
+            RoomEventKind::AuthResult => "auth_result",
+            RoomEventKind::AuthReset => "auth_reset",
+            RoomEventKind::SessionExpired => "session_expired",
+        }
+    }
+}
+
+/// Change to a session, published to everyone following the session's room
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RoomEvent {
+    pub room_id: String,
+    /// Platform instance the room belongs to
+    pub instance: String,
+    pub session_id: String,
+    pub kind: RoomEventKind,
+}
+
+/// Publish an event to all current subscribers. Events are only delivered
+/// within this process; with several instances of a plugin, hosts only see
+/// events for sessions handled by the instance they are connected to.
+pub fn publish(event: RoomEvent) {
+    // Sending only fails if nobody is subscribed, in which case the event can
+    // be dropped
+    let _ = ROOM_EVENTS.send(event);
+}
+
+/// Subscribe to the events of all rooms published from now on
+pub fn subscribe() -> broadcast::Receiver<RoomEvent> {
+    ROOM_EVENTS.subscribe()
+}
+
+/// Wait for the next event in the room `room_id` of the platform instance
+/// `instance`. Returns `None` once no more events can be published.
+pub async fn next_in_room(
+    events: &mut broadcast::Receiver<RoomEvent>,
+    room_id: &str,
+    instance: &str,
+) -> Option<RoomEvent> {
+    loop {
+        match events.recv().await {
+            Ok(event) if event.room_id == room_id && event.instance == instance => {
+                return Some(event)
+            }
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Wait for the next event of the given kind in any room. Returns `None` once
+/// no more events can be published. Events missed by falling behind are
+/// skipped.
+pub async fn next_of_kind(
+    events: &mut broadcast::Receiver<RoomEvent>,
+    kind: RoomEventKind,
+) -> Option<RoomEvent> {
+    loop {
+        match events.recv().await {
+            Ok(event) if event.kind == kind => return Some(event),
+            Ok(_) => continue,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                eprintln!("Skipped {} session events", missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{next_in_room, next_of_kind, publish, subscribe, RoomEvent, RoomEventKind};
+
+    #[test]
+    fn test_room_events() {
+        tokio_test::block_on(async {
+            let mut events = subscribe();
+            let rooms = [("other", "here"), ("room", "elsewhere"), ("room", "here")];
+            for (room_id, instance) in rooms {
+                publish(RoomEvent {
+                    room_id: room_id.to_owned(),
+                    instance: instance.to_owned(),
+                    session_id: "session".to_owned(),
+                    kind: RoomEventKind::AuthResult,
+                });
+            }
+
+            let event = next_in_room(&mut events, "room", "here").await.unwrap();
+            assert_eq!(event.room_id, "room");
+            assert_eq!(event.instance, "here");
+            assert_eq!(event.kind.name(), "auth_result");
+
+            let mut events = subscribe();
+            for kind in [RoomEventKind::SessionCreated, RoomEventKind::AuthResult] {
+                publish(RoomEvent {
+                    room_id: "room".to_owned(),
+                    instance: "here".to_owned(),
+                    session_id: "session".to_owned(),
+                    kind,
+                });
+            }
+            let event = next_of_kind(&mut events, RoomEventKind::AuthResult)
+                .await
+                .unwrap();
+            assert_eq!(event.kind, RoomEventKind::AuthResult);
+        });
+    }
+}
@@ -0,0 +1,194 @@
+use std::{ops::Range, time::SystemTime};
+
+use serde::{Serialize, Serializer};
+use strum_macros::Display;
+use verder_helpen_proto::AuthStatus;
+
+pub use crate::util::Pseudonymizer;
+use crate::{
+    error::Error,
+    session::{Session, SessionDb, SessionState},
+};
+
+/// How far a session got
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ExportOutcome {
+    /// No authentication result was received yet
+    Pending,
+    /// A successful authentication result was received
+    Authenticated,
+    /// An unsuccessful authentication result was received
+    Failed,
+    Cancelled,
+    Expired,
+}
+
+impl ExportOutcome {
+    fn of(session: &Session) -> Self {
+        match (session.state, &session.auth_result) {
+            (SessionState::Cancelled, _) => ExportOutcome::Cancelled,
+            (SessionState::Expired, _) => ExportOutcome::Expired,
+            (_, Some(auth_result)) if matches!(auth_result.status, AuthStatus::Success) => {
+                ExportOutcome::Authenticated
+            }
+            (_, Some(_)) => ExportOutcome::Failed,
+            (_, None) => ExportOutcome::Pending,
+        }
+    }
+}
+
+fn rfc3339<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&humantime::format_rfc3339_seconds(*time))
+}
+
+fn optional_rfc3339<S: Serializer>(
+    time: &Option<SystemTime>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match time {
+        Some(time) => rfc3339(time, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Metadata of a single session, without names, attributes or anything else
+/// identifying the guest. Times are serialized as RFC 3339.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ExportRecord {
+    /// Pseudonym of the session ID
+    pub session: String,
+    /// Pseudonym of the room ID
+    pub room: String,
+    pub purpose: String,
+    pub domain: String,
+    pub outcome: ExportOutcome,
+    #[serde(serialize_with = "rfc3339")]
+    pub created_at: SystemTime,
+    #[serde(serialize_with = "rfc3339")]
+    pub last_activity: SystemTime,
+    /// Time at which the authentication result was received, if any
+    #[serde(serialize_with = "optional_rfc3339")]
+    pub completed_at: Option<SystemTime>,
+}
+
+impl ExportRecord {
+    pub fn new(session: &Session, pseudonymizer: &Pseudonymizer) -> Result<Self, Error> {
+        Ok(ExportRecord {
+            session: pseudonymizer.pseudonym(&session.guest_token.id)?,
+            room: pseudonymizer.pseudonym(&session.guest_token.room_id)?,
+            purpose: session.guest_token.purpose.clone(),
+            domain: session.guest_token.domain.to_string(),
+            outcome: ExportOutcome::of(session),
+            created_at: session.created_at,
+            last_activity: session.last_activity,
+            completed_at: session
+                .auth_result
+                .as_ref()
+                .map(|auth_result| auth_result.received_at),
+        })
+    }
+}
+
+/// Pseudonymized metadata of the sessions created within `range`, in order of
+/// creation. Only covers sessions that were not removed by the cleanup yet;
+/// use [`crate::session::stats`] for counts over longer periods.
+pub async fn export(
+    range: Range<SystemTime>,
+    pseudonymizer: &Pseudonymizer,
+    db: &impl SessionDb,
+) -> Result<Vec<ExportRecord>, Error> {
+    Session::find_created_between(range, db)
+        .await?
+        .iter()
+        .map(|session| ExportRecord::new(session, pseudonymizer))
+        .collect()
+}
+
+/// Column names of [`to_csv`], in order
+const CSV_HEADER: &str =
+    "session,room,purpose,domain,outcome,created_at,last_activity,completed_at";
+
+/// Quote a CSV field if needed. Fields starting with characters spreadsheets
+/// take for a formula are prefixed with `'`, so opening an export never
+/// evaluates anything.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", value)
+    } else {
+        value.to_owned()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+/// `records` as CSV with a header line, as defined in RFC 4180
+pub fn to_csv(records: &[ExportRecord]) -> String {
+    let time = |time: SystemTime| humantime::format_rfc3339_seconds(time).to_string();
+    let mut csv = format!("{}\r\n", CSV_HEADER);
+    for record in records {
+        let fields = [
+            record.session.clone(),
+            record.room.clone(),
+            csv_field(&record.purpose),
+            csv_field(&record.domain),
+            record.outcome.to_string(),
+            time(record.created_at),
+            time(record.last_activity),
+            record.completed_at.map(time).unwrap_or_default(),
+        ];
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+/// `records` as a JSON array
+pub fn to_json(records: &[ExportRecord]) -> Result<String, Error> {
+    Ok(serde_json::to_string(records)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{to_csv, to_json, ExportOutcome, ExportRecord, Pseudonymizer};
+
+    #[test]
+    fn test_export() {
+        let pseudonymizer = Pseudonymizer::from_salt(b"thirty-two bytes of salt at least").unwrap();
+        let pseudonym = pseudonymizer.pseudonym("room-16").unwrap();
+        assert_eq!(pseudonym.len(), 64);
+        assert_eq!(pseudonym, pseudonymizer.pseudonym("room-16").unwrap());
+        assert_ne!(pseudonym, pseudonymizer.pseudonym("room-17").unwrap());
+        let other = Pseudonymizer::from_salt(b"another salt of thirty-two bytes").unwrap();
+        assert_ne!(pseudonym, other.pseudonym("room-16").unwrap());
+
+        let created_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let record = ExportRecord {
+            session: pseudonymizer.pseudonym("session-1").unwrap(),
+            room: pseudonym,
+            purpose: "=report, move".to_owned(),
+            domain: "guest".to_owned(),
+            outcome: ExportOutcome::Authenticated,
+            created_at,
+            last_activity: created_at,
+            completed_at: Some(created_at + Duration::from_secs(60)),
+        };
+
+        let csv = to_csv(&[record.clone()]);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].contains(",\"'=report, move\",guest,authenticated,"));
+        assert!(lines[1].ends_with("2023-11-14T22:13:20Z,2023-11-14T22:14:20Z"));
+
+        let json: serde_json::Value = serde_json::from_str(&to_json(&[record]).unwrap()).unwrap();
+        assert_eq!(json[0]["outcome"], "authenticated");
+        assert_eq!(json[0]["created_at"], "2023-11-14T22:13:20Z");
+        assert!(!json.to_string().contains("room-16"));
+    }
+}
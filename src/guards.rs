@@ -0,0 +1,461 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    ops::Deref,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+#[cfg(feature = "rocket")]
+use rocket::{
+    http::Status,
+    outcome::Outcome,
+    request::{self, FromRequest, Request},
+};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "rocket")]
+use crate::config::CurrentConfig;
+#[cfg(all(feature = "sessions", feature = "rocket"))]
+use crate::session::SessionDBConn;
+#[cfg(feature = "sessions")]
+use crate::session::SessionDb;
+use crate::{
+    config::AuthDuringCommConfig,
+    error::Error,
+    types::{
+        unverified_instance, verify_platform_token, ClaimRequirements, GuestToken, HostToken,
+        VerifiedToken,
+    },
+};
+
+/// Number of used tokens a [`ReplayCache`] remembers, unless created otherwise
+pub const DEFAULT_REPLAY_CACHE_CAPACITY: usize = 100_000;
+
+/// Record the use of token `$1`, valid until `$2` seconds since the Unix
+/// epoch. Returns no row if the token was used before. Expired tokens are
+/// removed.
+#[cfg(feature = "sessions")]
+const RECORD_TOKEN_USE: &str = "
+    WITH pruned AS (
+        DELETE FROM used_token
+        WHERE expires_at < now()
+        AND key <> $1
+    )
+    INSERT INTO used_token (key, expires_at)
+    VALUES ($1, to_timestamp($2))
+    ON CONFLICT (key) DO UPDATE
+    SET expires_at = EXCLUDED.expires_at
+    WHERE used_token.expires_at < now()
+    RETURNING key";
+
+/// Query parameter from which a host token is read if there is no
+/// `Authorization` header
+pub(crate) const HOST_TOKEN_PARAM: &str = "host_token";
+
+/// Query parameter from which a guest token is read if there is no
+/// `Authorization` header
+pub(crate) const GUEST_TOKEN_PARAM: &str = "guest_token";
+
+/// Verify a host token against the host verifier configured for its platform
+/// instance, see [`AuthDuringCommConfig::instance`]. The token must
+/// carry an expiration time, and name a host and instance outside the guest
+/// domain. Its age is limited according to the [`TokenTimingPolicy`].
+pub fn verify_host_token(
+    config: &AuthDuringCommConfig,
+    jwt: &str,
+) -> Result<VerifiedToken<HostToken>, Error> {
+    let timing = config.token_timing_policy();
+    let requirements = ClaimRequirements {
+        require_exp: true,
+        clock_skew: timing.clock_skew(),
+        max_age: timing.max_token_age(),
+        ..Default::default()
+    };
+    let verifier = config.host_verifier_for(unverified_instance(jwt).as_deref());
+    verify_platform_token(jwt, verifier, &requirements, SystemTime::now())
+        .map_err(|e| Error::Unauthorized(format!("Invalid host token: {}", e)))
+}
+
+/// Verify a guest token against the guest verifier configured for its platform
+/// instance. The token must
+/// carry an expiration and issue time, and must be issued for the configured
+/// `guest_token_audience` if any. Its age is limited according to the
+/// [`TokenTimingPolicy`].
+pub fn verify_guest_token(
+    config: &AuthDuringCommConfig,
+    jwt: &str,
+) -> Result<VerifiedToken<GuestToken>, Error> {
+    let timing = config.token_timing_policy();
+    let requirements = ClaimRequirements {
+        require_exp: true,
+        require_iat: true,
+        audience: config.guest_token_audience().map(str::to_owned),
+        clock_skew: timing.clock_skew(),
+        max_age: timing.max_token_age(),
+    };
+    let verifier = config.guest_verifier_for(unverified_instance(jwt).as_deref());
+    verify_platform_token(jwt, verifier, &requirements, SystemTime::now())
+        .map_err(|e| Error::Unauthorized(format!("Invalid guest token: {}", e)))
+}
+
+/// Get a platform token from the `Authorization: Bearer` header, falling back
+/// to the query parameter `param`
+#[cfg(feature = "rocket")]
+fn platform_jwt<'r>(request: &'r Request<'_>, param: &str) -> Option<&'r str> {
+    request
+        .headers()
+        .get_one("Authorization")
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .or_else(|| request.query_value::<&str>(param).and_then(Result::ok))
+}
+
+/// Host token taken from the `Authorization: Bearer` header or the
+/// `host_token` query parameter, verified against the configured host verifier.
+/// The token must carry an expiration time, and is accepted only once if
+/// `reject_reused_host_tokens` is set in the [`TokenReplayPolicy`]. Request
+/// guards can't read form bodies, so tokens posted in a form must be verified
+/// using [`crate::types::FromPlatformJwt`] instead.
+#[derive(Debug)]
+pub struct ValidatedHostToken(pub HostToken);
+
+impl Deref for ValidatedHostToken {
+    type Target = HostToken;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(feature = "rocket")]
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ValidatedHostToken {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Error> {
+        // if we don't have a config, panic
+        let config = CurrentConfig::from_rocket(request.rocket()).unwrap();
+
+        let jwt = match platform_jwt(request, HOST_TOKEN_PARAM) {
+            Some(jwt) => jwt,
+            None => {
+                return Outcome::Error((
+                    Status::Unauthorized,
+                    Error::Unauthorized("Missing host token".to_owned()),
+                ))
+            }
+        };
+
+        let auth_during_comm_config = config.auth_during_comm_config();
+        let token = match verify_host_token(auth_during_comm_config, jwt) {
+            Ok(token) => token,
+            Err(e) => return Outcome::Error((Status::Unauthorized, e)),
+        };
+
+        let policy = auth_during_comm_config.token_replay_policy();
+        if policy.reject_reused_host_tokens {
+            match record_use(request, policy, jwt, &token).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    return Outcome::Error((
+                        Status::Unauthorized,
+                        Error::Unauthorized("Host token was already used".to_owned()),
+                    ))
+                }
+                Err(e) => return Outcome::Error((Status::InternalServerError, e)),
+            }
+        }
+
+        Outcome::Success(ValidatedHostToken(token.claims))
+    }
+}
+
+/// Replay protection for platform tokens, configured through `token_replay`
+/// in the auth during comm configuration. By default, every token is accepted
+/// as often as it is presented until it expires.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TokenReplayPolicy {
+    /// Accept guest tokens only once
+    pub reject_reused_guest_tokens: bool,
+    /// Accept host tokens only once
+    pub reject_reused_host_tokens: bool,
+    /// Remember used tokens in the session database instead of a
+    /// [`ReplayCache`], so that every token is accepted once across all
+    /// instances of a plugin
+    pub shared: bool,
+}
+
+/// Tolerance for the clocks of communication platforms, configured through
+/// `token_timing` in the auth during comm configuration
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TokenTimingPolicy {
+    /// Seconds the clock of a platform may run ahead of or behind ours.
+    /// Tokens are accepted this long after they expire, and when issued this
+    /// far in the future.
+    pub clock_skew_secs: u64,
+    /// Maximum age of tokens in seconds, counted from their issue time. Tokens
+    /// without an issue time are refused if set.
+    pub max_token_age_secs: Option<u64>,
+}
+
+impl TokenTimingPolicy {
+    pub fn clock_skew(&self) -> Duration {
+        Duration::from_secs(self.clock_skew_secs)
+    }
+
+    pub fn max_token_age(&self) -> Option<Duration> {
+        self.max_token_age_secs.map(Duration::from_secs)
+    }
+}
+
+/// Tokens that were already used, remembered until they expire. Must be
+/// managed by Rocket for [`ValidatedGuestToken`] to work, unless used tokens
+/// are remembered in the session database.
+#[derive(Debug)]
+pub struct ReplayCache {
+    seen: Mutex<SeenTokens>,
+    capacity: usize,
+}
+
+/// Keys of the used tokens in a [`ReplayCache`], also ordered by expiry time
+/// so that expired tokens are forgotten without going over all of them
+#[derive(Debug, Default)]
+struct SeenTokens {
+    keys: HashSet<String>,
+    by_expiry: BTreeMap<SystemTime, Vec<String>>,
+}
+
+impl SeenTokens {
+    fn insert(&mut self, key: &str, expires_at: SystemTime) {
+        self.keys.insert(key.to_owned());
+        self.by_expiry
+            .entry(expires_at)
+            .or_default()
+            .push(key.to_owned());
+    }
+
+    /// Forget the tokens that expired at or before `now`
+    fn forget_expired(&mut self, now: SystemTime) {
+        while let Some(&first) = self.by_expiry.keys().next() {
+            if first > now {
+                break;
+            }
+            for key in self.by_expiry.remove(&first).unwrap_or_default() {
+                self.keys.remove(&key);
+            }
+        }
+    }
+
+    /// Forget one of the tokens expiring first
+    fn forget_first_expiring(&mut self) {
+        let first = match self.by_expiry.keys().next() {
+            Some(first) => *first,
+            None => return,
+        };
+        if let Some(keys) = self.by_expiry.get_mut(&first) {
+            if let Some(key) = keys.pop() {
+                self.keys.remove(&key);
+            }
+            if keys.is_empty() {
+                self.by_expiry.remove(&first);
+            }
+        }
+    }
+}
+
+impl Default for ReplayCache {
+    fn default() -> Self {
+        ReplayCache::with_capacity(DEFAULT_REPLAY_CACHE_CAPACITY)
+    }
+}
+
+impl ReplayCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cache remembering at most `capacity` tokens. When full, the token
+    /// expiring first is forgotten, and could be used once more.
+    pub fn with_capacity(capacity: usize) -> Self {
+        ReplayCache {
+            seen: Mutex::new(SeenTokens::default()),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Record the use of the token identified by `key`, which is valid until
+    /// `expires_at`. Returns false if the token was used before.
+    pub fn record(&self, key: &str, expires_at: SystemTime) -> bool {
+        let now = SystemTime::now();
+        let mut seen = self.seen.lock().unwrap();
+        seen.forget_expired(now);
+
+        if seen.keys.contains(key) {
+            return false;
+        }
+        if seen.keys.len() >= self.capacity {
+            seen.forget_first_expiring();
+        }
+        seen.insert(key, expires_at);
+        true
+    }
+
+    /// Record the use of `token`, verified from `jwt`. Returns false if the
+    /// token was used before.
+    pub fn record_token<T>(&self, jwt: &str, token: &VerifiedToken<T>) -> bool {
+        let (key, expires_at) = replay_key(jwt, token);
+        self.record(key, expires_at)
+    }
+}
+
+/// Key and expiry time under which the use of `token`, verified from `jwt`,
+/// is remembered
+fn replay_key<'a, T>(jwt: &'a str, token: &'a VerifiedToken<T>) -> (&'a str, SystemTime) {
+    // Without a token ID, the signature uniquely identifies the token
+    let key = token
+        .jwt_id
+        .as_deref()
+        .unwrap_or_else(|| jwt.rsplit('.').next().unwrap_or(jwt));
+    // Tokens checked for replay are required to have an expiration time
+    let expires_at = token.expires_at.unwrap_or_else(SystemTime::now);
+    (key, expires_at)
+}
+
+/// Record the use of the token identified by `key` in the session database.
+/// Returns false if the token was used before.
+#[cfg(feature = "sessions")]
+async fn record_shared(
+    key: String,
+    expires_at: SystemTime,
+    db: &impl SessionDb,
+) -> Result<bool, Error> {
+    let expires_at = expires_at
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0.0, |since_epoch| since_epoch.as_secs_f64());
+    let row = db
+        .run(move |c| c.query_opt(RECORD_TOKEN_USE, &[&key, &expires_at]))
+        .await?;
+    Ok(row.is_some())
+}
+
+/// Record the use of `token`, verified from `jwt`, according to `policy`.
+/// Returns false if the token was used before.
+#[cfg(feature = "rocket")]
+async fn record_use<T>(
+    request: &Request<'_>,
+    policy: &TokenReplayPolicy,
+    jwt: &str,
+    token: &VerifiedToken<T>,
+) -> Result<bool, Error> {
+    #[cfg(feature = "sessions")]
+    if policy.shared {
+        let (key, expires_at) = replay_key(jwt, token);
+        return match request.guard::<SessionDBConn>().await {
+            Outcome::Success(db) => record_shared(key.to_owned(), expires_at, &db).await,
+            _ => Err(Error::InternalServer(
+                "Session database unavailable for replay protection".to_owned(),
+            )),
+        };
+    }
+    #[cfg(not(feature = "sessions"))]
+    let _ = policy;
+
+    match request.rocket().state::<ReplayCache>() {
+        Some(replay_cache) => Ok(replay_cache.record_token(jwt, token)),
+        None => Err(Error::InternalServer(
+            "No replay cache configured".to_owned(),
+        )),
+    }
+}
+
+/// Guest token taken from the `Authorization: Bearer` header or the
+/// `guest_token` query parameter, verified against the configured guest
+/// verifier. The token must carry an expiration and issue time, must be issued
+/// for the configured `guest_token_audience` if any, and is accepted only once
+/// if `reject_reused_guest_tokens` is set in the [`TokenReplayPolicy`]. Replay
+/// protection requires a [`ReplayCache`] to be managed, unless used tokens are
+/// remembered in the session database.
+#[derive(Debug)]
+pub struct ValidatedGuestToken(pub GuestToken);
+
+impl Deref for ValidatedGuestToken {
+    type Target = GuestToken;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(feature = "rocket")]
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ValidatedGuestToken {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Error> {
+        // if we don't have a config, panic
+        let config = CurrentConfig::from_rocket(request.rocket()).unwrap();
+
+        let jwt = match platform_jwt(request, GUEST_TOKEN_PARAM) {
+            Some(jwt) => jwt,
+            None => {
+                return Outcome::Error((
+                    Status::Unauthorized,
+                    Error::Unauthorized("Missing guest token".to_owned()),
+                ))
+            }
+        };
+
+        let auth_during_comm_config = config.auth_during_comm_config();
+        let token = match verify_guest_token(auth_during_comm_config, jwt) {
+            Ok(token) => token,
+            Err(e) => return Outcome::Error((Status::Unauthorized, e)),
+        };
+
+        let policy = auth_during_comm_config.token_replay_policy();
+        if policy.reject_reused_guest_tokens {
+            match record_use(request, policy, jwt, &token).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    return Outcome::Error((
+                        Status::Unauthorized,
+                        Error::Unauthorized("Guest token was already used".to_owned()),
+                    ))
+                }
+                Err(e) => return Outcome::Error((Status::InternalServerError, e)),
+            }
+        }
+
+        Outcome::Success(ValidatedGuestToken(token.claims))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::ReplayCache;
+
+    #[test]
+    fn test_replay_cache() {
+        let cache = ReplayCache::new();
+        let later = SystemTime::now() + Duration::from_secs(60);
+
+        assert!(cache.record("a", later));
+        assert!(!cache.record("a", later));
+        assert!(cache.record("b", later));
+
+        // Expired entries are forgotten
+        assert!(cache.record("c", SystemTime::UNIX_EPOCH));
+        assert!(cache.record("c", later));
+
+        // When full, the entry expiring first is forgotten
+        let cache = ReplayCache::with_capacity(2);
+        let much_later = later + Duration::from_secs(60);
+        assert!(cache.record("a", later));
+        assert!(cache.record("b", much_later));
+        assert!(cache.record("c", much_later));
+        assert!(!cache.record("b", much_later));
+        assert!(cache.record("a", later));
+    }
+}
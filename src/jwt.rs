@@ -1,17 +1,24 @@
 use josekit::{
-    jws::{JwsHeader, JwsSigner},
-    jwt::JwtPayload,
+    jwe::JweDecrypter,
+    jws::{JwsHeader, JwsSigner, JwsVerifier},
+    jwt::{JwtPayload, JwtPayloadValidator},
 };
+use serde::de::DeserializeOwned;
 use thiserror::Error;
+use verder_helpen_proto::AuthResult;
 #[cfg(feature = "auth_during_comm")]
 use verder_helpen_proto::StartRequestAuthOnly;
 
 use crate::types::AuthSelectParams;
+#[cfg(feature = "sessions")]
+use crate::webhook::ResultNotification;
 
 #[derive(Error, Debug)]
 pub enum JwtError {
     #[error("Invalid Structure for key {0}")]
     InvalidStructure(&'static str),
+    #[error("Claim {0} rejected")]
+    InvalidClaim(&'static str),
     #[error("JSON error: {0}")]
     Json(#[from] serde_json::Error),
     #[error("JWT error: {0}")]
@@ -71,18 +78,129 @@ pub fn sign_auth_select_params(
     Ok(jws)
 }
 
+/// Sign a notification about a registered authentication result, for delivery
+/// to the webhook of the host system
+#[cfg(feature = "sessions")]
+pub fn sign_result_notification(
+    notification: &ResultNotification,
+    signer: &dyn JwsSigner,
+) -> Result<String, JwtError> {
+    let mut sig_header = JwsHeader::new();
+    sig_header.set_token_type("JWT");
+    if let Some(kid) = signer.key_id() {
+        sig_header.set_key_id(kid);
+    }
+    let mut sig_payload = JwtPayload::new();
+    sig_payload.set_subject("verder-helpen-result-notification");
+
+    sig_payload.set_claim(
+        "room_id",
+        Some(serde_json::to_value(&notification.room_id)?),
+    )?;
+    sig_payload.set_claim(
+        "session_id",
+        Some(serde_json::to_value(&notification.session_id)?),
+    )?;
+    sig_payload.set_claim(
+        "purpose",
+        Some(serde_json::to_value(&notification.purpose)?),
+    )?;
+    sig_payload.set_claim(
+        "auth_result",
+        Some(serde_json::to_value(&notification.auth_result)?),
+    )?;
+
+    sig_payload.set_issued_at(&std::time::SystemTime::now());
+    sig_payload
+        .set_expires_at(&(std::time::SystemTime::now() + std::time::Duration::from_secs(5 * 60)));
+
+    Ok(josekit::jwt::encode_with_signer(
+        &sig_payload,
+        &sig_header,
+        signer,
+    )?)
+}
+
+/// Verify a signed JWT with a previous key and sign its claims again with the
+/// current key. All claims, including the expiration time, are kept as-is.
+pub fn resign_jwt(
+    old_signed: &str,
+    old_verifier: &dyn JwsVerifier,
+    signer: &dyn JwsSigner,
+) -> Result<String, JwtError> {
+    let (payload, _) = josekit::jwt::decode_with_verifier(old_signed, old_verifier)?;
+    let mut validator = JwtPayloadValidator::new();
+    validator.set_base_time(std::time::SystemTime::now());
+    validator.validate(&payload)?;
+
+    let mut sig_header = JwsHeader::new();
+    sig_header.set_token_type("JWT");
+    if let Some(kid) = signer.key_id() {
+        sig_header.set_key_id(kid);
+    }
+
+    Ok(josekit::jwt::encode_with_signer(
+        &payload,
+        &sig_header,
+        signer,
+    )?)
+}
+
+/// Decrypt an authentication result JWE, returning the signed result nested
+/// in its `njwt` claim without verifying it
+pub fn decrypt_auth_result(jwe: &str, decrypter: &dyn JweDecrypter) -> Result<String, JwtError> {
+    let (payload, _) = josekit::jwt::decode_with_decrypter(jwe, decrypter)?;
+    match payload.claim("njwt") {
+        Some(serde_json::Value::String(jws)) => Ok(jws.clone()),
+        _ => Err(JwtError::InvalidStructure("njwt")),
+    }
+}
+
+/// Verify a signed authentication result as returned by
+/// [`decrypt_auth_result`], and its expiration time unless `check_expiry` is
+/// unset
+pub fn verify_auth_result(
+    jws: &str,
+    verifier: &dyn JwsVerifier,
+    check_expiry: bool,
+) -> Result<AuthResult, JwtError> {
+    let (payload, _) = josekit::jwt::decode_with_verifier(jws, verifier)?;
+    if check_expiry {
+        let mut validator = JwtPayloadValidator::new();
+        validator.set_base_time(std::time::SystemTime::now());
+        validator.validate(&payload)?;
+    }
+
+    Ok(AuthResult {
+        status: optional_claim(&payload, "status")?.ok_or(JwtError::InvalidStructure("status"))?,
+        attributes: optional_claim(&payload, "attributes")?,
+        session_url: optional_claim(&payload, "session_url")?,
+    })
+}
+
+fn optional_claim<T: DeserializeOwned>(
+    payload: &JwtPayload,
+    name: &str,
+) -> Result<Option<T>, JwtError> {
+    Ok(payload
+        .claim(name)
+        .filter(|value| !value.is_null())
+        .map(|value| serde_json::from_value(value.clone()))
+        .transpose()?)
+}
+
 #[cfg(test)]
 mod tests {
     use std::convert::TryFrom;
 
     use josekit::{
-        jws::{JwsSigner, JwsVerifier},
-        jwt::JwtPayloadValidator,
+        jws::{alg::hmac::HmacJwsAlgorithm, JwsHeader, JwsSigner, JwsVerifier},
+        jwt::{JwtPayload, JwtPayloadValidator},
     };
     use verder_helpen_jwt::SignKeyConfig;
     use verder_helpen_proto::StartRequestAuthOnly;
 
-    use super::{sign_auth_select_params, sign_start_auth_request};
+    use super::{resign_jwt, sign_auth_select_params, sign_start_auth_request};
     use crate::prelude::AuthSelectParams;
 
     const RSA_PRIVKEY: &'static str =
@@ -199,4 +317,83 @@ mod tests {
             "bla"
         );
     }
+
+    #[cfg(feature = "sessions")]
+    #[test]
+    fn test_sign_result_notification() {
+        use verder_helpen_proto::AuthStatus;
+
+        use super::sign_result_notification;
+        use crate::{auth_result::StoredAuthResult, webhook::ResultNotification};
+
+        let signer = Box::<dyn JwsSigner>::try_from(
+            serde_json::from_str::<SignKeyConfig>(RSA_PRIVKEY).unwrap(),
+        )
+        .unwrap();
+        let verifier = Box::<dyn JwsVerifier>::try_from(
+            serde_json::from_str::<SignKeyConfig>(RSA_PUBKEY).unwrap(),
+        )
+        .unwrap();
+
+        let notification = ResultNotification {
+            room_id: "room".into(),
+            session_id: "session".into(),
+            purpose: "test".into(),
+            auth_result: StoredAuthResult {
+                status: AuthStatus::Success,
+                attributes: None,
+                session_url: None,
+                received_at: std::time::SystemTime::now(),
+            },
+        };
+        let result = sign_result_notification(&notification, signer.as_ref()).unwrap();
+
+        let (payload, _) = josekit::jwt::decode_with_verifier(result, verifier.as_ref()).unwrap();
+        assert_eq!(payload.subject(), Some("verder-helpen-result-notification"));
+        assert_eq!(payload.claim("room_id").unwrap().as_str().unwrap(), "room");
+        assert_eq!(
+            payload.claim("session_id").unwrap().as_str().unwrap(),
+            "session"
+        );
+        let auth_result = serde_json::from_value::<StoredAuthResult>(
+            payload.claim("auth_result").unwrap().clone(),
+        )
+        .unwrap();
+        assert!(matches!(auth_result.status, AuthStatus::Success));
+    }
+
+    #[test]
+    fn test_resign_jwt() {
+        let old_key = HmacJwsAlgorithm::Hs256
+            .signer_from_bytes("old-secret-old-secret-old-secret")
+            .unwrap();
+        let old_verifier = HmacJwsAlgorithm::Hs256
+            .verifier_from_bytes("old-secret-old-secret-old-secret")
+            .unwrap();
+        let signer = Box::<dyn JwsSigner>::try_from(
+            serde_json::from_str::<SignKeyConfig>(RSA_PRIVKEY).unwrap(),
+        )
+        .unwrap();
+        let verifier = Box::<dyn JwsVerifier>::try_from(
+            serde_json::from_str::<SignKeyConfig>(RSA_PUBKEY).unwrap(),
+        )
+        .unwrap();
+
+        let mut payload = JwtPayload::new();
+        payload.set_subject("session-view");
+        payload.set_expires_at(
+            &(std::time::SystemTime::now() + std::time::Duration::from_secs(5 * 60)),
+        );
+        let mut header = JwsHeader::new();
+        header.set_token_type("JWT");
+        let old_signed = josekit::jwt::encode_with_signer(&payload, &header, &old_key).unwrap();
+
+        let resigned = resign_jwt(&old_signed, &old_verifier, signer.as_ref()).unwrap();
+        assert!(josekit::jwt::decode_with_verifier(&old_signed, verifier.as_ref()).is_err());
+        let (payload, _) =
+            josekit::jwt::decode_with_verifier(&resigned, verifier.as_ref()).unwrap();
+        assert_eq!(payload.subject(), Some("session-view"));
+
+        assert!(resign_jwt(&resigned, &old_verifier, signer.as_ref()).is_err());
+    }
 }
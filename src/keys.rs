@@ -0,0 +1,840 @@
+use std::{
+    borrow::Cow,
+    convert::TryFrom,
+    future::Future,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use josekit::{
+    jwe::{JweAlgorithm, JweContentEncryption, JweDecrypter, JweHeader},
+    jwk::{Jwk, JwkSet},
+    jws::{JwsAlgorithm, JwsVerifier, ES256},
+    JoseError,
+};
+#[cfg(feature = "rocket")]
+use rocket::{
+    fairing::{self, Fairing, Info, Kind},
+    Build, Orbit, Phase, Rocket,
+};
+use serde::Deserialize;
+use verder_helpen_jwt::{EncryptionKeyConfig, SignKeyConfig};
+
+#[cfg(feature = "rocket")]
+use crate::config::{CurrentConfig, ReloadableConfig};
+use crate::{error::Error, jwt::JwtError, secrets::SecretKey};
+
+/// Time between two refreshes of a JWKS, unless configured otherwise
+pub const DEFAULT_JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Minimum time between two refreshes of a JWKS triggered by a signature with
+/// an unknown key ID
+pub const MIN_JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keys for verifying authentication results, as read from `signature_pubkey`:
+/// a single inline key, a list of inline keys with optional key IDs, or the URL
+/// of a JWKS published by the core
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum RawSignatureKeys {
+    Jwks {
+        jwks_url: String,
+        /// Time between two refreshes of the JWKS, e.g. "1h"
+        refresh_interval: Option<String>,
+    },
+    Inline(SignKeyConfig),
+    Multiple(Vec<RawSignatureKey>),
+}
+
+/// Signature key configuration with an optional `kid` next to the key itself
+#[derive(Deserialize, Debug)]
+#[serde(try_from = "serde_json::Value")]
+pub struct RawSignatureKey {
+    kid: Option<String>,
+    config: SignKeyConfig,
+}
+
+impl TryFrom<serde_json::Value> for RawSignatureKey {
+    type Error = serde_json::Error;
+
+    fn try_from(mut raw: serde_json::Value) -> Result<Self, Self::Error> {
+        let kid = take_kid(&mut raw)?;
+        Ok(RawSignatureKey {
+            kid,
+            config: serde_json::from_value(raw)?,
+        })
+    }
+}
+
+/// Remove the `kid` from a raw key configuration
+fn take_kid(raw: &mut serde_json::Value) -> Result<Option<String>, serde_json::Error> {
+    raw.as_object_mut()
+        .and_then(|raw| raw.remove("kid"))
+        .map(serde_json::from_value)
+        .transpose()
+}
+
+/// Keys for verifying the signature of authentication results
+#[derive(Debug)]
+pub enum SignatureKeys {
+    Inline(Vec<VerificationKey>),
+    Jwks(JwksKeys),
+}
+
+impl TryFrom<RawSignatureKeys> for SignatureKeys {
+    type Error = Error;
+
+    fn try_from(raw: RawSignatureKeys) -> Result<Self, Error> {
+        match raw {
+            RawSignatureKeys::Inline(config) => Ok(SignatureKeys::Inline(vec![VerificationKey {
+                kid: None,
+                verifier: Box::<dyn JwsVerifier>::try_from(config)?,
+            }])),
+            RawSignatureKeys::Multiple(raw_keys) => {
+                if raw_keys.is_empty() {
+                    return Err(Error::Config("No signature keys configured".to_string()));
+                }
+                let keys = raw_keys
+                    .into_iter()
+                    .map(|raw_key| {
+                        let verifier = Box::<dyn JwsVerifier>::try_from(raw_key.config)?;
+                        let kid = raw_key
+                            .kid
+                            .or_else(|| verifier.key_id().map(str::to_string));
+                        Ok(VerificationKey { kid, verifier })
+                    })
+                    .collect::<Result<_, Error>>()?;
+                Ok(SignatureKeys::Inline(keys))
+            }
+            RawSignatureKeys::Jwks {
+                jwks_url,
+                refresh_interval,
+            } => {
+                let refresh_interval = match refresh_interval {
+                    Some(interval) => humantime::parse_duration(&interval).map_err(|e| {
+                        Error::Config(format!("Invalid JWKS refresh_interval: {}", e))
+                    })?,
+                    None => DEFAULT_JWKS_REFRESH_INTERVAL,
+                };
+                Ok(SignatureKeys::Jwks(JwksKeys::new(
+                    jwks_url,
+                    refresh_interval,
+                )))
+            }
+        }
+    }
+}
+
+impl SignatureKeys {
+    /// A single inline key
+    pub fn single(verifier: Box<dyn JwsVerifier>) -> Self {
+        SignatureKeys::Inline(vec![VerificationKey {
+            kid: None,
+            verifier,
+        }])
+    }
+
+    /// Verifier for the key with ID `kid`, see
+    /// [`SignatureKeys::verifier_for_jws`]
+    pub fn verifier_for(&self, kid: Option<&str>) -> Result<Box<dyn JwsVerifier>, Error> {
+        self.select(kid, None)
+    }
+
+    /// Verifier for the signed authentication result `jws`, as decrypted from
+    /// the JWE sent by the core. If the signature carries a key ID of one of
+    /// the keys, that key is used. Otherwise, all keys without key ID (or, for
+    /// signatures without key ID, all keys) for the signature algorithm are
+    /// tried.
+    pub fn verifier_for_jws(&self, jws: &str) -> Result<Box<dyn JwsVerifier>, Error> {
+        if let SignatureKeys::Inline(keys) = self {
+            if let [key] = keys.as_slice() {
+                return Ok(key.verifier.box_clone());
+            }
+        }
+
+        let (kid, alg) = signature_key_id(jws)?;
+        self.select(kid.as_deref(), alg.as_deref())
+    }
+
+    /// Like [`SignatureKeys::verifier_for_jws`], but if the key ID of the
+    /// signature is not in the JWKS, the JWKS is refreshed once before giving
+    /// up, so keys rotated in by the core are picked up without waiting for
+    /// the periodic refresh. Such refreshes happen at most once every
+    /// [`MIN_JWKS_REFRESH_INTERVAL`].
+    pub async fn refreshed_verifier_for_jws(
+        &self,
+        jws: &str,
+    ) -> Result<Box<dyn JwsVerifier>, Error> {
+        let jwks = match self {
+            SignatureKeys::Jwks(jwks) => jwks,
+            SignatureKeys::Inline(_) => return self.verifier_for_jws(jws),
+        };
+
+        let (kid, alg) = signature_key_id(jws)?;
+        match self.select(kid.as_deref(), alg.as_deref()) {
+            Err(e) if kid.is_some() && !jwks.has_key(kid.as_deref()) => {
+                if !jwks.refresh_for_unknown_key().await {
+                    return Err(e);
+                }
+                self.select(kid.as_deref(), alg.as_deref())
+            }
+            result => result,
+        }
+    }
+
+    /// Verifier standing in for the single verifier of earlier versions. For
+    /// inline keys, it accepts signatures of any key for the algorithm of the
+    /// first key. As the keys of a JWKS are not known in advance, it refuses
+    /// all signatures for a JWKS.
+    pub(crate) fn legacy_verifier(&self) -> Box<dyn JwsVerifier> {
+        match self {
+            SignatureKeys::Inline(keys) => select_key(keys, None, None)
+                .unwrap_or_else(|_| Box::new(AnyKeyVerifier { verifiers: vec![] })),
+            SignatureKeys::Jwks(_) => Box::new(AnyKeyVerifier { verifiers: vec![] }),
+        }
+    }
+
+    fn select(&self, kid: Option<&str>, alg: Option<&str>) -> Result<Box<dyn JwsVerifier>, Error> {
+        match self {
+            SignatureKeys::Inline(keys) => select_key(keys, kid, alg),
+            SignatureKeys::Jwks(jwks) => {
+                let keys = jwks.keys.read().unwrap();
+                // Keys of a JWKS are told apart by their key IDs, so signatures
+                // without one are only accepted for a JWKS with a single key
+                if kid.is_none() && keys.len() > 1 {
+                    return Err(Error::BadRequest("Signature has no key ID"));
+                }
+                select_key(&keys, kid, alg)
+            }
+        }
+    }
+}
+
+/// Key ID and algorithm in the header of the signature `jws`
+fn signature_key_id(jws: &str) -> Result<(Option<String>, Option<String>), Error> {
+    let header = josekit::jwt::decode_header(jws).map_err(JwtError::from)?;
+    let claim = |name| {
+        header
+            .claim(name)
+            .and_then(|value| value.as_str())
+            .map(str::to_string)
+    };
+    Ok((claim("kid"), claim("alg")))
+}
+
+/// Public key for verifying signatures, with its key ID if known
+#[derive(Debug)]
+pub struct VerificationKey {
+    pub kid: Option<String>,
+    pub verifier: Box<dyn JwsVerifier>,
+}
+
+fn select_key(
+    keys: &[VerificationKey],
+    kid: Option<&str>,
+    alg: Option<&str>,
+) -> Result<Box<dyn JwsVerifier>, Error> {
+    if let Some(key) = keys
+        .iter()
+        .find(|key| kid.is_some() && key.kid.as_deref() == kid)
+    {
+        return Ok(key.verifier.box_clone());
+    }
+
+    let mut candidates = keys
+        .iter()
+        .filter(|key| kid.is_none() || key.kid.is_none())
+        .filter(|key| alg.map_or(true, |alg| key.verifier.algorithm().name() == alg))
+        .peekable();
+    // All candidates must share an algorithm, as it is checked against the
+    // header of the signature before verifying
+    let algorithm = match candidates.peek() {
+        Some(key) => key.verifier.algorithm().name().to_string(),
+        None => return Err(Error::BadRequest("Unknown signature key ID")),
+    };
+    let verifiers: Vec<Box<dyn JwsVerifier>> = candidates
+        .filter(|key| key.verifier.algorithm().name() == algorithm)
+        .map(|key| key.verifier.box_clone())
+        .collect();
+
+    if verifiers.len() == 1 {
+        return Ok(verifiers.into_iter().next().unwrap());
+    }
+    Ok(Box::new(AnyKeyVerifier { verifiers }))
+}
+
+/// Verifier accepting signatures valid for any of its keys, which all use the
+/// same algorithm. Without keys, all signatures are refused.
+#[derive(Debug)]
+struct AnyKeyVerifier {
+    verifiers: Vec<Box<dyn JwsVerifier>>,
+}
+
+impl JwsVerifier for AnyKeyVerifier {
+    fn algorithm(&self) -> &dyn JwsAlgorithm {
+        match self.verifiers.first() {
+            Some(verifier) => verifier.algorithm(),
+            None => &ES256,
+        }
+    }
+
+    fn key_id(&self) -> Option<&str> {
+        None
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), JoseError> {
+        let mut result = Err(JoseError::InvalidSignature(
+            Error::BadRequest("No signature keys available").into(),
+        ));
+        for verifier in &self.verifiers {
+            result = verifier.verify(message, signature);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    fn box_clone(&self) -> Box<dyn JwsVerifier> {
+        Box::new(AnyKeyVerifier {
+            verifiers: self.verifiers.iter().map(|v| v.box_clone()).collect(),
+        })
+    }
+}
+
+/// Signature keys fetched from a JWKS URL. The keys are shared between clones,
+/// so a refresh is seen by all of them.
+#[derive(Debug, Clone)]
+pub struct JwksKeys {
+    url: String,
+    refresh_interval: Duration,
+    keys: Arc<RwLock<Vec<VerificationKey>>>,
+    /// Time of the last refresh triggered by an unknown key ID
+    last_unknown_key_refresh: Arc<Mutex<Option<Instant>>>,
+}
+
+impl JwksKeys {
+    pub fn new(url: String, refresh_interval: Duration) -> Self {
+        JwksKeys {
+            url,
+            refresh_interval,
+            keys: Arc::default(),
+            last_unknown_key_refresh: Arc::default(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn refresh_interval(&self) -> Duration {
+        self.refresh_interval
+    }
+
+    /// Spawn a task refreshing the keys every refresh interval, until
+    /// `shutdown` completes. The keys are expected to be fetched already. With
+    /// Rocket, this is done by attaching a [`JwksFairing`].
+    pub fn watch(&self, shutdown: impl Future<Output = ()> + Send + 'static) {
+        let jwks = self.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = periodic_refresh(jwks) => {}
+                _ = shutdown => {}
+            }
+        });
+    }
+
+    /// Replace the keys with those usable for verifying signatures from
+    /// `jwk_set`, returning the number of keys. Keys of unsupported types are
+    /// skipped.
+    pub fn set_keys(&self, jwk_set: &JwkSet) -> usize {
+        let keys: Vec<VerificationKey> = jwk_set
+            .keys()
+            .into_iter()
+            .filter(|jwk| jwk.key_use().map_or(true, |key_use| key_use == "sig"))
+            .filter_map(|jwk| match verifier_from_jwk(jwk) {
+                Ok(verifier) => Some(VerificationKey {
+                    kid: jwk.key_id().map(str::to_string),
+                    verifier,
+                }),
+                Err(e) => {
+                    eprintln!("Skipping unusable key in JWKS: {}", e);
+                    None
+                }
+            })
+            .collect();
+
+        let n = keys.len();
+        *self.keys.write().unwrap() = keys;
+        n
+    }
+
+    /// Whether a key with ID `kid` is among the current keys
+    fn has_key(&self, kid: Option<&str>) -> bool {
+        self.keys
+            .read()
+            .unwrap()
+            .iter()
+            .any(|key| key.kid.as_deref() == kid)
+    }
+
+    /// Refresh the keys after encountering an unknown key ID, unless such a
+    /// refresh happened less than [`MIN_JWKS_REFRESH_INTERVAL`] ago. Returns
+    /// whether the keys were refreshed.
+    async fn refresh_for_unknown_key(&self) -> bool {
+        {
+            let mut last = self.last_unknown_key_refresh.lock().unwrap();
+            if last.map_or(false, |last| last.elapsed() < MIN_JWKS_REFRESH_INTERVAL) {
+                return false;
+            }
+            *last = Some(Instant::now());
+        }
+
+        match self.refresh().await {
+            Ok(_) => true,
+            Err(e) => {
+                eprintln!("Could not refresh JWKS for an unknown key ID: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Fetch the JWKS and replace the keys with its keys. On failure, the
+    /// current keys are kept.
+    pub async fn refresh(&self) -> Result<usize, Error> {
+        let body = reqwest::get(&self.url)
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let jwk_set = JwkSet::from_bytes(&body).map_err(JwtError::from)?;
+        Ok(self.set_keys(&jwk_set))
+    }
+}
+
+fn verifier_from_jwk(jwk: &Jwk) -> Result<Box<dyn JwsVerifier>, Error> {
+    use josekit::jws::{EdDSA, ES256, ES384, ES512, PS256, PS384, PS512, RS256, RS384, RS512};
+
+    let algorithm = jwk.algorithm().or(match (jwk.key_type(), jwk.curve()) {
+        ("EC", Some("P-256")) => Some("ES256"),
+        ("EC", Some("P-384")) => Some("ES384"),
+        ("EC", Some("P-521")) => Some("ES512"),
+        ("RSA", _) => Some("RS256"),
+        ("OKP", _) => Some("EdDSA"),
+        _ => None,
+    });
+
+    let verifier: Result<Box<dyn JwsVerifier>, JoseError> = match algorithm {
+        Some("ES256") => ES256.verifier_from_jwk(jwk).map(|v| Box::new(v) as _),
+        Some("ES384") => ES384.verifier_from_jwk(jwk).map(|v| Box::new(v) as _),
+        Some("ES512") => ES512.verifier_from_jwk(jwk).map(|v| Box::new(v) as _),
+        Some("RS256") => RS256.verifier_from_jwk(jwk).map(|v| Box::new(v) as _),
+        Some("RS384") => RS384.verifier_from_jwk(jwk).map(|v| Box::new(v) as _),
+        Some("RS512") => RS512.verifier_from_jwk(jwk).map(|v| Box::new(v) as _),
+        Some("PS256") => PS256.verifier_from_jwk(jwk).map(|v| Box::new(v) as _),
+        Some("PS384") => PS384.verifier_from_jwk(jwk).map(|v| Box::new(v) as _),
+        Some("PS512") => PS512.verifier_from_jwk(jwk).map(|v| Box::new(v) as _),
+        Some("EdDSA") => EdDSA.verifier_from_jwk(jwk).map(|v| Box::new(v) as _),
+        _ => {
+            return Err(Error::Config(format!(
+                "Unsupported key type {}",
+                jwk.key_type()
+            )))
+        }
+    };
+    Ok(verifier.map_err(JwtError::from)?)
+}
+
+/// Fairing fetching the JWKS configured as `signature_pubkey` before launch,
+/// and refreshing it periodically afterwards. Launch fails if the JWKS can
+/// not be fetched. Does nothing for an inline key. Requires the configuration
+/// to be managed, see [`CurrentConfig`]. The JWKS of a [`ReloadableConfig`] is
+/// refreshed by its [`ReloadFairing`](crate::config::ReloadFairing) instead.
+#[cfg(feature = "rocket")]
+#[derive(Default)]
+pub struct JwksFairing;
+
+#[cfg(feature = "rocket")]
+#[rocket::async_trait]
+impl Fairing for JwksFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "JWKS refresh",
+            kind: Kind::Ignite | Kind::Liftoff,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let jwks = match configured_jwks(&rocket) {
+            Some(jwks) => jwks,
+            None => return Ok(rocket),
+        };
+
+        match jwks.refresh().await {
+            Ok(_) => Ok(rocket),
+            Err(e) => {
+                eprintln!("Could not fetch JWKS from {}: {}", jwks.url(), e);
+                Err(rocket)
+            }
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        if rocket.state::<ReloadableConfig>().is_some() {
+            return;
+        }
+        if let Some(jwks) = configured_jwks(rocket) {
+            jwks.watch(rocket.shutdown());
+        }
+    }
+}
+
+/// The JWKS of the configuration managed by `rocket`, if configured
+#[cfg(feature = "rocket")]
+fn configured_jwks<P: Phase>(rocket: &Rocket<P>) -> Option<JwksKeys> {
+    let config = CurrentConfig::from_rocket(rocket)?;
+    match config.signature_keys() {
+        SignatureKeys::Jwks(jwks) => Some(jwks.clone()),
+        SignatureKeys::Inline(_) => None,
+    }
+}
+
+/// Refresh the JWKS every refresh interval, keeping the current keys if a
+/// refresh fails
+async fn periodic_refresh(jwks: JwksKeys) {
+    let mut interval = tokio::time::interval(jwks.refresh_interval());
+    // The first tick completes immediately, and the keys were just fetched on
+    // ignite
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        if let Err(e) = jwks.refresh().await {
+            eprintln!("Could not refresh JWKS, keeping current keys: {}", e);
+        }
+    }
+}
+
+/// Keys for decrypting authentication results, as read from
+/// `decryption_privkey`: either a single key, or a list of keys with their key
+/// IDs, the first of which is the current key
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum RawDecryptionKeys {
+    // Tried first, as a secret key accepts any value, including a list
+    Multiple(Vec<RawDecryptionKey>),
+    Single(SecretKey<EncryptionKeyConfig>),
+}
+
+/// Decryption key configuration with an optional `kid` next to the key itself
+#[derive(Deserialize, Debug)]
+#[serde(try_from = "serde_json::Value")]
+pub struct RawDecryptionKey {
+    kid: Option<String>,
+    config: SecretKey<EncryptionKeyConfig>,
+}
+
+impl TryFrom<serde_json::Value> for RawDecryptionKey {
+    type Error = serde_json::Error;
+
+    fn try_from(mut raw: serde_json::Value) -> Result<Self, Self::Error> {
+        let kid = take_kid(&mut raw)?;
+        Ok(RawDecryptionKey {
+            kid,
+            config: SecretKey::from(raw),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct DecryptionKey {
+    kid: Option<String>,
+    decrypter: Box<dyn JweDecrypter>,
+}
+
+impl Clone for DecryptionKey {
+    fn clone(&self) -> Self {
+        DecryptionKey {
+            kid: self.kid.clone(),
+            decrypter: self.decrypter.box_clone(),
+        }
+    }
+}
+
+/// Decrypter for JWEs encrypted to any of a list of keys, for key rollover.
+/// The key is chosen by the key ID in the JWE header; JWEs without a known key
+/// ID are decrypted with the current key. All keys must use the same
+/// algorithm.
+#[derive(Debug, Clone)]
+pub struct DecryptionKeys {
+    keys: Vec<DecryptionKey>,
+}
+
+impl DecryptionKeys {
+    /// Decryption keys consisting of only the current key
+    pub fn single(decrypter: Box<dyn JweDecrypter>, kid: Option<String>) -> Self {
+        let kid = kid.or_else(|| decrypter.key_id().map(str::to_string));
+        DecryptionKeys {
+            keys: vec![DecryptionKey { kid, decrypter }],
+        }
+    }
+
+    /// Build the decryption keys, using `current_key_id` as the key ID of the
+    /// current key if given
+    pub fn new(raw: RawDecryptionKeys, current_key_id: Option<String>) -> Result<Self, Error> {
+        let raw_keys = match raw {
+            RawDecryptionKeys::Single(config) => vec![RawDecryptionKey { kid: None, config }],
+            RawDecryptionKeys::Multiple(raw_keys) => raw_keys,
+        };
+
+        let mut keys = raw_keys
+            .into_iter()
+            .map(|raw_key| {
+                let decrypter = Box::<dyn JweDecrypter>::try_from(raw_key.config.resolve()?)?;
+                let kid = raw_key
+                    .kid
+                    .or_else(|| decrypter.key_id().map(str::to_string));
+                Ok(DecryptionKey { kid, decrypter })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let current = keys
+            .first_mut()
+            .ok_or_else(|| Error::Config("No decryption keys configured".to_string()))?;
+        if current_key_id.is_some() {
+            current.kid = current_key_id;
+        }
+        let algorithm = current.decrypter.algorithm().name().to_string();
+        if keys
+            .iter()
+            .any(|key| key.decrypter.algorithm().name() != algorithm)
+        {
+            return Err(Error::Config(
+                "All decryption keys must use the same algorithm".to_string(),
+            ));
+        }
+
+        Ok(DecryptionKeys { keys })
+    }
+
+    /// Key ID of the current key, if known
+    pub fn current_key_id(&self) -> Option<&str> {
+        self.keys[0].kid.as_deref()
+    }
+
+    /// Key IDs of all keys, starting with the current key
+    pub fn key_ids(&self) -> Vec<&str> {
+        self.keys
+            .iter()
+            .filter_map(|key| key.kid.as_deref())
+            .collect()
+    }
+
+    fn select(&self, kid: Option<&str>) -> &dyn JweDecrypter {
+        kid.and_then(|kid| self.keys.iter().find(|key| key.kid.as_deref() == Some(kid)))
+            .unwrap_or(&self.keys[0])
+            .decrypter
+            .as_ref()
+    }
+}
+
+impl JweDecrypter for DecryptionKeys {
+    fn algorithm(&self) -> &dyn JweAlgorithm {
+        self.keys[0].decrypter.algorithm()
+    }
+
+    fn key_id(&self) -> Option<&str> {
+        // Not reporting a key ID, so that JWEs for any of the keys are accepted
+        None
+    }
+
+    fn decrypt(
+        &self,
+        encrypted_key: Option<&[u8]>,
+        cencryption: &dyn JweContentEncryption,
+        header: &JweHeader,
+    ) -> Result<Cow<[u8]>, JoseError> {
+        self.select(header.key_id())
+            .decrypt(encrypted_key, cencryption, header)
+    }
+
+    fn box_clone(&self) -> Box<dyn JweDecrypter> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use josekit::{
+        jwe::{JweHeader, ECDH_ES},
+        jwk::{
+            alg::ec::{EcCurve, EcKeyPair},
+            JwkSet,
+        },
+        jws::{JwsHeader, ES256},
+        jwt::JwtPayload,
+    };
+
+    use super::{DecryptionKey, DecryptionKeys, JwksKeys, SignatureKeys, VerificationKey};
+
+    #[test]
+    fn test_jwks_key_selection() {
+        let mut jwk_set = JwkSet::new();
+        let mut signers = vec![];
+        for kid in ["first", "second"] {
+            let key_pair = ES256.generate_key_pair().unwrap();
+            let mut jwk = key_pair.to_jwk_public_key();
+            jwk.set_key_id(kid);
+            jwk_set.push_key(jwk);
+            signers.push(
+                ES256
+                    .signer_from_der(key_pair.to_der_private_key())
+                    .unwrap(),
+            );
+        }
+
+        let jwks = JwksKeys::new(
+            "https://example.com/jwks".to_owned(),
+            Duration::from_secs(60),
+        );
+        assert_eq!(jwks.set_keys(&jwk_set), 2);
+        let keys = SignatureKeys::Jwks(jwks);
+
+        let mut header = JwsHeader::new();
+        header.set_key_id("second");
+        let jwt =
+            josekit::jwt::encode_with_signer(&JwtPayload::new(), &header, &signers[1]).unwrap();
+
+        let verifier = keys.verifier_for(Some("second")).unwrap();
+        assert!(josekit::jwt::decode_with_verifier(&jwt, verifier.as_ref()).is_ok());
+        let verifier = keys.verifier_for(Some("first")).unwrap();
+        assert!(josekit::jwt::decode_with_verifier(&jwt, verifier.as_ref()).is_err());
+        assert!(keys.verifier_for(Some("third")).is_err());
+        assert!(keys.verifier_for(None).is_err());
+        let verifier = keys.verifier_for_jws(&jwt).unwrap();
+        assert!(josekit::jwt::decode_with_verifier(&jwt, verifier.as_ref()).is_ok());
+    }
+
+    #[test]
+    fn test_inline_key_selection() {
+        let mut keys = vec![];
+        let mut signers = vec![];
+        for kid in [Some("production"), None] {
+            let key_pair = ES256.generate_key_pair().unwrap();
+            keys.push(VerificationKey {
+                kid: kid.map(str::to_owned),
+                verifier: Box::new(
+                    ES256
+                        .verifier_from_der(key_pair.to_der_public_key())
+                        .unwrap(),
+                ),
+            });
+            signers.push(
+                ES256
+                    .signer_from_der(key_pair.to_der_private_key())
+                    .unwrap(),
+            );
+        }
+        let keys = SignatureKeys::Inline(keys);
+
+        let header = JwsHeader::new();
+        let without_kid =
+            josekit::jwt::encode_with_signer(&JwtPayload::new(), &header, &signers[1]).unwrap();
+        // Without key ID, any of the keys is accepted
+        let verifier = keys.verifier_for(None).unwrap();
+        assert!(josekit::jwt::decode_with_verifier(&without_kid, verifier.as_ref()).is_ok());
+        let verifier = keys.verifier_for_jws(&without_kid).unwrap();
+        assert!(josekit::jwt::decode_with_verifier(&without_kid, verifier.as_ref()).is_ok());
+
+        // An unknown key ID falls back to the keys without key ID
+        let mut header = JwsHeader::new();
+        header.set_key_id("staging");
+        let unknown_kid =
+            josekit::jwt::encode_with_signer(&JwtPayload::new(), &header, &signers[1]).unwrap();
+        let verifier = keys.verifier_for_jws(&unknown_kid).unwrap();
+        assert!(josekit::jwt::decode_with_verifier(&unknown_kid, verifier.as_ref()).is_ok());
+        let verifier = keys.verifier_for(Some("production")).unwrap();
+        assert!(josekit::jwt::decode_with_verifier(&unknown_kid, verifier.as_ref()).is_err());
+    }
+
+    #[test]
+    fn test_unknown_key_id_refresh() {
+        tokio_test::block_on(async {
+            let key_pair = ES256.generate_key_pair().unwrap();
+            let mut jwk = key_pair.to_jwk_public_key();
+            jwk.set_key_id("known");
+            let mut jwk_set = JwkSet::new();
+            jwk_set.push_key(jwk);
+            let signer = ES256
+                .signer_from_der(key_pair.to_der_private_key())
+                .unwrap();
+
+            // Nothing listens on the discard port, so refreshes fail
+            let jwks = JwksKeys::new(
+                "http://127.0.0.1:9/jwks".to_owned(),
+                Duration::from_secs(60),
+            );
+            jwks.set_keys(&jwk_set);
+            let keys = SignatureKeys::Jwks(jwks.clone());
+
+            let mut header = JwsHeader::new();
+            header.set_key_id("known");
+            let known =
+                josekit::jwt::encode_with_signer(&JwtPayload::new(), &header, &signer).unwrap();
+            header.set_key_id("rotated");
+            let rotated =
+                josekit::jwt::encode_with_signer(&JwtPayload::new(), &header, &signer).unwrap();
+
+            assert!(keys.refreshed_verifier_for_jws(&known).await.is_ok());
+            assert!(jwks.last_unknown_key_refresh.lock().unwrap().is_none());
+
+            assert!(keys.refreshed_verifier_for_jws(&rotated).await.is_err());
+            let attempted = jwks.last_unknown_key_refresh.lock().unwrap().unwrap();
+            // A second unknown key ID within the minimum interval does not
+            // refresh again
+            assert!(keys.refreshed_verifier_for_jws(&rotated).await.is_err());
+            assert_eq!(
+                jwks.last_unknown_key_refresh.lock().unwrap().unwrap(),
+                attempted
+            );
+            // The current keys are kept
+            assert!(keys.verifier_for(Some("known")).is_ok());
+        });
+    }
+
+    #[test]
+    fn test_decryption_key_selection() {
+        let mut keys = vec![];
+        let mut encrypters = vec![];
+        for kid in ["new", "old"] {
+            let key_pair = EcKeyPair::generate(EcCurve::P256).unwrap();
+            keys.push(DecryptionKey {
+                kid: Some(kid.to_owned()),
+                decrypter: Box::new(
+                    ECDH_ES
+                        .decrypter_from_der(key_pair.to_der_private_key())
+                        .unwrap(),
+                ),
+            });
+            encrypters.push(
+                ECDH_ES
+                    .encrypter_from_der(key_pair.to_der_public_key())
+                    .unwrap(),
+            );
+        }
+        let keys = DecryptionKeys { keys };
+        assert_eq!(keys.key_ids(), vec!["new", "old"]);
+
+        let mut header = JweHeader::new();
+        header.set_content_encryption("A128GCM");
+        let without_kid =
+            josekit::jwe::serialize_compact(b"hello", &header, &encrypters[1]).unwrap();
+        header.set_key_id("old");
+        let with_kid = josekit::jwe::serialize_compact(b"hello", &header, &encrypters[1]).unwrap();
+
+        let (payload, _) = josekit::jwe::deserialize_compact(&with_kid, &keys).unwrap();
+        assert_eq!(payload, b"hello");
+        assert!(josekit::jwe::deserialize_compact(&without_kid, &keys).is_err());
+    }
+}
@@ -1,44 +1,146 @@
+/// Audit log of security-relevant events
+pub mod audit;
 /// Common authentication and authorisation mechanisms
 pub mod auth;
+#[cfg(feature = "auth_during_comm")]
+/// Helpers for authenticating guests during communication
+pub mod auth_during_comm;
+/// Decryption and verification of authentication results
+pub mod auth_result;
+#[cfg(feature = "axum")]
+/// Adapters for communication plugins built on axum instead of Rocket
+pub mod axum;
 /// Common configuration mechanisms
 pub mod config;
+#[cfg(feature = "auth_during_comm")]
+/// Client for the Verder Helpen core
+pub mod core_client;
+/// CSRF tokens for HTML forms
+pub mod csrf;
+#[cfg(feature = "email")]
+/// Mailing of authentication results to a host address
+pub mod email;
 /// Error type with responder implementation
 pub mod error;
+#[cfg(feature = "sessions")]
+/// Live events about sessions, per room
+pub mod events;
+#[cfg(feature = "sessions")]
+/// Pseudonymized exports of session metadata for reporting
+pub mod export;
+#[cfg(feature = "auth_during_comm")]
+/// Verification of platform tokens, and request guards doing so
+pub mod guards;
 /// JWT signing functionality
 pub mod jwt;
-#[cfg(feature = "session_db")]
+/// Keys for verifying authentication results, inline or from a JWKS
+pub mod keys;
+#[cfg(feature = "rocket")]
+/// Structured logging of requests
+pub mod logging;
+#[cfg(feature = "metrics")]
+/// Prometheus metrics and structured events for monitoring and alerting
+pub mod metrics;
+#[cfg(feature = "openapi")]
+/// OpenAPI document describing the mounted routes of this crate
+pub mod openapi;
+/// Rate limiting of public endpoints
+pub mod rate_limit;
+/// Allow-list of URLs guests may be redirected to
+pub mod redirect;
+/// Rendering of verified attributes
+pub mod render;
+#[cfg(all(feature = "sentry", feature = "rocket"))]
+/// Error and panic reporting to Sentry
+pub mod reporting;
+/// IDs tracing requests across the plugin and the core
+pub mod request_id;
+#[cfg(feature = "rocket")]
+/// Ready-made routes for communication plugins
+pub mod routes;
+/// Secrets read from files or environment variables
+pub mod secrets;
+#[cfg(feature = "sessions")]
 /// Database manipulation code for keeping track of sessions based on platform
 /// tokens
 pub mod session;
+/// Waiting for background work on shutdown
+pub mod shutdown;
+#[cfg(feature = "sessions")]
+/// Delivery of registered authentication results to configurable destinations
+pub mod sinks;
 /// Tera templates
 pub mod templates;
+#[cfg(any(feature = "test-support", all(test, feature = "sessions")))]
+/// Helpers for testing plugins, such as a mock core
+pub mod test_support;
 /// Translation messages and request guard
 pub mod translations;
 /// Common types
 pub mod types;
 /// Utilities
 pub mod util;
+#[cfg(feature = "sessions")]
+/// Notifications of registered authentication results to the host system
+pub mod webhook;
 // credential collection and rendering
 #[cfg(feature = "platform_token")]
 pub mod credentials;
 #[macro_use]
 extern crate lazy_static;
 
+/// The figment version the configuration is read with, so that plugins can
+/// extend [`config::Config::figment`] with its providers without depending on
+/// a matching version themselves
+pub use figment;
+#[cfg(feature = "rocket")]
+/// The Rocket version the request guards, fairings and routes are built for
+pub use rocket;
+
 pub mod prelude {
-    #[cfg(feature = "session_db")]
+    #[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
     pub use crate::credentials::get_credentials_for_host;
     #[cfg(feature = "platform_token")]
     pub use crate::credentials::{collect_credentials, render_credentials};
-    #[cfg(feature = "session_db")]
-    pub use crate::session::{Session, SessionDBConn};
+    #[cfg(all(feature = "email", feature = "rocket"))]
+    pub use crate::email::auth_result_mailer;
+    #[cfg(feature = "auth_during_comm")]
+    pub use crate::guards::{
+        ReplayCache, TokenReplayPolicy, TokenTimingPolicy, ValidatedGuestToken, ValidatedHostToken,
+    };
+    #[cfg(feature = "async-db")]
+    pub use crate::session::AsyncSessionDB;
+    #[cfg(feature = "memory-store")]
+    pub use crate::session::InMemorySessionStore;
+    #[cfg(all(feature = "sessions", feature = "rocket"))]
+    pub use crate::session::{cleanup_fairing, SessionDBConn};
+    #[cfg(feature = "sessions")]
+    pub use crate::session::{Session, SessionDb, SessionPool, SessionState, SessionStore};
+    #[cfg(feature = "sessions")]
+    pub use crate::sinks::ResultSink;
     #[cfg(feature = "platform_token")]
     pub use crate::types::{FromPlatformJwt, GuestToken, HostToken};
+    #[cfg(feature = "rocket")]
+    pub use crate::{
+        audit::audit_fairing,
+        config::{config_fairing, CurrentConfig},
+        csrf::csrf_fairing,
+        rate_limit::rate_limit_fairing,
+    };
     pub use crate::{
         auth::{render_login, render_unauthorized, AuthProvider, Authorized, LoginUrl},
         config::Config,
+        csrf::{CsrfForm, CsrfProtected},
         error::Error,
         jwt::sign_auth_select_params,
-        types::{AuthSelectParams, Credentials, GuestAuthResult, StartRequest},
+        rate_limit::RateLimited,
+        types::{
+            AttrId, AuthSelectParams, Credentials, GuestAuthResult, RoomId, SessionId, StartRequest,
+        },
         util::random_string,
     };
+    #[cfg(feature = "auth_during_comm")]
+    pub use crate::{auth_during_comm::widget_url_for, core_client::start_authentication};
+    #[cfg(all(feature = "sessions", feature = "rocket"))]
+    pub use crate::{sinks::result_sinks_fairing, webhook::webhook_fairing};
 }
@@ -0,0 +1,158 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use rocket::{
+    fairing::{self, Fairing, Info, Kind},
+    Build, Data, Request, Response, Rocket,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    config::{Config, CurrentConfig},
+    request_id::request_id,
+    util::{set_log_pseudonymizer, Pseudonymizer},
+};
+
+/// Time at which the request was received, kept in the request-local cache
+struct RequestStart(Instant);
+
+/// Fairing logging every request as a single line of JSON to stderr, with the
+/// route that handled it, the response status, the time taken and the
+/// pseudonymized room ID for routes taking one, and the
+/// [`crate::request_id::RequestId`] of the request. Paths and query strings
+/// are not logged, as they may contain tokens.
+///
+/// Room IDs are pseudonymized with the salt configured as `log_salt`, which
+/// the fairing also installs for the IDs recorded in `tracing` spans. Without
+/// it, room IDs are left out.
+///
+/// Meant to replace the request lines Rocket logs itself: set Rocket's
+/// `log_level` to `"critical"` in production.
+#[derive(Debug, Default)]
+pub struct JsonLogFairing;
+
+impl JsonLogFairing {
+    pub fn new() -> Self {
+        JsonLogFairing
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for JsonLogFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "JSON request log",
+            kind: Kind::Ignite | Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let pseudonymizer = CurrentConfig::from_rocket(&rocket)
+            .and_then(|config| config.log_pseudonymizer().cloned());
+        set_log_pseudonymizer(pseudonymizer);
+        Ok(rocket)
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let RequestStart(start) = request.local_cache(|| RequestStart(Instant::now()));
+        let route = request.route().map(|route| route.uri.to_string());
+        let config = CurrentConfig::from_rocket(request.rocket());
+        let pseudonymizer = config.as_deref().and_then(Config::log_pseudonymizer);
+        let entry = log_entry(
+            request.method().as_str(),
+            route.as_deref(),
+            response.status().code,
+            start.elapsed(),
+            room_id(request).zip(pseudonymizer),
+            request_id(request).as_str(),
+        );
+        eprintln!("{}", entry);
+    }
+}
+
+/// Room ID of the request, if the route that handled it has a `<room_id>`
+/// segment
+fn room_id<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+    let index = request
+        .route()?
+        .uri
+        .unmounted()
+        .path()
+        .segments()
+        .position(|segment| segment == "<room_id>")?;
+    request.routed_segment(index)
+}
+
+/// Log entry of a single request, in the format shared with the other
+/// Verder Helpen components. The room ID is logged as its pseudonym.
+fn log_entry(
+    method: &str,
+    route: Option<&str>,
+    status: u16,
+    duration: Duration,
+    room_id: Option<(&str, &Pseudonymizer)>,
+    request_id: &str,
+) -> Value {
+    let level = match status {
+        500.. => "error",
+        400..=499 => "warn",
+        _ => "info",
+    };
+    let mut entry = json!({
+        "timestamp": humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        "level": level,
+        "message": "request",
+        "method": method,
+        "route": route,
+        "status": status,
+        "duration_ms": duration.as_secs_f64() * 1000.0,
+        "request_id": request_id,
+    });
+    if let Some(room_id_hash) =
+        room_id.and_then(|(room_id, pseudonymizer)| pseudonymizer.pseudonym(room_id).ok())
+    {
+        entry["room_id_hash"] = json!(room_id_hash);
+    }
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::log_entry;
+    use crate::util::Pseudonymizer;
+
+    #[test]
+    fn test_log_entry() {
+        let pseudonymizer =
+            Pseudonymizer::from_salt(b"log-salt-log-salt-log-salt-log-salt").unwrap();
+        let entry = log_entry(
+            "GET",
+            Some("/host/<room_id>"),
+            404,
+            Duration::from_millis(12),
+            Some(("16", &pseudonymizer)),
+            "abc",
+        );
+        assert_eq!(entry["level"], "warn");
+        assert_eq!(entry["route"], "/host/<room_id>");
+        assert_eq!(entry["status"], 404);
+        assert_eq!(
+            entry["room_id_hash"],
+            pseudonymizer.pseudonym("16").unwrap()
+        );
+        let other = Pseudonymizer::from_salt(b"other-salt-other-salt-other-salt").unwrap();
+        assert_ne!(entry["room_id_hash"], other.pseudonym("16").unwrap());
+        assert_eq!(entry["request_id"], "abc");
+        assert!(!entry.to_string().contains("\"16\""));
+
+        let entry = log_entry("POST", None, 200, Duration::ZERO, None, "abc");
+        assert_eq!(entry["level"], "info");
+        assert!(entry["route"].is_null());
+        assert!(entry.get("room_id_hash").is_none());
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounter, IntCounterVec, Opts,
+    Registry, TextEncoder,
+};
+#[cfg(feature = "rocket")]
+use rocket::{http::ContentType, Route};
+use serde_json::json;
+
+use crate::error::Error;
+#[cfg(feature = "platform_token")]
+use crate::types::TokenFailureReason;
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+    static ref SESSIONS_CREATED: IntCounter =
+        register(IntCounter::new("comm_sessions_created_total", "Sessions created").unwrap());
+    static ref AUTH_RESULTS_RECEIVED: IntCounter = register(
+        IntCounter::new(
+            "comm_auth_results_received_total",
+            "Authentication results registered with a session"
+        )
+        .unwrap()
+    );
+    static ref CLEANUPS: IntCounter =
+        register(IntCounter::new("comm_session_cleanups_total", "Session cleanup runs").unwrap());
+    static ref SESSIONS_CLEANED: IntCounter = register(
+        IntCounter::new(
+            "comm_sessions_cleaned_total",
+            "Sessions removed by session cleanups"
+        )
+        .unwrap()
+    );
+    static ref TOKEN_FAILURES: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new(
+                "comm_token_verification_failures_total",
+                "Platform tokens that failed verification"
+            ),
+            &["token_type", "reason"]
+        )
+        .unwrap()
+    );
+    static ref CONNECTIONS_RECYCLED: IntCounter = register(
+        IntCounter::new(
+            "comm_db_connections_recycled_total",
+            "Pooled database connections closed by the pool"
+        )
+        .unwrap()
+    );
+    static ref DB_QUERY_DURATION: HistogramVec = register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "comm_db_query_duration_seconds",
+                "Duration of session database operations"
+            ),
+            &["query"]
+        )
+        .unwrap()
+    );
+    static ref CORE_REQUEST_DURATION: HistogramVec = register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "comm_core_request_duration_seconds",
+                "Duration of requests to the core, including retries"
+            ),
+            &["endpoint"]
+        )
+        .unwrap()
+    );
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(metric: T) -> T {
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("Could not register metric");
+    metric
+}
+
+/// Emit a structured event for a platform token that failed verification.
+/// Only the token type and the failure reason are logged, never the token
+/// itself.
+#[cfg(feature = "platform_token")]
+pub fn token_verification_failed(token_type: &str, reason: TokenFailureReason) {
+    TOKEN_FAILURES
+        .with_label_values(&[token_type, &reason.to_string()])
+        .inc();
+    eprintln!(
+        "{}",
+        json!({
+            "event": "token_verification_failed",
+            "token_type": token_type,
+            "reason": reason,
+        })
+    );
+}
+
+static RECYCLED_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Record that a pooled database connection was closed, so that it will be
+/// replaced by a fresh one
+pub fn connection_recycled(age: Duration) {
+    let total = RECYCLED_CONNECTIONS.fetch_add(1, Ordering::Relaxed) + 1;
+    CONNECTIONS_RECYCLED.inc();
+    eprintln!(
+        "{}",
+        json!({
+            "event": "connection_recycled",
+            "age_secs": age.as_secs(),
+            "total": total,
+        })
+    );
+}
+
+/// Number of pooled database connections recycled since startup
+pub fn recycled_connections() -> u64 {
+    RECYCLED_CONNECTIONS.load(Ordering::Relaxed)
+}
+
+/// Record that a session was created
+pub fn session_created() {
+    SESSIONS_CREATED.inc();
+}
+
+/// Record that an authentication result was registered with a session
+pub fn auth_result_received() {
+    AUTH_RESULTS_RECEIVED.inc();
+}
+
+/// Record a session cleanup run, removing `removed` sessions
+pub fn cleanup_completed(removed: u64) {
+    CLEANUPS.inc();
+    SESSIONS_CLEANED.inc_by(removed);
+}
+
+/// Start timing the session database operation `query`. The duration is
+/// recorded when the returned timer is dropped.
+pub fn db_query_timer(query: &str) -> HistogramTimer {
+    DB_QUERY_DURATION.with_label_values(&[query]).start_timer()
+}
+
+/// Start timing a request to the core endpoint `endpoint`. The duration is
+/// recorded when the returned timer is dropped.
+pub fn core_request_timer(endpoint: &str) -> HistogramTimer {
+    CORE_REQUEST_DURATION
+        .with_label_values(&[endpoint])
+        .start_timer()
+}
+
+/// All metrics in the Prometheus text exposition format
+pub fn render() -> Result<String, Error> {
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .map_err(|e| Error::InternalServer(format!("Could not encode metrics: {}", e)))?;
+    String::from_utf8(buffer).map_err(|e| Error::InternalServer(e.to_string()))
+}
+
+#[cfg(feature = "rocket")]
+#[rocket::get("/metrics")]
+fn metrics() -> Result<(ContentType, String), Error> {
+    Ok((ContentType::Plain, render()?))
+}
+
+/// Routes exposing the metrics for Prometheus at `/metrics`. These should not
+/// be reachable from outside, so mount them on an internal-only base or
+/// restrict access in the reverse proxy.
+#[cfg(feature = "rocket")]
+pub fn routes() -> Vec<Route> {
+    rocket::routes![metrics]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cleanup_completed, db_query_timer, render, session_created};
+
+    #[test]
+    fn test_render() {
+        session_created();
+        cleanup_completed(3);
+        drop(db_query_timer("persist"));
+
+        let rendered = render().unwrap();
+        assert!(rendered.contains("comm_sessions_created_total"));
+        assert!(rendered.contains("comm_sessions_cleaned_total"));
+        assert!(rendered.contains("comm_db_query_duration_seconds_count{query=\"persist\"}"));
+    }
+
+    #[test]
+    #[cfg(feature = "platform_token")]
+    fn test_token_failure_reasons() {
+        use super::token_verification_failed;
+        use crate::types::TokenFailureReason;
+
+        token_verification_failed("guest", TokenFailureReason::BadSignature);
+        token_verification_failed("guest", TokenFailureReason::Expired);
+        token_verification_failed("guest", TokenFailureReason::WrongAudience);
+
+        let rendered = render().unwrap();
+        for reason in ["bad_signature", "expired", "wrong_audience"] {
+            assert!(rendered.contains(&format!(
+                "comm_token_verification_failures_total{{reason=\"{}\",token_type=\"guest\"}}",
+                reason
+            )));
+        }
+    }
+}
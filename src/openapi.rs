@@ -0,0 +1,334 @@
+use std::collections::{btree_map::Entry, BTreeMap};
+
+use rocket::{http::Method, serde::json::Json, Orbit, Phase, Rocket, Route};
+use utoipa::openapi::{
+    path::{OperationBuilder, ParameterBuilder, ParameterIn, PathItemType},
+    request_body::RequestBodyBuilder,
+    security::{
+        ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityRequirement, SecurityScheme,
+    },
+    ComponentsBuilder, ContentBuilder, InfoBuilder, ObjectBuilder, OpenApi, OpenApiBuilder,
+    PathItem, PathsBuilder, Required, Response, ResponseBuilder, SchemaType,
+};
+
+/// Security scheme of platform tokens in the `Authorization: Bearer` header
+const BEARER_SCHEME: &str = "bearer";
+/// Security scheme of host tokens in the `host_token` query parameter
+const HOST_TOKEN_SCHEME: &str = "host_token";
+/// Security scheme of guest tokens in the `guest_token` query parameter
+const GUEST_TOKEN_SCHEME: &str = "guest_token";
+
+fn string_schema() -> ObjectBuilder {
+    ObjectBuilder::new().schema_type(SchemaType::String)
+}
+
+fn object() -> ObjectBuilder {
+    ObjectBuilder::new().schema_type(SchemaType::Object)
+}
+
+/// Response without a described body
+fn response(description: &str) -> Response {
+    ResponseBuilder::new().description(description).build()
+}
+
+/// Response with a body of `content_type`, described by `schema`
+fn response_with(description: &str, content_type: &str, schema: ObjectBuilder) -> Response {
+    ResponseBuilder::new()
+        .description(description)
+        .content(content_type, ContentBuilder::new().schema(schema).build())
+        .build()
+}
+
+/// Server-Sent Events stream
+fn event_stream() -> Response {
+    response_with("Event stream", "text/event-stream", string_schema())
+}
+
+/// Operation of the group `tag`, with the responses every route may give
+fn operation(tag: &str, summary: &str) -> OperationBuilder {
+    OperationBuilder::new()
+        .tag(tag)
+        .summary(Some(summary))
+        .response("429", response("Too many requests"))
+        .response("500", response("Internal error"))
+}
+
+/// Require a platform token, in the `Authorization: Bearer` header or the
+/// query parameter named after `scheme`
+fn with_token(operation: OperationBuilder, scheme: &str) -> OperationBuilder {
+    operation
+        .security(SecurityRequirement::new(BEARER_SCHEME, [] as [&str; 0]))
+        .security(SecurityRequirement::new(scheme, [] as [&str; 0]))
+        .response("400", response("Malformed token"))
+        .response("401", response("Missing, invalid or expired token"))
+}
+
+/// Description of the route with handler `name`, or `None` for routes not
+/// provided by this crate
+fn describe(name: &str) -> Option<OperationBuilder> {
+    let operation = match name {
+        "live" => operation("health", "Liveness check")
+            .response("200", response_with("Alive", "application/json", object())),
+        "ready" => operation("health", "Readiness check")
+            .response("200", response_with("Ready", "application/json", object()))
+            .response(
+                "503",
+                response_with("Not ready", "application/json", object()),
+            ),
+        "receive_auth_result" => operation("auth_result", "Receive an authentication result")
+            .description(Some(
+                "Called by the core with the authentication result of the session with the \
+                 attribute ID, as a JWE",
+            ))
+            .request_body(Some(
+                RequestBodyBuilder::new()
+                    .content(
+                        "text/plain",
+                        ContentBuilder::new().schema(string_schema()).build(),
+                    )
+                    .required(Some(Required::True))
+                    .build(),
+            ))
+            .response("204", response("Result registered"))
+            .response("400", response("Result can't be decrypted or verified"))
+            .response("404", response("No session with the attribute ID"))
+            .response(
+                "409",
+                response("Session already has a result, or was closed"),
+            ),
+        "room_event_stream" => with_token(
+            operation("room_events", "Follow the events of a room")
+                .response("200", event_stream())
+                .response("403", response("Token not valid for the room")),
+            HOST_TOKEN_SCHEME,
+        ),
+        "room_event_socket" => with_token(
+            operation(
+                "room_events",
+                "Follow the events of a room over a WebSocket",
+            )
+            .response("101", response("Switching to the WebSocket protocol"))
+            .response("403", response("Token not valid for the room")),
+            HOST_TOKEN_SCHEME,
+        ),
+        "host_sessions" => with_token(
+            operation("host", "Credentials of the guests in a room")
+                .response(
+                    "200",
+                    ResponseBuilder::new()
+                        .description("Credentials, as JSON or as an HTML page")
+                        .content(
+                            "application/json",
+                            ContentBuilder::new().schema(object()).build(),
+                        )
+                        .content(
+                            "text/html",
+                            ContentBuilder::new().schema(string_schema()).build(),
+                        )
+                        .build(),
+                )
+                .response("403", response("Token not valid for the room"))
+                .response("503", response("Session database unavailable")),
+            HOST_TOKEN_SCHEME,
+        ),
+        "host_events" => with_token(
+            operation("host", "Follow the changes to the sessions of a room")
+                .response("200", event_stream())
+                .response("403", response("Token not valid for the room")),
+            HOST_TOKEN_SCHEME,
+        ),
+        "guest_init" => with_token(
+            operation("guest", "Start a session for a guest")
+                .response("303", response("Redirect to the auth-select widget"))
+                .response("503", response("Session database unavailable")),
+            GUEST_TOKEN_SCHEME,
+        ),
+        "guest_start" => operation("guest", "Start authentication at the core")
+            .description(Some(
+                "Called by the auth-select widget once the guest chose a method",
+            ))
+            .request_body(Some(
+                RequestBodyBuilder::new()
+                    .content(
+                        "application/json",
+                        ContentBuilder::new()
+                            .schema(
+                                object()
+                                    .property("purpose", string_schema())
+                                    .property("auth_method", string_schema())
+                                    .required("purpose")
+                                    .required("auth_method"),
+                            )
+                            .build(),
+                    )
+                    .required(Some(Required::True))
+                    .build(),
+            ))
+            .response(
+                "200",
+                response_with(
+                    "URL to send the guest to",
+                    "application/json",
+                    object()
+                        .property("client_url", string_schema())
+                        .required("client_url"),
+                ),
+            )
+            .response("400", response("Purpose or redirect URL not allowed"))
+            .response("404", response("No session with the attribute ID"))
+            .response("502", response("Core refused the request"))
+            .response("503", response("Core or session database unavailable")),
+        "metrics" => operation("metrics", "Prometheus metrics").response(
+            "200",
+            response_with("Metrics", "text/plain", string_schema()),
+        ),
+        _ => return None,
+    };
+    Some(operation.operation_id(Some(name)))
+}
+
+fn path_item_type(method: Method) -> Option<PathItemType> {
+    Some(match method {
+        Method::Get => PathItemType::Get,
+        Method::Post => PathItemType::Post,
+        Method::Put => PathItemType::Put,
+        Method::Delete => PathItemType::Delete,
+        Method::Patch => PathItemType::Patch,
+        _ => return None,
+    })
+}
+
+/// The path of a mounted route in OpenAPI syntax, with its parameters: e.g.
+/// `/host/{room_id}` for `/host/<room_id>?<query>`
+fn openapi_path(uri: &str) -> (String, Vec<String>) {
+    let path = uri.split('?').next().unwrap_or_default();
+    let mut parameters = vec![];
+    let segments: Vec<String> = path
+        .split('/')
+        .map(
+            |segment| match segment.strip_prefix('<').and_then(|s| s.strip_suffix('>')) {
+                Some(name) => {
+                    let name = name.trim_end_matches("..");
+                    parameters.push(name.to_owned());
+                    format!("{{{}}}", name)
+                }
+                None => segment.to_owned(),
+            },
+        )
+        .collect();
+    (segments.join("/"), parameters)
+}
+
+/// OpenAPI document describing the routes of this crate mounted on `rocket`,
+/// at the paths they are mounted at. Routes of the plugin itself are left out;
+/// plugins can add them to the returned document.
+pub fn spec<P: Phase>(rocket: &Rocket<P>) -> OpenApi {
+    let mut items: BTreeMap<String, PathItem> = BTreeMap::new();
+    for route in rocket.routes() {
+        let (operation, method) = match (
+            route.name.as_deref().and_then(describe),
+            path_item_type(route.method),
+        ) {
+            (Some(operation), Some(method)) => (operation, method),
+            _ => continue,
+        };
+        let (path, parameters) = openapi_path(&route.uri.to_string());
+        let operation = parameters
+            .into_iter()
+            .fold(operation, |operation, name| {
+                operation.parameter(
+                    ParameterBuilder::new()
+                        .name(name)
+                        .parameter_in(ParameterIn::Path)
+                        .required(Required::True)
+                        .schema(Some(string_schema())),
+                )
+            })
+            .build();
+        match items.entry(path) {
+            Entry::Occupied(mut item) => {
+                item.get_mut().operations.insert(method, operation);
+            }
+            Entry::Vacant(item) => {
+                item.insert(PathItem::new(method, operation));
+            }
+        }
+    }
+
+    let paths = items
+        .into_iter()
+        .fold(PathsBuilder::new(), |paths, (path, item)| {
+            paths.path(path, item)
+        });
+    let components = ComponentsBuilder::new()
+        .security_scheme(
+            BEARER_SCHEME,
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        )
+        .security_scheme(
+            HOST_TOKEN_SCHEME,
+            SecurityScheme::ApiKey(ApiKey::Query(ApiKeyValue::new(HOST_TOKEN_SCHEME))),
+        )
+        .security_scheme(
+            GUEST_TOKEN_SCHEME,
+            SecurityScheme::ApiKey(ApiKey::Query(ApiKeyValue::new(GUEST_TOKEN_SCHEME))),
+        )
+        .build();
+    OpenApiBuilder::new()
+        .info(
+            InfoBuilder::new()
+                .title("Verder Helpen communication plugin")
+                .version(env!("CARGO_PKG_VERSION")),
+        )
+        .paths(paths)
+        .components(Some(components))
+        .build()
+}
+
+#[rocket::get("/openapi.json")]
+fn openapi(rocket: &Rocket<Orbit>) -> Json<OpenApi> {
+    Json(spec(rocket))
+}
+
+/// Route serving the document of [`spec`] for the running Rocket instance at
+/// `/openapi.json`
+pub fn routes() -> Vec<Route> {
+    rocket::routes![openapi]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{openapi_path, spec};
+
+    #[test]
+    #[cfg(feature = "sessions")]
+    fn test_spec() {
+        assert_eq!(
+            openapi_path("/host/<room_id>/events?<host_token>"),
+            (
+                "/host/{room_id}/events".to_owned(),
+                vec!["room_id".to_owned()]
+            )
+        );
+
+        let rocket = rocket::build()
+            .mount("/health", crate::routes::health())
+            .mount("/", crate::routes::auth_result());
+        let spec = serde_json::to_value(spec(&rocket)).unwrap();
+        let paths = &spec["paths"];
+        assert!(paths["/health/live"]["get"].is_object());
+        let receive = &paths["/auth_result/{attr_id}"]["post"];
+        assert_eq!(receive["operationId"], "receive_auth_result");
+        assert_eq!(receive["parameters"][0]["name"], "attr_id");
+        assert!(receive["responses"]["409"].is_object());
+        assert_eq!(
+            spec["components"]["securitySchemes"]["host_token"]["in"],
+            "query"
+        );
+    }
+}
@@ -0,0 +1,321 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "rocket")]
+use rocket::{
+    fairing::{AdHoc, Fairing},
+    http::Status,
+    outcome::Outcome,
+    request::{self, FromRequest, Request},
+};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "rocket")]
+use crate::config::CurrentConfig;
+#[cfg(all(feature = "sessions", feature = "rocket"))]
+use crate::session::SessionDBConn;
+#[cfg(feature = "sessions")]
+use crate::session::SessionDb;
+use crate::{config::ConfigValidation, error::Error};
+
+/// Take a token from the bucket of `$1`, holding at most `$2` tokens and
+/// refilled with `$3` tokens per second. Returns no row if the bucket is
+/// empty. Buckets idle for `$4` seconds are full again, and are removed.
+#[cfg(feature = "sessions")]
+const TAKE_TOKEN: &str = "
+    WITH pruned AS (
+        DELETE FROM rate_limit
+        WHERE updated_at < now() - make_interval(secs => $4)
+        AND key <> $1
+    )
+    INSERT INTO rate_limit (key, tokens, updated_at)
+    VALUES ($1, $2::float8 - 1, now())
+    ON CONFLICT (key) DO UPDATE
+    SET tokens = LEAST(
+            $2::float8,
+            rate_limit.tokens + EXTRACT(EPOCH FROM now() - rate_limit.updated_at) * $3::float8
+        ) - 1,
+        updated_at = now()
+    WHERE LEAST(
+            $2::float8,
+            rate_limit.tokens + EXTRACT(EPOCH FROM now() - rate_limit.updated_at) * $3::float8
+        ) >= 1
+    RETURNING tokens";
+
+/// What requests are counted against
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    /// The IP address of the client
+    #[default]
+    Ip,
+    /// The bearer token in the `Authorization` header, or the IP address of
+    /// the client for requests without one
+    Token,
+}
+
+/// Where the token buckets are kept
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitStore {
+    /// In the memory of this process, limiting each instance separately
+    #[default]
+    Memory,
+    /// In the session database, limiting all instances together
+    #[cfg(feature = "sessions")]
+    Database,
+}
+
+/// Rate limit as configured through `[global.rate_limit]`
+#[derive(Deserialize, Debug)]
+pub struct RawRateLimitConfig {
+    /// Number of requests allowed in a burst
+    requests: u32,
+    /// Time in which a full burst is allowed again, e.g. "1m"
+    per: String,
+    #[serde(default)]
+    key: RateLimitKey,
+    #[serde(default)]
+    store: RateLimitStore,
+}
+
+/// Token bucket rate limit: every key may make `requests` requests at once,
+/// after which it regains the right to a request every `per / requests`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    pub requests: u32,
+    pub per: Duration,
+    pub key: RateLimitKey,
+    pub store: RateLimitStore,
+}
+
+impl RateLimitConfig {
+    /// Check the raw configuration, recording all problems in `validation`
+    pub(crate) fn validate(
+        raw_config: RawRateLimitConfig,
+        validation: &mut ConfigValidation,
+    ) -> Option<RateLimitConfig> {
+        if raw_config.requests == 0 {
+            validation.problem("rate_limit.requests must be at least 1".to_string());
+        }
+        let per = validation.check(
+            "rate_limit.per",
+            humantime::parse_duration(&raw_config.per)
+                .map_err(|e| Error::Config(format!("Invalid per: {}", e))),
+        )?;
+        if per.is_zero() {
+            validation.problem("rate_limit.per must be positive".to_string());
+            return None;
+        }
+
+        Some(RateLimitConfig {
+            requests: raw_config.requests.max(1),
+            per,
+            key: raw_config.key,
+            store: raw_config.store,
+        })
+    }
+
+    /// Tokens regained per second
+    fn refill_rate(&self) -> f64 {
+        self.requests as f64 / self.per.as_secs_f64()
+    }
+}
+
+/// Token bucket of a single key
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Rate limiter for public endpoints. Must be managed by Rocket, e.g. through
+/// [`rate_limit_fairing`], for [`RateLimited`] to work.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: Option<RateLimitConfig>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Rate limiter enforcing `config`, or allowing everything if `None`
+    pub fn new(config: Option<RateLimitConfig>) -> Self {
+        RateLimiter {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a request against the in-memory bucket of `key`, regardless of
+    /// the configured store, returning false if it exceeds the limit. Lets
+    /// plugins not using Rocket, or limiting by other keys, apply the limit.
+    pub fn allow_key(&self, key: &str) -> bool {
+        match &self.config {
+            Some(config) => self.take_in_memory(config, key, Instant::now()),
+            None => true,
+        }
+    }
+
+    /// Take a token from the in-memory bucket of `key`. Returns false if the
+    /// bucket is empty.
+    fn take_in_memory(&self, config: &RateLimitConfig, key: &str, now: Instant) -> bool {
+        let capacity = config.requests as f64;
+        let rate = config.refill_rate();
+        let mut buckets = self.buckets.lock().unwrap();
+        // Buckets that refilled completely are the same as new ones
+        buckets.retain(|_, bucket| {
+            bucket.tokens + now.duration_since(bucket.updated_at).as_secs_f64() * rate < capacity
+        });
+
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: capacity,
+            updated_at: now,
+        });
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated_at = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Take a token from the bucket of `key` in the session database
+    #[cfg(feature = "sessions")]
+    async fn take_in_database(
+        config: &RateLimitConfig,
+        key: String,
+        db: &impl SessionDb,
+    ) -> Result<bool, Error> {
+        let capacity = config.requests as f64;
+        let rate = config.refill_rate();
+        let idle = config.per.as_secs_f64();
+        let row = db
+            .run(move |c| c.query_opt(TAKE_TOKEN, &[&key, &capacity, &rate, &idle]))
+            .await?;
+        Ok(row.is_some())
+    }
+
+    /// Count a request against its key, returning false if it exceeds the
+    /// limit
+    #[cfg(feature = "rocket")]
+    async fn allow(&self, request: &Request<'_>) -> Result<bool, Error> {
+        let config = match &self.config {
+            Some(config) => config,
+            None => return Ok(true),
+        };
+        let key = match request_key(request, config.key) {
+            Some(key) => key,
+            // Without a client address there is nothing to count against
+            None => return Ok(true),
+        };
+
+        match config.store {
+            RateLimitStore::Memory => Ok(self.take_in_memory(config, &key, Instant::now())),
+            #[cfg(feature = "sessions")]
+            RateLimitStore::Database => match request.guard::<SessionDBConn>().await {
+                Outcome::Success(db) => Self::take_in_database(config, key, &db).await,
+                _ => Err(Error::InternalServer(
+                    "Session database unavailable for rate limiting".to_owned(),
+                )),
+            },
+        }
+    }
+}
+
+/// Key the request is counted against. Tokens are identified by their
+/// signature, so that the tokens themselves are never stored.
+#[cfg(feature = "rocket")]
+fn request_key(request: &Request<'_>, key: RateLimitKey) -> Option<String> {
+    let token = match key {
+        RateLimitKey::Token => request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .and_then(|jwt| jwt.rsplit('.').next()),
+        RateLimitKey::Ip => None,
+    };
+    match token {
+        Some(token) => Some(format!("token:{}", token)),
+        None => request.client_ip().map(|ip| format!("ip:{}", ip)),
+    }
+}
+
+/// Request guard counting the request against the configured rate limit, and
+/// refusing it with 429 Too Many Requests once the limit is exceeded. Add it
+/// to unauthenticated routes, such as those starting sessions or receiving
+/// attributes. Requires a [`RateLimiter`] to be managed.
+#[derive(Debug)]
+pub struct RateLimited;
+
+#[cfg(feature = "rocket")]
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RateLimited {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Error> {
+        let limiter = match request.rocket().state::<RateLimiter>() {
+            Some(limiter) => limiter,
+            None => {
+                return Outcome::Error((
+                    Status::InternalServerError,
+                    Error::InternalServer("No rate limiter configured".to_owned()),
+                ))
+            }
+        };
+
+        match limiter.allow(request).await {
+            Ok(true) => Outcome::Success(RateLimited),
+            Ok(false) => Outcome::Error((Status::TooManyRequests, Error::TooManyRequests)),
+            Err(e) => Outcome::Error((Status::InternalServerError, e)),
+        }
+    }
+}
+
+/// Fairing managing a [`RateLimiter`] enforcing the configured
+/// `[global.rate_limit]`, or allowing everything if none is configured.
+/// Requires the configuration to be managed, see [`CurrentConfig`].
+#[cfg(feature = "rocket")]
+pub fn rate_limit_fairing() -> impl Fairing {
+    AdHoc::on_ignite("Rate limiter", |rocket| {
+        Box::pin(async move {
+            let config = CurrentConfig::from_rocket(&rocket)
+                .expect("No configuration found")
+                .rate_limit();
+            rocket.manage(RateLimiter::new(config))
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{RateLimitConfig, RateLimitKey, RateLimitStore, RateLimiter};
+
+    #[test]
+    fn test_token_bucket() {
+        let config = RateLimitConfig {
+            requests: 2,
+            per: Duration::from_secs(10),
+            key: RateLimitKey::Ip,
+            store: RateLimitStore::Memory,
+        };
+        let limiter = RateLimiter::new(Some(config));
+        let start = Instant::now();
+
+        assert!(limiter.take_in_memory(&config, "a", start));
+        assert!(limiter.take_in_memory(&config, "a", start));
+        assert!(!limiter.take_in_memory(&config, "a", start));
+        assert!(limiter.take_in_memory(&config, "b", start));
+
+        // One token is regained every 5 seconds
+        let later = start + Duration::from_secs(5);
+        assert!(limiter.take_in_memory(&config, "a", later));
+        assert!(!limiter.take_in_memory(&config, "a", later));
+    }
+}
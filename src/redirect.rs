@@ -0,0 +1,160 @@
+use reqwest::Url;
+
+use crate::{config::ConfigValidation, error::Error};
+
+/// Pattern a redirect URL may match, as configured in
+/// `redirect_url_allowlist`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RedirectPattern {
+    /// HTTPS URLs on exactly this host, configured as e.g. `example.com`
+    Host(String),
+    /// HTTPS URLs on any subdomain of this host, configured as e.g.
+    /// `*.example.com`
+    Subdomains(String),
+    /// URLs with the same scheme, host and port, whose path starts with the
+    /// path of this URL, configured as e.g. `https://example.com/rooms/`
+    Prefix(Url),
+}
+
+impl RedirectPattern {
+    /// Parse a pattern: a URL if it has a scheme, and a host otherwise
+    pub fn parse(pattern: &str) -> Result<Self, Error> {
+        if pattern.contains("://") {
+            let url = Url::parse(pattern)
+                .map_err(|e| Error::Config(format!("Invalid URL {:?}: {}", pattern, e)))?;
+            if url.host_str().is_none() || url.query().is_some() || url.fragment().is_some() {
+                return Err(Error::Config(format!(
+                    "URL pattern {:?} must have a host, and no query or fragment",
+                    pattern
+                )));
+            }
+            return Ok(RedirectPattern::Prefix(url));
+        }
+
+        let (host, subdomains) = match pattern.strip_prefix("*.") {
+            Some(host) => (host, true),
+            None => (pattern, false),
+        };
+        // Hosts are compared as the URL parser normalizes them
+        let host = Url::parse(&format!("https://{}", host))
+            .ok()
+            .filter(|url| url.path() == "/" && url.port().is_none())
+            .and_then(|url| url.host_str().map(str::to_owned))
+            .ok_or_else(|| Error::Config(format!("Invalid host pattern {:?}", pattern)))?;
+        Ok(if subdomains {
+            RedirectPattern::Subdomains(host)
+        } else {
+            RedirectPattern::Host(host)
+        })
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host,
+            None => return false,
+        };
+        match self {
+            RedirectPattern::Host(allowed) => url.scheme() == "https" && host == allowed,
+            RedirectPattern::Subdomains(allowed) => {
+                url.scheme() == "https"
+                    && host
+                        .strip_suffix(allowed.as_str())
+                        .is_some_and(|subdomain| subdomain.ends_with('.') && subdomain.len() > 1)
+            }
+            RedirectPattern::Prefix(prefix) => {
+                url.scheme() == prefix.scheme()
+                    && url.host_str() == prefix.host_str()
+                    && url.port_or_known_default() == prefix.port_or_known_default()
+                    && url.path().starts_with(prefix.path())
+            }
+        }
+    }
+}
+
+/// URLs guests may be redirected to, taken from their guest tokens. Without
+/// any patterns, every URL is allowed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RedirectAllowList {
+    patterns: Vec<RedirectPattern>,
+}
+
+impl RedirectAllowList {
+    pub fn new(patterns: Vec<RedirectPattern>) -> Self {
+        RedirectAllowList { patterns }
+    }
+
+    /// Check the configured patterns, recording all problems in `validation`
+    pub(crate) fn validate(
+        raw_patterns: Vec<String>,
+        validation: &mut ConfigValidation,
+    ) -> Option<RedirectAllowList> {
+        let patterns: Vec<Option<RedirectPattern>> = raw_patterns
+            .iter()
+            .map(|pattern| {
+                validation.check("redirect_url_allowlist", RedirectPattern::parse(pattern))
+            })
+            .collect();
+        patterns
+            .into_iter()
+            .collect::<Option<_>>()
+            .map(RedirectAllowList::new)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    pub fn patterns(&self) -> &[RedirectPattern] {
+        &self.patterns
+    }
+
+    /// Check that guests may be redirected to `url`, failing with
+    /// `Error::BadRequest` if it does not match any pattern
+    pub fn check(&self, url: &str) -> Result<(), Error> {
+        if self.patterns.is_empty() {
+            return Ok(());
+        }
+        match Url::parse(url) {
+            Ok(url) if self.patterns.iter().any(|pattern| pattern.matches(&url)) => Ok(()),
+            _ => Err(Error::BadRequest("Redirect URL not allowed")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RedirectAllowList, RedirectPattern};
+
+    #[test]
+    fn test_redirect_allow_list() {
+        let allow_list = RedirectAllowList::new(
+            [
+                "example.com",
+                "*.verderhelpen.nl",
+                "http://localhost:8000/rooms/",
+            ]
+            .iter()
+            .map(|pattern| RedirectPattern::parse(pattern).unwrap())
+            .collect(),
+        );
+
+        assert!(allow_list.check("https://example.com/room/16").is_ok());
+        assert!(allow_list.check("https://EXAMPLE.com").is_ok());
+        assert!(allow_list.check("http://example.com/").is_err());
+        assert!(allow_list.check("https://example.com.evil.org/").is_err());
+        assert!(allow_list
+            .check("https://evil.org/?https://example.com")
+            .is_err());
+        assert!(allow_list.check("https://meet.verderhelpen.nl/").is_ok());
+        assert!(allow_list.check("https://verderhelpen.nl/").is_err());
+        assert!(allow_list.check("https://evilverderhelpen.nl/").is_err());
+        assert!(allow_list.check("http://localhost:8000/rooms/16").is_ok());
+        assert!(allow_list.check("http://localhost:8001/rooms/16").is_err());
+        assert!(allow_list.check("http://localhost:8000/other").is_err());
+        assert!(allow_list.check("not a url").is_err());
+
+        assert!(RedirectAllowList::default().check("not a url").is_ok());
+        assert!(RedirectPattern::parse("example.com/path").is_err());
+        assert!(RedirectPattern::parse("https://example.com/?q").is_err());
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use tera::Context;
+
+use crate::{
+    auth_result::StoredAuthResult,
+    error::Error,
+    templates::{RenderType, RenderedContent, TEMPLATES},
+    translations::Translations,
+    types::Credentials,
+};
+
+/// Ordering and labels of rendered attributes, configured through
+/// `[global.attribute_display]`
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct AttributeDisplay {
+    /// Attributes shown first, in this order. Other attributes follow in
+    /// alphabetical order.
+    pub order: Vec<String>,
+    /// Labels for attributes, taking precedence over translations
+    pub labels: HashMap<String, String>,
+}
+
+impl AttributeDisplay {
+    /// Sort attributes according to the configured order
+    pub fn sort(&self, attributes: HashMap<String, String>) -> Vec<(String, String)> {
+        let mut attributes: Vec<(String, String)> = attributes.into_iter().collect();
+        attributes.sort_by(|x, y| {
+            let position = |key: &str| self.order.iter().position(|k| k == key);
+            match (position(&x.0), position(&y.0)) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (None, None) => x.0.cmp(&y.0),
+            }
+        });
+        attributes
+    }
+
+    /// Label for an attribute: the configured label, else the translation of
+    /// its key, else the key itself
+    pub fn label(&self, key: &str, translations: &Translations) -> String {
+        match self.labels.get(key) {
+            Some(label) => label.clone(),
+            None => translations.get(key, key),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct RenderedAttribute {
+    key: String,
+    label: String,
+    value: String,
+}
+
+#[derive(Serialize, Debug)]
+struct RenderedCredentials {
+    purpose: Option<String>,
+    name: Option<String>,
+    attributes: Vec<RenderedAttribute>,
+}
+
+/// Render credentials to HTML, plain text or JSON, with attributes ordered
+/// and labelled according to `display`
+pub fn render_attributes(
+    credentials: Vec<Credentials>,
+    render_type: RenderType,
+    translations: Translations,
+    display: &AttributeDisplay,
+) -> Result<RenderedContent, Error> {
+    let rendered: Vec<RenderedCredentials> = credentials
+        .into_iter()
+        .map(|credentials| RenderedCredentials {
+            purpose: credentials.purpose,
+            name: credentials.name,
+            attributes: display
+                .sort(credentials.attributes)
+                .into_iter()
+                .map(|(key, value)| RenderedAttribute {
+                    label: display.label(&key, &translations),
+                    key,
+                    value,
+                })
+                .collect(),
+        })
+        .collect();
+
+    let content = match render_type {
+        RenderType::Json => serde_json::to_string(&rendered)?,
+        RenderType::Text => render_text(&rendered),
+        RenderType::Html | RenderType::HtmlPage => {
+            // The templates expect attributes as (key, value) pairs, labelled
+            // through the translations
+            let mut labels = translations.all().clone();
+            let sorted: Vec<_> = rendered
+                .into_iter()
+                .map(|credentials| {
+                    let attributes: Vec<(String, String)> = credentials
+                        .attributes
+                        .into_iter()
+                        .map(|attribute| {
+                            labels.insert(attribute.key.clone(), attribute.label);
+                            (attribute.key, attribute.value)
+                        })
+                        .collect();
+                    serde_json::json!({
+                        "purpose": credentials.purpose,
+                        "name": credentials.name,
+                        "attributes": attributes,
+                    })
+                })
+                .collect();
+
+            let mut context = Context::new();
+            context.insert("translations", &labels);
+            context.insert("credentials", &sorted);
+            if render_type == RenderType::HtmlPage {
+                TEMPLATES.render("base.html", &context)?
+            } else {
+                TEMPLATES.render("credentials.html", &context)?
+            }
+        }
+    };
+
+    Ok(RenderedContent {
+        content,
+        render_type,
+    })
+}
+
+fn render_text(rendered: &[RenderedCredentials]) -> String {
+    let mut text = String::new();
+    for credentials in rendered {
+        if let Some(name) = &credentials.name {
+            text.push_str(name);
+            text.push('\n');
+        }
+        for attribute in &credentials.attributes {
+            text.push_str(&format!("{}: {}\n", attribute.label, attribute.value));
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Render the attributes of a single authentication result, see
+/// [`render_attributes`]
+pub fn render_auth_result(
+    auth_result: &StoredAuthResult,
+    name: Option<String>,
+    purpose: Option<String>,
+    render_type: RenderType,
+    translations: Translations,
+    display: &AttributeDisplay,
+) -> Result<RenderedContent, Error> {
+    let credentials = Credentials {
+        purpose,
+        name,
+        attributes: auth_result.attributes.clone().unwrap_or_default(),
+    };
+    render_attributes(vec![credentials], render_type, translations, display)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{render_attributes, AttributeDisplay};
+    use crate::{templates::RenderType, translations::Translations, types::Credentials};
+
+    fn credentials() -> Vec<Credentials> {
+        vec![Credentials {
+            purpose: Some("test".to_string()),
+            name: Some("Henk Dieter".to_string()),
+            attributes: HashMap::from([
+                ("age".to_string(), "42".to_string()),
+                ("email".to_string(), "hd@example.com".to_string()),
+                ("city".to_string(), "Nijmegen".to_string()),
+            ]),
+        }]
+    }
+
+    fn translations() -> Translations {
+        Translations {
+            translations: HashMap::from([("age".to_string(), "Leeftijd".to_string())]),
+            language: "nl".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_render_attributes() {
+        let display = AttributeDisplay {
+            order: vec!["email".to_string()],
+            labels: HashMap::from([("city".to_string(), "Woonplaats".to_string())]),
+        };
+
+        let text =
+            render_attributes(credentials(), RenderType::Text, translations(), &display).unwrap();
+        assert_eq!(
+            text.content,
+            "Henk Dieter\nemail: hd@example.com\nLeeftijd: 42\nWoonplaats: Nijmegen\n\n"
+        );
+
+        let json =
+            render_attributes(credentials(), RenderType::Json, translations(), &display).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json.content).unwrap();
+        assert_eq!(json[0]["attributes"][0]["key"], "email");
+        assert_eq!(json[0]["attributes"][2]["label"], "Woonplaats");
+
+        let html =
+            render_attributes(credentials(), RenderType::Html, translations(), &display).unwrap();
+        let email = html.content.find("hd@example.com").unwrap();
+        let city = html.content.find("Woonplaats").unwrap();
+        assert!(email < city);
+    }
+}
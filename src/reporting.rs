@@ -0,0 +1,80 @@
+use std::sync::Mutex;
+
+use rocket::{
+    fairing::{self, Fairing, Info, Kind},
+    Build, Orbit, Request, Rocket,
+};
+
+use crate::{config::CurrentConfig, error::Error};
+
+/// Fairing setting up the Sentry client from the configured `sentry_dsn`.
+/// Once attached, panics are reported, as are database errors and failed
+/// requests to the core that are returned from request handlers. Without a
+/// DSN, nothing is reported. Requires the configuration to be managed.
+#[derive(Default)]
+pub struct SentryFairing {
+    guard: Mutex<Option<sentry::ClientInitGuard>>,
+}
+
+impl SentryFairing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for SentryFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Sentry",
+            kind: Kind::Ignite | Kind::Shutdown,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let dsn = CurrentConfig::from_rocket(&rocket)
+            .and_then(|config| config.sentry_dsn().map(str::to_owned));
+        let dsn = match dsn {
+            Some(dsn) => dsn.parse::<sentry::types::Dsn>(),
+            None => return Ok(rocket),
+        };
+        let dsn = match dsn {
+            Ok(dsn) => dsn,
+            Err(e) => {
+                eprintln!("Invalid Sentry DSN: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        let guard = sentry::init(sentry::ClientOptions {
+            dsn: Some(dsn),
+            release: sentry::release_name!(),
+            ..Default::default()
+        });
+        *self.guard.lock().unwrap() = Some(guard);
+        Ok(rocket)
+    }
+
+    async fn on_shutdown(&self, _rocket: &Rocket<Orbit>) {
+        // Dropping the guard flushes pending events
+        self.guard.lock().unwrap().take();
+    }
+}
+
+/// Report `error` to Sentry if it points at a problem in the deployment rather
+/// than in the request: database errors and failing requests to the core. The
+/// request method and path are attached; query strings are left out, as they
+/// may contain tokens.
+pub fn report_error(error: &Error, request: &Request<'_>) {
+    if !matches!(error, Error::Database(_) | Error::Core(_)) {
+        return;
+    }
+
+    sentry::with_scope(
+        |scope| {
+            scope.set_tag("method", request.method());
+            scope.set_tag("path", request.uri().path());
+        },
+        || sentry::capture_error(error),
+    );
+}
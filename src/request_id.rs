@@ -0,0 +1,124 @@
+#[cfg(feature = "rocket")]
+use std::convert::Infallible;
+use std::fmt;
+
+#[cfg(feature = "rocket")]
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Header,
+    outcome::Outcome,
+    request::{self, FromRequest},
+    Data, Request, Response,
+};
+
+#[cfg(feature = "rocket")]
+use crate::error::TRACE_ID_HEADER;
+use crate::{error::TRACE_ID_LENGTH, util::random_string};
+
+/// Maximum length of request IDs taken from incoming requests
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// ID identifying a request across the comm plugin and the core, taken from
+/// the `X-Request-Id` header of the request or generated. It is the trace ID
+/// of error responses, and is logged and forwarded with requests to the core.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// Generate a new random request ID
+    pub fn generate() -> Self {
+        RequestId(random_string(TRACE_ID_LENGTH))
+    }
+
+    /// Take the request ID from the value of an `X-Request-Id` header, or
+    /// generate one if there is none or it is unfit for logs and headers
+    pub fn from_header(value: Option<&str>) -> Self {
+        match value {
+            Some(id)
+                if !id.is_empty()
+                    && id.len() <= MAX_REQUEST_ID_LENGTH
+                    && id.chars().all(|c| c.is_ascii_graphic()) =>
+            {
+                RequestId(id.to_owned())
+            }
+            _ => RequestId::generate(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The ID of `request`, taken or generated when first asked for and the same
+/// for the rest of the request
+#[cfg(feature = "rocket")]
+pub fn request_id<'r>(request: &'r Request<'_>) -> &'r RequestId {
+    request.local_cache(|| RequestId::from_header(request.headers().get_one(TRACE_ID_HEADER)))
+}
+
+#[cfg(feature = "rocket")]
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r RequestId {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Infallible> {
+        Outcome::Success(request_id(request))
+    }
+}
+
+/// Fairing assigning every request its [`RequestId`] on arrival, and returning
+/// it in the `X-Request-Id` header of every response
+#[cfg(feature = "rocket")]
+#[derive(Debug, Default)]
+pub struct RequestIdFairing;
+
+#[cfg(feature = "rocket")]
+impl RequestIdFairing {
+    pub fn new() -> Self {
+        RequestIdFairing
+    }
+}
+
+#[cfg(feature = "rocket")]
+#[rocket::async_trait]
+impl Fairing for RequestIdFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request ID",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+        request_id(request);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let id = request_id(request).as_str().to_owned();
+        response.set_header(Header::new(TRACE_ID_HEADER, id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RequestId;
+
+    #[test]
+    fn test_request_id() {
+        assert_eq!(RequestId::from_header(Some("abc-123")).as_str(), "abc-123");
+        assert_ne!(RequestId::from_header(Some("abc 123")).as_str(), "abc 123");
+        assert_ne!(
+            RequestId::from_header(Some(&"a".repeat(200))).as_str(),
+            "a".repeat(200)
+        );
+        assert_eq!(RequestId::from_header(None).as_str().len(), 16);
+        assert_ne!(RequestId::generate(), RequestId::generate());
+    }
+}
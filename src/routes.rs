@@ -0,0 +1,551 @@
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+use std::convert::Infallible;
+
+#[cfg(feature = "websocket")]
+use rocket::futures::StreamExt;
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+use rocket::{
+    http::Accept,
+    outcome::Outcome,
+    request::{self, FromRequest, Request},
+    response::{
+        stream::{Event, EventStream},
+        Redirect,
+    },
+    tokio, Shutdown,
+};
+use rocket::{http::Status, serde::json::Json, Route};
+#[cfg(feature = "websocket")]
+use rocket_ws::{Message, WebSocket};
+use serde_json::{json, Map, Value};
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+use verder_helpen_proto::{ClientUrlResponse, StartRequestAuthOnly};
+
+use crate::config::{Config, CurrentConfig};
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+use crate::{
+    auth_during_comm::widget_url_for,
+    core_client::start_authentication_session_with,
+    credentials::{credentials_for_host, render_credentials},
+    events,
+    guards::{ValidatedGuestToken, ValidatedHostToken},
+    request_id::RequestId,
+    session::SessionReader,
+    templates::{RenderType, RenderedContent},
+    translations::Translations,
+    types::{Credentials, GuestToken, HostToken, RoomId, StartRequest},
+};
+#[cfg(feature = "sessions")]
+use crate::{
+    auth_result::{decrypt_and_verify_refreshed, StoredAuthResult},
+    error::Error,
+    session::{Session, SessionDBConn},
+    types::AttrId,
+};
+
+#[rocket::get("/live")]
+fn live() -> Json<Value> {
+    Json(json!({ "status": "live" }))
+}
+
+#[cfg(feature = "sessions")]
+async fn check_session_db(db: Option<SessionDBConn>) -> Result<(), String> {
+    let db = db.ok_or_else(|| "no connection available".to_owned())?;
+    db.run(|c| c.execute("SELECT 1", &[]))
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "auth_during_comm")]
+async fn check_core(config: &Config) -> Option<Result<(), String>> {
+    if !config
+        .auth_during_comm_config()
+        .core_request_policy()
+        .readiness_check
+    {
+        return None;
+    }
+    Some(
+        crate::core_client::check_reachable(config)
+            .await
+            .map_err(|e| e.to_string()),
+    )
+}
+
+#[cfg(not(feature = "auth_during_comm"))]
+async fn check_core(_config: &Config) -> Option<Result<(), String>> {
+    None
+}
+
+#[cfg(feature = "sessions")]
+#[rocket::get("/ready")]
+async fn ready(config: CurrentConfig<'_>, db: Option<SessionDBConn>) -> (Status, Json<Value>) {
+    readiness(&config, vec![("session_db", check_session_db(db).await)]).await
+}
+
+#[cfg(not(feature = "sessions"))]
+#[rocket::get("/ready")]
+async fn ready(config: CurrentConfig<'_>) -> (Status, Json<Value>) {
+    readiness(&config, vec![]).await
+}
+
+/// Combine the results of the readiness checks into a response, adding the
+/// core reachability check if configured
+async fn readiness(
+    config: &Config,
+    mut checks: Vec<(&'static str, Result<(), String>)>,
+) -> (Status, Json<Value>) {
+    if let Some(result) = check_core(config).await {
+        checks.push(("core", result));
+    }
+
+    let ready = checks.iter().all(|(_, result)| result.is_ok());
+    let checks: Map<String, Value> = checks
+        .into_iter()
+        .map(|(name, result)| {
+            let outcome = match result {
+                Ok(()) => "ok".to_owned(),
+                Err(e) => {
+                    eprintln!("Readiness check {} failed: {}", name, e);
+                    "failed".to_owned()
+                }
+            };
+            (name.to_owned(), Value::String(outcome))
+        })
+        .collect();
+
+    let (status, description) = if ready {
+        (Status::Ok, "ready")
+    } else {
+        (Status::ServiceUnavailable, "unavailable")
+    };
+    (
+        status,
+        Json(json!({ "status": description, "checks": checks })),
+    )
+}
+
+#[cfg(feature = "sessions")]
+#[rocket::post("/auth_result/<attr_id>", data = "<jwe>")]
+async fn receive_auth_result(
+    attr_id: AttrId,
+    jwe: String,
+    config: CurrentConfig<'_>,
+    db: SessionDBConn,
+) -> Result<Status, Error> {
+    let auth_result =
+        StoredAuthResult::from(decrypt_and_verify_refreshed(jwe.trim(), &config).await?);
+    let session = Session::find_by_attr_id(attr_id.clone(), &db).await?;
+    config.check_required_attributes(&session.guest_token.purpose, &auth_result)?;
+    match Session::register_auth_result(attr_id.clone(), auth_result, &db).await {
+        Ok(()) => Ok(Status::NoContent),
+        // Tell apart unknown sessions from those that can't take a result
+        Err(Error::NotFound) => match Session::find_by_attr_id(attr_id, &db).await {
+            Ok(session) if session.auth_result.is_some() => {
+                Err(Error::Conflict("Authentication result already registered"))
+            }
+            Ok(_) => Err(Error::Conflict(
+                "Session no longer accepts an authentication result",
+            )),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Route receiving authentication results from the core, for mounting at the
+/// root of the internal URL. `POST /auth_result/<attr_id>` takes the
+/// authentication result JWE as its body, decrypts and verifies it with the
+/// configured keys, and registers it with the session with that attribute ID.
+/// Responds with:
+/// - `204 No Content` once the result is registered,
+/// - `400 Bad Request` if the result can't be decrypted or verified, or a
+///   successful result lacks the attributes configured for the purpose of the
+///   session,
+/// - `404 Not Found` if there is no session with the attribute ID,
+/// - `409 Conflict` if the session already has a result, or was expired or
+///   cancelled.
+///
+/// Results larger than Rocket's `string` limit, 8 KiB by default, are refused.
+/// Requires the [`Config`] to be managed and the [`SessionDBConn`] fairing to
+/// be attached.
+#[cfg(feature = "sessions")]
+pub fn auth_result() -> Vec<Route> {
+    rocket::routes![receive_auth_result]
+}
+
+/// Liveness and readiness routes, for mounting at e.g. `/health`. `live`
+/// always succeeds; `ready` checks the session database connection and, if
+/// `core_requests.readiness_check` is set, whether the core is reachable.
+/// Failing checks result in `503 Service Unavailable`. Requires the
+/// [`Config`] to be managed.
+pub fn health() -> Vec<Route> {
+    rocket::routes![live, ready]
+}
+
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+#[rocket::get("/<room_id>")]
+fn room_event_stream(
+    room_id: RoomId,
+    host: ValidatedHostToken,
+    shutdown: Shutdown,
+) -> Result<EventStream![], Error> {
+    host.authorize_room(&room_id, &host.instance)?;
+    Ok(event_stream(room_id, host.0.instance, shutdown))
+}
+
+/// Server-Sent Events stream of the events in room `room_id` of the platform
+/// instance `instance`, ending on shutdown
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+fn event_stream(room_id: RoomId, instance: String, mut shutdown: Shutdown) -> EventStream![] {
+    // Subscribe before responding, so no events are missed
+    let mut events = events::subscribe();
+    EventStream! {
+        loop {
+            let event = tokio::select! {
+                event = events::next_in_room(&mut events, &room_id, &instance) => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                _ = &mut shutdown => break,
+            };
+            yield Event::json(&event).event(event.kind.name());
+        }
+    }
+}
+
+/// Server-Sent Events stream of changes to the sessions in a room, for mounting
+/// at e.g. `/events`. `GET /<room_id>` requires a host token for that room, see
+/// [`ValidatedHostToken`], and only streams the events of sessions on the
+/// platform instance of the host. Each event is named after its
+/// [`events::RoomEventKind`] and carries the [`events::RoomEvent`] as JSON.
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+pub fn room_events() -> Vec<Route> {
+    rocket::routes![room_event_stream]
+}
+
+/// Customization of the host dashboard served by [`host`]. Every method does
+/// what most plugins need by default; manage a [`HostFlow`] to override some
+/// of them.
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+#[rocket::async_trait]
+pub trait HostFlowHooks: Send + Sync {
+    /// Check that `host` may follow the sessions in room `room_id` of its
+    /// platform instance. By default, hosts may only follow the room their
+    /// token was issued for.
+    async fn authorize(&self, host: &HostToken, room_id: &RoomId) -> Result<(), Error> {
+        host.authorize_room(room_id, &host.instance)
+    }
+
+    /// Select the sessions shown to `host`, by default all sessions in the
+    /// room. Sessions of other platform instances were left out already.
+    fn sessions(&self, _host: &HostToken, sessions: Vec<Session>) -> Vec<Session> {
+        sessions
+    }
+
+    /// Render the credentials of the guests, by default using
+    /// [`render_credentials`]
+    fn render(
+        &self,
+        credentials: Vec<Credentials>,
+        render_type: RenderType,
+        translations: Translations,
+        config: &Config,
+    ) -> Result<RenderedContent, Error> {
+        render_credentials(credentials, render_type, translations, config)
+    }
+}
+
+/// Host dashboard without customizations
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+pub struct DefaultHostFlow;
+
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+impl HostFlowHooks for DefaultHostFlow {}
+
+/// Hooks customizing the routes in [`host`], to be managed by Rocket. Without
+/// it, [`DefaultHostFlow`] is used.
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+pub struct HostFlow(pub Box<dyn HostFlowHooks>);
+
+/// The hooks of the managed [`HostFlow`], or [`DefaultHostFlow`]. Unlike
+/// `&State<HostFlow>`, this does not require a [`HostFlow`] to be managed.
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+struct HostHooks<'r>(&'r dyn HostFlowHooks);
+
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for HostHooks<'r> {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Infallible> {
+        let hooks: &dyn HostFlowHooks = match request.rocket().state::<HostFlow>() {
+            Some(flow) => flow.0.as_ref(),
+            None => &DefaultHostFlow,
+        };
+        Outcome::Success(HostHooks(hooks))
+    }
+}
+
+/// Render as JSON if the client prefers it, and as an HTML page otherwise
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+fn render_type_for(accept: Option<&Accept>) -> RenderType {
+    match accept {
+        Some(accept) if accept.preferred().media_type().is_json() => RenderType::Json,
+        _ => RenderType::HtmlPage,
+    }
+}
+
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+#[rocket::get("/<room_id>")]
+async fn host_sessions(
+    room_id: RoomId,
+    host: ValidatedHostToken,
+    accept: Option<&Accept>,
+    translations: Translations,
+    config: CurrentConfig<'_>,
+    HostHooks(hooks): HostHooks<'_>,
+    db: SessionReader,
+) -> Result<RenderedContent, Error> {
+    hooks.authorize(&host, &room_id).await?;
+
+    let sessions = db
+        .find_by_room_id(room_id)
+        .await?
+        .into_iter()
+        .filter(|session| session.guest_token.instance == host.instance)
+        .collect();
+    let sessions = hooks.sessions(&host, sessions);
+    let credentials = credentials_for_host(&host, sessions);
+    hooks.render(credentials, render_type_for(accept), translations, &config)
+}
+
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+#[rocket::get("/<room_id>/events")]
+async fn host_events(
+    room_id: RoomId,
+    host: ValidatedHostToken,
+    HostHooks(hooks): HostHooks<'_>,
+    shutdown: Shutdown,
+) -> Result<EventStream![], Error> {
+    hooks.authorize(&host, &room_id).await?;
+    Ok(event_stream(room_id, host.0.instance, shutdown))
+}
+
+/// Standard host flow, for mounting at e.g. `/host`. Both routes require a
+/// host token, see [`ValidatedHostToken`]:
+/// - `GET /<room_id>` shows the credentials of the guests in the room, as JSON
+///   to clients preferring it and as an HTML page otherwise. Viewing does not
+///   mark the sessions as active, so an open dashboard doesn't keep them alive.
+/// - `GET /<room_id>/events` is a Server-Sent Events stream like
+///   [`room_events`], telling the dashboard when to refresh.
+///
+/// Manage a [`HostFlow`] to customize authorization, the sessions shown and
+/// their rendering. Requires the [`Config`] to be managed and the
+/// [`SessionDBConn`] fairing to be attached. Sessions are read from the
+/// replica if the [`crate::session::SessionReplicaConn`] fairing is attached,
+/// see [`SessionReader`].
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+pub fn host() -> Vec<Route> {
+    rocket::routes![host_sessions, host_events]
+}
+
+#[cfg(feature = "websocket")]
+#[rocket::get("/<room_id>")]
+fn room_event_socket(
+    room_id: RoomId,
+    host: ValidatedHostToken,
+    ws: WebSocket,
+    mut shutdown: Shutdown,
+) -> Result<rocket_ws::Stream!['static], Error> {
+    host.authorize_room(&room_id, &host.instance)?;
+
+    // Subscribe before responding, so no events are missed
+    let mut events = events::subscribe();
+    Ok(rocket_ws::Stream! { ws =>
+        let mut ws = ws;
+        loop {
+            let event = tokio::select! {
+                event = events::next_in_room(&mut events, &room_id, &host.instance) => event,
+                message = ws.next() => match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => None,
+                    // Hosts only listen, anything they send is ignored
+                    Some(Ok(_)) => continue,
+                },
+                _ = &mut shutdown => None,
+            };
+            match event.map(|event| serde_json::to_string(&event)) {
+                Some(Ok(text)) => yield Message::Text(text),
+                Some(Err(_)) => continue,
+                None => break,
+            }
+        }
+    })
+}
+
+/// WebSocket through which hosts receive changes to the sessions in a room, for
+/// mounting at e.g. `/ws`. `GET /<room_id>` requires a host token for that
+/// room, see [`ValidatedHostToken`]. Each message is an [`events::RoomEvent`]
+/// as JSON, with its `kind` being `session_created`, `auth_result`,
+/// `auth_reset` or `session_expired`.
+#[cfg(feature = "websocket")]
+pub fn room_socket() -> Vec<Route> {
+    rocket::routes![room_event_socket]
+}
+
+/// Customization of the guest flow served by [`guest`]. Every method does what
+/// most plugins need by default; manage a [`GuestFlow`] to override some of
+/// them.
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+pub trait GuestFlowHooks: Send + Sync {
+    /// Session to create for a guest arriving with `guest_token`, by default
+    /// one with a generated attribute ID, see [`AttrId::generate`]
+    fn new_session(&self, guest_token: GuestToken) -> Session {
+        Session::new(guest_token, None)
+    }
+
+    /// URL to which the auth-select widget posts the [`StartRequest`] for
+    /// `session`. By default the `start` route under the external guest URL,
+    /// for [`guest`] mounted at its root.
+    fn start_url(&self, config: &Config, session: &Session) -> String {
+        format!(
+            "{}/start/{}",
+            config.external_guest_url().trim_end_matches('/'),
+            session.attr_id
+        )
+    }
+
+    /// Request to start authentication at the core. By default, the guest
+    /// returns to the redirect URL of its token afterwards, and the core sends
+    /// the attributes to the `auth_result` route under the internal URL.
+    fn start_request(
+        &self,
+        config: &Config,
+        session: &Session,
+        request: StartRequest,
+    ) -> StartRequestAuthOnly {
+        StartRequestAuthOnly {
+            purpose: request.purpose,
+            auth_method: request.auth_method,
+            comm_url: session.guest_token.redirect_url.clone(),
+            attr_url: Some(format!(
+                "{}/auth_result/{}",
+                config.internal_url().trim_end_matches('/'),
+                session.attr_id
+            )),
+        }
+    }
+}
+
+/// Guest flow without customizations
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+pub struct DefaultGuestFlow;
+
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+impl GuestFlowHooks for DefaultGuestFlow {}
+
+/// Hooks customizing the routes in [`guest`], to be managed by Rocket.
+/// Without it, [`DefaultGuestFlow`] is used.
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+pub struct GuestFlow(pub Box<dyn GuestFlowHooks>);
+
+/// The hooks of the managed [`GuestFlow`], or [`DefaultGuestFlow`]
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+struct GuestHooks<'r>(&'r dyn GuestFlowHooks);
+
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for GuestHooks<'r> {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Infallible> {
+        let hooks: &dyn GuestFlowHooks = match request.rocket().state::<GuestFlow>() {
+            Some(flow) => flow.0.as_ref(),
+            None => &DefaultGuestFlow,
+        };
+        Outcome::Success(GuestHooks(hooks))
+    }
+}
+
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+#[rocket::get("/")]
+async fn guest_init(
+    guest: ValidatedGuestToken,
+    config: CurrentConfig<'_>,
+    translations: Translations,
+    GuestHooks(hooks): GuestHooks<'_>,
+    db: SessionDBConn,
+) -> Result<Redirect, Error> {
+    config.check_purpose(&guest.purpose)?;
+    config.check_redirect_url(&guest.redirect_url)?;
+    let session = hooks.new_session(guest.0);
+    session
+        .persist_with_room_limit(
+            config.max_active_rooms(),
+            config.session_lifetime(),
+            config.session_expiry(),
+            &db,
+        )
+        .await?;
+
+    let widget_url = widget_url_for(
+        config.auth_during_comm_config(),
+        &translations,
+        &session.guest_token,
+        &session.guest_token.purpose,
+        &hooks.start_url(&config, &session),
+    )?;
+    Ok(Redirect::to(widget_url))
+}
+
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+#[rocket::post("/start/<attr_id>", data = "<request>")]
+async fn guest_start(
+    attr_id: AttrId,
+    request: Json<StartRequest>,
+    config: CurrentConfig<'_>,
+    GuestHooks(hooks): GuestHooks<'_>,
+    request_id: &RequestId,
+    db: SessionDBConn,
+) -> Result<Json<ClientUrlResponse>, Error> {
+    let session = Session::find_by_attr_id(attr_id, &db).await?;
+    if request.purpose != session.guest_token.purpose {
+        return Err(Error::BadRequest("Purpose does not match the session"));
+    }
+
+    let start_request = hooks.start_request(&config, &session, request.into_inner());
+    // The core redirects the guest here once authentication finishes
+    config.check_redirect_url(&start_request.comm_url)?;
+    let core_session =
+        start_authentication_session_with(&config, start_request, Some(request_id)).await?;
+    Session::mark_auth_started_with(
+        session.guest_token.id,
+        Some(core_session.core_session_id),
+        &db,
+    )
+    .await?;
+    Ok(Json(core_session.client_url))
+}
+
+/// Standard guest flow, for mounting at the root of the external guest URL:
+/// - `GET /` takes a guest token, see [`ValidatedGuestToken`], creates and
+///   persists a session for it, and redirects the guest to the auth-select
+///   widget. Tokens for purposes that are not configured, or with a redirect
+///   URL that is not allowed, are refused with `400 Bad Request`, see
+///   [`Config::check_purpose`] and [`Config::check_redirect_url`]. So are
+///   tokens that would open a new room once the configured maximum of active
+///   rooms is reached, see [`Session::persist_with_room_limit`].
+/// - `POST /start/<attr_id>` receives the [`StartRequest`] the widget sends
+///   once the guest chose an authentication method, checks the redirect URL
+///   again, starts authentication at the core, and responds with the URL to
+///   send the guest to.
+///
+/// Manage a [`GuestFlow`] to customize the sessions created and the requests
+/// to the core. Requires the [`Config`] to be managed, the [`SessionDBConn`]
+/// fairing to be attached, and a [`crate::guards::ReplayCache`] to be managed
+/// unless used tokens are remembered in the session database.
+#[cfg(all(feature = "sessions", feature = "auth_during_comm"))]
+pub fn guest() -> Vec<Route> {
+    rocket::routes![guest_init, guest_start]
+}
@@ -0,0 +1,134 @@
+use std::{fmt::Debug, marker::PhantomData};
+
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+
+use crate::error::Error;
+
+/// Secret configuration value, given inline or as `{ file = "..." }` or
+/// `{ env = "..." }`, to be read from a file (e.g. a Docker or Kubernetes
+/// secret) or an environment variable when the configuration is resolved
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Secret {
+    Inline(String),
+    File { file: String },
+    Env { env: String },
+}
+
+impl Secret {
+    /// Read the secret value. Trailing newlines are stripped from secrets read
+    /// from files.
+    pub fn resolve(&self) -> Result<String, Error> {
+        match self {
+            Secret::Inline(value) => Ok(value.clone()),
+            Secret::File { file } => std::fs::read_to_string(file)
+                .map(|value| value.trim_end_matches(&['\r', '\n'][..]).to_string())
+                .map_err(|e| Error::Config(format!("Could not read secret from {}: {}", file, e))),
+            Secret::Env { env } => std::env::var(env).map_err(|_| {
+                Error::Config(format!("Secret environment variable {} is not set", env))
+            }),
+        }
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Secret::Inline(_) => f.debug_struct("Secret").finish(),
+            Secret::File { file } => f.debug_struct("Secret").field("file", file).finish(),
+            Secret::Env { env } => f.debug_struct("Secret").field("env", env).finish(),
+        }
+    }
+}
+
+/// Key configuration of type `T` whose `key` may be given as a [`Secret`]. The
+/// key is only read when resolving the configuration.
+#[derive(Deserialize)]
+#[serde(from = "Value")]
+pub struct SecretKey<T> {
+    raw: Value,
+    config: PhantomData<T>,
+}
+
+impl<T> From<Value> for SecretKey<T> {
+    fn from(raw: Value) -> Self {
+        SecretKey {
+            raw,
+            config: PhantomData,
+        }
+    }
+}
+
+impl<T: DeserializeOwned> SecretKey<T> {
+    /// Read the key, returning the raw key configuration including the key
+    /// itself, and the key configuration parsed from it
+    pub fn resolve_raw(self) -> Result<(Value, T), Error> {
+        let mut raw = self.raw;
+        if let Some(key) = raw.get_mut("key").filter(|key| key.is_object()) {
+            let secret: Secret = serde_json::from_value(key.take())?;
+            *key = Value::String(secret.resolve()?);
+        }
+        let config = serde_json::from_value(raw.clone())?;
+        Ok((raw, config))
+    }
+
+    /// Read the key and parse the key configuration
+    pub fn resolve(self) -> Result<T, Error> {
+        Ok(self.resolve_raw()?.1)
+    }
+}
+
+impl<T> Debug for SecretKey<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecretKey")
+            .field("type", &self.raw.get("type"))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::{Secret, SecretKey};
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct KeyConfig {
+        key: String,
+    }
+
+    #[test]
+    fn test_resolve_secrets() {
+        let path = std::env::temp_dir().join("comm-common-test-secret");
+        std::fs::write(&path, "from file\n").unwrap();
+        std::env::set_var("COMM_COMMON_TEST_SECRET", "from env");
+
+        let inline: Secret = serde_json::from_value(json!("inline")).unwrap();
+        assert_eq!(inline.resolve().unwrap(), "inline");
+        assert_eq!(format!("{:?}", inline), "Secret");
+
+        let file: Secret = serde_json::from_value(json!({ "file": path })).unwrap();
+        assert_eq!(file.resolve().unwrap(), "from file");
+
+        let env = json!({ "env": "COMM_COMMON_TEST_SECRET" });
+        let env: Secret = serde_json::from_value(env).unwrap();
+        assert_eq!(env.resolve().unwrap(), "from env");
+
+        let missing = json!({ "env": "COMM_COMMON_MISSING" });
+        let missing: Secret = serde_json::from_value(missing).unwrap();
+        assert!(missing.resolve().is_err());
+
+        let key = json!({ "key": { "env": "COMM_COMMON_TEST_SECRET" } });
+        let key: SecretKey<KeyConfig> = serde_json::from_value(key).unwrap();
+        assert_eq!(
+            key.resolve().unwrap(),
+            KeyConfig {
+                key: "from env".to_string()
+            }
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,111 +1,1069 @@
-use std::{str::FromStr, time::Duration};
+use std::{
+    cmp::Ordering,
+    fs::OpenOptions,
+    io::Write,
+    ops::Range,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
 
-use rocket::tokio;
-use rocket_sync_db_pools::{database, postgres};
+use postgres::{GenericClient, Row, Transaction};
+#[cfg(feature = "rocket")]
+use rocket::fairing::{AdHoc, Fairing};
+#[cfg(feature = "rocket")]
+use rocket_sync_db_pools::database;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    audit::{self, AuditEvent, AuditEventKind},
+    auth_result::StoredAuthResult,
     error::Error,
-    types::{GuestToken, SessionDomain},
+    events::{self, RoomEvent, RoomEventKind},
+    types::{AttrId, GuestToken, HostToken, RoomId, SessionDomain, SessionId},
+    util::{log_error, random_join_code},
+};
+#[cfg(feature = "rocket")]
+use crate::{config::CurrentConfig, shutdown::spawn_tracked};
+
+#[cfg(feature = "async-db")]
+mod async_db;
+mod encryption;
+mod funnel;
+#[cfg(feature = "memory-store")]
+mod memory;
+mod migrations;
+mod overview;
+mod pool;
+#[cfg(feature = "rocket")]
+mod replica;
+mod state;
+mod store;
+mod transaction;
+
+#[cfg(feature = "async-db")]
+pub use self::async_db::AsyncSessionDB;
+use self::encryption::{decode_auth_result, encode_auth_result};
+#[cfg(feature = "memory-store")]
+pub use self::memory::InMemorySessionStore;
+#[cfg(feature = "rocket")]
+pub use self::replica::{SessionReader, SessionReplicaConn};
+pub use self::{
+    encryption::{AuthResultKey, AuthResultKeySource},
+    funnel::{stats, SessionStats},
+    migrations::{ensure_schema, migrate_legacy_auth_results, run_migrations},
+    overview::{dedup_joins, group_by_guest, GuestOverview, RoomOverview},
+    pool::{ConnectionOptions, SessionClient, SessionConn, SessionDb, SessionPool, SslMode},
+    state::SessionState,
+    store::SessionStore,
+    transaction::SessionTransaction,
 };
 
+/// Time after which an inactive session is removed, unless configured
+/// otherwise through `session_lifetime`
+pub const DEFAULT_SESSION_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Time between two cleanups of inactive sessions, unless configured
+/// otherwise through `session_cleanup_interval`
+pub const DEFAULT_CLEANUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Length of generated join codes
+const JOIN_CODE_LENGTH: usize = 8;
+
+/// Time between the first two checks for an authentication result while
+/// waiting for it. The time between checks doubles after every check.
+const WAIT_POLL_INITIAL: Duration = Duration::from_millis(250);
+
+/// Maximum time between two checks for an authentication result
+const WAIT_POLL_MAX: Duration = Duration::from_secs(5);
+
+/// Advisory lock key used to serialize the creation of new rooms
+const ROOM_LIMIT_LOCK: i64 = 0x7665_7264_6572;
+
+#[cfg(feature = "rocket")]
 #[database("session")]
-pub struct SessionDBConn(postgres::Client);
+pub struct SessionDBConn(SessionClient);
+
+#[cfg(feature = "rocket")]
+#[async_trait::async_trait]
+impl SessionDb for SessionDBConn {
+    async fn run<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut SessionClient) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        SessionDBConn::run(self, f).await
+    }
+}
+
+/// Columns needed to reconstruct a [`Session`] from a row
+const SESSION_COLUMNS: &str = "
+    session_id,
+    room_id,
+    domain,
+    redirect_url,
+    purpose,
+    name,
+    instance,
+    attr_id,
+    auth_result::text AS auth_result,
+    join_code,
+    state,
+    last_activity,
+    created_at,
+    core_session_id";
+
+/// Insert a new session, taking the values of all [`SESSION_COLUMNS`] but
+/// `last_activity` and `created_at` as parameters, and count it in the
+/// `session_stats` if it is `committed`. Uncommitted sessions are counted once
+/// committed.
+const INSERT_SESSION: &str = "
+    WITH inserted AS (
+        INSERT INTO session (
+            session_id,
+            room_id,
+            domain,
+            redirect_url,
+            purpose,
+            name,
+            instance,
+            attr_id,
+            auth_result,
+            join_code,
+            state,
+            committed,
+            last_activity
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9::text::jsonb, $10, $11, $12, now())
+        RETURNING purpose, committed, auth_result IS NOT NULL AS completed
+    )
+    INSERT INTO session_stats (day, purpose, created, completed)
+    SELECT current_date, purpose, 1, completed::int FROM inserted
+    WHERE committed
+    ON CONFLICT (day, purpose) DO UPDATE
+    SET created = session_stats.created + EXCLUDED.created,
+        completed = session_stats.completed + EXCLUDED.completed;";
+
+/// Store an authentication result with the session matching an attribute ID,
+/// moving it to state `$3` if it is in one of the states `$4`, and count it in
+/// the `session_stats`. Returns the room ID, instance and session ID of the
+/// session.
+const REGISTER_AUTH_RESULT: &str = "
+    WITH registered AS (
+        UPDATE session
+        SET (auth_result, auth_result_at, state, last_activity) =
+            ($1::text::jsonb, now(), $3, now())
+        WHERE auth_result IS NULL
+        AND state = ANY($4)
+        AND attr_id = $2
+        RETURNING room_id, instance, session_id, purpose
+    ), counted AS (
+        INSERT INTO session_stats (day, purpose, completed)
+        SELECT current_date, purpose, 1 FROM registered
+        ON CONFLICT (day, purpose) DO UPDATE
+        SET completed = session_stats.completed + 1
+    )
+    SELECT room_id, instance, session_id FROM registered;";
+
+/// Event announcing the authentication result registered through
+/// [`REGISTER_AUTH_RESULT`], returning `row`
+fn auth_result_event(row: &Row) -> RoomEvent {
+    RoomEvent {
+        room_id: row.get("room_id"),
+        instance: row.get("instance"),
+        session_id: row.get("session_id"),
+        kind: RoomEventKind::AuthResult,
+    }
+}
+
+/// Record the change to a session announced by `event` in the audit log as
+/// `kind`
+fn audit_room_event(event: &RoomEvent, kind: AuditEventKind) {
+    audit::record(AuditEvent::new(kind).session(&event.room_id, &event.session_id));
+}
+
+/// Remove cancelled sessions, sessions ended early by [`Session::expire_now`],
+/// and sessions that expired under `expiry` with a lifetime of `$1` seconds,
+/// returning their room IDs, session IDs, whether they were committed, and the
+/// [`ARCHIVE_COLUMNS`], which include the instance. Expired sessions that never
+/// completed authentication are counted in the `session_stats`. If `archive`
+/// is set, the metadata of the removed sessions is copied to the
+/// `session_archive`.
+fn clean_sessions_query(expiry: SessionExpiry, archive: bool) -> String {
+    let archived = if archive {
+        ", archived AS (
+            INSERT INTO session_archive (
+                purpose, domain, instance, state, created_at, auth_result_at
+            )
+            SELECT purpose, domain, instance, state, created_at, auth_result_at
+            FROM removed
+        )"
+    } else {
+        ""
+    };
+    format!(
+        "
+        WITH removed AS (
+            DELETE FROM session
+            WHERE {} < now() - make_interval(secs => $1)
+            OR state IN ('cancelled', 'expired')
+            RETURNING room_id, session_id, committed, {}
+        ), counted AS (
+            INSERT INTO session_stats (day, purpose, expired)
+            SELECT current_date, purpose, count(*) FROM removed
+            WHERE state NOT IN ('auth_completed', 'cancelled')
+            GROUP BY purpose
+            ON CONFLICT (day, purpose) DO UPDATE
+            SET expired = session_stats.expired + EXCLUDED.expired
+        ){}
+        SELECT room_id, session_id, committed, {} FROM removed
+        ",
+        expiry.column(),
+        ARCHIVE_COLUMNS,
+        archived,
+        ARCHIVE_COLUMNS
+    )
+}
+
+/// Columns of a session kept when it is archived
+const ARCHIVE_COLUMNS: &str = "purpose, domain, instance, state, created_at, auth_result_at";
+
+/// Metadata of a session removed by the cleanup, as archived to a file
+#[derive(Debug, Serialize)]
+struct ArchivedSession {
+    purpose: String,
+    domain: String,
+    instance: String,
+    state: String,
+    created_at: String,
+    auth_result_at: Option<String>,
+    archived_at: String,
+}
+
+impl ArchivedSession {
+    fn from_row(row: &Row, archived_at: SystemTime) -> Self {
+        let time = |time: SystemTime| humantime::format_rfc3339_seconds(time).to_string();
+        ArchivedSession {
+            purpose: row.get("purpose"),
+            domain: row.get("domain"),
+            instance: row.get("instance"),
+            state: row.get("state"),
+            created_at: time(row.get("created_at")),
+            auth_result_at: row.get::<_, Option<SystemTime>>("auth_result_at").map(time),
+            archived_at: time(archived_at),
+        }
+    }
+}
+
+/// Append the metadata of the sessions in `rows` to the file at `path`, as JSON
+/// lines
+fn archive_to_file(path: &str, rows: &[Row]) -> Result<(), Error> {
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let archived_at = SystemTime::now();
+    let mut lines = String::new();
+    for row in rows {
+        lines.push_str(&serde_json::to_string(&ArchivedSession::from_row(
+            row,
+            archived_at,
+        ))?);
+        lines.push('\n');
+    }
+    let write = || -> std::io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(lines.as_bytes())?;
+        file.sync_data()
+    };
+    write().map_err(|e| {
+        Error::InternalServer(format!("Could not archive sessions to {}: {}", path, e))
+    })
+}
+
+/// Remove the authentication results registered a number of seconds ago or
+/// longer, including those in the history of reset results. Results stored
+/// along with a new session count as registered at the last activity of the
+/// session.
+const PURGE_AUTH_RESULTS: &str = "
+    WITH purged_history AS (
+        DELETE FROM auth_result_history
+        WHERE COALESCE(auth_result_at, reset_at) < now() - make_interval(secs => $1)
+    )
+    UPDATE session
+    SET auth_result = NULL, auth_result_at = NULL
+    WHERE auth_result IS NOT NULL
+    AND COALESCE(auth_result_at, last_activity) < now() - make_interval(secs => $1)";
+
+/// Remove sessions without an authentication result that have been inactive
+/// for a number of seconds, returning their room IDs, instances, session IDs,
+/// states and whether they were committed
+const PURGE_PENDING_SESSIONS: &str = "
+    DELETE FROM session
+    WHERE auth_result IS NULL
+    AND last_activity < now() - make_interval(secs => $1)
+    RETURNING room_id, instance, session_id, state, committed";
+
+/// Remove all sessions in room `$1` together with their audit entries,
+/// returning their room IDs, instances, session IDs, states and whether they
+/// were committed
+const PURGE_ROOM: &str = "
+    WITH removed AS (
+        DELETE FROM session
+        WHERE room_id = $1
+        RETURNING room_id, instance, session_id, state, committed
+    ), removed_audit AS (
+        DELETE FROM session_audit
+        WHERE session_id IN (SELECT session_id FROM removed)
+    )
+    SELECT room_id, instance, session_id, state, committed FROM removed";
+
+/// Publish an expiry event for each session removed by
+/// [`clean_sessions_query`], [`PURGE_PENDING_SESSIONS`] or [`PURGE_ROOM`] that
+/// was committed and not cancelled. The creation of uncommitted sessions was
+/// never announced.
+fn publish_expired(rows: &[Row]) {
+    for row in rows {
+        if row.get("committed")
+            && row.get::<_, &str>("state") != SessionState::Cancelled.to_string()
+        {
+            events::publish(RoomEvent {
+                room_id: row.get("room_id"),
+                instance: row.get("instance"),
+                session_id: row.get("session_id"),
+                kind: RoomEventKind::SessionExpired,
+            });
+        }
+    }
+}
+
+/// Move the session matching `key_column = $2` to state `$1` if it is in one
+/// of the states `$3`, additionally applying the assignments in `set`
+fn transition_query(key_column: &str, set: &str) -> String {
+    format!(
+        "
+        UPDATE session
+        SET state = $1, {}
+        WHERE {} = $2
+        AND state = ANY($3)
+        ",
+        set, key_column
+    )
+}
+
+/// Check whether a session matching `key_column = $1` exists
+fn exists_query(key_column: &str) -> String {
+    format!(
+        "SELECT EXISTS(SELECT 1 FROM session WHERE {} = $1)",
+        key_column
+    )
+}
+
+/// Assignments applied when cancelling a session
+fn cancel_assignments(clear_auth_result: bool) -> &'static str {
+    if clear_auth_result {
+        "auth_result = NULL, auth_result_at = NULL, last_activity = now()"
+    } else {
+        "last_activity = now()"
+    }
+}
+
+/// Mark a page of at most `$2` committed sessions in room `$1` as active and
+/// return them, skipping the first `$3` sessions in order of creation. The
+/// returned rows are not ordered.
+fn find_page_by_room_id_query() -> String {
+    format!(
+        "
+        WITH page AS (
+            SELECT id
+            FROM session
+            WHERE room_id = $1
+            AND committed
+            ORDER BY created_at, session_id COLLATE \"C\"
+            LIMIT $2
+            OFFSET $3
+        )
+        UPDATE session
+        SET last_activity = now()
+        FROM page
+        WHERE session.id = page.id
+        RETURNING {}
+        ",
+        SESSION_COLUMNS
+    )
+}
+
+/// Find all committed sessions in room `$1` in order of creation, without
+/// marking them as active
+fn find_by_room_id_readonly_query() -> String {
+    format!(
+        "
+        SELECT {}
+        FROM session
+        WHERE room_id = $1
+        AND committed
+        ORDER BY created_at, session_id COLLATE \"C\"
+        ",
+        SESSION_COLUMNS
+    )
+}
+
+/// Find a page of at most `$2` committed sessions in room `$1` in order of
+/// creation, skipping the first `$3`, without marking them as active
+fn find_page_by_room_id_readonly_query() -> String {
+    format!(
+        "
+        SELECT {}
+        FROM session
+        WHERE room_id = $1
+        AND committed
+        ORDER BY created_at, session_id COLLATE \"C\"
+        LIMIT $2
+        OFFSET $3
+        ",
+        SESSION_COLUMNS
+    )
+}
+
+/// Find all committed sessions created from `$1` up to `$2` in order of
+/// creation, without marking them as active
+fn find_created_between_query() -> String {
+    format!(
+        "
+        SELECT {}
+        FROM session
+        WHERE created_at >= $1
+        AND created_at < $2
+        AND committed
+        ORDER BY created_at, session_id COLLATE \"C\"
+        ",
+        SESSION_COLUMNS
+    )
+}
+
+/// Find the session matching `key_column = $1`, without marking it as active
+fn select_query(key_column: &str) -> String {
+    format!(
+        "SELECT {} FROM session WHERE {} = $1",
+        SESSION_COLUMNS, key_column
+    )
+}
+
+/// Remember core authentication session `$1` for the session with ID `$2`
+const SET_CORE_SESSION_ID: &str = "UPDATE session SET core_session_id = $1 WHERE session_id = $2";
+
+/// Move the authentication result of the session with ID `$1` to its history,
+/// recording `$2` as having reset it, and clear the result, giving the session
+/// attribute ID `$3` and moving it to state `$4` if it is in one of the states
+/// `$5`
+fn reset_auth_result_query() -> String {
+    format!(
+        "
+        WITH archived AS (
+            INSERT INTO auth_result_history (session_id, auth_result, auth_result_at, reset_by)
+            SELECT session_id, auth_result, auth_result_at, $2
+            FROM session
+            WHERE session_id = $1
+            AND auth_result IS NOT NULL
+            AND state = ANY($5)
+        )
+        UPDATE session
+        SET (auth_result, auth_result_at, core_session_id, attr_id, state, last_activity) =
+            (NULL, NULL, NULL, $3, $4, now())
+        WHERE session_id = $1
+        AND auth_result IS NOT NULL
+        AND state = ANY($5)
+        RETURNING {}
+        ",
+        SESSION_COLUMNS
+    )
+}
+
+/// Earlier authentication results of the session with ID `$1`, oldest first
+const FIND_AUTH_RESULT_HISTORY: &str = "
+    SELECT auth_result::text AS auth_result, reset_at, reset_by
+    FROM auth_result_history
+    WHERE session_id = $1
+    ORDER BY reset_at, id";
+
+/// Mark the session with ID `$1` as active
+const TOUCH_SESSION: &str = "UPDATE session SET last_activity = now() WHERE session_id = $1";
+
+/// Count the committed sessions in room `$1`
+const COUNT_BY_ROOM_ID: &str = "SELECT COUNT(*) FROM session WHERE room_id = $1 AND committed";
+
+/// A page of the sessions in a room, in order of creation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Page {
+    /// Maximum number of sessions on the page
+    pub limit: u32,
+    /// Number of sessions preceding the page
+    pub offset: u32,
+}
+
+/// How the lifetime of a session is counted, configured through
+/// `session_expiry`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionExpiry {
+    /// Sessions expire once inactive for the session lifetime. Every lookup
+    /// marking a session as active postpones its expiry.
+    #[default]
+    Sliding,
+    /// Sessions expire the session lifetime after their creation, however
+    /// active they are
+    Absolute,
+}
+
+impl SessionExpiry {
+    /// Column the session lifetime is counted from
+    fn column(self) -> &'static str {
+        match self {
+            SessionExpiry::Sliding => "last_activity",
+            SessionExpiry::Absolute => "created_at",
+        }
+    }
+}
+
+/// Whether a lookup marks the sessions it finds as active
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActivityUpdate {
+    /// Mark the sessions as active, postponing their expiry under
+    /// [`SessionExpiry::Sliding`]
+    Touch,
+    /// Leave the last activity of the sessions as is, e.g. for lookups on
+    /// behalf of hosts or monitoring
+    Preserve,
+}
+
+/// Where the cleanup archives the metadata of the sessions it removes,
+/// configured as e.g. `archive = { type = "database" }`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ArchiveTarget {
+    /// The `session_archive` table of the session database
+    Database,
+    /// A file at `path`, appending a JSON line per session
+    File { path: String },
+}
+
+/// Retention of session data beyond the session lifetime, configured through
+/// `[global.retention]`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Time after which inactive sessions without an authentication result
+    /// are removed, if sooner than the session lifetime
+    pub pending_session_lifetime: Option<Duration>,
+    /// Time after their registration after which authentication results are
+    /// removed from their sessions
+    pub auth_result_lifetime: Option<Duration>,
+    /// Where to archive the metadata of the sessions removed by the cleanup,
+    /// if anywhere. Attributes, names and identifiers are never archived.
+    pub archive: Option<ArchiveTarget>,
+}
+
+/// Order sessions by creation time, using the session ID to order sessions
+/// created at the same time. The queries listing sessions order the same way,
+/// comparing session IDs bytewise through the `C` collation.
+pub(crate) fn creation_order(a: &Session, b: &Session) -> Ordering {
+    a.created_at
+        .cmp(&b.created_at)
+        .then_with(|| a.guest_token.id.cmp(&b.guest_token.id))
+}
+
+/// Mark all sessions matching `key_column = $1` as active and return them
+fn find_query(key_column: &str) -> String {
+    format!(
+        "
+        UPDATE session
+        SET last_activity = now()
+        WHERE {} = $1
+        RETURNING {}
+        ",
+        key_column, SESSION_COLUMNS
+    )
+}
+
+/// Mark all committed sessions in room `$1` as active and return them
+fn find_by_room_id_query() -> String {
+    format!(
+        "
+        UPDATE session
+        SET last_activity = now()
+        WHERE room_id = $1
+        AND committed
+        RETURNING {}
+        ",
+        SESSION_COLUMNS
+    )
+}
+
+/// Authentication result replaced through [`Session::reset_auth_result`]
+#[derive(Debug, Serialize, Clone)]
+pub struct PastAuthResult {
+    pub auth_result: StoredAuthResult,
+    /// Time at which the result was reset
+    pub reset_at: SystemTime,
+    /// ID of the host who reset the result
+    pub reset_by: Option<String>,
+}
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Session {
     /// The guest token associated with this session
     pub guest_token: GuestToken,
     /// The autheniction result. `None` if none was received yet
-    pub auth_result: Option<String>,
+    pub auth_result: Option<StoredAuthResult>,
     /// ID used to match incoming attributes with this session
-    pub attr_id: String,
+    pub attr_id: AttrId,
+    /// One-time code with which a guest can join this session
+    pub join_code: Option<String>,
+    /// Current state of this session
+    pub state: SessionState,
+    /// Time at which this session was last marked as active
+    pub last_activity: SystemTime,
+    /// Time at which this session was created
+    pub created_at: SystemTime,
+    /// Handle of the authentication session the core started for this
+    /// session, if authentication was started
+    #[serde(default)]
+    pub core_session_id: Option<String>,
 }
 
 impl Session {
-    /// Create a new session
-    pub fn new(guest_token: GuestToken, attr_id: String) -> Self {
+    /// Create a new session, with a freshly generated attribute ID if
+    /// `attr_id` is `None`
+    pub fn new(guest_token: GuestToken, attr_id: impl Into<Option<AttrId>>) -> Self {
         Self {
-            attr_id,
+            attr_id: attr_id.into().unwrap_or_else(AttrId::generate),
             guest_token,
             auth_result: None,
+            join_code: None,
+            state: SessionState::Created,
+            last_activity: SystemTime::now(),
+            created_at: SystemTime::now(),
+            core_session_id: None,
+        }
+    }
+
+    /// Whether this session has been inactive for at least `lifetime`
+    pub fn is_expired(&self, lifetime: Duration) -> bool {
+        self.last_activity
+            .elapsed()
+            .is_ok_and(|inactive| inactive >= lifetime)
+    }
+
+    /// Time at which this session expires, given the session lifetime and how
+    /// it is counted
+    pub fn expires_at(&self, lifetime: Duration, expiry: SessionExpiry) -> SystemTime {
+        match expiry {
+            SessionExpiry::Sliding => self.last_activity + lifetime,
+            SessionExpiry::Absolute => self.created_at + lifetime,
+        }
+    }
+
+    /// Attach a freshly generated one-time join code to this session
+    pub fn with_join_code(self) -> Self {
+        Self {
+            join_code: Some(random_join_code(JOIN_CODE_LENGTH)),
+            ..self
+        }
+    }
+
+    /// Event of the given kind about this session
+    pub fn event(&self, kind: RoomEventKind) -> RoomEvent {
+        RoomEvent {
+            room_id: self.guest_token.room_id.to_string(),
+            instance: self.guest_token.instance.clone(),
+            session_id: self.guest_token.id.to_string(),
+            kind,
+        }
+    }
+
+    /// Record the creation of this session in the metrics, and announce it to
+    /// the room
+    fn announce_created(&self) {
+        #[cfg(feature = "metrics")]
+        crate::metrics::session_created();
+        let event = self.event(RoomEventKind::SessionCreated);
+        audit_room_event(&event, AuditEventKind::SessionCreated);
+        events::publish(event);
+    }
+
+    /// The authentication result serialized for storage as JSONB, encrypted
+    /// if a key is configured
+    fn auth_result_json(&self, key: Option<&AuthResultKey>) -> Result<Option<String>, Error> {
+        self.auth_result
+            .as_ref()
+            .map(|auth_result| encode_auth_result(key, auth_result))
+            .transpose()
+    }
+
+    fn from_row(r: &Row, key: Option<&AuthResultKey>) -> Result<Self, Error> {
+        let domain = SessionDomain::from_str(r.get("domain"))?;
+        let guest_token = GuestToken {
+            id: r.get("session_id"),
+            room_id: r.get("room_id"),
+            domain,
+            redirect_url: r.get("redirect_url"),
+            name: r.get("name"),
+            instance: r.get("instance"),
+            purpose: r.get("purpose"),
+        };
+        Ok(Session {
+            guest_token,
+            attr_id: r.get("attr_id"),
+            auth_result: r
+                .get::<_, Option<&str>>("auth_result")
+                .map(|auth_result| decode_auth_result(key, auth_result))
+                .transpose()?,
+            join_code: r.get("join_code"),
+            state: SessionState::from_str(r.get("state"))?,
+            last_activity: r.get("last_activity"),
+            created_at: r.get("created_at"),
+            core_session_id: r.get("core_session_id"),
+        })
+    }
+
+    /// Insert the session, holding it back from listings and the
+    /// `session_stats` unless `committed`
+    fn insert<C: GenericClient>(
+        &self,
+        c: &mut C,
+        key: Option<&AuthResultKey>,
+        committed: bool,
+    ) -> Result<u64, Error> {
+        c.execute(
+            INSERT_SESSION,
+            &[
+                &self.guest_token.id,
+                &self.guest_token.room_id,
+                &self.guest_token.domain.to_string(),
+                &self.guest_token.redirect_url,
+                &self.guest_token.purpose,
+                &self.guest_token.name,
+                &self.guest_token.instance,
+                &self.attr_id,
+                &self.auth_result_json(key)?,
+                &self.join_code,
+                &self.state.to_string(),
+                &committed,
+            ],
+        )
+        .map_err(Session::map_insert_error)
+    }
+
+    /// Report a violated unique index by what collided. Indexes are named
+    /// `session_<column>_idx`.
+    fn map_insert_error(e: postgres::Error) -> Error {
+        if let Some(&postgres::error::SqlState::UNIQUE_VIOLATION) = e.code() {
+            let constraint = e
+                .as_db_error()
+                .and_then(|e| e.constraint())
+                .unwrap_or_default();
+            if constraint.contains("join_code") {
+                Error::Conflict("A session with that join code already exists")
+            } else {
+                Error::BadRequest("A session with that ID already exists")
+            }
+        } else {
+            Error::from(e)
         }
     }
 
     /// Persist a sessions. This can only be done for newly created sessions,
-    /// as the session id is unique.
-    pub async fn persist(&self, db: &SessionDBConn) -> Result<(), Error> {
+    /// as the session id is unique. The purpose of the session is not checked
+    /// here; plugins must call [`crate::config::Config::check_purpose`] first.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, err, fields(
+            room_id = crate::util::log_pseudonym(&self.guest_token.room_id),
+            attr_id = crate::util::log_pseudonym(&self.attr_id),
+        ))
+    )]
+    pub async fn persist(&self, db: &impl SessionDb) -> Result<(), Error> {
+        #[cfg(feature = "metrics")]
+        let _timer = crate::metrics::db_query_timer("persist");
         let this = self.clone();
-        let res = db
-            .run(move |c| {
-                c.execute(
-                    "INSERT INTO session (
-                session_id,
-                room_id,
-                domain,
-                redirect_url,
-                purpose,
-                name,
-                instance,
-                attr_id,
-                auth_result,
-                last_activity
-            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, now());",
-                    &[
-                        &this.guest_token.id,
-                        &this.guest_token.room_id,
-                        &this.guest_token.domain.to_string(),
-                        &this.guest_token.redirect_url,
-                        &this.guest_token.purpose,
-                        &this.guest_token.name,
-                        &this.guest_token.instance,
-                        &this.attr_id,
-                        &this.auth_result,
-                    ],
-                )
-            })
-            .await;
+        db.run(move |c| {
+            let key = c.auth_result_key();
+            this.insert(&mut **c, key.as_deref(), true)
+        })
+        .await?;
+        self.announce_created();
+        Ok(())
+    }
 
-        res.map_err(|e| {
-            if let Some(&postgres::error::SqlState::UNIQUE_VIOLATION) = e.code() {
-                Error::BadRequest("A session with that ID already exists")
-            } else {
-                Error::from(e)
+    /// Persist a session together with an audit entry recording its creation
+    /// by `actor`. Either both are stored, or neither is.
+    pub async fn persist_with_audit(
+        &self,
+        actor: String,
+        db: &impl SessionDb,
+    ) -> Result<(), Error> {
+        let this = self.clone();
+        db.run(move |c| -> Result<(), Error> {
+            let key = c.auth_result_key();
+            let mut transaction = c.transaction()?;
+            this.insert(&mut transaction, key.as_deref(), true)?;
+            transaction.execute(
+                "INSERT INTO session_audit (
+                    session_id,
+                    actor,
+                    event,
+                    created_at
+                ) VALUES ($1, $2, 'created', now());",
+                &[&this.guest_token.id, &actor],
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await?;
+        self.announce_created();
+        Ok(())
+    }
+
+    /// Persist a session, refusing to open a new room once `max_rooms`
+    /// distinct rooms are active. A room is active while it holds a session
+    /// that is not cancelled and has not expired under `expiry` with the given
+    /// `lifetime`, so rooms awaiting cleanup don't count. Sessions joining an
+    /// active room are always accepted. Without a limit, this is equivalent to
+    /// [`Session::persist`].
+    pub async fn persist_with_room_limit(
+        &self,
+        max_rooms: Option<u64>,
+        lifetime: Duration,
+        expiry: SessionExpiry,
+        db: &impl SessionDb,
+    ) -> Result<(), Error> {
+        let max_rooms = match max_rooms {
+            Some(max_rooms) => max_rooms,
+            None => return self.persist(db).await,
+        };
+
+        let this = self.clone();
+        db.run(move |c| -> Result<(), Error> {
+            let key = c.auth_result_key();
+            let mut transaction = c.transaction()?;
+            this.insert_with_room_limit(
+                &mut transaction,
+                key.as_deref(),
+                true,
+                max_rooms,
+                lifetime,
+                expiry,
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await?;
+        self.announce_created();
+        Ok(())
+    }
+
+    /// Insert the session within `transaction` like [`Session::insert`],
+    /// refusing to open a new room once `max_rooms` distinct rooms are active,
+    /// as described for [`Session::persist_with_room_limit`]. Uncommitted
+    /// sessions count, so that they keep their room available until committed
+    /// or removed.
+    fn insert_with_room_limit(
+        &self,
+        transaction: &mut Transaction<'_>,
+        key: Option<&AuthResultKey>,
+        committed: bool,
+        max_rooms: u64,
+        lifetime: Duration,
+        expiry: SessionExpiry,
+    ) -> Result<(), Error> {
+        // Serialize room creation, so concurrent sessions can't both take the
+        // last available room
+        transaction.execute("SELECT pg_advisory_xact_lock($1)", &[&ROOM_LIMIT_LOCK])?;
+
+        let active = format!(
+            "state != 'cancelled' AND {} >= now() - make_interval(secs => $1)",
+            expiry.column()
+        );
+        let lifetime = lifetime.as_secs_f64();
+        let room_exists: bool = transaction
+            .query_one(
+                format!(
+                    "SELECT EXISTS(SELECT 1 FROM session WHERE room_id = $2 AND {})",
+                    active
+                )
+                .as_str(),
+                &[&lifetime, &self.guest_token.room_id],
+            )?
+            .get(0);
+        if !room_exists {
+            let active_rooms: i64 = transaction
+                .query_one(
+                    format!(
+                        "SELECT COUNT(DISTINCT room_id) FROM session WHERE {}",
+                        active
+                    )
+                    .as_str(),
+                    &[&lifetime],
+                )?
+                .get(0);
+            if active_rooms as u64 >= max_rooms {
+                return Err(Error::BadRequest("Maximum number of active rooms reached"));
             }
-        })?;
+        }
+
+        self.insert(transaction, key, committed)?;
         Ok(())
     }
 
     /// Mark a session as active
-    pub async fn mark_active(&self, db: &SessionDBConn) -> Result<(), Error> {
-        let this = self.clone();
-        match db
+    pub async fn mark_active(&self, db: &impl SessionDb) -> Result<(), Error> {
+        let session_id = self.guest_token.id.clone();
+        db.run(move |c| {
+            let statement = c.prepare_cached(TOUCH_SESSION)?;
+            c.execute(&statement, &[&session_id])
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Mark all sessions in a room as active, returning the number of sessions
+    /// that were touched
+    pub async fn bump_all_in_room(room_id: RoomId, db: &impl SessionDb) -> Result<u64, Error> {
+        let n = db
             .run(move |c| {
                 c.execute(
                     "UPDATE session
                 SET last_activity = now()
-                WHERE session_id = $1",
-                    &[&this.guest_token.id],
+                WHERE room_id = $1",
+                    &[&room_id],
                 )
             })
-            .await
-        {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.into()),
+            .await?;
+
+        Ok(n)
+    }
+
+    /// Move the session matching `key_column = key` to the state `target`,
+    /// additionally applying the assignments in `set`. Fails with
+    /// `Error::NotFound` for unknown sessions, and with `Error::Conflict` if
+    /// the current state of the session does not allow the transition.
+    fn transition<C: GenericClient>(
+        c: &mut C,
+        key_column: &str,
+        key: &str,
+        target: SessionState,
+        set: &str,
+    ) -> Result<(), Error> {
+        let n = c.execute(
+            transition_query(key_column, set).as_str(),
+            &[&target.to_string(), &key, &target.predecessor_names()],
+        )?;
+        if n == 1 {
+            return Ok(());
         }
+
+        let exists: bool = c
+            .query_one(exists_query(key_column).as_str(), &[&key])?
+            .get(0);
+        if exists {
+            Err(Error::Conflict("Session state does not allow this"))
+        } else {
+            Err(Error::NotFound)
+        }
+    }
+
+    /// Record that the guest started authenticating in the session
+    /// `session_id`
+    pub async fn mark_auth_started(
+        session_id: SessionId,
+        db: &impl SessionDb,
+    ) -> Result<(), Error> {
+        Session::mark_auth_started_with(session_id, None, db).await
+    }
+
+    /// Record that the guest started authenticating in the session
+    /// `session_id`, remembering the handle of the authentication session at
+    /// the core if known, see [`Session::find_by_core_session_id`]
+    pub async fn mark_auth_started_with(
+        session_id: SessionId,
+        core_session_id: Option<String>,
+        db: &impl SessionDb,
+    ) -> Result<(), Error> {
+        db.run(move |c| -> Result<(), Error> {
+            let mut transaction = c.transaction()?;
+            Session::transition(
+                &mut transaction,
+                "session_id",
+                &session_id,
+                SessionState::AuthStarted,
+                "last_activity = now()",
+            )?;
+            if let Some(core_session_id) = core_session_id {
+                transaction.execute(SET_CORE_SESSION_ID, &[&core_session_id, &session_id])?;
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Make a session immediately eligible for removal by the next cleanup, by
+    /// expiring it and moving its last activity back to the Unix epoch
+    pub async fn expire_now(session_id: SessionId, db: &impl SessionDb) -> Result<(), Error> {
+        let event = db
+            .run(move |c| -> Result<RoomEvent, Error> {
+                Session::transition(
+                    &mut **c,
+                    "session_id",
+                    &session_id,
+                    SessionState::Expired,
+                    "last_activity = 'epoch'",
+                )?;
+                let row = c.query_one(
+                    "SELECT room_id, instance FROM session WHERE session_id = $1",
+                    &[&session_id],
+                )?;
+                Ok(RoomEvent {
+                    room_id: row.get("room_id"),
+                    instance: row.get("instance"),
+                    session_id: session_id.into(),
+                    kind: RoomEventKind::SessionExpired,
+                })
+            })
+            .await?;
+
+        events::publish(event);
+        Ok(())
+    }
+
+    /// Cancel the session matching `attr_id`, e.g. because the guest abandoned
+    /// the authentication flow. If `clear_auth_result` is set, any received
+    /// authentication result is removed as well. Cancelled sessions are
+    /// removed by the next cleanup.
+    pub async fn cancel(
+        attr_id: AttrId,
+        clear_auth_result: bool,
+        db: &impl SessionDb,
+    ) -> Result<(), Error> {
+        db.run(move |c| {
+            Session::transition(
+                &mut **c,
+                "attr_id",
+                &attr_id,
+                SessionState::Cancelled,
+                cancel_assignments(clear_auth_result),
+            )
+        })
+        .await
     }
 
-    /// Restart authentication for a guest token if it already exists.
-    /// if not, this function returns false.
+    /// Restart authentication for a guest token if it already exists and has
+    /// not completed authentication. If not, this function returns false.
     pub async fn restart_auth(
         token: GuestToken,
-        new_attr_id: String,
-        db: &SessionDBConn,
+        new_attr_id: AttrId,
+        db: &impl SessionDb,
     ) -> Result<bool, Error> {
         let n = db
             .run(move |c| {
                 c.execute(
-                    "UPDATE session SET attr_id=$1 WHERE
+                    "UPDATE session SET (attr_id, state) = ($1, $9) WHERE
                 session_id = $2 AND
                 room_id = $3 AND
                 domain = $4 AND
@@ -113,6 +1071,7 @@ impl Session {
                 purpose = $6 AND
                 name = $7 AND
                 instance = $8 AND
+                state = ANY($10) AND
                 auth_result IS NULL",
                     &[
                         &new_attr_id,
@@ -123,6 +1082,8 @@ impl Session {
                         &token.purpose,
                         &token.name,
                         &token.instance,
+                        &SessionState::AuthStarted.to_string(),
+                        &SessionState::AuthStarted.predecessor_names(),
                     ],
                 )
             })
@@ -131,141 +1092,767 @@ impl Session {
         Ok(n == 1)
     }
 
-    /// Register an authentication result with a session. Fails if the session
-    /// already contains an authentication result.
+    /// Discard the authentication result of the session `session_id`, so that
+    /// the guest can authenticate again, e.g. after rejoining the room. Only a
+    /// host of the room of the session may do so, see
+    /// [`Session::load_for_host_action`]. The result is kept in the history of
+    /// the session, see [`Session::auth_result_history`], and the session
+    /// gets a new attribute ID, so that the previous result can't be
+    /// delivered again. Fails with `Error::Conflict` if the session has no
+    /// authentication result.
+    pub async fn reset_auth_result(
+        session_id: SessionId,
+        host: &HostToken,
+        db: &impl SessionDb,
+    ) -> Result<Self, Error> {
+        let session = Session::load_for_host_action(session_id, host, db).await?;
+        let session_id = session.guest_token.id;
+        let actor = host.id.clone();
+        let session = db
+            .run(move |c| -> Result<Option<Session>, Error> {
+                let key = c.auth_result_key();
+                c.query_opt(
+                    reset_auth_result_query().as_str(),
+                    &[
+                        &session_id,
+                        &actor,
+                        &AttrId::generate(),
+                        &SessionState::Created.to_string(),
+                        &SessionState::Created.predecessor_names(),
+                    ],
+                )?
+                .as_ref()
+                .map(|row| Session::from_row(row, key.as_deref()))
+                .transpose()
+            })
+            .await?
+            .ok_or(Error::Conflict(
+                "Session has no authentication result to reset",
+            ))?;
+
+        let event = session.event(RoomEventKind::AuthReset);
+        audit::record(
+            AuditEvent::new(AuditEventKind::AuthResultReset)
+                .session(&event.room_id, &event.session_id)
+                .actor(host.id.clone()),
+        );
+        events::publish(event);
+        Ok(session)
+    }
+
+    /// Authentication results the session `session_id` held before they were
+    /// reset through [`Session::reset_auth_result`], oldest first
+    pub async fn auth_result_history(
+        session_id: SessionId,
+        db: &impl SessionDb,
+    ) -> Result<Vec<PastAuthResult>, Error> {
+        db.run(move |c| -> Result<Vec<PastAuthResult>, Error> {
+            let key = c.auth_result_key();
+            c.query(FIND_AUTH_RESULT_HISTORY, &[&session_id])?
+                .iter()
+                .map(|row| {
+                    Ok(PastAuthResult {
+                        auth_result: decode_auth_result(key.as_deref(), row.get("auth_result"))?,
+                        reset_at: row.get("reset_at"),
+                        reset_by: row.get("reset_by"),
+                    })
+                })
+                .collect()
+        })
+        .await
+    }
+
+    /// Register an authentication result with a session, completing its
+    /// authentication. Fails if the session already contains an authentication
+    /// result, or was expired or cancelled.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, err, fields(attr_id = crate::util::log_pseudonym(&attr_id)))
+    )]
     pub async fn register_auth_result(
-        attr_id: String,
-        auth_result: String,
-        db: &SessionDBConn,
+        attr_id: AttrId,
+        auth_result: StoredAuthResult,
+        db: &impl SessionDb,
     ) -> Result<(), Error> {
-        let n = db
-            .run(move |c| {
-                c.execute(
-                    "UPDATE session
-                    SET (auth_result, last_activity) = ($1, now())
-                    WHERE auth_result IS NULL
-                    AND attr_id = $2;",
-                    &[&auth_result, &attr_id],
-                )
+        #[cfg(feature = "metrics")]
+        let _timer = crate::metrics::db_query_timer("register_auth_result");
+        let row = db
+            .run(move |c| -> Result<Option<Row>, Error> {
+                let key = c.auth_result_key();
+                let auth_result = encode_auth_result(key.as_deref(), &auth_result)?;
+                let statement = c.prepare_cached(REGISTER_AUTH_RESULT)?;
+                Ok(c.query_opt(
+                    &statement,
+                    &[
+                        &auth_result,
+                        &attr_id,
+                        &SessionState::AuthCompleted.to_string(),
+                        &SessionState::AuthCompleted.predecessor_names(),
+                    ],
+                )?)
+            })
+            .await?
+            .ok_or(Error::NotFound)?;
+
+        let event = auth_result_event(&row);
+        audit_room_event(&event, AuditEventKind::AuthResultStored);
+        events::publish(event);
+        #[cfg(feature = "metrics")]
+        crate::metrics::auth_result_received();
+        Ok(())
+    }
+
+    /// Register authentication results with the sessions matching their
+    /// attribute IDs, e.g. for results aggregated by the core or re-delivered
+    /// after an outage. All results are registered in a single transaction: if
+    /// any session does not accept its result, none are registered, and this
+    /// fails like [`Session::register_auth_result`].
+    pub async fn register_auth_results(
+        auth_results: Vec<(AttrId, StoredAuthResult)>,
+        db: &impl SessionDb,
+    ) -> Result<(), Error> {
+        #[cfg(feature = "metrics")]
+        let _timer = crate::metrics::db_query_timer("register_auth_results");
+        let rows = db
+            .run(move |c| -> Result<Vec<Row>, Error> {
+                let key = c.auth_result_key();
+                let auth_results = auth_results
+                    .into_iter()
+                    .map(|(attr_id, auth_result)| {
+                        Ok((attr_id, encode_auth_result(key.as_deref(), &auth_result)?))
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?;
+                let mut transaction = c.transaction()?;
+                let statement = transaction.prepare(REGISTER_AUTH_RESULT)?;
+                let state = SessionState::AuthCompleted.to_string();
+                let predecessors = SessionState::AuthCompleted.predecessor_names();
+
+                let mut rows = Vec::with_capacity(auth_results.len());
+                for (attr_id, auth_result) in &auth_results {
+                    let row = transaction
+                        .query_opt(&statement, &[auth_result, attr_id, &state, &predecessors])?
+                        .ok_or(Error::NotFound)?;
+                    rows.push(row);
+                }
+                transaction.commit()?;
+                Ok(rows)
             })
             .await?;
 
-        match n {
-            1 => Ok(()),
-            _ => Err(Error::NotFound),
+        for row in &rows {
+            let event = auth_result_event(row);
+            audit_room_event(&event, AuditEventKind::AuthResultStored);
+            events::publish(event);
+            #[cfg(feature = "metrics")]
+            crate::metrics::auth_result_received();
         }
+        Ok(())
+    }
+
+    /// Find sessions by room ID, in order of creation, marking them as active
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, err, fields(room_id = crate::util::log_pseudonym(&room_id)))
+    )]
+    pub async fn find_by_room_id(room_id: RoomId, db: &impl SessionDb) -> Result<Vec<Self>, Error> {
+        Session::find_by_room_id_with(room_id, ActivityUpdate::Touch, db).await
     }
 
-    /// Find sessions by room ID
-    pub async fn find_by_room_id(room_id: String, db: &SessionDBConn) -> Result<Vec<Self>, Error> {
-        let sessions = db
+    /// Find sessions by room ID, in order of creation, marking them as active
+    /// only if `activity` says so. Fails with `Error::NotFound` if the room has
+    /// no sessions.
+    pub async fn find_by_room_id_with(
+        room_id: RoomId,
+        activity: ActivityUpdate,
+        db: &impl SessionDb,
+    ) -> Result<Vec<Self>, Error> {
+        if activity == ActivityUpdate::Preserve {
+            return Session::find_by_room_id_readonly(room_id, db).await;
+        }
+
+        #[cfg(feature = "metrics")]
+        let _timer = crate::metrics::db_query_timer("find_by_room_id");
+        let mut sessions = db
             .run(move |c| -> Result<Vec<Session>, Error> {
-                let rows = c.query(
-                    "
-                    UPDATE session
-                    SET last_activity = now()
-                    WHERE room_id = $1
-                    RETURNING
-                        session_id,
-                        room_id,
-                        domain,
-                        redirect_url,
-                        purpose,
-                        name,
-                        instance,
-                        attr_id,
-                        auth_result
-                    ",
-                    &[&room_id],
-                )?;
+                let statement = c.prepare_cached(&find_by_room_id_query())?;
+                let rows = c.query(&statement, &[&room_id])?;
                 if rows.is_empty() {
                     return Err(Error::NotFound);
                 }
-                rows.into_iter()
-                    .map(|r| -> Result<_, Error> {
-                        let domain = SessionDomain::from_str(r.get("domain"))?;
-                        let guest_token = GuestToken {
-                            id: r.get("session_id"),
-                            room_id: r.get("room_id"),
-                            domain,
-                            redirect_url: r.get("redirect_url"),
-                            name: r.get("name"),
-                            instance: r.get("instance"),
-                            purpose: r.get("purpose"),
-                        };
-                        Ok(Session {
-                            guest_token,
-                            attr_id: r.get("attr_id"),
-                            auth_result: r.get("auth_result"),
-                        })
-                    })
+                let key = c.auth_result_key();
+                rows.iter()
+                    .map(|row| Session::from_row(row, key.as_deref()))
                     .collect()
             })
             .await?;
 
+        sessions.sort_by(creation_order);
         Ok(sessions)
     }
-}
 
-/// Remove all sessions that have been inactive for an hour or more
-pub async fn clean_db(db: &SessionDBConn) -> Result<(), Error> {
-    db.run(move |c| {
-        c.execute(
-            "DELETE FROM session WHERE last_activity < now() - INTERVAL '1 hour'",
-            &[],
-        )
-    })
-    .await?;
+    /// Find sessions by room ID, in order of creation, without marking them as
+    /// active. Unlike [`Session::find_by_room_id`], this only reads from the
+    /// database, so it can be used for monitoring and on read replicas.
+    pub async fn find_by_room_id_readonly(
+        room_id: RoomId,
+        db: &impl SessionDb,
+    ) -> Result<Vec<Self>, Error> {
+        #[cfg(feature = "metrics")]
+        let _timer = crate::metrics::db_query_timer("find_by_room_id_readonly");
+        db.run(move |c| Session::query_room_readonly(c, &room_id))
+            .await
+    }
+
+    /// Sessions in room `room_id` in order of creation, failing with
+    /// `Error::NotFound` if there are none
+    pub(crate) fn query_room_readonly(
+        c: &mut SessionClient,
+        room_id: &RoomId,
+    ) -> Result<Vec<Session>, Error> {
+        let statement = c.prepare_cached(&find_by_room_id_readonly_query())?;
+        let rows = c.query(&statement, &[room_id])?;
+        if rows.is_empty() {
+            return Err(Error::NotFound);
+        }
+        let key = c.auth_result_key();
+        rows.iter()
+            .map(|row| Session::from_row(row, key.as_deref()))
+            .collect()
+    }
+
+    /// Page `page` of the sessions in room `room_id` in order of creation
+    pub(crate) fn query_page_readonly(
+        c: &mut SessionClient,
+        room_id: &RoomId,
+        page: Page,
+    ) -> Result<Vec<Session>, Error> {
+        let statement = c.prepare_cached(&find_page_by_room_id_readonly_query())?;
+        let rows = c.query(
+            &statement,
+            &[room_id, &i64::from(page.limit), &i64::from(page.offset)],
+        )?;
+        let key = c.auth_result_key();
+        rows.iter()
+            .map(|row| Session::from_row(row, key.as_deref()))
+            .collect()
+    }
+
+    /// Number of sessions in room `room_id`
+    pub(crate) fn query_count(c: &mut SessionClient, room_id: &RoomId) -> Result<u64, Error> {
+        let statement = c.prepare_cached(COUNT_BY_ROOM_ID)?;
+        let count: i64 = c.query_one(&statement, &[room_id])?.get(0);
+        Ok(count as u64)
+    }
+
+    /// Mark the session with the given ID as active, keeping it alive. Fails
+    /// with `Error::NotFound` if there is no such session.
+    pub async fn touch(session_id: SessionId, db: &impl SessionDb) -> Result<(), Error> {
+        let n = db
+            .run(move |c| {
+                let statement = c.prepare_cached(TOUCH_SESSION)?;
+                c.execute(&statement, &[&session_id])
+            })
+            .await?;
+        match n {
+            0 => Err(Error::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait until an authentication result is registered with the session
+    /// matching `attr_id`, for at most `timeout`. Returns `None` if no result
+    /// arrived in time, and fails with `Error::NotFound` if there is no such
+    /// session. Results registered by this process are picked up immediately;
+    /// results registered by other instances are found by checking the
+    /// database at increasing intervals. Does not mark the session as active.
+    pub async fn wait_for_auth_result(
+        attr_id: AttrId,
+        timeout: Duration,
+        db: &impl SessionDb,
+    ) -> Result<Option<StoredAuthResult>, Error> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        // Subscribe before the first check, so that a result registered in
+        // between is not missed
+        let mut events = events::subscribe();
+        let mut poll_interval = WAIT_POLL_INITIAL;
+
+        loop {
+            let session = Session::select_one("attr_id", attr_id.to_string(), db).await?;
+            if session.auth_result.is_some() {
+                return Ok(session.auth_result);
+            }
+
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+
+            let registered = async {
+                while let Some(event) = events::next_in_room(
+                    &mut events,
+                    &session.guest_token.room_id,
+                    &session.guest_token.instance,
+                )
+                .await
+                {
+                    if session.guest_token.id == event.session_id.as_str()
+                        && event.kind == RoomEventKind::AuthResult
+                    {
+                        return;
+                    }
+                }
+                // No more events can arrive, so rely on polling only
+                std::future::pending::<()>().await
+            };
+            tokio::select! {
+                _ = registered => {}
+                _ = tokio::time::sleep_until(deadline.min(now + poll_interval)) => {}
+            }
+            poll_interval = WAIT_POLL_MAX.min(poll_interval * 2);
+        }
+    }
+
+    /// Find a page of the sessions in a room, in order of creation, marking
+    /// them as active. Unlike [`Session::find_by_room_id`], an empty page is
+    /// not an error.
+    pub async fn find_page_by_room_id(
+        room_id: RoomId,
+        page: Page,
+        db: &impl SessionDb,
+    ) -> Result<Vec<Self>, Error> {
+        #[cfg(feature = "metrics")]
+        let _timer = crate::metrics::db_query_timer("find_page_by_room_id");
+        let mut sessions = db
+            .run(move |c| -> Result<Vec<Session>, Error> {
+                let statement = c.prepare_cached(&find_page_by_room_id_query())?;
+                let rows = c.query(
+                    &statement,
+                    &[&room_id, &i64::from(page.limit), &i64::from(page.offset)],
+                )?;
+                let key = c.auth_result_key();
+                rows.iter()
+                    .map(|row| Session::from_row(row, key.as_deref()))
+                    .collect()
+            })
+            .await?;
+
+        sessions.sort_by(creation_order);
+        Ok(sessions)
+    }
+
+    /// Count the sessions in a room
+    pub async fn count_by_room_id(room_id: RoomId, db: &impl SessionDb) -> Result<u64, Error> {
+        db.run(move |c| Session::query_count(c, &room_id)).await
+    }
+
+    /// Find the session matching `key_column = key`, marking it as active if
+    /// `activity` says so
+    async fn find_one(
+        key_column: &'static str,
+        key: String,
+        activity: ActivityUpdate,
+        db: &impl SessionDb,
+    ) -> Result<Self, Error> {
+        if activity == ActivityUpdate::Preserve {
+            return Session::select_one(key_column, key, db).await;
+        }
+
+        #[cfg(feature = "metrics")]
+        let _timer = crate::metrics::db_query_timer(&format!("find_by_{}", key_column));
+        db.run(move |c| -> Result<Session, Error> {
+            let statement = c.prepare_cached(&find_query(key_column))?;
+            let row = c.query_opt(&statement, &[&key])?.ok_or(Error::NotFound)?;
+            Session::from_row(&row, c.auth_result_key().as_deref())
+        })
+        .await
+    }
+
+    /// Find the session matching `key_column = key`, without marking it as
+    /// active
+    async fn select_one(
+        key_column: &'static str,
+        key: String,
+        db: &impl SessionDb,
+    ) -> Result<Self, Error> {
+        db.run(move |c| -> Result<Session, Error> {
+            let statement = c.prepare_cached(&select_query(key_column))?;
+            let row = c.query_opt(&statement, &[&key])?.ok_or(Error::NotFound)?;
+            Session::from_row(&row, c.auth_result_key().as_deref())
+        })
+        .await
+    }
+
+    /// Find a session by the ID of its guest token, without marking it as
+    /// active. Fails with `Error::NotFound` if there is no such session.
+    pub(crate) async fn find_by_session_id_readonly(
+        session_id: SessionId,
+        db: &impl SessionDb,
+    ) -> Result<Self, Error> {
+        Session::select_one("session_id", session_id.into(), db).await
+    }
+
+    /// Find the session an authentication result with the given attribute ID
+    /// belongs to, marking it as active. Fails with `Error::NotFound` if there
+    /// is no such session.
+    pub async fn find_by_attr_id(attr_id: AttrId, db: &impl SessionDb) -> Result<Self, Error> {
+        Session::find_by_attr_id_with(attr_id, ActivityUpdate::Touch, db).await
+    }
+
+    /// Find the session an authentication result with the given attribute ID
+    /// belongs to, marking it as active only if `activity` says so
+    pub async fn find_by_attr_id_with(
+        attr_id: AttrId,
+        activity: ActivityUpdate,
+        db: &impl SessionDb,
+    ) -> Result<Self, Error> {
+        Session::find_one("attr_id", attr_id.into(), activity, db).await
+    }
+
+    /// Find a session by the ID of its guest token, marking it as active.
+    /// Fails with `Error::NotFound` if there is no such session.
+    pub async fn find_by_session_id(
+        session_id: SessionId,
+        db: &impl SessionDb,
+    ) -> Result<Self, Error> {
+        Session::find_by_session_id_with(session_id, ActivityUpdate::Touch, db).await
+    }
+
+    /// Find a session by the ID of its guest token, marking it as active only
+    /// if `activity` says so
+    pub async fn find_by_session_id_with(
+        session_id: SessionId,
+        activity: ActivityUpdate,
+        db: &impl SessionDb,
+    ) -> Result<Self, Error> {
+        Session::find_one("session_id", session_id.into(), activity, db).await
+    }
+
+    /// Find the session for which the core started the authentication session
+    /// `core_session_id`, without marking it as active, so that support staff
+    /// can trace failures at the core. Fails with `Error::NotFound` if there
+    /// is no such session.
+    pub async fn find_by_core_session_id(
+        core_session_id: String,
+        db: &impl SessionDb,
+    ) -> Result<Self, Error> {
+        Session::select_one("core_session_id", core_session_id, db).await
+    }
+
+    /// Find sessions by their session IDs. IDs for which no committed session
+    /// exists are left out of the result.
+    pub async fn find_by_ids(
+        session_ids: &[SessionId],
+        db: &impl SessionDb,
+    ) -> Result<Vec<Self>, Error> {
+        let session_ids = session_ids.to_vec();
+        db.run(move |c| -> Result<Vec<Session>, Error> {
+            let rows = c.query(
+                format!(
+                    "
+                    SELECT {}
+                    FROM session
+                    WHERE session_id = ANY($1)
+                    AND committed
+                    ",
+                    SESSION_COLUMNS
+                )
+                .as_str(),
+                &[&session_ids],
+            )?;
+            let key = c.auth_result_key();
+            rows.iter()
+                .map(|row| Session::from_row(row, key.as_deref()))
+                .collect()
+        })
+        .await
+    }
+
+    /// Find the sessions created within `range`, in order of creation, without
+    /// marking them as active
+    pub async fn find_created_between(
+        range: Range<SystemTime>,
+        db: &impl SessionDb,
+    ) -> Result<Vec<Self>, Error> {
+        #[cfg(feature = "metrics")]
+        let _timer = crate::metrics::db_query_timer("find_created_between");
+        db.run(move |c| -> Result<Vec<Session>, Error> {
+            let statement = c.prepare_cached(&find_created_between_query())?;
+            let rows = c.query(&statement, &[&range.start, &range.end])?;
+            let key = c.auth_result_key();
+            rows.iter()
+                .map(|row| Session::from_row(row, key.as_deref()))
+                .collect()
+        })
+        .await
+    }
+
+    /// Load a session a host wants to act upon. The host token must already
+    /// have been verified, e.g. through [`crate::types::FromPlatformJwt`].
+    /// Fails with `Error::Forbidden` if the token does not belong to a host, or
+    /// if the session is in another room than the host's, and with
+    /// `Error::NotFound` if the session does not exist.
+    pub async fn load_for_host_action(
+        session_id: SessionId,
+        host: &HostToken,
+        db: &impl SessionDb,
+    ) -> Result<Self, Error> {
+        if host.domain != SessionDomain::User {
+            return Err(Error::Forbidden(
+                "Token does not belong to a host".to_owned(),
+            ));
+        }
+
+        let session = Session::find_by_ids(&[session_id], db)
+            .await?
+            .pop()
+            .ok_or(Error::NotFound)?;
+        host.authorize_guest(&session.guest_token)?;
+        Ok(session)
+    }
+
+    /// Consume a one-time join code, returning the session it belongs to. The
+    /// code is marked as used, and stays reserved until its session is
+    /// removed. Fails with `Error::NotFound` for unknown codes, and with
+    /// `Error::Conflict` for codes that were already used or belong to a
+    /// session that was cancelled or expired.
+    pub async fn consume_join_code(code: String, db: &impl SessionDb) -> Result<Self, Error> {
+        db.run(move |c| -> Result<Session, Error> {
+            let row = c.query_opt(
+                format!(
+                    "
+                    UPDATE session
+                    SET (join_code_used, last_activity) = (true, now())
+                    WHERE join_code = $1
+                    AND NOT join_code_used
+                    AND state NOT IN ('cancelled', 'expired')
+                    RETURNING {}
+                    ",
+                    SESSION_COLUMNS
+                )
+                .as_str(),
+                &[&code],
+            )?;
+            if let Some(row) = row {
+                return Session::from_row(&row, c.auth_result_key().as_deref());
+            }
+
+            let used: Option<bool> = c
+                .query_opt(
+                    "SELECT join_code_used FROM session WHERE join_code = $1",
+                    &[&code],
+                )?
+                .map(|row| row.get(0));
+            match used {
+                Some(true) => Err(Error::Conflict("Join code was already used")),
+                Some(false) => Err(Error::Conflict("The session of this join code was closed")),
+                None => Err(Error::NotFound),
+            }
+        })
+        .await
+    }
+}
+
+/// Remove all cancelled sessions, and all sessions that have been inactive for
+/// the default session lifetime of an hour or more
+#[deprecated(note = "use `clean_expired_sessions` with the configured session lifetime")]
+pub async fn clean_db(db: &impl SessionDb) -> Result<(), Error> {
+    clean_expired_sessions(db, DEFAULT_SESSION_LIFETIME, SessionExpiry::default()).await
+}
+
+/// Remove all cancelled sessions, and all sessions that expired under `expiry`
+/// with the given `lifetime`
+pub async fn clean_expired_sessions(
+    db: &impl SessionDb,
+    lifetime: Duration,
+    expiry: SessionExpiry,
+) -> Result<(), Error> {
+    clean_db_with_archive(db, lifetime, expiry, None).await
+}
+
+/// Like [`clean_expired_sessions`], first archiving the metadata of the removed
+/// sessions to `archive`, if set. Sessions are only removed once archived, so a
+/// failure to archive them leaves them in place for the next cleanup.
+pub async fn clean_db_with_archive(
+    db: &impl SessionDb,
+    lifetime: Duration,
+    expiry: SessionExpiry,
+    archive: Option<&ArchiveTarget>,
+) -> Result<(), Error> {
+    #[cfg(feature = "metrics")]
+    let _timer = crate::metrics::db_query_timer("clean");
+    let archive = archive.cloned();
+    let removed = db
+        .run(move |c| -> Result<Vec<Row>, Error> {
+            let query = clean_sessions_query(expiry, archive == Some(ArchiveTarget::Database));
+            let statement = c.prepare_cached(&query)?;
+            let mut transaction = c.transaction()?;
+            let removed = transaction.query(&statement, &[&lifetime.as_secs_f64()])?;
+            if let Some(ArchiveTarget::File { path }) = &archive {
+                archive_to_file(path, &removed)?;
+            }
+            transaction.commit()?;
+            Ok(removed)
+        })
+        .await?;
+    publish_expired(&removed);
+    #[cfg(feature = "metrics")]
+    crate::metrics::cleanup_completed(removed.len() as u64);
+    Ok(())
+}
+
+/// Remove the authentication results registered `older_than` ago or longer
+/// from their sessions, returning the number of results removed
+pub async fn purge_auth_results(older_than: Duration, db: &impl SessionDb) -> Result<u64, Error> {
+    #[cfg(feature = "metrics")]
+    let _timer = crate::metrics::db_query_timer("purge_auth_results");
+    let purged = db
+        .run(move |c| c.execute(PURGE_AUTH_RESULTS, &[&older_than.as_secs_f64()]))
+        .await?;
+    Ok(purged)
+}
+
+/// Remove sessions without an authentication result that have been inactive
+/// for `older_than` or more, returning the number of sessions removed
+pub async fn purge_pending_sessions(
+    older_than: Duration,
+    db: &impl SessionDb,
+) -> Result<u64, Error> {
+    #[cfg(feature = "metrics")]
+    let _timer = crate::metrics::db_query_timer("purge_pending_sessions");
+    let removed = db
+        .run(move |c| c.query(PURGE_PENDING_SESSIONS, &[&older_than.as_secs_f64()]))
+        .await?;
+    publish_expired(&removed);
+    Ok(removed.len() as u64)
+}
+
+/// Remove all sessions in a room together with their audit entries, e.g. to
+/// honour a request for erasure. Returns the number of sessions removed.
+pub async fn purge_by_room_id(room_id: RoomId, db: &impl SessionDb) -> Result<u64, Error> {
+    #[cfg(feature = "metrics")]
+    let _timer = crate::metrics::db_query_timer("purge_by_room_id");
+    let removed = db.run(move |c| c.query(PURGE_ROOM, &[&room_id])).await?;
+    publish_expired(&removed);
+    Ok(removed.len() as u64)
+}
+
+/// Remove sessions that have been inactive for the default session lifetime
+/// every `period` minutes, five by default. Like [`run_periodic_cleanup`],
+/// this runs until cancelled, and never fails.
+#[deprecated(note = "use `cleanup_fairing`, or `run_periodic_cleanup` with the configured policy")]
+pub async fn periodic_cleanup(db: &impl SessionDb, period: Option<u64>) -> Result<(), Error> {
+    let period = period.map_or(DEFAULT_CLEANUP_INTERVAL, |minutes| {
+        Duration::from_secs(minutes * 60)
+    });
+    run_periodic_cleanup(
+        db,
+        period,
+        DEFAULT_SESSION_LIFETIME,
+        SessionExpiry::default(),
+        RetentionPolicy::default(),
+    )
+    .await;
     Ok(())
 }
 
-pub async fn periodic_cleanup(db: &SessionDBConn, period: Option<u64>) -> Result<(), Error> {
-    let duration = Duration::from_secs(period.unwrap_or(5) * 60);
-    let mut interval = tokio::time::interval(duration);
+/// Remove expired sessions, and session data `retention` does not allow to be
+/// kept, every `period`, until cancelled. Failures are logged, and the cleanup
+/// is tried again in the next period, so that e.g. a database restart does
+/// not stop it for good.
+pub async fn run_periodic_cleanup(
+    db: &impl SessionDb,
+    period: Duration,
+    lifetime: Duration,
+    expiry: SessionExpiry,
+    retention: RetentionPolicy,
+) {
+    let mut interval = tokio::time::interval(period);
 
     loop {
         interval.tick().await;
-        clean_db(db).await?;
+        if let Err(e) =
+            clean_db_with_archive(db, lifetime, expiry, retention.archive.as_ref()).await
+        {
+            log_error(format_args!("Session cleanup failed: {}", e));
+        }
+        if let Err(e) = db.apply_retention(&retention).await {
+            log_error(format_args!("Applying the retention policy failed: {}", e));
+        }
     }
 }
 
+/// Fairing spawning a task that periodically removes expired sessions, as
+/// configured through `session_lifetime`, `session_expiry` and
+/// `session_cleanup_interval`, and
+/// applies the retention policy configured through `[global.retention]`. The
+/// task stops when Rocket shuts down. Requires the configuration to be managed
+/// and the [`SessionDBConn`] fairing to be attached.
+#[cfg(feature = "rocket")]
+pub fn cleanup_fairing() -> impl Fairing {
+    AdHoc::on_liftoff("Session cleanup", |rocket| {
+        Box::pin(async move {
+            let config = CurrentConfig::from_rocket(rocket).expect("No configuration found");
+            let period = config.session_cleanup_interval();
+            let lifetime = config.session_lifetime();
+            let expiry = config.session_expiry();
+            let retention = config.retention();
+
+            let db = match SessionDBConn::get_one(rocket).await {
+                Some(db) => db,
+                None => {
+                    eprintln!("Session cleanup disabled: no session database available");
+                    return;
+                }
+            };
+            let shutdown = rocket.shutdown();
+
+            spawn_tracked(async move {
+                tokio::select! {
+                    _ = run_periodic_cleanup(&db, period, lifetime, expiry, retention) => {}
+                    _ = shutdown => {}
+                }
+            });
+        })
+    })
+}
+
 #[cfg(test)]
 mod tests {
-    use figment::{
-        providers::{Format, Toml},
-        Figment,
-    };
+    use std::time::{Duration, SystemTime};
+
     use serial_test::serial;
+    use verder_helpen_proto::AuthStatus;
 
-    use super::Session;
+    use super::{
+        cancel_assignments, clean_sessions_query, exists_query, find_by_room_id_query,
+        find_by_room_id_readonly_query, find_created_between_query, find_page_by_room_id_query,
+        find_page_by_room_id_readonly_query, find_query, select_query, transaction::COMMIT_SESSION,
+        transition_query, Page, Session, COUNT_BY_ROOM_ID, INSERT_SESSION, PURGE_AUTH_RESULTS,
+        PURGE_PENDING_SESSIONS, PURGE_ROOM, REGISTER_AUTH_RESULT, TOUCH_SESSION,
+    };
     use crate::{
-        prelude::{random_string, GuestToken, SessionDBConn},
-        session::clean_db,
+        error::Error,
+        prelude::{random_string, HostToken},
+        session::{
+            clean_db, clean_db_with_archive, clean_expired_sessions, purge_auth_results,
+            purge_by_room_id, purge_pending_sessions, ArchiveTarget, AuthResultKeySource,
+            ConnectionOptions, SessionConn, SessionDb, SessionExpiry, SessionPool, SessionState,
+            DEFAULT_SESSION_LIFETIME,
+        },
+        test_support::fixtures::{self, guest_token},
+        types::{AttrId, RoomId, SessionDomain, SessionId},
     };
 
-    async fn init_db() -> Option<SessionDBConn> {
+    pub(super) async fn init_db() -> Option<SessionConn> {
         if let Some(test_db) = option_env!("TEST_DB") {
-            // Easiest (perhaps only) way to get a SessionDBConn is to actually get us a
-            // rocket instance that has ignited. this is to deal with all the
-            // rewrites rocket does on that struct.
-            let figment = Figment::from(rocket::Config::default())
-                .select(rocket::Config::DEBUG_PROFILE)
-                .merge(
-                    Toml::string(&format!(
-                        r#"
-[global.databases]
-session = {{ url = "{}" }}
-"#,
-                        test_db
-                    ))
-                    .nested(),
-                );
-            let rocket = rocket::custom(figment)
-                .attach(SessionDBConn::fairing())
-                .ignite()
-                .await
-                .unwrap();
-            let db_session = SessionDBConn::get_one(&rocket).await.unwrap();
+            let pool = SessionPool::new(
+                test_db,
+                2,
+                &ConnectionOptions::default(),
+                AuthResultKeySource::Fixed(None),
+            )
+            .unwrap();
+            let db_session = pool.get().await.unwrap();
             db_session
                 .run(|c| {
                     c.batch_execute(include_str!("../schema.sql")).unwrap();
@@ -278,25 +1865,7 @@ session = {{ url = "{}" }}
         }
     }
 
-    fn bogus_session(id: Option<String>, room_id: Option<String>) -> Session {
-        let guest_token = GuestToken {
-            purpose: "test".to_owned(),
-            id: id.unwrap_or_else(|| random_string(32)),
-            domain: crate::types::SessionDomain::Guest,
-            redirect_url: "verderhelpen.nl".to_owned(),
-            name: "Test Verder Helpen".to_owned(),
-            room_id: room_id.unwrap_or_else(|| random_string(32)),
-            instance: "verderhelpen.nl".to_owned(),
-        };
-
-        Session {
-            guest_token: guest_token,
-            auth_result: None,
-            attr_id: random_string(32),
-        }
-    }
-
-    async fn insert_session_with_age(s: Session, db: &SessionDBConn, age: String) {
+    async fn insert_session_with_age(s: Session, db: &SessionConn, age: String) {
         db.run(move |c| {
             let query = format!(
                 "INSERT INTO session (
@@ -310,7 +1879,7 @@ session = {{ url = "{}" }}
                 attr_id,
                 auth_result,
                 last_activity
-            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, now() - INTERVAL '{}');",
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9::text::jsonb, now() - INTERVAL '{}');",
                 age
             );
 
@@ -325,7 +1894,7 @@ session = {{ url = "{}" }}
                     &s.guest_token.name,
                     &s.guest_token.instance,
                     &s.attr_id,
-                    &s.auth_result,
+                    &s.auth_result_json(None).unwrap(),
                 ],
             )
         })
@@ -340,12 +1909,12 @@ session = {{ url = "{}" }}
     fn test_register_auth_result() {
         tokio_test::block_on(async {
             if let Some(db) = init_db().await {
-                let s = bogus_session(None, None);
+                let s = fixtures::session(guest_token().build()).build();
                 s.persist(&db).await.unwrap();
 
                 Session::register_auth_result(
                     s.attr_id.to_owned(),
-                    "invalid_auth_result".to_owned(),
+                    fixtures::auth_result().attribute("age", "42").stored(),
                     &db,
                 )
                 .await
@@ -356,10 +1925,104 @@ session = {{ url = "{}" }}
                     .unwrap();
 
                 assert_eq!(sessions.len(), 1);
-                assert_eq!(
-                    sessions[0].auth_result,
-                    Some("invalid_auth_result".to_owned())
+                let auth_result = sessions[0].auth_result.as_ref().unwrap();
+                assert!(matches!(auth_result.status, AuthStatus::Success));
+                assert_eq!(auth_result.attribute("age"), Some("42"));
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_queries_match_schema() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                db.run(|c| {
+                    let queries = [
+                        INSERT_SESSION.to_owned(),
+                        REGISTER_AUTH_RESULT.to_owned(),
+                        PURGE_AUTH_RESULTS.to_owned(),
+                        PURGE_PENDING_SESSIONS.to_owned(),
+                        PURGE_ROOM.to_owned(),
+                        TOUCH_SESSION.to_owned(),
+                        COUNT_BY_ROOM_ID.to_owned(),
+                        COMMIT_SESSION.to_owned(),
+                        clean_sessions_query(SessionExpiry::Sliding, false),
+                        clean_sessions_query(SessionExpiry::Absolute, true),
+                        find_query("room_id"),
+                        find_by_room_id_query(),
+                        select_query("attr_id"),
+                        find_page_by_room_id_query(),
+                        find_page_by_room_id_readonly_query(),
+                        find_by_room_id_readonly_query(),
+                        find_created_between_query(),
+                        transition_query("attr_id", cancel_assignments(true)),
+                        exists_query("attr_id"),
+                    ];
+                    for query in &queries {
+                        if let Err(e) = c.prepare_cached(query) {
+                            panic!("Query does not match the schema: {}\n{}", e, query);
+                        }
+                    }
+                })
+                .await;
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_register_auth_results() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let room_id = RoomId::new(random_string(32)).unwrap();
+                let first =
+                    fixtures::session(guest_token().room_id(room_id.clone()).build()).build();
+                let second =
+                    fixtures::session(guest_token().room_id(room_id.clone()).build()).build();
+                first.persist(&db).await.unwrap();
+                second.persist(&db).await.unwrap();
+
+                // An unknown attribute ID rolls back the whole batch
+                assert!(matches!(
+                    Session::register_auth_results(
+                        vec![
+                            (
+                                first.attr_id.clone(),
+                                fixtures::auth_result().attribute("age", "42").stored()
+                            ),
+                            (
+                                AttrId::generate(),
+                                fixtures::auth_result().attribute("age", "42").stored()
+                            ),
+                        ],
+                        &db,
+                    )
+                    .await,
+                    Err(Error::NotFound)
+                ));
+                let sessions = Session::find_by_room_id(room_id.clone(), &db)
+                    .await
+                    .unwrap();
+                assert!(sessions.iter().all(|session| session.auth_result.is_none()));
+
+                Session::register_auth_results(
+                    vec![
+                        (
+                            first.attr_id.clone(),
+                            fixtures::auth_result().attribute("age", "42").stored(),
+                        ),
+                        (
+                            second.attr_id.clone(),
+                            fixtures::auth_result().attribute("age", "42").stored(),
+                        ),
+                    ],
+                    &db,
                 )
+                .await
+                .unwrap();
+                let sessions = Session::find_by_room_id(room_id, &db).await.unwrap();
+                assert!(sessions.iter().all(|session| session.auth_result.is_some()));
             }
         });
     }
@@ -369,27 +2032,28 @@ session = {{ url = "{}" }}
     fn test_clean_db() {
         tokio_test::block_on(async {
             if let Some(db) = init_db().await {
-                let room_id = "Room 123 Test".to_owned();
+                let room_id = RoomId::new("Room 123 Test").unwrap();
 
                 insert_session_with_age(
-                    bogus_session(None, Some(room_id.clone())),
+                    fixtures::session(guest_token().room_id(room_id.clone()).build()).build(),
                     &db,
                     "1 hour".into(),
                 )
                 .await;
                 insert_session_with_age(
-                    bogus_session(None, Some(room_id.clone())),
+                    fixtures::session(guest_token().room_id(room_id.clone()).build()).build(),
                     &db,
                     "2 hour".into(),
                 )
                 .await;
                 insert_session_with_age(
-                    bogus_session(None, Some(room_id.clone())),
+                    fixtures::session(guest_token().room_id(room_id.clone()).build()).build(),
                     &db,
                     "1 minute".into(),
                 )
                 .await;
 
+                #[allow(deprecated)]
                 clean_db(&db).await.unwrap();
 
                 let sessions = Session::find_by_room_id(room_id, &db).await.unwrap();
@@ -397,4 +2061,777 @@ session = {{ url = "{}" }}
             }
         });
     }
+
+    #[test]
+    #[serial]
+    fn test_clean_db_with_archive() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let room_id = RoomId::new(random_string(32)).unwrap();
+                let count_archived = || {
+                    db.run(|c| {
+                        c.query_one("SELECT count(*) FROM session_archive", &[])
+                            .unwrap()
+                            .get::<_, i64>(0)
+                    })
+                };
+
+                let before = count_archived().await;
+                insert_session_with_age(
+                    fixtures::session(guest_token().room_id(room_id.clone()).build()).build(),
+                    &db,
+                    "2 hour".into(),
+                )
+                .await;
+                clean_db_with_archive(
+                    &db,
+                    DEFAULT_SESSION_LIFETIME,
+                    SessionExpiry::Sliding,
+                    Some(&ArchiveTarget::Database),
+                )
+                .await
+                .unwrap();
+                assert!(count_archived().await > before);
+
+                let path = std::env::temp_dir().join(random_string(16));
+                insert_session_with_age(
+                    fixtures::session(guest_token().room_id(room_id.clone()).build()).build(),
+                    &db,
+                    "2 hour".into(),
+                )
+                .await;
+                clean_db_with_archive(
+                    &db,
+                    DEFAULT_SESSION_LIFETIME,
+                    SessionExpiry::Sliding,
+                    Some(&ArchiveTarget::File {
+                        path: path.to_string_lossy().into_owned(),
+                    }),
+                )
+                .await
+                .unwrap();
+                let archived = std::fs::read_to_string(&path).unwrap();
+                std::fs::remove_file(&path).unwrap();
+                assert!(archived.contains("\"purpose\":\"test\""));
+                assert!(!archived.contains(room_id.as_str()));
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_purge() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let room_id = RoomId::new("Room purge Test").unwrap();
+
+                insert_session_with_age(
+                    fixtures::session(guest_token().room_id(room_id.clone()).build()).build(),
+                    &db,
+                    "2 hour".into(),
+                )
+                .await;
+                let mut completed =
+                    fixtures::session(guest_token().room_id(room_id.clone()).build()).build();
+                completed.auth_result =
+                    Some(fixtures::auth_result().attribute("age", "42").stored());
+                insert_session_with_age(completed, &db, "2 hour".into()).await;
+                insert_session_with_age(
+                    fixtures::session(guest_token().room_id(room_id.clone()).build()).build(),
+                    &db,
+                    "1 minute".into(),
+                )
+                .await;
+
+                let hour = Duration::from_secs(60 * 60);
+                assert_eq!(purge_pending_sessions(hour, &db).await.unwrap(), 1);
+                assert_eq!(purge_auth_results(hour, &db).await.unwrap(), 1);
+                let sessions = Session::find_by_room_id(room_id.clone(), &db)
+                    .await
+                    .unwrap();
+                assert_eq!(sessions.len(), 2);
+                assert!(sessions.iter().all(|s| s.auth_result.is_none()));
+
+                assert_eq!(purge_by_room_id(room_id.clone(), &db).await.unwrap(), 2);
+                assert!(Session::find_by_room_id(room_id, &db)
+                    .await
+                    .unwrap()
+                    .is_empty());
+            }
+        });
+    }
+
+    #[test]
+    fn test_session_expiry() {
+        let mut session = fixtures::session(guest_token().build()).build();
+        session.created_at = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+        session.last_activity = SystemTime::now();
+
+        let now = SystemTime::now();
+        assert!(session.expires_at(DEFAULT_SESSION_LIFETIME, SessionExpiry::Sliding) > now);
+        assert!(session.expires_at(DEFAULT_SESSION_LIFETIME, SessionExpiry::Absolute) <= now);
+
+        let expiry: SessionExpiry = serde_yaml::from_str("absolute").unwrap();
+        assert_eq!(expiry, SessionExpiry::Absolute);
+    }
+
+    #[test]
+    #[serial]
+    fn test_bump_all_in_room() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let room_id = RoomId::new("Room 456 Test").unwrap();
+
+                insert_session_with_age(
+                    fixtures::session(guest_token().room_id(room_id.clone()).build()).build(),
+                    &db,
+                    "2 hour".into(),
+                )
+                .await;
+                insert_session_with_age(
+                    fixtures::session(guest_token().room_id(room_id.clone()).build()).build(),
+                    &db,
+                    "3 hour".into(),
+                )
+                .await;
+
+                let n = Session::bump_all_in_room(room_id.clone(), &db)
+                    .await
+                    .unwrap();
+                assert_eq!(n, 2);
+
+                clean_expired_sessions(&db, DEFAULT_SESSION_LIFETIME, SessionExpiry::Sliding)
+                    .await
+                    .unwrap();
+
+                let sessions = Session::find_by_room_id(room_id, &db).await.unwrap();
+                assert_eq!(sessions.len(), 2);
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_consume_join_code() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let s = fixtures::session(guest_token().build())
+                    .build()
+                    .with_join_code();
+                s.persist(&db).await.unwrap();
+                let code = s.join_code.clone().unwrap();
+
+                let consumed = Session::consume_join_code(code.clone(), &db).await.unwrap();
+                assert_eq!(consumed.guest_token.id, s.guest_token.id);
+
+                assert!(matches!(
+                    Session::consume_join_code(code.clone(), &db).await,
+                    Err(Error::Conflict("Join code was already used"))
+                ));
+                assert!(matches!(
+                    Session::consume_join_code("UNKNOWN".to_owned(), &db).await,
+                    Err(Error::NotFound)
+                ));
+
+                // The used code stays reserved
+                let mut colliding = fixtures::session(guest_token().build()).build();
+                colliding.join_code = Some(code);
+                assert!(matches!(
+                    colliding.persist(&db).await,
+                    Err(Error::Conflict(_))
+                ));
+
+                let closed = fixtures::session(guest_token().build())
+                    .build()
+                    .with_join_code();
+                closed.persist(&db).await.unwrap();
+                Session::cancel(closed.attr_id.clone(), false, &db)
+                    .await
+                    .unwrap();
+                assert!(matches!(
+                    Session::consume_join_code(closed.join_code.unwrap(), &db).await,
+                    Err(Error::Conflict("The session of this join code was closed"))
+                ));
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_persist_with_audit() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let s = fixtures::session(guest_token().build()).build();
+                s.persist_with_audit("host".to_owned(), &db).await.unwrap();
+
+                // A second attempt must fail without leaving another audit entry
+                assert!(s.persist_with_audit("host".to_owned(), &db).await.is_err());
+
+                let id = s.guest_token.id.clone();
+                let entries: i64 = db
+                    .run(move |c| {
+                        c.query_one(
+                            "SELECT COUNT(*) FROM session_audit WHERE session_id = $1",
+                            &[&id],
+                        )
+                        .unwrap()
+                        .get(0)
+                    })
+                    .await;
+                assert_eq!(entries, 1);
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_find_by_ids() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let first = fixtures::session(guest_token().build()).build();
+                let second = fixtures::session(guest_token().build()).build();
+                first.persist(&db).await.unwrap();
+                second.persist(&db).await.unwrap();
+
+                let sessions = Session::find_by_ids(
+                    &[
+                        first.guest_token.id.clone(),
+                        second.guest_token.id.clone(),
+                        SessionId::new(random_string(32)).unwrap(),
+                    ],
+                    &db,
+                )
+                .await
+                .unwrap();
+
+                assert_eq!(sessions.len(), 2);
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_find_by_attr_id_and_session_id() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let session = fixtures::session(guest_token().build()).build();
+                session.persist(&db).await.unwrap();
+
+                let found = Session::find_by_attr_id(session.attr_id.clone(), &db)
+                    .await
+                    .unwrap();
+                assert_eq!(found.guest_token.id, session.guest_token.id);
+
+                let found = Session::find_by_session_id(session.guest_token.id.clone(), &db)
+                    .await
+                    .unwrap();
+                assert_eq!(found.attr_id, session.attr_id);
+
+                assert!(matches!(
+                    Session::find_by_attr_id(AttrId::generate(), &db).await,
+                    Err(Error::NotFound)
+                ));
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_find_page_by_room_id() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let room_id = RoomId::new(random_string(32)).unwrap();
+                let mut sessions = Vec::new();
+                for _ in 0..3 {
+                    let session =
+                        fixtures::session(guest_token().room_id(room_id.clone()).build()).build();
+                    session.persist(&db).await.unwrap();
+                    sessions.push(session);
+                }
+
+                assert_eq!(
+                    Session::count_by_room_id(room_id.clone(), &db)
+                        .await
+                        .unwrap(),
+                    3
+                );
+
+                let all = Session::find_by_room_id(room_id.clone(), &db)
+                    .await
+                    .unwrap();
+                let first = Session::find_page_by_room_id(
+                    room_id.clone(),
+                    Page {
+                        limit: 2,
+                        offset: 0,
+                    },
+                    &db,
+                )
+                .await
+                .unwrap();
+                let second = Session::find_page_by_room_id(
+                    room_id.clone(),
+                    Page {
+                        limit: 2,
+                        offset: 2,
+                    },
+                    &db,
+                )
+                .await
+                .unwrap();
+
+                let paged: Vec<&str> = first
+                    .iter()
+                    .chain(second.iter())
+                    .map(|session| session.attr_id.as_str())
+                    .collect();
+                let expected: Vec<&str> =
+                    all.iter().map(|session| session.attr_id.as_str()).collect();
+                assert_eq!(paged, expected);
+                assert_eq!(second.len(), 1);
+
+                let readonly = db
+                    .run(move |c| {
+                        Session::query_page_readonly(
+                            c,
+                            &room_id,
+                            Page {
+                                limit: 2,
+                                offset: 0,
+                            },
+                        )
+                    })
+                    .await
+                    .unwrap();
+                let readonly: Vec<&str> = readonly
+                    .iter()
+                    .map(|session| session.attr_id.as_str())
+                    .collect();
+                assert_eq!(readonly, expected[..2]);
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_readonly_lookup_and_touch() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let session = fixtures::session(guest_token().build()).build();
+                let room_id = session.guest_token.room_id.clone();
+                let session_id = session.guest_token.id.clone();
+                insert_session_with_age(session, &db, "30 minutes".into()).await;
+
+                let before = Session::find_by_room_id_readonly(room_id.clone(), &db)
+                    .await
+                    .unwrap();
+                let after = Session::find_by_room_id_readonly(room_id.clone(), &db)
+                    .await
+                    .unwrap();
+                assert_eq!(before[0].last_activity, after[0].last_activity);
+
+                Session::touch(session_id, &db).await.unwrap();
+                let touched = Session::find_by_room_id_readonly(room_id, &db)
+                    .await
+                    .unwrap();
+                assert!(touched[0].last_activity > before[0].last_activity);
+
+                assert!(matches!(
+                    Session::touch(SessionId::new(random_string(32)).unwrap(), &db).await,
+                    Err(Error::NotFound)
+                ));
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_wait_for_auth_result() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let session = fixtures::session(guest_token().build()).build();
+                session.persist(&db).await.unwrap();
+
+                let timed_out = Session::wait_for_auth_result(
+                    session.attr_id.clone(),
+                    Duration::from_millis(100),
+                    &db,
+                )
+                .await
+                .unwrap();
+                assert!(timed_out.is_none());
+
+                let register = async {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    Session::register_auth_result(
+                        session.attr_id.clone(),
+                        fixtures::auth_result().attribute("age", "42").stored(),
+                        &db,
+                    )
+                    .await
+                    .unwrap();
+                };
+                let (received, _) = tokio::join!(
+                    Session::wait_for_auth_result(
+                        session.attr_id.clone(),
+                        Duration::from_secs(10),
+                        &db,
+                    ),
+                    register,
+                );
+                assert!(received.unwrap().is_some());
+
+                let unknown = AttrId::generate();
+                assert!(matches!(
+                    Session::wait_for_auth_result(unknown, Duration::from_secs(1), &db).await,
+                    Err(Error::NotFound)
+                ));
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_expire_now() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let room_id = RoomId::new("Room 789 Test").unwrap();
+                let expired =
+                    fixtures::session(guest_token().room_id(room_id.clone()).build()).build();
+                expired.persist(&db).await.unwrap();
+                fixtures::session(guest_token().room_id(room_id.clone()).build())
+                    .build()
+                    .persist(&db)
+                    .await
+                    .unwrap();
+
+                Session::expire_now(expired.guest_token.id.clone(), &db)
+                    .await
+                    .unwrap();
+                clean_expired_sessions(&db, DEFAULT_SESSION_LIFETIME, SessionExpiry::Sliding)
+                    .await
+                    .unwrap();
+
+                let sessions = Session::find_by_room_id(room_id, &db).await.unwrap();
+                assert_eq!(sessions.len(), 1);
+                assert_ne!(sessions[0].guest_token.id, expired.guest_token.id);
+
+                assert!(matches!(
+                    Session::expire_now(expired.guest_token.id, &db).await,
+                    Err(Error::NotFound)
+                ));
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_expire_now_with_absolute_expiry() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let room_id = RoomId::new("Room 790 Test").unwrap();
+                let expired =
+                    fixtures::session(guest_token().room_id(room_id.clone()).build()).build();
+                expired.persist(&db).await.unwrap();
+                fixtures::session(guest_token().room_id(room_id.clone()).build())
+                    .build()
+                    .persist(&db)
+                    .await
+                    .unwrap();
+
+                Session::expire_now(expired.guest_token.id.clone(), &db)
+                    .await
+                    .unwrap();
+                clean_expired_sessions(&db, DEFAULT_SESSION_LIFETIME, SessionExpiry::Absolute)
+                    .await
+                    .unwrap();
+
+                let sessions = Session::find_by_room_id(room_id, &db).await.unwrap();
+                assert_eq!(sessions.len(), 1);
+                assert_ne!(sessions[0].guest_token.id, expired.guest_token.id);
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_cancel() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let room_id = RoomId::new("Room cancel Test").unwrap();
+                let cancelled =
+                    fixtures::session(guest_token().room_id(room_id.clone()).build()).build();
+                cancelled.persist(&db).await.unwrap();
+                fixtures::session(guest_token().room_id(room_id.clone()).build())
+                    .build()
+                    .persist(&db)
+                    .await
+                    .unwrap();
+
+                Session::register_auth_result(
+                    cancelled.attr_id.clone(),
+                    fixtures::auth_result().attribute("age", "42").stored(),
+                    &db,
+                )
+                .await
+                .unwrap();
+                Session::cancel(cancelled.attr_id.clone(), true, &db)
+                    .await
+                    .unwrap();
+
+                let session = Session::find_by_ids(&[cancelled.guest_token.id.clone()], &db)
+                    .await
+                    .unwrap()
+                    .pop()
+                    .unwrap();
+                assert_eq!(session.state, SessionState::Cancelled);
+                assert!(session.auth_result.is_none());
+                assert!(matches!(
+                    Session::cancel(cancelled.attr_id.clone(), true, &db).await,
+                    Err(Error::Conflict(_))
+                ));
+
+                clean_expired_sessions(&db, DEFAULT_SESSION_LIFETIME, SessionExpiry::Sliding)
+                    .await
+                    .unwrap();
+                let sessions = Session::find_by_room_id(room_id, &db).await.unwrap();
+                assert_eq!(sessions.len(), 1);
+                assert!(matches!(
+                    Session::cancel(cancelled.attr_id, true, &db).await,
+                    Err(Error::NotFound)
+                ));
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_core_session_id() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let s = fixtures::session(guest_token().build()).build();
+                s.persist(&db).await.unwrap();
+                let core_session_id = random_string(32);
+
+                Session::mark_auth_started_with(
+                    s.guest_token.id.clone(),
+                    Some(core_session_id.clone()),
+                    &db,
+                )
+                .await
+                .unwrap();
+                let found = Session::find_by_core_session_id(core_session_id.clone(), &db)
+                    .await
+                    .unwrap();
+                assert_eq!(found.attr_id, s.attr_id);
+                assert_eq!(found.core_session_id, Some(core_session_id));
+                assert_eq!(found.state, SessionState::AuthStarted);
+
+                assert!(matches!(
+                    Session::find_by_core_session_id(random_string(32), &db).await,
+                    Err(Error::NotFound)
+                ));
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_session_state() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let s = fixtures::session(guest_token().build()).build();
+                s.persist(&db).await.unwrap();
+
+                Session::mark_auth_started(s.guest_token.id.clone(), &db)
+                    .await
+                    .unwrap();
+                Session::register_auth_result(
+                    s.attr_id.clone(),
+                    fixtures::auth_result().attribute("age", "42").stored(),
+                    &db,
+                )
+                .await
+                .unwrap();
+
+                let sessions = Session::find_by_ids(&[s.guest_token.id.clone()], &db)
+                    .await
+                    .unwrap();
+                assert_eq!(sessions[0].state, SessionState::AuthCompleted);
+
+                assert!(matches!(
+                    Session::mark_auth_started(s.guest_token.id.clone(), &db).await,
+                    Err(Error::Conflict(_))
+                ));
+                assert!(matches!(
+                    Session::mark_auth_started(SessionId::new("unknown").unwrap(), &db).await,
+                    Err(Error::NotFound)
+                ));
+
+                Session::expire_now(s.guest_token.id.clone(), &db)
+                    .await
+                    .unwrap();
+                assert!(matches!(
+                    Session::expire_now(s.guest_token.id.clone(), &db).await,
+                    Err(Error::Conflict(_))
+                ));
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_persist_with_room_limit() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let room_id = RoomId::new("Room 101 Test").unwrap();
+                let lifetime = Duration::from_secs(3600);
+                let expiry = SessionExpiry::Sliding;
+
+                fixtures::session(guest_token().room_id(room_id.clone()).build())
+                    .build()
+                    .persist_with_room_limit(Some(1), lifetime, expiry, &db)
+                    .await
+                    .unwrap();
+                fixtures::session(guest_token().room_id(room_id).build())
+                    .build()
+                    .persist_with_room_limit(Some(1), lifetime, expiry, &db)
+                    .await
+                    .unwrap();
+
+                assert!(matches!(
+                    fixtures::session(guest_token().build())
+                        .build()
+                        .persist_with_room_limit(Some(1), lifetime, expiry, &db)
+                        .await,
+                    Err(Error::BadRequest(_))
+                ));
+
+                // Rooms of expired sessions awaiting cleanup don't count
+                db.run(|c| {
+                    c.execute(
+                        "UPDATE session SET last_activity = now() - interval '2 hours'",
+                        &[],
+                    )
+                })
+                .await
+                .unwrap();
+                fixtures::session(guest_token().build())
+                    .build()
+                    .persist_with_room_limit(Some(1), lifetime, expiry, &db)
+                    .await
+                    .unwrap();
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_load_for_host_action() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let s = fixtures::session(guest_token().build()).build();
+                s.persist(&db).await.unwrap();
+
+                let host = HostToken {
+                    id: "host".to_owned(),
+                    domain: SessionDomain::User,
+                    room_id: s.guest_token.room_id.clone(),
+                    instance: s.guest_token.instance.clone(),
+                    expires_at: None,
+                };
+                let session = Session::load_for_host_action(s.guest_token.id.clone(), &host, &db)
+                    .await
+                    .unwrap();
+                assert_eq!(session.attr_id, s.attr_id);
+
+                let unknown = SessionId::new(random_string(32)).unwrap();
+                assert!(matches!(
+                    Session::load_for_host_action(unknown, &host, &db).await,
+                    Err(Error::NotFound)
+                ));
+
+                let other_room = HostToken {
+                    room_id: RoomId::new(random_string(32)).unwrap(),
+                    ..host
+                };
+                assert!(matches!(
+                    Session::load_for_host_action(s.guest_token.id.clone(), &other_room, &db).await,
+                    Err(Error::Forbidden(_))
+                ));
+
+                let guest = HostToken {
+                    domain: SessionDomain::Guest,
+                    ..other_room
+                };
+                assert!(matches!(
+                    Session::load_for_host_action(s.guest_token.id, &guest, &db).await,
+                    Err(Error::Forbidden(_))
+                ));
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_reset_auth_result() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let s = fixtures::session(guest_token().build()).build();
+                s.persist(&db).await.unwrap();
+                let host = HostToken {
+                    id: "host".to_owned(),
+                    domain: SessionDomain::User,
+                    room_id: s.guest_token.room_id.clone(),
+                    instance: s.guest_token.instance.clone(),
+                    expires_at: None,
+                };
+
+                assert!(matches!(
+                    Session::reset_auth_result(s.guest_token.id.clone(), &host, &db).await,
+                    Err(Error::Conflict(_))
+                ));
+
+                Session::register_auth_result(
+                    s.attr_id.clone(),
+                    fixtures::auth_result().attribute("age", "42").stored(),
+                    &db,
+                )
+                .await
+                .unwrap();
+                let reset = Session::reset_auth_result(s.guest_token.id.clone(), &host, &db)
+                    .await
+                    .unwrap();
+                assert_eq!(reset.state, SessionState::Created);
+                assert!(reset.auth_result.is_none());
+                assert_ne!(reset.attr_id, s.attr_id);
+                assert!(matches!(
+                    Session::find_by_attr_id(s.attr_id.clone(), &db).await,
+                    Err(Error::NotFound)
+                ));
+
+                let history = Session::auth_result_history(s.guest_token.id.clone(), &db)
+                    .await
+                    .unwrap();
+                assert_eq!(history.len(), 1);
+                assert_eq!(history[0].reset_by.as_deref(), Some("host"));
+
+                Session::register_auth_result(
+                    reset.attr_id,
+                    fixtures::auth_result().attribute("age", "42").stored(),
+                    &db,
+                )
+                .await
+                .unwrap();
+                let other_room = HostToken {
+                    room_id: RoomId::new(random_string(32)).unwrap(),
+                    ..host
+                };
+                assert!(matches!(
+                    Session::reset_auth_result(s.guest_token.id, &other_room, &db).await,
+                    Err(Error::Forbidden(_))
+                ));
+            }
+        });
+    }
 }
@@ -0,0 +1,422 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use deadpool_postgres::{
+    tokio_postgres::{self, NoTls},
+    Manager, ManagerConfig, Pool, Runtime,
+};
+#[cfg(feature = "rocket")]
+use rocket::{
+    fairing::{AdHoc, Fairing},
+    Build, Rocket,
+};
+
+use super::{
+    audit_room_event, auth_result_event, cancel_assignments, clean_sessions_query, creation_order,
+    encode_auth_result, exists_query, find_by_room_id_query, find_by_room_id_readonly_query,
+    find_page_by_room_id_query, find_query, publish_expired, transition_query, AuthResultKeySource,
+    ConnectionOptions, Page, Session, SessionExpiry, SessionState, SessionStore, COUNT_BY_ROOM_ID,
+    INSERT_SESSION, PURGE_AUTH_RESULTS, PURGE_PENDING_SESSIONS, PURGE_ROOM, REGISTER_AUTH_RESULT,
+    TOUCH_SESSION,
+};
+use crate::{
+    audit::AuditEventKind,
+    auth_result::StoredAuthResult,
+    error::Error,
+    events,
+    types::{AttrId, RoomId, SessionId},
+};
+
+/// Asynchronous pool of connections to the session database. Unlike
+/// [`super::SessionDBConn`], queries don't occupy a worker thread while
+/// waiting for the database. Reads the same `[global.databases.session]`
+/// configuration, and is used through the [`SessionStore`] trait.
+///
+/// Only the operations of [`SessionStore`] are supported. Everything else
+/// [`Session`] offers, such as join codes, the room limit, audited writes,
+/// restarting authentication, waiting for results and archiving cleanups,
+/// requires a [`super::SessionDBConn`].
+#[derive(Clone)]
+pub struct AsyncSessionDB {
+    pool: Pool,
+    auth_result_keys: AuthResultKeySource,
+}
+
+impl AsyncSessionDB {
+    /// Create a pool of at most `pool_size` connections to the database at
+    /// `url`, encrypting authentication results with the key from
+    /// `auth_result_keys`. Connections are only established once they are
+    /// needed.
+    pub fn new(
+        url: &str,
+        pool_size: usize,
+        auth_result_keys: AuthResultKeySource,
+    ) -> Result<Self, Error> {
+        AsyncSessionDB::with_options(
+            url,
+            pool_size,
+            &ConnectionOptions::default(),
+            auth_result_keys,
+        )
+    }
+
+    /// Like [`AsyncSessionDB::new`], applying the TLS and timeout settings in
+    /// `options`
+    pub fn with_options(
+        url: &str,
+        pool_size: usize,
+        options: &ConnectionOptions,
+        auth_result_keys: AuthResultKeySource,
+    ) -> Result<Self, Error> {
+        let invalid =
+            |e: &dyn std::fmt::Display| Error::Config(format!("Invalid session database: {}", e));
+        let mut pg_config: tokio_postgres::Config = url.parse().map_err(|e| invalid(&e))?;
+        let mode = options.ssl_mode(pg_config.get_ssl_mode());
+        pg_config.ssl_mode(ConnectionOptions::pg_ssl_mode(mode));
+        if let Some(parameters) = options.options() {
+            pg_config.options(&parameters);
+        }
+        let manager = match options.tls_connector(mode).map_err(|e| invalid(&e))? {
+            Some(tls) => Manager::from_config(pg_config, tls, ManagerConfig::default()),
+            None => Manager::from_config(pg_config, NoTls, ManagerConfig::default()),
+        };
+        let pool = Pool::builder(manager)
+            .max_size(pool_size)
+            .runtime(Runtime::Tokio1)
+            .build()
+            .map_err(|e| invalid(&e))?;
+        Ok(AsyncSessionDB {
+            pool,
+            auth_result_keys,
+        })
+    }
+
+    #[cfg(feature = "rocket")]
+    fn from_rocket(rocket: &Rocket<Build>) -> Result<Self, Error> {
+        let auth_result_keys = AuthResultKeySource::from_rocket(rocket).ok_or_else(|| {
+            Error::Config(
+                "No configuration found, manage it before attaching the session database"
+                    .to_owned(),
+            )
+        })?;
+        let invalid = |e| Error::Config(format!("Invalid session database: {}", e));
+        let config = rocket_sync_db_pools::Config::from("session", rocket).map_err(invalid)?;
+        let options: ConnectionOptions = rocket_sync_db_pools::Config::figment("session", rocket)
+            .extract()
+            .map_err(invalid)?;
+        AsyncSessionDB::with_options(
+            &config.url,
+            config.pool_size as usize,
+            &options,
+            auth_result_keys,
+        )
+    }
+
+    /// Close the pool: waiting requests for a connection fail, and
+    /// connections are closed once returned to the pool
+    pub fn close(&self) {
+        self.pool.close();
+    }
+
+    /// Fairing creating the pool on ignition, and managing it as state
+    #[cfg(feature = "rocket")]
+    pub fn fairing() -> impl Fairing {
+        AdHoc::try_on_ignite("Async session database", |rocket| async {
+            match AsyncSessionDB::from_rocket(&rocket) {
+                Ok(db) => Ok(rocket.manage(db)),
+                Err(e) => {
+                    eprintln!("Could not set up session database: {}", e);
+                    Err(rocket)
+                }
+            }
+        })
+    }
+}
+
+#[async_trait]
+impl SessionStore for AsyncSessionDB {
+    async fn persist(&self, session: &Session) -> Result<(), Error> {
+        let client = self.pool.get().await?;
+        client
+            .execute(
+                INSERT_SESSION,
+                &[
+                    &session.guest_token.id,
+                    &session.guest_token.room_id,
+                    &session.guest_token.domain.to_string(),
+                    &session.guest_token.redirect_url,
+                    &session.guest_token.purpose,
+                    &session.guest_token.name,
+                    &session.guest_token.instance,
+                    &session.attr_id,
+                    &session.auth_result_json(self.auth_result_keys.current().as_deref())?,
+                    &session.join_code,
+                    &session.state.to_string(),
+                    &true,
+                ],
+            )
+            .await
+            .map_err(Session::map_insert_error)?;
+        session.announce_created();
+        Ok(())
+    }
+
+    async fn register_auth_result(
+        &self,
+        attr_id: AttrId,
+        auth_result: StoredAuthResult,
+    ) -> Result<(), Error> {
+        let key = self.auth_result_keys.current();
+        let auth_result = encode_auth_result(key.as_deref(), &auth_result)?;
+        let client = self.pool.get().await?;
+        let statement = client.prepare_cached(REGISTER_AUTH_RESULT).await?;
+        let row = client
+            .query_opt(
+                &statement,
+                &[
+                    &auth_result,
+                    &attr_id,
+                    &SessionState::AuthCompleted.to_string(),
+                    &SessionState::AuthCompleted.predecessor_names(),
+                ],
+            )
+            .await?
+            .ok_or(Error::NotFound)?;
+
+        let event = auth_result_event(&row);
+        audit_room_event(&event, AuditEventKind::AuthResultStored);
+        events::publish(event);
+        Ok(())
+    }
+
+    async fn cancel(&self, attr_id: AttrId, clear_auth_result: bool) -> Result<(), Error> {
+        let client = self.pool.get().await?;
+        let target = SessionState::Cancelled;
+        let n = client
+            .execute(
+                transition_query("attr_id", cancel_assignments(clear_auth_result)).as_str(),
+                &[&target.to_string(), &attr_id, &target.predecessor_names()],
+            )
+            .await?;
+        if n == 1 {
+            return Ok(());
+        }
+
+        let exists: bool = client
+            .query_one(exists_query("attr_id").as_str(), &[&attr_id])
+            .await?
+            .get(0);
+        if exists {
+            Err(Error::Conflict("Session state does not allow this"))
+        } else {
+            Err(Error::NotFound)
+        }
+    }
+
+    async fn find_by_room_id(&self, room_id: RoomId) -> Result<Vec<Session>, Error> {
+        let client = self.pool.get().await?;
+        let statement = client.prepare_cached(&find_by_room_id_query()).await?;
+        let rows = client.query(&statement, &[&room_id]).await?;
+        if rows.is_empty() {
+            return Err(Error::NotFound);
+        }
+        let key = self.auth_result_keys.current();
+        let mut sessions = rows
+            .iter()
+            .map(|row| Session::from_row(row, key.as_deref()))
+            .collect::<Result<Vec<_>, _>>()?;
+        sessions.sort_by(creation_order);
+        Ok(sessions)
+    }
+
+    async fn find_by_room_id_readonly(&self, room_id: RoomId) -> Result<Vec<Session>, Error> {
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare_cached(&find_by_room_id_readonly_query())
+            .await?;
+        let rows = client.query(&statement, &[&room_id]).await?;
+        if rows.is_empty() {
+            return Err(Error::NotFound);
+        }
+        let key = self.auth_result_keys.current();
+        rows.iter()
+            .map(|row| Session::from_row(row, key.as_deref()))
+            .collect()
+    }
+
+    async fn touch(&self, session_id: SessionId) -> Result<(), Error> {
+        let client = self.pool.get().await?;
+        let statement = client.prepare_cached(TOUCH_SESSION).await?;
+        match client.execute(&statement, &[&session_id]).await? {
+            0 => Err(Error::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    async fn find_page_by_room_id(
+        &self,
+        room_id: RoomId,
+        page: Page,
+    ) -> Result<Vec<Session>, Error> {
+        let client = self.pool.get().await?;
+        let statement = client.prepare_cached(&find_page_by_room_id_query()).await?;
+        let rows = client
+            .query(
+                &statement,
+                &[&room_id, &i64::from(page.limit), &i64::from(page.offset)],
+            )
+            .await?;
+        let key = self.auth_result_keys.current();
+        let mut sessions = rows
+            .iter()
+            .map(|row| Session::from_row(row, key.as_deref()))
+            .collect::<Result<Vec<_>, _>>()?;
+        sessions.sort_by(creation_order);
+        Ok(sessions)
+    }
+
+    async fn count_by_room_id(&self, room_id: RoomId) -> Result<u64, Error> {
+        let client = self.pool.get().await?;
+        let statement = client.prepare_cached(COUNT_BY_ROOM_ID).await?;
+        let count: i64 = client.query_one(&statement, &[&room_id]).await?.get(0);
+        Ok(count as u64)
+    }
+
+    async fn find_by_attr_id(&self, attr_id: AttrId) -> Result<Session, Error> {
+        let client = self.pool.get().await?;
+        let statement = client.prepare_cached(&find_query("attr_id")).await?;
+        let row = client
+            .query_opt(&statement, &[&attr_id])
+            .await?
+            .ok_or(Error::NotFound)?;
+        Session::from_row(&row, self.auth_result_keys.current().as_deref())
+    }
+
+    async fn find_by_session_id(&self, session_id: SessionId) -> Result<Session, Error> {
+        let client = self.pool.get().await?;
+        let statement = client.prepare_cached(&find_query("session_id")).await?;
+        let row = client
+            .query_opt(&statement, &[&session_id])
+            .await?
+            .ok_or(Error::NotFound)?;
+        Session::from_row(&row, self.auth_result_keys.current().as_deref())
+    }
+
+    async fn clean(&self, lifetime: Duration, expiry: SessionExpiry) -> Result<(), Error> {
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare_cached(&clean_sessions_query(expiry, false))
+            .await?;
+        let removed = client.query(&statement, &[&lifetime.as_secs_f64()]).await?;
+        publish_expired(&removed);
+        Ok(())
+    }
+
+    async fn purge_auth_results(&self, older_than: Duration) -> Result<u64, Error> {
+        let client = self.pool.get().await?;
+        Ok(client
+            .execute(PURGE_AUTH_RESULTS, &[&older_than.as_secs_f64()])
+            .await?)
+    }
+
+    async fn purge_pending_sessions(&self, older_than: Duration) -> Result<u64, Error> {
+        let client = self.pool.get().await?;
+        let removed = client
+            .query(PURGE_PENDING_SESSIONS, &[&older_than.as_secs_f64()])
+            .await?;
+        publish_expired(&removed);
+        Ok(removed.len() as u64)
+    }
+
+    async fn purge_by_room_id(&self, room_id: RoomId) -> Result<u64, Error> {
+        let client = self.pool.get().await?;
+        let removed = client.query(PURGE_ROOM, &[&room_id]).await?;
+        publish_expired(&removed);
+        Ok(removed.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+    use verder_helpen_proto::{AuthResult, AuthStatus};
+
+    use super::AsyncSessionDB;
+    use crate::{
+        auth_result::StoredAuthResult,
+        prelude::{random_string, GuestToken},
+        session::{
+            AuthResultKeySource, Session, SessionExpiry, SessionStore, DEFAULT_SESSION_LIFETIME,
+        },
+        types::{AttrId, RoomId, SessionDomain, SessionId},
+    };
+
+    #[test]
+    #[serial]
+    fn test_async_session_db() {
+        tokio_test::block_on(async {
+            if let Some(test_db) = option_env!("TEST_DB") {
+                let db = AsyncSessionDB::new(test_db, 2, AuthResultKeySource::Fixed(None)).unwrap();
+                db.pool
+                    .get()
+                    .await
+                    .unwrap()
+                    .batch_execute(include_str!("../../schema.sql"))
+                    .await
+                    .unwrap();
+
+                let session = Session::new(
+                    GuestToken {
+                        purpose: "test".to_owned(),
+                        id: SessionId::new(random_string(32)).unwrap(),
+                        domain: SessionDomain::Guest,
+                        redirect_url: "verderhelpen.nl".to_owned(),
+                        name: "Test Verder Helpen".to_owned(),
+                        room_id: RoomId::new(random_string(32)).unwrap(),
+                        instance: "verderhelpen.nl".to_owned(),
+                    },
+                    AttrId::generate(),
+                );
+                db.persist(&session).await.unwrap();
+                assert!(db.persist(&session).await.is_err());
+
+                db.register_auth_result(
+                    session.attr_id.clone(),
+                    StoredAuthResult::from(AuthResult {
+                        status: AuthStatus::Success,
+                        attributes: None,
+                        session_url: Some("https://example.com".to_owned()),
+                    }),
+                )
+                .await
+                .unwrap();
+                db.clean(DEFAULT_SESSION_LIFETIME, SessionExpiry::Sliding)
+                    .await
+                    .unwrap();
+
+                let sessions = db
+                    .find_by_room_id(session.guest_token.room_id.clone())
+                    .await
+                    .unwrap();
+                assert_eq!(sessions.len(), 1);
+                assert_eq!(
+                    sessions[0]
+                        .auth_result
+                        .as_ref()
+                        .unwrap()
+                        .session_url
+                        .as_deref(),
+                    Some("https://example.com")
+                );
+
+                let found = db.find_by_attr_id(session.attr_id.clone()).await.unwrap();
+                assert_eq!(found.guest_token.id, session.guest_token.id);
+                let found = db
+                    .find_by_session_id(session.guest_token.id.clone())
+                    .await
+                    .unwrap();
+                assert_eq!(found.attr_id, session.attr_id);
+            }
+        });
+    }
+}
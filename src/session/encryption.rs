@@ -0,0 +1,200 @@
+use std::{fmt::Debug, sync::Arc};
+
+use josekit::{
+    jwe::{
+        self,
+        alg::direct::{DirectJweDecrypter, DirectJweEncrypter},
+        Dir, JweHeader,
+    },
+    jwk::Jwk,
+};
+#[cfg(feature = "rocket")]
+use rocket::{Phase, Rocket};
+use serde_json::Value;
+
+use crate::{
+    auth_result::StoredAuthResult,
+    config::{Config, ReloadableConfig},
+    error::Error,
+    jwt::JwtError,
+};
+
+/// Symmetric key encrypting authentication results stored in the session
+/// database, configured through `auth_result_encryption_key`
+pub struct AuthResultKey {
+    encrypter: DirectJweEncrypter,
+    decrypter: DirectJweDecrypter,
+}
+
+impl Debug for AuthResultKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthResultKey").finish()
+    }
+}
+
+impl AuthResultKey {
+    /// Key from a symmetric JWK (`"kty": "oct"`) holding 256 bits, used with
+    /// A256GCM
+    pub fn from_jwk(jwk: &Jwk) -> Result<Self, Error> {
+        let key = AuthResultKey {
+            encrypter: Dir.encrypter_from_jwk(jwk).map_err(JwtError::from)?,
+            decrypter: Dir.decrypter_from_jwk(jwk).map_err(JwtError::from)?,
+        };
+        // Keys of the wrong size are only refused when encrypting, so try
+        // once to report them right away
+        key.encrypt(b"{}")?;
+        Ok(key)
+    }
+
+    fn encrypt(&self, payload: &[u8]) -> Result<String, Error> {
+        let mut header = JweHeader::new();
+        header.set_content_encryption("A256GCM");
+        Ok(jwe::serialize_compact(payload, &header, &self.encrypter).map_err(JwtError::from)?)
+    }
+
+    fn decrypt(&self, jwe: &str) -> Result<Vec<u8>, Error> {
+        let (payload, _) =
+            jwe::deserialize_compact(jwe, &self.decrypter).map_err(JwtError::from)?;
+        Ok(payload)
+    }
+}
+
+/// Where connections to the session database take the configured
+/// `auth_result_encryption_key` from. A reloadable configuration is read on
+/// every use, so that a reloaded key is used right away.
+#[derive(Clone)]
+pub enum AuthResultKeySource {
+    /// A key that never changes, or `None` to store results in plaintext
+    Fixed(Option<Arc<AuthResultKey>>),
+    /// The key of the current configuration
+    Reloadable(ReloadableConfig),
+}
+
+impl AuthResultKeySource {
+    /// The key configured in `config`
+    pub fn from_config(config: &Config) -> Self {
+        AuthResultKeySource::Fixed(config.auth_result_encryption_key().cloned())
+    }
+
+    /// The key of the managed [`ReloadableConfig`] if there is one, and that
+    /// of the managed [`Config`] otherwise. `None` if no configuration is
+    /// managed.
+    #[cfg(feature = "rocket")]
+    pub fn from_rocket<P: Phase>(rocket: &Rocket<P>) -> Option<Self> {
+        match rocket.state::<ReloadableConfig>() {
+            Some(config) => Some(AuthResultKeySource::Reloadable(config.clone())),
+            None => rocket
+                .state::<Config>()
+                .map(AuthResultKeySource::from_config),
+        }
+    }
+
+    /// The key to encrypt and decrypt with now
+    pub fn current(&self) -> Option<Arc<AuthResultKey>> {
+        match self {
+            AuthResultKeySource::Fixed(key) => key.clone(),
+            AuthResultKeySource::Reloadable(config) => {
+                config.current().auth_result_encryption_key().cloned()
+            }
+        }
+    }
+}
+
+impl From<ReloadableConfig> for AuthResultKeySource {
+    fn from(config: ReloadableConfig) -> Self {
+        AuthResultKeySource::Reloadable(config)
+    }
+}
+
+/// Serialize an authentication result for storage as JSONB: as a JSON string
+/// holding a JWE if a key is given, and as a plain JSON object otherwise
+pub(crate) fn encode_auth_result(
+    key: Option<&AuthResultKey>,
+    auth_result: &StoredAuthResult,
+) -> Result<String, Error> {
+    let json = serde_json::to_string(auth_result)?;
+    match key {
+        Some(key) => Ok(serde_json::to_string(&key.encrypt(json.as_bytes())?)?),
+        None => Ok(json),
+    }
+}
+
+/// Whether a stored JWE is an authentication result as received from the core,
+/// as stored before results were kept as JSON, rather than one encrypted with
+/// the storage key
+pub(crate) fn is_legacy_jwe(jwe: &str) -> bool {
+    josekit::jwt::decode_header(jwe)
+        .ok()
+        .and_then(|header| {
+            header
+                .claim("alg")
+                .and_then(Value::as_str)
+                .map(|alg| alg != "dir")
+        })
+        .unwrap_or(false)
+}
+
+/// Deserialize an authentication result stored by [`encode_auth_result`].
+/// Results stored in plaintext, e.g. before a key was configured, are read
+/// as-is.
+pub(crate) fn decode_auth_result(
+    key: Option<&AuthResultKey>,
+    stored: &str,
+) -> Result<StoredAuthResult, Error> {
+    match serde_json::from_str(stored)? {
+        Value::String(jwe) if is_legacy_jwe(&jwe) => Err(Error::InternalServer(
+            "Authentication result was stored before migration 5, convert it with \
+             session::migrate_legacy_auth_results"
+                .to_string(),
+        )),
+        Value::String(jwe) => {
+            let key = key.ok_or_else(|| {
+                Error::Config(
+                    "Authentication result is encrypted, but no auth_result_encryption_key is \
+                     configured"
+                        .to_string(),
+                )
+            })?;
+            Ok(serde_json::from_slice(&key.decrypt(&jwe)?)?)
+        }
+        plaintext => Ok(serde_json::from_value(plaintext)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::SystemTime};
+
+    use josekit::jwk::Jwk;
+    use verder_helpen_proto::AuthStatus;
+
+    use super::{decode_auth_result, encode_auth_result, AuthResultKey};
+    use crate::auth_result::StoredAuthResult;
+
+    #[test]
+    fn test_auth_result_encryption() {
+        let jwk =
+            Jwk::from_bytes(r#"{"kty":"oct","k":"sCd0jw0cUTu4ZCa8pAPo9R2SE7FvAwbNeI7cR0X9DLM"}"#)
+                .unwrap();
+        let key = AuthResultKey::from_jwk(&jwk).unwrap();
+        let auth_result = StoredAuthResult {
+            status: AuthStatus::Success,
+            attributes: Some(HashMap::from([("age".to_string(), "42".to_string())])),
+            session_url: None,
+            received_at: SystemTime::now(),
+        };
+
+        let stored = encode_auth_result(Some(&key), &auth_result).unwrap();
+        assert!(serde_json::from_str::<String>(&stored).is_ok());
+        let decoded = decode_auth_result(Some(&key), &stored).unwrap();
+        assert_eq!(decoded.attribute("age"), Some("42"));
+        assert!(decode_auth_result(None, &stored).is_err());
+
+        let plaintext = encode_auth_result(None, &auth_result).unwrap();
+        let decoded = decode_auth_result(Some(&key), &plaintext).unwrap();
+        assert_eq!(decoded.attribute("age"), Some("42"));
+
+        let short = Jwk::from_bytes(r#"{"kty":"oct","k":"c2hvcnQ"}"#).unwrap();
+        assert!(AuthResultKey::from_jwk(&short).is_err());
+    }
+}
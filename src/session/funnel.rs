@@ -0,0 +1,131 @@
+use std::{ops::Range, time::SystemTime};
+
+use serde::Serialize;
+
+use super::SessionDb;
+use crate::error::Error;
+
+/// Daily counts per purpose of the days starting in the range from `$1` up to
+/// `$2`, ordered by day and purpose
+const FIND_STATS: &str = "
+    SELECT day::timestamp AS day, purpose, created, completed, expired
+    FROM session_stats
+    WHERE day::timestamp >= $1
+    AND day::timestamp < $2
+    ORDER BY day, purpose";
+
+/// How far the sessions for a single purpose got on a single day. Days are
+/// counted in the time zone of the session database.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SessionStats {
+    /// Start of the day
+    pub day: SystemTime,
+    pub purpose: String,
+    /// Sessions created
+    pub created: u64,
+    /// Authentication results registered
+    pub completed: u64,
+    /// Sessions removed by the cleanup without ever completing authentication
+    pub expired: u64,
+}
+
+impl SessionStats {
+    /// Fraction of the created sessions that completed authentication, if any
+    /// were created
+    pub fn conversion(&self) -> Option<f64> {
+        if self.created == 0 {
+            return None;
+        }
+        Some(self.completed as f64 / self.created as f64)
+    }
+}
+
+/// Session funnel counts of the days starting within `range`, ordered by day
+/// and purpose. Days and purposes without any sessions are left out.
+pub async fn stats(
+    range: Range<SystemTime>,
+    db: &impl SessionDb,
+) -> Result<Vec<SessionStats>, Error> {
+    #[cfg(feature = "metrics")]
+    let _timer = crate::metrics::db_query_timer("stats");
+    db.run(move |c| -> Result<Vec<SessionStats>, Error> {
+        Ok(c.query(FIND_STATS, &[&range.start, &range.end])?
+            .iter()
+            .map(|row| SessionStats {
+                day: row.get("day"),
+                purpose: row.get("purpose"),
+                created: row.get::<_, i64>("created") as u64,
+                completed: row.get::<_, i64>("completed") as u64,
+                expired: row.get::<_, i64>("expired") as u64,
+            })
+            .collect())
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use serial_test::serial;
+    use verder_helpen_proto::{AuthResult, AuthStatus};
+
+    use super::stats;
+    use crate::{
+        session::{tests::init_db, Session},
+        types::{AttrId, GuestToken, RoomId, SessionDomain, SessionId},
+        util::random_string,
+    };
+
+    #[test]
+    #[serial]
+    fn test_stats() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let purpose = random_string(16);
+                let session = |attr_id: &AttrId| {
+                    Session::new(
+                        GuestToken {
+                            purpose: purpose.clone(),
+                            id: SessionId::new(random_string(32)).unwrap(),
+                            domain: SessionDomain::Guest,
+                            redirect_url: "verderhelpen.nl".to_owned(),
+                            name: "Test Verder Helpen".to_owned(),
+                            room_id: RoomId::new(random_string(32)).unwrap(),
+                            instance: "verderhelpen.nl".to_owned(),
+                        },
+                        attr_id.clone(),
+                    )
+                };
+                let completed = AttrId::generate();
+                session(&completed).persist(&db).await.unwrap();
+                session(&AttrId::generate()).persist(&db).await.unwrap();
+                Session::register_auth_result(
+                    completed,
+                    AuthResult {
+                        status: AuthStatus::Success,
+                        attributes: None,
+                        session_url: None,
+                    }
+                    .into(),
+                    &db,
+                )
+                .await
+                .unwrap();
+
+                let day = Duration::from_secs(24 * 60 * 60);
+                let now = SystemTime::now();
+                let found = stats(now - day..now + day, &db).await.unwrap();
+                let found = found.iter().find(|stats| stats.purpose == purpose).unwrap();
+                assert_eq!(found.created, 2);
+                assert_eq!(found.completed, 1);
+                assert_eq!(found.conversion(), Some(0.5));
+
+                assert!(stats(now + day..now + day * 2, &db)
+                    .await
+                    .unwrap()
+                    .is_empty());
+            }
+        });
+    }
+}
@@ -0,0 +1,449 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+
+use super::{
+    audit_room_event, creation_order, Page, Session, SessionExpiry, SessionState, SessionStore,
+};
+use crate::{
+    audit::AuditEventKind,
+    auth_result::StoredAuthResult,
+    error::Error,
+    events::{self, RoomEventKind},
+    types::{AttrId, RoomId, SessionId},
+};
+
+/// Session store keeping all sessions in memory, for use in tests and demos.
+/// Sessions are lost when the process exits.
+#[derive(Default)]
+pub struct InMemorySessionStore {
+    sessions: Mutex<Vec<Session>>,
+}
+
+impl InMemorySessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Find the session matching `predicate`, marking it as active
+    fn find_one(&self, predicate: impl Fn(&Session) -> bool) -> Result<Session, Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .iter_mut()
+            .find(|session| predicate(session))
+            .ok_or(Error::NotFound)?;
+        session.last_activity = SystemTime::now();
+        Ok(session.clone())
+    }
+}
+
+#[async_trait]
+impl SessionStore for InMemorySessionStore {
+    async fn persist(&self, session: &Session) -> Result<(), Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.iter().any(|existing| {
+            existing.guest_token.id == session.guest_token.id || existing.attr_id == session.attr_id
+        }) {
+            return Err(Error::BadRequest("A session with that ID already exists"));
+        }
+
+        sessions.push(Session {
+            last_activity: SystemTime::now(),
+            ..session.clone()
+        });
+        let event = session.event(RoomEventKind::SessionCreated);
+        audit_room_event(&event, AuditEventKind::SessionCreated);
+        events::publish(event);
+        Ok(())
+    }
+
+    async fn register_auth_result(
+        &self,
+        attr_id: AttrId,
+        auth_result: StoredAuthResult,
+    ) -> Result<(), Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .iter_mut()
+            .find(|session| {
+                session.attr_id == attr_id
+                    && session.auth_result.is_none()
+                    && session.state.can_transition_to(SessionState::AuthCompleted)
+            })
+            .ok_or(Error::NotFound)?;
+
+        session.auth_result = Some(auth_result);
+        session.state = SessionState::AuthCompleted;
+        session.last_activity = SystemTime::now();
+        let event = session.event(RoomEventKind::AuthResult);
+        audit_room_event(&event, AuditEventKind::AuthResultStored);
+        events::publish(event);
+        Ok(())
+    }
+
+    async fn cancel(&self, attr_id: AttrId, clear_auth_result: bool) -> Result<(), Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .iter_mut()
+            .find(|session| session.attr_id == attr_id)
+            .ok_or(Error::NotFound)?;
+        if !session.state.can_transition_to(SessionState::Cancelled) {
+            return Err(Error::Conflict("Session state does not allow this"));
+        }
+
+        session.state = SessionState::Cancelled;
+        if clear_auth_result {
+            session.auth_result = None;
+        }
+        session.last_activity = SystemTime::now();
+        Ok(())
+    }
+
+    async fn find_by_room_id(&self, room_id: RoomId) -> Result<Vec<Session>, Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = SystemTime::now();
+        let mut found: Vec<Session> = sessions
+            .iter_mut()
+            .filter(|session| session.guest_token.room_id == room_id)
+            .map(|session| {
+                session.last_activity = now;
+                session.clone()
+            })
+            .collect();
+
+        if found.is_empty() {
+            return Err(Error::NotFound);
+        }
+        found.sort_by(creation_order);
+        Ok(found)
+    }
+
+    async fn find_by_room_id_readonly(&self, room_id: RoomId) -> Result<Vec<Session>, Error> {
+        let sessions = self.sessions.lock().unwrap();
+        let mut found: Vec<Session> = sessions
+            .iter()
+            .filter(|session| session.guest_token.room_id == room_id)
+            .cloned()
+            .collect();
+
+        if found.is_empty() {
+            return Err(Error::NotFound);
+        }
+        found.sort_by(creation_order);
+        Ok(found)
+    }
+
+    async fn touch(&self, session_id: SessionId) -> Result<(), Error> {
+        self.find_one(|session| session.guest_token.id == session_id)
+            .map(|_| ())
+    }
+
+    async fn find_page_by_room_id(
+        &self,
+        room_id: RoomId,
+        page: Page,
+    ) -> Result<Vec<Session>, Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut in_room: Vec<&mut Session> = sessions
+            .iter_mut()
+            .filter(|session| session.guest_token.room_id == room_id)
+            .collect();
+        in_room.sort_by(|a, b| creation_order(a, b));
+
+        let now = SystemTime::now();
+        Ok(in_room
+            .into_iter()
+            .skip(page.offset as usize)
+            .take(page.limit as usize)
+            .map(|session| {
+                session.last_activity = now;
+                session.clone()
+            })
+            .collect())
+    }
+
+    async fn count_by_room_id(&self, room_id: RoomId) -> Result<u64, Error> {
+        let sessions = self.sessions.lock().unwrap();
+        Ok(sessions
+            .iter()
+            .filter(|session| session.guest_token.room_id == room_id)
+            .count() as u64)
+    }
+
+    async fn find_by_attr_id(&self, attr_id: AttrId) -> Result<Session, Error> {
+        self.find_one(|session| session.attr_id == attr_id)
+    }
+
+    async fn find_by_session_id(&self, session_id: SessionId) -> Result<Session, Error> {
+        self.find_one(|session| session.guest_token.id == session_id)
+    }
+
+    async fn clean(&self, lifetime: Duration, expiry: SessionExpiry) -> Result<(), Error> {
+        let now = SystemTime::now();
+        self.sessions.lock().unwrap().retain(|session| {
+            if session.state == SessionState::Cancelled {
+                return false;
+            }
+            if session.state == SessionState::Expired || session.expires_at(lifetime, expiry) <= now
+            {
+                events::publish(session.event(RoomEventKind::SessionExpired));
+                return false;
+            }
+            true
+        });
+        Ok(())
+    }
+
+    async fn purge_auth_results(&self, older_than: Duration) -> Result<u64, Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut purged = 0;
+        for session in sessions.iter_mut() {
+            let registered_at = match &session.auth_result {
+                Some(auth_result) => auth_result.received_at,
+                None => continue,
+            };
+            if registered_at.elapsed().is_ok_and(|age| age >= older_than) {
+                session.auth_result = None;
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    async fn purge_pending_sessions(&self, older_than: Duration) -> Result<u64, Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|session| {
+            if session.auth_result.is_none() && session.is_expired(older_than) {
+                events::publish(session.event(RoomEventKind::SessionExpired));
+                return false;
+            }
+            true
+        });
+        Ok((before - sessions.len()) as u64)
+    }
+
+    async fn purge_by_room_id(&self, room_id: RoomId) -> Result<u64, Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|session| {
+            if session.guest_token.room_id == room_id {
+                events::publish(session.event(RoomEventKind::SessionExpired));
+                return false;
+            }
+            true
+        });
+        Ok((before - sessions.len()) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::InMemorySessionStore;
+    use crate::{
+        error::Error,
+        session::{
+            Page, RetentionPolicy, SessionExpiry, SessionState, SessionStore,
+            DEFAULT_SESSION_LIFETIME,
+        },
+        test_support::fixtures::{self, guest_token},
+        types::{AttrId, RoomId, SessionId},
+    };
+
+    fn room(room_id: &str) -> RoomId {
+        RoomId::new(room_id).unwrap()
+    }
+
+    #[test]
+    fn test_in_memory_store() {
+        tokio_test::block_on(async {
+            let store = InMemorySessionStore::new();
+            let s = fixtures::session(guest_token().room_id(room("room")).build()).build();
+            store.persist(&s).await.unwrap();
+            store
+                .persist(&fixtures::session(guest_token().room_id(room("room")).build()).build())
+                .await
+                .unwrap();
+            assert!(store.persist(&s).await.is_err());
+
+            store
+                .register_auth_result(
+                    s.attr_id.clone(),
+                    fixtures::auth_result().session_url("first").stored(),
+                )
+                .await
+                .unwrap();
+            assert!(matches!(
+                store
+                    .register_auth_result(
+                        s.attr_id.clone(),
+                        fixtures::auth_result().session_url("again").stored()
+                    )
+                    .await,
+                Err(Error::NotFound)
+            ));
+
+            let sessions = store.find_by_room_id(room("room")).await.unwrap();
+            assert_eq!(sessions.len(), 2);
+            let found = store.find_by_attr_id(s.attr_id.clone()).await.unwrap();
+            assert_eq!(found.guest_token.id, s.guest_token.id);
+            let found = store
+                .find_by_session_id(s.guest_token.id.clone())
+                .await
+                .unwrap();
+            assert_eq!(found.attr_id, s.attr_id);
+            assert!(matches!(
+                store.find_by_attr_id(AttrId::generate()).await,
+                Err(Error::NotFound)
+            ));
+
+            assert_eq!(store.count_by_room_id(room("room")).await.unwrap(), 2);
+            let readonly = store.find_by_room_id_readonly(room("room")).await.unwrap();
+            assert_eq!(readonly.len(), 2);
+            store.touch(s.guest_token.id.clone()).await.unwrap();
+            assert!(matches!(
+                store.touch(SessionId::new("unknown").unwrap()).await,
+                Err(Error::NotFound)
+            ));
+            let first = Page {
+                limit: 1,
+                offset: 0,
+            };
+            let page = store
+                .find_page_by_room_id(room("room"), first)
+                .await
+                .unwrap();
+            assert_eq!(page.len(), 1);
+            assert_eq!(page[0].attr_id, sessions[0].attr_id);
+            let beyond = Page {
+                limit: 1,
+                offset: 2,
+            };
+            let page = store
+                .find_page_by_room_id(room("room"), beyond)
+                .await
+                .unwrap();
+            assert!(page.is_empty());
+            assert!(sessions.iter().any(|session| {
+                session
+                    .auth_result
+                    .as_ref()
+                    .and_then(|auth_result| auth_result.session_url.as_deref())
+                    == Some("first")
+            }));
+
+            store
+                .clean(DEFAULT_SESSION_LIFETIME, SessionExpiry::Sliding)
+                .await
+                .unwrap();
+            assert_eq!(store.find_by_room_id(room("room")).await.unwrap().len(), 2);
+            assert_eq!(
+                store
+                    .purge_auth_results(DEFAULT_SESSION_LIFETIME)
+                    .await
+                    .unwrap(),
+                0
+            );
+
+            store.cancel(s.attr_id.clone(), true).await.unwrap();
+            assert!(matches!(
+                store.cancel(s.attr_id.clone(), true).await,
+                Err(Error::Conflict(_))
+            ));
+            store
+                .clean(DEFAULT_SESSION_LIFETIME, SessionExpiry::Sliding)
+                .await
+                .unwrap();
+            assert_eq!(store.find_by_room_id(room("room")).await.unwrap().len(), 1);
+
+            store
+                .clean(Duration::from_secs(0), SessionExpiry::Absolute)
+                .await
+                .unwrap();
+            assert!(matches!(
+                store.find_by_room_id(room("room")).await,
+                Err(Error::NotFound)
+            ));
+        });
+    }
+
+    #[test]
+    fn test_in_memory_clean_expired_state() {
+        tokio_test::block_on(async {
+            let store = InMemorySessionStore::new();
+            store
+                .persist(
+                    &fixtures::session(guest_token().room_id(room("room")).build())
+                        .state(SessionState::Expired)
+                        .build(),
+                )
+                .await
+                .unwrap();
+            store
+                .persist(&fixtures::session(guest_token().room_id(room("room")).build()).build())
+                .await
+                .unwrap();
+
+            store
+                .clean(DEFAULT_SESSION_LIFETIME, SessionExpiry::Absolute)
+                .await
+                .unwrap();
+            let sessions = store.find_by_room_id(room("room")).await.unwrap();
+            assert_eq!(sessions.len(), 1);
+            assert_eq!(sessions[0].state, SessionState::Created);
+        });
+    }
+
+    #[test]
+    fn test_in_memory_retention() {
+        tokio_test::block_on(async {
+            let store = InMemorySessionStore::new();
+            let completed = fixtures::session(guest_token().room_id(room("room")).build()).build();
+            store.persist(&completed).await.unwrap();
+            store
+                .persist(&fixtures::session(guest_token().room_id(room("room")).build()).build())
+                .await
+                .unwrap();
+            store
+                .persist(&fixtures::session(guest_token().room_id(room("other")).build()).build())
+                .await
+                .unwrap();
+            store
+                .register_auth_result(
+                    completed.attr_id.clone(),
+                    fixtures::auth_result().session_url("first").stored(),
+                )
+                .await
+                .unwrap();
+
+            let retention = RetentionPolicy {
+                pending_session_lifetime: Some(Duration::from_secs(0)),
+                auth_result_lifetime: None,
+                archive: None,
+            };
+            store.apply_retention(&retention).await.unwrap();
+            let remaining = store.find_by_room_id(room("room")).await.unwrap();
+            assert_eq!(remaining.len(), 1);
+            assert!(remaining[0].auth_result.is_some());
+
+            let purged = store
+                .purge_auth_results(Duration::from_secs(0))
+                .await
+                .unwrap();
+            assert_eq!(purged, 1);
+            let found = store.find_by_attr_id(completed.attr_id).await.unwrap();
+            assert!(found.auth_result.is_none());
+
+            assert_eq!(store.purge_by_room_id(room("room")).await.unwrap(), 1);
+            assert!(matches!(
+                store.find_by_room_id(room("room")).await,
+                Err(Error::NotFound)
+            ));
+        });
+    }
+}
@@ -0,0 +1,400 @@
+use std::time::SystemTime;
+
+use super::{
+    encryption::{encode_auth_result, is_legacy_jwe},
+    SessionDb,
+};
+use crate::{
+    auth_result::{decrypt_stored, StoredAuthResult},
+    config::Config,
+    error::{DatabaseError, Error},
+};
+
+/// Versioned schema migrations for the session database, in the order in
+/// which they must be applied. `schema.sql` contains the resulting schema, and
+/// records the migrations up to the latest as applied.
+const MIGRATIONS: &[(i32, &str)] = &[
+    (1, include_str!("../../migrations/0001_create_session.sql")),
+    (2, include_str!("../../migrations/0002_add_join_code.sql")),
+    (
+        3,
+        include_str!("../../migrations/0003_create_session_audit.sql"),
+    ),
+    (
+        4,
+        include_str!("../../migrations/0004_add_session_state.sql"),
+    ),
+    (
+        5,
+        include_str!("../../migrations/0005_store_auth_result_as_jsonb.sql"),
+    ),
+    (
+        6,
+        include_str!("../../migrations/0006_add_session_created_at.sql"),
+    ),
+    (
+        7,
+        include_str!("../../migrations/0007_add_auth_result_at.sql"),
+    ),
+    (
+        8,
+        include_str!("../../migrations/0008_create_audit_log.sql"),
+    ),
+    (
+        9,
+        include_str!("../../migrations/0009_create_rate_limit.sql"),
+    ),
+    (
+        10,
+        include_str!("../../migrations/0010_create_used_token.sql"),
+    ),
+    (
+        11,
+        include_str!("../../migrations/0011_index_session_activity.sql"),
+    ),
+    (
+        12,
+        include_str!("../../migrations/0012_add_core_session_id.sql"),
+    ),
+    (
+        13,
+        include_str!("../../migrations/0013_create_auth_result_history.sql"),
+    ),
+    (
+        14,
+        include_str!("../../migrations/0014_create_session_stats.sql"),
+    ),
+    (
+        15,
+        include_str!("../../migrations/0015_create_session_archive.sql"),
+    ),
+    (
+        16,
+        include_str!("../../migrations/0016_add_session_committed.sql"),
+    ),
+];
+
+/// Columns of the session table the session queries rely on
+const SESSION_COLUMNS: &[&str] = &[
+    "id",
+    "session_id",
+    "room_id",
+    "domain",
+    "redirect_url",
+    "purpose",
+    "name",
+    "instance",
+    "attr_id",
+    "auth_result",
+    "auth_result_at",
+    "join_code",
+    "join_code_used",
+    "state",
+    "last_activity",
+    "created_at",
+    "core_session_id",
+    "committed",
+];
+
+/// Columns of the session table lookups and cleanups filter on, each of which
+/// must be the leading column of an index
+const SESSION_INDEXES: &[&str] = &[
+    "session_id",
+    "attr_id",
+    "room_id",
+    "join_code",
+    "last_activity",
+    "created_at",
+    "core_session_id",
+];
+
+/// Tables besides the session table that session queries write to
+const SESSION_TABLES: &[&str] = &["auth_result_history", "session_stats", "session_archive"];
+
+/// Leading columns of the indexes on the session table
+const INDEXED_COLUMNS: &str = "
+    SELECT a.attname::text
+    FROM pg_index i
+    JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = i.indkey[0]
+    WHERE i.indrelid = 'session'::regclass";
+
+/// Bring the session database schema up to date, returning the number of
+/// migrations that were applied. Applied migrations are tracked in the
+/// `schema_migrations` table, so this can safely be run on every startup.
+/// Concurrent runs are serialized by locking that table.
+pub async fn run_migrations(db: &impl SessionDb) -> Result<usize, Error> {
+    db.run(|c| -> Result<usize, Error> {
+        c.batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                version integer NOT NULL,
+                applied_at timestamp NOT NULL,
+                PRIMARY KEY (version)
+            );",
+        )?;
+
+        let mut transaction = c.transaction()?;
+        transaction.batch_execute("LOCK TABLE schema_migrations IN EXCLUSIVE MODE;")?;
+        let current: i32 = transaction
+            .query_one(
+                "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+                &[],
+            )?
+            .get(0);
+
+        let pending: Vec<_> = MIGRATIONS
+            .iter()
+            .filter(|(version, _)| *version > current)
+            .collect();
+        for (version, migration) in &pending {
+            transaction.batch_execute(migration)?;
+            transaction.execute(
+                "INSERT INTO schema_migrations (version, applied_at) VALUES ($1, now())",
+                &[version],
+            )?;
+        }
+
+        transaction.commit()?;
+        Ok(pending.len())
+    })
+    .await
+}
+
+/// Convert the authentication results stored before migration 5, the JWEs as
+/// received from the core, to the current format, returning the number of
+/// results converted. The JWEs are decrypted and verified with the keys in
+/// `config`, accepting expired results, so run this while the decryption keys
+/// of the time are still configured. Results that can't be decrypted are
+/// reported and left in place.
+pub async fn migrate_legacy_auth_results(
+    config: &Config,
+    db: &impl SessionDb,
+) -> Result<u64, Error> {
+    let stored = db
+        .run(|c| {
+            c.query(
+                "SELECT id, auth_result #>> '{}' AS jwe,
+                    COALESCE(auth_result_at, last_activity) AS received_at
+                FROM session
+                WHERE jsonb_typeof(auth_result) = 'string'",
+                &[],
+            )
+        })
+        .await?;
+
+    let mut converted = vec![];
+    for row in stored {
+        let jwe: String = row.get("jwe");
+        if !is_legacy_jwe(&jwe) {
+            continue;
+        }
+        match decrypt_stored(&jwe, config) {
+            Ok(auth_result) => {
+                let auth_result = StoredAuthResult {
+                    received_at: row.get::<_, SystemTime>("received_at"),
+                    ..auth_result.into()
+                };
+                converted.push((row.get::<_, i32>("id"), auth_result));
+            }
+            Err(e) => eprintln!("Could not convert stored authentication result: {}", e),
+        }
+    }
+
+    let count = converted.len() as u64;
+    db.run(move |c| -> Result<(), Error> {
+        let key = c.auth_result_key();
+        let mut transaction = c.transaction()?;
+        for (id, auth_result) in &converted {
+            transaction.execute(
+                "UPDATE session SET auth_result = $2::text::jsonb WHERE id = $1",
+                &[id, &encode_auth_result(key.as_deref(), auth_result)?],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    })
+    .await?;
+    Ok(count)
+}
+
+/// Check that the session table has all columns and indexes this version
+/// relies on, so that an outdated schema is found on startup rather than by
+/// failing requests. Fails with `DatabaseError::Schema` naming everything
+/// that is missing; run [`run_migrations`] to bring the schema up to date.
+pub async fn ensure_schema(db: &impl SessionDb) -> Result<(), Error> {
+    let (columns, indexed, missing_tables) = db
+        .run(
+            |c| -> Result<(Vec<String>, Vec<String>, Vec<String>), Error> {
+                let table_exists: bool = c
+                    .query_one("SELECT to_regclass('session') IS NOT NULL", &[])?
+                    .get(0);
+                if !table_exists {
+                    return Err(DatabaseError::Schema("no session table".to_owned()).into());
+                }
+
+                let columns = c
+                    .query(
+                        "SELECT column_name::text
+                    FROM information_schema.columns
+                    WHERE table_name = 'session'
+                    AND table_schema = current_schema()",
+                        &[],
+                    )?
+                    .iter()
+                    .map(|row| row.get(0))
+                    .collect();
+                let indexed = c
+                    .query(INDEXED_COLUMNS, &[])?
+                    .iter()
+                    .map(|row| row.get(0))
+                    .collect();
+                let missing_tables = c
+                    .query(
+                        "SELECT name FROM unnest($1::text[]) AS name WHERE to_regclass(name) IS \
+                         NULL",
+                        &[&SESSION_TABLES],
+                    )?
+                    .iter()
+                    .map(|row| row.get(0))
+                    .collect();
+                Ok((columns, indexed, missing_tables))
+            },
+        )
+        .await?;
+
+    let mut missing: Vec<String> = SESSION_COLUMNS
+        .iter()
+        .filter(|column| !columns.iter().any(|found| found == *column))
+        .map(|column| format!("column session.{}", column))
+        .collect();
+    missing.extend(
+        SESSION_INDEXES
+            .iter()
+            .filter(|column| !indexed.iter().any(|found| found == *column))
+            .map(|column| format!("index on session.{}", column)),
+    );
+    missing.extend(
+        missing_tables
+            .iter()
+            .map(|table| format!("table {}", table)),
+    );
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(DatabaseError::Schema(format!("missing {}", missing.join(", "))).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+
+    use super::{ensure_schema, run_migrations, MIGRATIONS};
+    use crate::{
+        error::{DatabaseError, Error},
+        session::{tests::init_db, Session, SessionDb},
+        types::{AttrId, RoomId, SessionId},
+    };
+
+    #[test]
+    #[serial]
+    fn test_run_migrations() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                db.run(|c| {
+                    c.batch_execute(
+                        "DROP TABLE IF EXISTS auth_result_history;
+                        DROP TABLE IF EXISTS session_stats;
+                        DROP TABLE IF EXISTS session_archive;
+                        DROP TABLE IF EXISTS session;
+                        DROP TABLE IF EXISTS session_audit;
+                        DROP TABLE IF EXISTS audit_log;
+                        DROP TABLE IF EXISTS rate_limit;
+                        DROP TABLE IF EXISTS used_token;
+                        DROP TABLE IF EXISTS schema_migrations;",
+                    )
+                })
+                .await
+                .unwrap();
+                assert!(matches!(
+                    ensure_schema(&db).await,
+                    Err(Error::Database(DatabaseError::Schema(_)))
+                ));
+
+                assert_eq!(run_migrations(&db).await.unwrap(), MIGRATIONS.len());
+                assert_eq!(run_migrations(&db).await.unwrap(), 0);
+                ensure_schema(&db).await.unwrap();
+
+                // The migrated schema supports the full session API
+                let s = Session::new(
+                    crate::types::GuestToken {
+                        purpose: "test".to_owned(),
+                        id: SessionId::new("migrated").unwrap(),
+                        domain: crate::types::SessionDomain::Guest,
+                        redirect_url: "verderhelpen.nl".to_owned(),
+                        name: "Test Verder Helpen".to_owned(),
+                        room_id: RoomId::new("migrated room").unwrap(),
+                        instance: "verderhelpen.nl".to_owned(),
+                    },
+                    AttrId::generate(),
+                )
+                .with_join_code();
+                s.persist_with_audit("test".to_owned(), &db).await.unwrap();
+                let joined = Session::consume_join_code(s.join_code.unwrap(), &db)
+                    .await
+                    .unwrap();
+                assert_eq!(joined.guest_token.id, "migrated");
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_run_migrations_after_schema() {
+        tokio_test::block_on(async {
+            // The database is set up from schema.sql, which must record every
+            // migration as applied
+            if let Some(db) = init_db().await {
+                assert_eq!(run_migrations(&db).await.unwrap(), 0);
+                ensure_schema(&db).await.unwrap();
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(feature = "auth_during_comm")]
+    fn test_migrate_legacy_auth_results() {
+        use super::migrate_legacy_auth_results;
+        use crate::test_support::fixtures::{auth_result, guest_token, session, TestKeys};
+
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let keys = TestKeys::generate();
+                let s = session(guest_token().build()).build();
+                s.persist(&db).await.unwrap();
+
+                // Results used to be stored as the JWE received from the core
+                let jwe = keys.auth_result_jwe(&auth_result().attribute("age", "42").build());
+                let attr_id = s.attr_id.clone();
+                db.run(move |c| {
+                    c.execute(
+                        "UPDATE session SET auth_result = to_jsonb($2::text) WHERE attr_id = $1",
+                        &[&attr_id, &jwe],
+                    )
+                })
+                .await
+                .unwrap();
+                assert!(Session::find_by_attr_id(s.attr_id.clone(), &db)
+                    .await
+                    .is_err());
+
+                let config = keys.config();
+                assert_eq!(migrate_legacy_auth_results(&config, &db).await.unwrap(), 1);
+                let migrated = Session::find_by_attr_id(s.attr_id, &db).await.unwrap();
+                assert_eq!(migrated.auth_result.unwrap().attribute("age"), Some("42"));
+                assert_eq!(migrate_legacy_auth_results(&config, &db).await.unwrap(), 0);
+            }
+        });
+    }
+}
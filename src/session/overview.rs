@@ -0,0 +1,241 @@
+use std::{collections::HashMap, time::SystemTime};
+
+use serde::Serialize;
+
+use super::{creation_order, Session, SessionDb, SessionState};
+use crate::{
+    auth_result::StoredAuthResult,
+    error::Error,
+    types::{RoomId, SessionId},
+};
+
+/// A guest in a room, identified by the name and platform instance in their
+/// guest tokens
+type GuestKey = (String, String);
+
+fn guest_key(session: &Session) -> GuestKey {
+    (
+        session.guest_token.name.clone(),
+        session.guest_token.instance.clone(),
+    )
+}
+
+/// Group `sessions` per guest. Groups are in order of the first join of their
+/// guest, and the sessions within a group in order of creation.
+pub fn group_by_guest(mut sessions: Vec<Session>) -> Vec<Vec<Session>> {
+    sessions.sort_by(creation_order);
+    let mut groups: Vec<Vec<Session>> = Vec::new();
+    let mut index: HashMap<GuestKey, usize> = HashMap::new();
+    for session in sessions {
+        match index.get(&guest_key(&session)) {
+            Some(&i) => groups[i].push(session),
+            None => {
+                index.insert(guest_key(&session), groups.len());
+                groups.push(vec![session]);
+            }
+        }
+    }
+    groups
+}
+
+/// Index of the session that represents a guest who joined several times: the
+/// latest session holding an authentication result, or the latest session if
+/// none does. `sessions` must be in order of creation.
+fn current_index(sessions: &[Session]) -> usize {
+    sessions
+        .iter()
+        .rposition(|session| session.auth_result.is_some())
+        .unwrap_or(sessions.len() - 1)
+}
+
+/// Keep a single session per guest, dropping the sessions of repeated joins,
+/// see [`GuestOverview::current_session_id`]. The result is in order of the
+/// first join of the guests.
+pub fn dedup_joins(sessions: Vec<Session>) -> Vec<Session> {
+    group_by_guest(sessions)
+        .into_iter()
+        .map(|mut group| {
+            let current = current_index(&group);
+            group.swap_remove(current)
+        })
+        .collect()
+}
+
+/// Everything a host needs to know about a single guest in a room, combining
+/// all times the guest joined
+#[derive(Debug, Clone, Serialize)]
+pub struct GuestOverview {
+    pub name: String,
+    pub instance: String,
+    pub purpose: String,
+    /// Session representing the guest: the latest one holding an
+    /// authentication result, or the latest one if none does
+    pub current_session_id: SessionId,
+    /// State of the current session
+    pub state: SessionState,
+    /// Authentication result of the current session, if any
+    pub auth_result: Option<StoredAuthResult>,
+    /// IDs of all sessions of the guest, in order of creation
+    pub session_ids: Vec<SessionId>,
+    /// Time at which the guest first joined the room
+    pub first_joined_at: SystemTime,
+    /// Time at which the guest last joined the room
+    pub last_joined_at: SystemTime,
+}
+
+impl GuestOverview {
+    /// Overview of the sessions of a single guest, in order of creation
+    fn from_sessions(mut sessions: Vec<Session>) -> Self {
+        let session_ids = sessions
+            .iter()
+            .map(|session| session.guest_token.id.clone())
+            .collect();
+        let first_joined_at = sessions[0].created_at;
+        let last_joined_at = sessions[sessions.len() - 1].created_at;
+        let current = sessions.swap_remove(current_index(&sessions));
+        GuestOverview {
+            name: current.guest_token.name,
+            instance: current.guest_token.instance,
+            purpose: current.guest_token.purpose,
+            current_session_id: current.guest_token.id,
+            state: current.state,
+            auth_result: current.auth_result,
+            session_ids,
+            first_joined_at,
+            last_joined_at,
+        }
+    }
+
+    /// Number of times the guest joined the room
+    pub fn joins(&self) -> usize {
+        self.session_ids.len()
+    }
+}
+
+/// The guests in a room, for host interfaces to render directly. Maps both
+/// ways between guests and their sessions.
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomOverview {
+    pub room_id: RoomId,
+    /// Guests in order of their first join
+    pub guests: Vec<GuestOverview>,
+    #[serde(skip)]
+    by_session: HashMap<SessionId, usize>,
+}
+
+impl RoomOverview {
+    /// Overview of the guests with sessions in `room_id`, ignoring sessions
+    /// in other rooms
+    pub fn new(room_id: RoomId, sessions: Vec<Session>) -> Self {
+        let sessions = sessions
+            .into_iter()
+            .filter(|session| session.guest_token.room_id == room_id)
+            .collect();
+        let guests: Vec<GuestOverview> = group_by_guest(sessions)
+            .into_iter()
+            .map(GuestOverview::from_sessions)
+            .collect();
+        let by_session = guests
+            .iter()
+            .enumerate()
+            .flat_map(|(i, guest)| guest.session_ids.iter().map(move |id| (id.clone(), i)))
+            .collect();
+
+        RoomOverview {
+            room_id,
+            guests,
+            by_session,
+        }
+    }
+
+    /// Overview of the room `room_id`, without marking its sessions as active.
+    /// Fails with `Error::NotFound` if the room has no sessions.
+    pub async fn find(room_id: RoomId, db: &impl SessionDb) -> Result<Self, Error> {
+        let sessions = Session::find_by_room_id_readonly(room_id.clone(), db).await?;
+        Ok(RoomOverview::new(room_id, sessions))
+    }
+
+    /// The guest with `name` from platform instance `instance`
+    pub fn guest(&self, name: &str, instance: &str) -> Option<&GuestOverview> {
+        self.guests
+            .iter()
+            .find(|guest| guest.name == name && guest.instance == instance)
+    }
+
+    /// The guest any of whose sessions has ID `session_id`
+    pub fn guest_for_session(&self, session_id: &SessionId) -> Option<&GuestOverview> {
+        self.by_session.get(session_id).map(|&i| &self.guests[i])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use verder_helpen_proto::{AuthResult, AuthStatus};
+
+    use super::{dedup_joins, RoomOverview};
+    use crate::{
+        auth_result::StoredAuthResult,
+        session::Session,
+        types::{AttrId, GuestToken, RoomId, SessionDomain, SessionId},
+    };
+
+    fn join(room_id: &RoomId, id: &str, name: &str, after: u64) -> Session {
+        let mut session = Session::new(
+            GuestToken {
+                purpose: "test".to_owned(),
+                id: SessionId::new(id).unwrap(),
+                domain: SessionDomain::Guest,
+                redirect_url: "https://example.com".to_owned(),
+                name: name.to_owned(),
+                room_id: room_id.clone(),
+                instance: "verderhelpen.nl".to_owned(),
+            },
+            AttrId::generate(),
+        );
+        session.created_at = SystemTime::UNIX_EPOCH + Duration::from_secs(after);
+        session
+    }
+
+    #[test]
+    fn test_room_overview() {
+        let room_id = RoomId::new("16").unwrap();
+        let mut authenticated = join(&room_id, "alice-1", "Alice", 1);
+        authenticated.auth_result = Some(StoredAuthResult::from(AuthResult {
+            status: AuthStatus::Success,
+            attributes: None,
+            session_url: None,
+        }));
+        let sessions = vec![
+            join(&room_id, "bob-1", "Bob", 2),
+            join(&room_id, "alice-2", "Alice", 3),
+            authenticated,
+            join(&RoomId::new("17").unwrap(), "carol-1", "Carol", 4),
+        ];
+
+        let overview = RoomOverview::new(room_id, sessions.clone());
+        assert_eq!(overview.guests.len(), 2);
+        let alice = &overview.guests[0];
+        assert_eq!(alice.name, "Alice");
+        assert_eq!(alice.joins(), 2);
+        assert_eq!(alice.current_session_id, "alice-1");
+        assert!(alice.auth_result.is_some());
+        assert_eq!(
+            overview
+                .guest_for_session(&SessionId::new("alice-2").unwrap())
+                .map(|guest| guest.name.as_str()),
+            Some("Alice")
+        );
+        assert!(overview.guest("Bob", "verderhelpen.nl").is_some());
+        assert!(overview
+            .guest_for_session(&SessionId::new("carol-1").unwrap())
+            .is_none());
+
+        let deduplicated: Vec<String> = dedup_joins(sessions)
+            .into_iter()
+            .map(|session| session.guest_token.id.to_string())
+            .collect();
+        assert_eq!(deduplicated, ["alice-1", "bob-1", "carol-1"]);
+    }
+}
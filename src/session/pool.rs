@@ -0,0 +1,477 @@
+use std::{
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use native_tls::{Certificate, TlsConnector};
+use postgres::{config::SslMode as PgSslMode, NoTls, Statement};
+use postgres_native_tls::MakeTlsConnector;
+use r2d2::ManageConnection;
+use r2d2_postgres::PostgresConnectionManager;
+#[cfg(feature = "rocket")]
+use rocket::{figment, Build, Rocket};
+#[cfg(feature = "rocket")]
+use rocket_sync_db_pools::{Config, PoolResult, Poolable};
+use serde::Deserialize;
+
+use super::encryption::{AuthResultKey, AuthResultKeySource};
+use crate::error::{DatabaseError, Error};
+
+/// Connection recycling settings, read from the same table as the other
+/// database settings (e.g. `[global.databases.session]`)
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct RecyclePolicy {
+    /// Maximum age of a connection in seconds, after which it is closed
+    max_lifetime: u64,
+    /// Time in seconds after which an idle connection is closed
+    idle_timeout: u64,
+    /// Whether to check that a connection is still alive before handing it
+    /// out
+    test_on_checkout: bool,
+    /// Number of times to retry establishing a connection before giving up
+    reconnect_attempts: u32,
+    /// Time in milliseconds before the first retry, doubling after every
+    /// retry
+    reconnect_backoff_ms: u64,
+}
+
+impl Default for RecyclePolicy {
+    fn default() -> Self {
+        RecyclePolicy {
+            max_lifetime: 30 * 60,
+            idle_timeout: 10 * 60,
+            test_on_checkout: true,
+            reconnect_attempts: 5,
+            reconnect_backoff_ms: 100,
+        }
+    }
+}
+
+/// Maximum time between two attempts to establish a connection
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Time to wait for a connection from a [`SessionPool`], as Rocket does by
+/// default
+const DEFAULT_CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl RecyclePolicy {
+    /// Time to wait before retry `retry`, counting from 0
+    fn reconnect_backoff(&self, retry: u32) -> Duration {
+        Duration::from_millis(self.reconnect_backoff_ms)
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(MAX_RECONNECT_BACKOFF)
+    }
+
+    /// Builder for a pool of at most `pool_size` connections recycled by this
+    /// policy, waiting at most `timeout` for a connection
+    fn pool_builder(
+        &self,
+        pool_size: u32,
+        timeout: Duration,
+    ) -> r2d2::Builder<SessionConnectionManager> {
+        let builder = r2d2::Pool::builder()
+            .max_size(pool_size)
+            .connection_timeout(timeout)
+            .max_lifetime(Some(Duration::from_secs(self.max_lifetime)))
+            .idle_timeout(Some(Duration::from_secs(self.idle_timeout)))
+            .test_on_check_out(self.test_on_checkout);
+
+        #[cfg(feature = "metrics")]
+        let builder = builder.event_handler(Box::new(RecycleMetrics));
+
+        builder
+    }
+}
+
+/// Whether and how connections to the session database use TLS, named after
+/// the `sslmode` settings of libpq
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SslMode {
+    /// Never use TLS
+    Disable,
+    /// Use TLS if the server supports it, without verifying its certificate
+    Prefer,
+    /// Always use TLS. The certificate of the server is only verified if
+    /// `ssl_root_cert` is set, and then only against that certificate.
+    Require,
+    /// Always use TLS, verifying that the certificate of the server is issued
+    /// by a trusted authority or by `ssl_root_cert`
+    VerifyCa,
+    /// Like `verify-ca`, additionally verifying that the certificate is issued
+    /// for the host connected to
+    VerifyFull,
+}
+
+/// Connection settings, read from the same table as the other database
+/// settings (e.g. `[global.databases.session]`). The `sslmode` in the URL is
+/// still used if `sslmode` is not set here, but only `require` is taken from
+/// it, as its default `prefer` would otherwise change existing deployments.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ConnectionOptions {
+    pub sslmode: Option<SslMode>,
+    /// Path to a PEM file with the certificate of the authority that issued
+    /// the certificate of the server, as provided by managed Postgres hosting
+    pub ssl_root_cert: Option<String>,
+    /// Time in milliseconds after which the server aborts a statement
+    pub statement_timeout_ms: Option<u64>,
+}
+
+impl ConnectionOptions {
+    /// The mode to connect with, given the mode in the URL
+    pub(crate) fn ssl_mode(&self, url_mode: PgSslMode) -> SslMode {
+        match (self.sslmode, url_mode) {
+            (Some(mode), _) => mode,
+            (None, PgSslMode::Require) => SslMode::Require,
+            (None, _) => SslMode::Disable,
+        }
+    }
+
+    /// The mode to pass to the Postgres client for `mode`
+    pub(crate) fn pg_ssl_mode(mode: SslMode) -> PgSslMode {
+        match mode {
+            SslMode::Disable => PgSslMode::Disable,
+            SslMode::Prefer => PgSslMode::Prefer,
+            SslMode::Require | SslMode::VerifyCa | SslMode::VerifyFull => PgSslMode::Require,
+        }
+    }
+
+    /// Connector for TLS connections in `mode`, or `None` if no TLS is used
+    pub(crate) fn tls_connector(&self, mode: SslMode) -> Result<Option<MakeTlsConnector>, String> {
+        let verify_ca = match mode {
+            SslMode::Disable => return Ok(None),
+            SslMode::Prefer => false,
+            SslMode::Require => self.ssl_root_cert.is_some(),
+            SslMode::VerifyCa | SslMode::VerifyFull => true,
+        };
+
+        let mut builder = TlsConnector::builder();
+        if let Some(path) = &self.ssl_root_cert {
+            let pem = std::fs::read(path)
+                .map_err(|e| format!("Could not read ssl_root_cert {}: {}", path, e))?;
+            let certificate = Certificate::from_pem(&pem)
+                .map_err(|e| format!("Invalid ssl_root_cert {}: {}", path, e))?;
+            builder.add_root_certificate(certificate);
+        }
+        let connector = builder
+            .danger_accept_invalid_certs(!verify_ca)
+            .danger_accept_invalid_hostnames(mode != SslMode::VerifyFull)
+            .build()
+            .map_err(|e| format!("Could not set up TLS: {}", e))?;
+        Ok(Some(MakeTlsConnector::new(connector)))
+    }
+
+    /// Run-time parameters to set on every connection
+    pub(crate) fn options(&self) -> Option<String> {
+        self.statement_timeout_ms
+            .map(|timeout| format!("-c statement_timeout={}", timeout))
+    }
+}
+
+/// Postgres client pooled with a configurable recycling policy, caching the
+/// statements prepared on its connection
+pub struct SessionClient {
+    client: postgres::Client,
+    /// Statements prepared on this connection, by their query
+    statements: HashMap<String, Statement>,
+    /// Key encrypting the authentication results stored through this
+    /// connection
+    auth_result_keys: AuthResultKeySource,
+}
+
+impl SessionClient {
+    fn new(client: postgres::Client, auth_result_keys: AuthResultKeySource) -> Self {
+        SessionClient {
+            client,
+            statements: HashMap::new(),
+            auth_result_keys,
+        }
+    }
+
+    /// The configured `auth_result_encryption_key`, if any
+    pub(crate) fn auth_result_key(&self) -> Option<Arc<AuthResultKey>> {
+        self.auth_result_keys.current()
+    }
+
+    /// Prepare `query`, reusing the statement if it was prepared on this
+    /// connection before. Saves parsing and planning the query again on every
+    /// request.
+    pub fn prepare_cached(&mut self, query: &str) -> Result<Statement, postgres::Error> {
+        if let Some(statement) = self.statements.get(query) {
+            return Ok(statement.clone());
+        }
+        let statement = self.client.prepare(query)?;
+        self.statements.insert(query.to_owned(), statement.clone());
+        Ok(statement)
+    }
+}
+
+impl Deref for SessionClient {
+    type Target = postgres::Client;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+impl DerefMut for SessionClient {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.client
+    }
+}
+
+/// Manager connecting with or without TLS, see [`ConnectionOptions`]
+enum Connector {
+    Plain(PostgresConnectionManager<NoTls>),
+    Tls(PostgresConnectionManager<MakeTlsConnector>),
+}
+
+/// Connection manager retrying to establish connections with backoff, so that
+/// the pool recovers by itself once the database is reachable again, e.g.
+/// after a failover. Broken connections are dropped when returned to the pool,
+/// and with `test_on_checkout` dead connections are never handed out.
+pub struct SessionConnectionManager {
+    connector: Connector,
+    policy: RecyclePolicy,
+    auth_result_keys: AuthResultKeySource,
+}
+
+impl SessionConnectionManager {
+    /// Manager connecting to the database at `url` with the TLS and timeout
+    /// settings in `options`
+    fn new(
+        url: &str,
+        options: &ConnectionOptions,
+        policy: RecyclePolicy,
+        auth_result_keys: AuthResultKeySource,
+    ) -> Result<Self, String> {
+        let mut pg_config: postgres::Config =
+            url.parse().map_err(|e: postgres::Error| e.to_string())?;
+        let mode = options.ssl_mode(pg_config.get_ssl_mode());
+        pg_config.ssl_mode(ConnectionOptions::pg_ssl_mode(mode));
+        if let Some(parameters) = options.options() {
+            pg_config.options(&parameters);
+        }
+        let connector = match options.tls_connector(mode)? {
+            Some(tls) => Connector::Tls(PostgresConnectionManager::new(pg_config, tls)),
+            None => Connector::Plain(PostgresConnectionManager::new(pg_config, NoTls)),
+        };
+        Ok(SessionConnectionManager {
+            connector,
+            policy,
+            auth_result_keys,
+        })
+    }
+
+    fn try_connect(&self) -> Result<postgres::Client, postgres::Error> {
+        match &self.connector {
+            Connector::Plain(manager) => manager.connect(),
+            Connector::Tls(manager) => manager.connect(),
+        }
+    }
+}
+
+impl ManageConnection for SessionConnectionManager {
+    type Connection = SessionClient;
+    type Error = postgres::Error;
+
+    fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        let mut retry = 0;
+        loop {
+            match self.try_connect() {
+                Ok(client) => return Ok(SessionClient::new(client, self.auth_result_keys.clone())),
+                Err(e) if retry < self.policy.reconnect_attempts => {
+                    let backoff = self.policy.reconnect_backoff(retry);
+                    eprintln!(
+                        "Could not connect to the session database, retrying in {:?}: {}",
+                        backoff, e
+                    );
+                    std::thread::sleep(backoff);
+                    retry += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
+        match &self.connector {
+            Connector::Plain(manager) => manager.is_valid(&mut conn.client),
+            Connector::Tls(manager) => manager.is_valid(&mut conn.client),
+        }
+    }
+
+    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        match &self.connector {
+            Connector::Plain(manager) => manager.has_broken(&mut conn.client),
+            Connector::Tls(manager) => manager.has_broken(&mut conn.client),
+        }
+    }
+}
+
+#[cfg(feature = "rocket")]
+impl Poolable for SessionClient {
+    type Error = postgres::Error;
+    type Manager = SessionConnectionManager;
+
+    fn pool(db_name: &str, rocket: &Rocket<Build>) -> PoolResult<Self> {
+        // Refuse to start rather than storing results in plaintext when a key
+        // is configured, but the configuration is only managed later
+        let auth_result_keys = AuthResultKeySource::from_rocket(rocket).ok_or_else(|| {
+            figment::Error::from(format!(
+                "No configuration found for database {}, manage it before attaching the database",
+                db_name
+            ))
+        })?;
+        let config = Config::from(db_name, rocket)?;
+        let policy: RecyclePolicy = Config::figment(db_name, rocket).extract()?;
+        let options: ConnectionOptions = Config::figment(db_name, rocket).extract()?;
+
+        let timeout = Duration::from_secs(config.timeout as u64);
+        let builder = policy.pool_builder(config.pool_size, timeout);
+        let manager =
+            SessionConnectionManager::new(&config.url, &options, policy, auth_result_keys)
+                .map_err(figment::Error::from)?;
+        Ok(builder.build(manager)?)
+    }
+}
+
+/// Connection to the session database that session queries run on, such as
+/// [`super::SessionDBConn`] in Rocket, or a [`SessionConn`] taken from a
+/// [`SessionPool`] in plugins built on other frameworks
+#[async_trait]
+pub trait SessionDb: Send + Sync {
+    /// Run `f` with the client of this connection, on a thread where blocking
+    /// is allowed
+    async fn run<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut SessionClient) -> R + Send + 'static,
+        R: Send + 'static;
+}
+
+/// Pool of connections to the session database for plugins not built on
+/// Rocket, recycling connections like the pool behind
+/// [`super::SessionDBConn`]. Connections are established in the background.
+#[derive(Clone)]
+pub struct SessionPool(r2d2::Pool<SessionConnectionManager>);
+
+impl SessionPool {
+    /// Create a pool of at most `pool_size` connections to the database at
+    /// `url` with the TLS and timeout settings in `options`, encrypting
+    /// authentication results with the key from `auth_result_keys`
+    pub fn new(
+        url: &str,
+        pool_size: u32,
+        options: &ConnectionOptions,
+        auth_result_keys: AuthResultKeySource,
+    ) -> Result<Self, Error> {
+        let policy = RecyclePolicy::default();
+        let builder = policy.pool_builder(pool_size, DEFAULT_CONNECTION_TIMEOUT);
+        let manager = SessionConnectionManager::new(url, options, policy, auth_result_keys)
+            .map_err(|e| Error::Config(format!("Invalid session database: {}", e)))?;
+        Ok(SessionPool(builder.build_unchecked(manager)))
+    }
+
+    /// Take a connection from the pool, waiting for one to become available.
+    /// Fails with `DatabaseError::Unavailable` if none becomes available in
+    /// time.
+    pub async fn get(&self) -> Result<SessionConn, Error> {
+        let pool = self.0.clone();
+        let conn = tokio::task::spawn_blocking(move || pool.get())
+            .await
+            .map_err(|e| DatabaseError::Unavailable(e.to_string()))?
+            .map_err(|e| DatabaseError::Unavailable(e.to_string()))?;
+        Ok(SessionConn(Arc::new(Mutex::new(conn))))
+    }
+}
+
+/// Connection taken from a [`SessionPool`], returned to the pool once dropped
+pub struct SessionConn(Arc<Mutex<r2d2::PooledConnection<SessionConnectionManager>>>);
+
+#[async_trait]
+impl SessionDb for SessionConn {
+    async fn run<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut SessionClient) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let conn = self.0.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap();
+            f(&mut conn)
+        })
+        .await;
+        match result {
+            Ok(result) => result,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+}
+
+/// Reports connections that are closed by the pool, either because they
+/// reached their maximum age or idle time, or because they were found broken
+#[cfg(feature = "metrics")]
+#[derive(Debug)]
+struct RecycleMetrics;
+
+#[cfg(feature = "metrics")]
+impl r2d2::HandleEvent for RecycleMetrics {
+    fn handle_release(&self, event: r2d2::event::ReleaseEvent) {
+        crate::metrics::connection_recycled(event.age());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use figment::{providers::Serialized, Figment};
+    use postgres::config::SslMode as PgSslMode;
+
+    use super::{ConnectionOptions, RecyclePolicy, SslMode};
+
+    #[test]
+    fn test_connection_options() {
+        let options: ConnectionOptions = Figment::from(Serialized::defaults(serde_json::json!({
+            "url": "postgres://localhost/comm",
+            "sslmode": "verify-full",
+            "statement_timeout_ms": 5000,
+        })))
+        .extract()
+        .unwrap();
+        assert_eq!(options.ssl_mode(PgSslMode::Prefer), SslMode::VerifyFull);
+        assert_eq!(
+            options.options().as_deref(),
+            Some("-c statement_timeout=5000")
+        );
+        assert!(options
+            .tls_connector(SslMode::VerifyFull)
+            .unwrap()
+            .is_some());
+
+        let default = ConnectionOptions::default();
+        assert_eq!(default.ssl_mode(PgSslMode::Prefer), SslMode::Disable);
+        assert_eq!(default.ssl_mode(PgSslMode::Require), SslMode::Require);
+        assert!(default.tls_connector(SslMode::Disable).unwrap().is_none());
+        assert!(default.options().is_none());
+
+        let missing = ConnectionOptions {
+            ssl_root_cert: Some("/nonexistent/ca.pem".to_owned()),
+            ..Default::default()
+        };
+        assert!(missing.tls_connector(SslMode::VerifyCa).is_err());
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let policy = RecyclePolicy::default();
+        assert_eq!(policy.reconnect_backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.reconnect_backoff(3), Duration::from_millis(800));
+        assert_eq!(policy.reconnect_backoff(40), Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,87 @@
+use rocket::{
+    request::{FromRequest, Outcome},
+    Request,
+};
+use rocket_sync_db_pools::database;
+
+use super::{Page, Session, SessionClient, SessionDBConn};
+use crate::{error::Error, types::RoomId};
+
+/// Read-only connection to a replica of the session database, configured in
+/// `[global.databases.session_replica]`
+#[database("session_replica")]
+pub struct SessionReplicaConn(SessionClient);
+
+/// Connection for queries that only read sessions, such as those of the host
+/// dashboard. Uses the replica if its fairing is attached, and the primary
+/// session database otherwise or while the replica is unavailable. Reads from
+/// a replica may lag slightly behind the writes to the primary. Only offers
+/// queries that don't write, as a replica refuses writes; write through a
+/// [`SessionDBConn`] instead.
+pub enum SessionReader {
+    Primary(SessionDBConn),
+    Replica(SessionReplicaConn),
+}
+
+impl SessionReader {
+    /// Run `f` with a client of the database read from
+    async fn run<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut SessionClient) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        match self {
+            SessionReader::Primary(db) => db.run(f).await,
+            SessionReader::Replica(db) => db.run(f).await,
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        matches!(self, SessionReader::Replica(_))
+    }
+
+    /// Find sessions by room ID, in order of creation, without marking them as
+    /// active. Fails with `Error::NotFound` if the room has no sessions.
+    pub async fn find_by_room_id(&self, room_id: RoomId) -> Result<Vec<Session>, Error> {
+        #[cfg(feature = "metrics")]
+        let _timer = crate::metrics::db_query_timer("find_by_room_id_readonly");
+        self.run(move |c| Session::query_room_readonly(c, &room_id))
+            .await
+    }
+
+    /// Find a page of the sessions in a room, in order of creation, without
+    /// marking them as active. An empty page is not an error.
+    pub async fn find_page_by_room_id(
+        &self,
+        room_id: RoomId,
+        page: Page,
+    ) -> Result<Vec<Session>, Error> {
+        #[cfg(feature = "metrics")]
+        let _timer = crate::metrics::db_query_timer("find_page_by_room_id_readonly");
+        self.run(move |c| Session::query_page_readonly(c, &room_id, page))
+            .await
+    }
+
+    /// Count the sessions in a room
+    pub async fn count_by_room_id(&self, room_id: RoomId) -> Result<u64, Error> {
+        self.run(move |c| Session::query_count(c, &room_id)).await
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SessionReader {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        if SessionReplicaConn::pool(request.rocket()).is_some() {
+            if let Outcome::Success(db) = request.guard::<SessionReplicaConn>().await {
+                return Outcome::Success(SessionReader::Replica(db));
+            }
+            eprintln!("Session database replica unavailable, reading from the primary");
+        }
+        request
+            .guard::<SessionDBConn>()
+            .await
+            .map(SessionReader::Primary)
+    }
+}
@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+
+/// Lifecycle of a session. Transitions are enforced by the [`super::Session`]
+/// methods, see [`SessionState::can_transition_to`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display, EnumString)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum SessionState {
+    /// The session was created, but authentication was not started yet
+    Created,
+    /// The guest started authenticating, but no result was received yet
+    AuthStarted,
+    /// An authentication result was received
+    AuthCompleted,
+    /// The session was expired, and will be removed by the next cleanup
+    Expired,
+    /// The session was cancelled by the host or guest
+    Cancelled,
+}
+
+impl SessionState {
+    /// States from which a session may move to this state
+    pub fn predecessors(self) -> &'static [SessionState] {
+        use SessionState::*;
+        match self {
+            // A received result may be reset for the guest to authenticate
+            // again, see `Session::reset_auth_result`
+            Created => &[AuthCompleted],
+            // Authentication may be restarted as long as no result was received
+            AuthStarted => &[Created, AuthStarted],
+            AuthCompleted => &[Created, AuthStarted],
+            Expired | Cancelled => &[Created, AuthStarted, AuthCompleted],
+        }
+    }
+
+    /// Whether a session in this state may move to `next`
+    pub fn can_transition_to(self, next: SessionState) -> bool {
+        next.predecessors().contains(&self)
+    }
+
+    /// Names of the [`SessionState::predecessors`], as stored in the database
+    pub(super) fn predecessor_names(self) -> Vec<String> {
+        self.predecessors()
+            .iter()
+            .map(SessionState::to_string)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::SessionState::{self, *};
+
+    #[test]
+    fn test_transitions() {
+        assert!(Created.can_transition_to(AuthStarted));
+        assert!(AuthStarted.can_transition_to(AuthStarted));
+        assert!(AuthStarted.can_transition_to(AuthCompleted));
+        assert!(AuthCompleted.can_transition_to(Cancelled));
+        assert!(!AuthCompleted.can_transition_to(AuthStarted));
+        assert!(!Cancelled.can_transition_to(AuthCompleted));
+        assert!(!Expired.can_transition_to(Cancelled));
+        assert!(!AuthStarted.can_transition_to(Created));
+        assert!(AuthCompleted.can_transition_to(Created));
+
+        assert_eq!(AuthStarted.to_string(), "auth_started");
+        assert_eq!(
+            SessionState::from_str("auth_completed").unwrap(),
+            AuthCompleted
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use super::{
+    clean_expired_sessions, purge_auth_results, purge_by_room_id, purge_pending_sessions, Page,
+    RetentionPolicy, Session, SessionDb, SessionExpiry,
+};
+use crate::{
+    auth_result::StoredAuthResult,
+    error::Error,
+    types::{AttrId, RoomId, SessionId},
+};
+
+/// Storage backend for sessions. Implemented for every connection to the
+/// Postgres backed session database, i.e. every [`SessionDb`], but plugins
+/// can provide their own implementation for other storage.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Persist a newly created session. Fails if a session with the same ID
+    /// already exists.
+    async fn persist(&self, session: &Session) -> Result<(), Error>;
+
+    /// Register an authentication result with the session matching `attr_id`.
+    /// Fails if that session already contains an authentication result.
+    async fn register_auth_result(
+        &self,
+        attr_id: AttrId,
+        auth_result: StoredAuthResult,
+    ) -> Result<(), Error>;
+
+    /// Cancel the session matching `attr_id`, optionally removing its
+    /// authentication result. Fails with `Error::NotFound` for unknown
+    /// sessions, and with `Error::Conflict` if the session can't be cancelled.
+    async fn cancel(&self, attr_id: AttrId, clear_auth_result: bool) -> Result<(), Error>;
+
+    /// Find all sessions in a room in order of creation, marking them as
+    /// active. Fails with `Error::NotFound` if the room has no sessions.
+    async fn find_by_room_id(&self, room_id: RoomId) -> Result<Vec<Session>, Error>;
+
+    /// Find all sessions in a room in order of creation, without marking them
+    /// as active. Fails with `Error::NotFound` if the room has no sessions.
+    async fn find_by_room_id_readonly(&self, room_id: RoomId) -> Result<Vec<Session>, Error>;
+
+    /// Mark the session with the given guest token ID as active. Fails with
+    /// `Error::NotFound` if there is no such session.
+    async fn touch(&self, session_id: SessionId) -> Result<(), Error>;
+
+    /// Find a page of the sessions in a room in order of creation, marking
+    /// them as active
+    async fn find_page_by_room_id(
+        &self,
+        room_id: RoomId,
+        page: Page,
+    ) -> Result<Vec<Session>, Error>;
+
+    /// Count the sessions in a room
+    async fn count_by_room_id(&self, room_id: RoomId) -> Result<u64, Error>;
+
+    /// Find the session matching `attr_id`, marking it as active. Fails with
+    /// `Error::NotFound` if there is no such session.
+    async fn find_by_attr_id(&self, attr_id: AttrId) -> Result<Session, Error>;
+
+    /// Find the session with the given guest token ID, marking it as active.
+    /// Fails with `Error::NotFound` if there is no such session.
+    async fn find_by_session_id(&self, session_id: SessionId) -> Result<Session, Error>;
+
+    /// Remove all cancelled sessions, and all sessions that expired under
+    /// `expiry` with the given `lifetime`
+    async fn clean(&self, lifetime: Duration, expiry: SessionExpiry) -> Result<(), Error>;
+
+    /// Remove the authentication results registered `older_than` ago or longer
+    /// from their sessions, returning the number of results removed
+    async fn purge_auth_results(&self, older_than: Duration) -> Result<u64, Error>;
+
+    /// Remove sessions without an authentication result that have been
+    /// inactive for `older_than` or more, returning the number removed
+    async fn purge_pending_sessions(&self, older_than: Duration) -> Result<u64, Error>;
+
+    /// Remove all sessions in a room, e.g. to honour a request for erasure.
+    /// Returns the number of sessions removed.
+    async fn purge_by_room_id(&self, room_id: RoomId) -> Result<u64, Error>;
+
+    /// Remove the session data `retention` does not allow to be kept
+    async fn apply_retention(&self, retention: &RetentionPolicy) -> Result<(), Error> {
+        if let Some(lifetime) = retention.pending_session_lifetime {
+            self.purge_pending_sessions(lifetime).await?;
+        }
+        if let Some(lifetime) = retention.auth_result_lifetime {
+            self.purge_auth_results(lifetime).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<D: SessionDb> SessionStore for D {
+    async fn persist(&self, session: &Session) -> Result<(), Error> {
+        session.persist(self).await
+    }
+
+    async fn register_auth_result(
+        &self,
+        attr_id: AttrId,
+        auth_result: StoredAuthResult,
+    ) -> Result<(), Error> {
+        Session::register_auth_result(attr_id, auth_result, self).await
+    }
+
+    async fn cancel(&self, attr_id: AttrId, clear_auth_result: bool) -> Result<(), Error> {
+        Session::cancel(attr_id, clear_auth_result, self).await
+    }
+
+    async fn find_by_room_id(&self, room_id: RoomId) -> Result<Vec<Session>, Error> {
+        Session::find_by_room_id(room_id, self).await
+    }
+
+    async fn find_by_room_id_readonly(&self, room_id: RoomId) -> Result<Vec<Session>, Error> {
+        Session::find_by_room_id_readonly(room_id, self).await
+    }
+
+    async fn touch(&self, session_id: SessionId) -> Result<(), Error> {
+        Session::touch(session_id, self).await
+    }
+
+    async fn find_page_by_room_id(
+        &self,
+        room_id: RoomId,
+        page: Page,
+    ) -> Result<Vec<Session>, Error> {
+        Session::find_page_by_room_id(room_id, page, self).await
+    }
+
+    async fn count_by_room_id(&self, room_id: RoomId) -> Result<u64, Error> {
+        Session::count_by_room_id(room_id, self).await
+    }
+
+    async fn find_by_attr_id(&self, attr_id: AttrId) -> Result<Session, Error> {
+        Session::find_by_attr_id(attr_id, self).await
+    }
+
+    async fn find_by_session_id(&self, session_id: SessionId) -> Result<Session, Error> {
+        Session::find_by_session_id(session_id, self).await
+    }
+
+    async fn clean(&self, lifetime: Duration, expiry: SessionExpiry) -> Result<(), Error> {
+        clean_expired_sessions(self, lifetime, expiry).await
+    }
+
+    async fn purge_auth_results(&self, older_than: Duration) -> Result<u64, Error> {
+        purge_auth_results(older_than, self).await
+    }
+
+    async fn purge_pending_sessions(&self, older_than: Duration) -> Result<u64, Error> {
+        purge_pending_sessions(older_than, self).await
+    }
+
+    async fn purge_by_room_id(&self, room_id: RoomId) -> Result<u64, Error> {
+        purge_by_room_id(room_id, self).await
+    }
+}
@@ -0,0 +1,264 @@
+use std::time::Duration;
+
+use super::{Session, SessionDb, SessionExpiry};
+use crate::{error::Error, util::log_error};
+
+/// Commit the session with ID `$1`, so that it shows up in listings, and count
+/// it in the `session_stats`. Returns the session ID if it was committed.
+pub(super) const COMMIT_SESSION: &str = "
+    WITH committed AS (
+        UPDATE session SET committed = true
+        WHERE session_id = $1
+        AND NOT committed
+        RETURNING session_id, purpose, auth_result IS NOT NULL AS completed
+    ), counted AS (
+        INSERT INTO session_stats (day, purpose, created, completed)
+        SELECT current_date, purpose, 1, completed::int FROM committed
+        ON CONFLICT (day, purpose) DO UPDATE
+        SET created = session_stats.created + EXCLUDED.created,
+            completed = session_stats.completed + EXCLUDED.completed
+    )
+    SELECT session_id FROM committed";
+
+/// Remove the session with ID `$1`
+const DELETE_SESSION: &str = "DELETE FROM session WHERE session_id = $1";
+
+/// A persisted session awaiting the outcome of a step outside the database,
+/// such as starting authentication at the core. Until committed, the session
+/// is left out of room listings, counts, exports and the `session_stats`, and
+/// its creation is not announced. It is removed again when rolled back, so that
+/// a failing core leaves no orphan sessions behind. A transaction dropped
+/// without being committed or rolled back leaves its session hidden until the
+/// cleanup removes it.
+#[must_use = "a session transaction must be committed or rolled back"]
+pub struct SessionTransaction<'a, D> {
+    session: Session,
+    db: &'a D,
+}
+
+impl<'a, D: SessionDb> SessionTransaction<'a, D> {
+    /// Persist `session` uncommitted, pending a commit or rollback. Like
+    /// [`Session::persist`], this does not limit the number of active rooms;
+    /// use [`SessionTransaction::begin_with_room_limit`] for that.
+    pub async fn begin(session: Session, db: &'a D) -> Result<Self, Error> {
+        SessionTransaction::start(session, None, db).await
+    }
+
+    /// Persist `session` uncommitted, refusing to open a new room once
+    /// `max_rooms` distinct rooms are active, as
+    /// [`Session::persist_with_room_limit`] does. The uncommitted session
+    /// counts towards the limit, so its room remains available until the
+    /// transaction is finished.
+    pub async fn begin_with_room_limit(
+        session: Session,
+        max_rooms: Option<u64>,
+        lifetime: Duration,
+        expiry: SessionExpiry,
+        db: &'a D,
+    ) -> Result<Self, Error> {
+        let room_limit = max_rooms.map(|max_rooms| (max_rooms, lifetime, expiry));
+        SessionTransaction::start(session, room_limit, db).await
+    }
+
+    async fn start(
+        session: Session,
+        room_limit: Option<(u64, Duration, SessionExpiry)>,
+        db: &'a D,
+    ) -> Result<Self, Error> {
+        let this = session.clone();
+        db.run(move |c| -> Result<(), Error> {
+            let key = c.auth_result_key();
+            let mut transaction = c.transaction()?;
+            match room_limit {
+                Some((max_rooms, lifetime, expiry)) => this.insert_with_room_limit(
+                    &mut transaction,
+                    key.as_deref(),
+                    false,
+                    max_rooms,
+                    lifetime,
+                    expiry,
+                )?,
+                None => {
+                    this.insert(&mut transaction, key.as_deref(), false)?;
+                }
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await?;
+        Ok(SessionTransaction { session, db })
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Keep the session, making it show up in listings and the `session_stats`
+    /// and announcing its creation. Fails with `Error::NotFound` if the session
+    /// was removed in the meantime.
+    pub async fn commit(self) -> Result<Session, Error> {
+        let session_id = self.session.guest_token.id.clone();
+        let committed = self
+            .db
+            .run(move |c| c.query(COMMIT_SESSION, &[&session_id]))
+            .await?;
+        if committed.is_empty() {
+            return Err(Error::NotFound);
+        }
+        self.session.announce_created();
+        Ok(self.session)
+    }
+
+    /// Remove the session again
+    pub async fn rollback(self) -> Result<(), Error> {
+        let session_id = self.session.guest_token.id;
+        self.db
+            .run(move |c| c.execute(DELETE_SESSION, &[&session_id]))
+            .await?;
+        Ok(())
+    }
+
+    /// Commit if `outcome` is a success, and roll back otherwise, returning
+    /// the session along with the outcome. Fails with the error of `outcome`,
+    /// even if rolling back fails as well.
+    pub async fn finish<T>(self, outcome: Result<T, Error>) -> Result<(Session, T), Error> {
+        match outcome {
+            Ok(value) => Ok((self.commit().await?, value)),
+            Err(e) => {
+                let session_id = self.session.guest_token.id.clone();
+                if let Err(rollback_error) = self.rollback().await {
+                    log_error(format_args!(
+                        "Could not roll back session {}: {}",
+                        session_id, rollback_error
+                    ));
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use serial_test::serial;
+
+    use super::SessionTransaction;
+    use crate::{
+        error::Error,
+        session::{
+            stats, tests::init_db, Session, SessionConn, SessionExpiry, DEFAULT_SESSION_LIFETIME,
+        },
+        test_support::fixtures::{self, guest_token},
+        util::random_string,
+    };
+
+    /// Number of sessions for `purpose` counted as created around now
+    async fn created(purpose: &str, db: &SessionConn) -> u64 {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let now = SystemTime::now();
+        stats(now - day..now + day, db)
+            .await
+            .unwrap()
+            .iter()
+            .find(|stats| stats.purpose == purpose)
+            .map_or(0, |stats| stats.created)
+    }
+
+    #[test]
+    #[serial]
+    fn test_session_transaction() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let purpose = random_string(16);
+                let failed =
+                    fixtures::session(guest_token().purpose(purpose.clone()).build()).build();
+                let transaction = SessionTransaction::begin(failed.clone(), &db)
+                    .await
+                    .unwrap();
+                let outcome: Result<(), Error> = Err(Error::InternalServer("core".to_owned()));
+                assert!(transaction.finish(outcome).await.is_err());
+                assert!(matches!(
+                    Session::find_by_attr_id(failed.attr_id, &db).await,
+                    Err(Error::NotFound)
+                ));
+                assert_eq!(created(&purpose, &db).await, 0);
+
+                let started =
+                    fixtures::session(guest_token().purpose(purpose.clone()).build()).build();
+                let room_id = started.guest_token.room_id.clone();
+                let transaction = SessionTransaction::begin(started.clone(), &db)
+                    .await
+                    .unwrap();
+                assert!(matches!(
+                    Session::find_by_room_id(room_id.clone(), &db).await,
+                    Err(Error::NotFound)
+                ));
+                assert_eq!(
+                    Session::count_by_room_id(room_id.clone(), &db)
+                        .await
+                        .unwrap(),
+                    0
+                );
+                assert_eq!(created(&purpose, &db).await, 0);
+
+                let (session, url) = transaction.finish(Ok("https://example.com")).await.unwrap();
+                assert_eq!(session.attr_id, started.attr_id);
+                assert_eq!(url, "https://example.com");
+                assert_eq!(
+                    Session::find_by_room_id(room_id.clone(), &db)
+                        .await
+                        .unwrap()
+                        .len(),
+                    1
+                );
+                assert_eq!(Session::count_by_room_id(room_id, &db).await.unwrap(), 1);
+                assert_eq!(created(&purpose, &db).await, 1);
+            }
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_session_transaction_room_limit() {
+        tokio_test::block_on(async {
+            if let Some(db) = init_db().await {
+                let lifetime = DEFAULT_SESSION_LIFETIME;
+                let expiry = SessionExpiry::Sliding;
+                let first = SessionTransaction::begin_with_room_limit(
+                    fixtures::session(guest_token().build()).build(),
+                    Some(1),
+                    lifetime,
+                    expiry,
+                    &db,
+                )
+                .await
+                .unwrap();
+
+                // The uncommitted session holds the only available room
+                let second = SessionTransaction::begin_with_room_limit(
+                    fixtures::session(guest_token().build()).build(),
+                    Some(1),
+                    lifetime,
+                    expiry,
+                    &db,
+                )
+                .await;
+                assert!(matches!(second, Err(Error::BadRequest(_))));
+
+                first.rollback().await.unwrap();
+                let third = SessionTransaction::begin_with_room_limit(
+                    fixtures::session(guest_token().build()).build(),
+                    Some(1),
+                    lifetime,
+                    expiry,
+                    &db,
+                )
+                .await
+                .unwrap();
+                third.commit().await.unwrap();
+            }
+        });
+    }
+}
@@ -0,0 +1,104 @@
+use std::{future::Future, sync::Mutex, time::Duration};
+
+#[cfg(feature = "rocket")]
+use rocket::fairing::{AdHoc, Fairing};
+use tokio::{task::JoinHandle, time::Instant};
+
+#[cfg(feature = "async-db")]
+use crate::session::AsyncSessionDB;
+
+lazy_static! {
+    static ref IN_FLIGHT: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+}
+
+/// Spawn `work` as a task that [`drain`] waits for on shutdown, such as the
+/// delivery of an authentication result. The task itself must stop once
+/// shutdown starts, or finish soon after.
+pub fn spawn_tracked(work: impl Future<Output = ()> + Send + 'static) {
+    let task = tokio::spawn(work);
+    let mut in_flight = IN_FLIGHT.lock().unwrap();
+    in_flight.retain(|task| !task.is_finished());
+    in_flight.push(task);
+}
+
+/// Wait up to `timeout` for all tasks spawned through [`spawn_tracked`] to
+/// finish, including tasks spawned while waiting. Returns the number of tasks
+/// still running after that.
+pub async fn drain(timeout: Duration) -> usize {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let mut tasks = std::mem::take(&mut *IN_FLIGHT.lock().unwrap());
+        if tasks.is_empty() {
+            return 0;
+        }
+
+        let finished = tokio::time::timeout_at(deadline, async {
+            for task in tasks.iter_mut() {
+                // Panicked tasks have nothing left to wait for
+                let _ = task.await;
+            }
+        })
+        .await;
+        if finished.is_err() {
+            let remaining = tasks.iter().filter(|task| !task.is_finished()).count();
+            return remaining + IN_FLIGHT.lock().unwrap().len();
+        }
+    }
+}
+
+/// Fairing that, on shutdown, waits for the background work of this crate to
+/// finish: the session cleanup stops, authentication results registered so
+/// far are delivered to the result sinks, and the audit log is flushed. Waits
+/// at most the grace period configured for Rocket through `shutdown.grace`.
+/// Afterwards, the asynchronous session database pool is closed, if managed.
+/// Attach it last, so that it runs after the fairings starting the work.
+#[cfg(feature = "rocket")]
+pub fn shutdown_fairing() -> impl Fairing {
+    AdHoc::on_shutdown("Drain background work", |rocket| {
+        Box::pin(async move {
+            let grace = Duration::from_secs(rocket.config().shutdown.grace.into());
+            let remaining = drain(grace).await;
+            if remaining > 0 {
+                eprintln!(
+                    "Shutting down with {} background tasks unfinished",
+                    remaining
+                );
+            }
+
+            #[cfg(feature = "async-db")]
+            if let Some(db) = rocket.state::<AsyncSessionDB>() {
+                db.close();
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::{drain, spawn_tracked};
+
+    #[test]
+    fn test_drain() {
+        tokio_test::block_on(async {
+            let done = Arc::new(AtomicBool::new(false));
+            let work_done = done.clone();
+            spawn_tracked(async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                work_done.store(true, Ordering::SeqCst);
+            });
+            assert_eq!(drain(Duration::from_secs(5)).await, 0);
+            assert!(done.load(Ordering::SeqCst));
+
+            spawn_tracked(tokio::time::sleep(Duration::from_secs(60)));
+            assert_eq!(drain(Duration::from_millis(10)).await, 1);
+        });
+    }
+}
@@ -0,0 +1,290 @@
+use std::{future::Future, sync::Arc};
+
+use async_trait::async_trait;
+#[cfg(feature = "rocket")]
+use rocket::fairing::{AdHoc, Fairing};
+use serde::Deserialize;
+use tokio::sync::broadcast::error::TryRecvError;
+
+#[cfg(feature = "email")]
+use crate::email::{AuthResultMailer, EmailConfig, RawEmailConfig};
+use crate::{
+    auth_result::StoredAuthResult,
+    config::{Config, ConfigValidation},
+    error::Error,
+    events::{self, RoomEvent, RoomEventKind},
+    session::{Session, SessionDb},
+    shutdown::spawn_tracked,
+    types::SessionId,
+    webhook::WebhookSink,
+};
+#[cfg(feature = "rocket")]
+use crate::{config::CurrentConfig, session::SessionDBConn};
+
+/// Destination for registered authentication results, such as a webhook or
+/// the mailbox of a host
+#[async_trait]
+pub trait ResultSink: Send + Sync {
+    /// Deliver the authentication result registered with `session`
+    async fn deliver(&self, session: &Session, auth_result: &StoredAuthResult)
+        -> Result<(), Error>;
+}
+
+/// Sink logging registered authentication results to stderr. Only the status
+/// and the names of the attributes are logged, never their values.
+pub struct LogSink;
+
+#[async_trait]
+impl ResultSink for LogSink {
+    async fn deliver(
+        &self,
+        session: &Session,
+        auth_result: &StoredAuthResult,
+    ) -> Result<(), Error> {
+        let mut attributes: Vec<&str> = auth_result
+            .attributes
+            .iter()
+            .flat_map(|attributes| attributes.keys())
+            .map(String::as_str)
+            .collect();
+        attributes.sort_unstable();
+        eprintln!(
+            "Authentication result for session {} in room {}: {:?}, attributes [{}]",
+            session.guest_token.id,
+            session.guest_token.room_id,
+            auth_result.status,
+            attributes.join(", ")
+        );
+        Ok(())
+    }
+}
+
+/// Destination of authentication results as configured in
+/// `[[global.result_sinks]]`, selected by `type`
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RawResultSinkConfig {
+    Webhook {
+        url: String,
+    },
+    #[cfg(feature = "email")]
+    Email(RawEmailConfig),
+    Log,
+}
+
+/// Checked destination of authentication results
+#[derive(Debug, Clone)]
+pub enum ResultSinkConfig {
+    /// POST signed notifications to `url`, see [`WebhookSink`]
+    Webhook { url: String },
+    /// Mail rendered attributes, see [`AuthResultMailer`]
+    #[cfg(feature = "email")]
+    Email(EmailConfig),
+    /// Log to stderr, see [`LogSink`]
+    Log,
+}
+
+impl ResultSinkConfig {
+    /// Check the raw configuration, recording all problems in `validation`.
+    /// Webhooks need a key to sign their notifications with, as indicated by
+    /// `webhook_signer`.
+    pub(crate) fn validate(
+        raw_config: RawResultSinkConfig,
+        webhook_signer: bool,
+        validation: &mut ConfigValidation,
+    ) -> Option<ResultSinkConfig> {
+        match raw_config {
+            RawResultSinkConfig::Webhook { url } => {
+                validation.url("result_sinks.url", &url);
+                if !webhook_signer {
+                    validation.problem(
+                        "result_sinks: webhooks require a result_signing_privkey".to_string(),
+                    );
+                }
+                Some(ResultSinkConfig::Webhook { url })
+            }
+            #[cfg(feature = "email")]
+            RawResultSinkConfig::Email(raw_email) => {
+                EmailConfig::validate(raw_email, validation).map(ResultSinkConfig::Email)
+            }
+            RawResultSinkConfig::Log => Some(ResultSinkConfig::Log),
+        }
+    }
+
+    /// Name of the kind of sink, as used for `type` in the configuration
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ResultSinkConfig::Webhook { .. } => "webhook",
+            #[cfg(feature = "email")]
+            ResultSinkConfig::Email(_) => "email",
+            ResultSinkConfig::Log => "log",
+        }
+    }
+
+    /// Sink delivering to this destination, using the keys, translations
+    /// and attribute display of `config`
+    pub fn build(&self, config: &Config) -> Result<Arc<dyn ResultSink>, Error> {
+        let sink: Arc<dyn ResultSink> = match self {
+            ResultSinkConfig::Webhook { url } => {
+                let signer = config.result_webhook_signer().ok_or_else(|| {
+                    Error::Config("No result_signing_privkey configured".to_string())
+                })?;
+                Arc::new(WebhookSink::new(url.clone(), signer.box_clone()))
+            }
+            #[cfg(feature = "email")]
+            ResultSinkConfig::Email(email) => {
+                Arc::new(AuthResultMailer::new(email.clone(), config)?)
+            }
+            ResultSinkConfig::Log => Arc::new(LogSink),
+        };
+        Ok(sink)
+    }
+}
+
+/// Deliver the authentication result announced by `event` to all of `sinks`.
+/// Every delivery runs in its own task, so that slow or retrying sinks do not
+/// hold up the others.
+async fn deliver_all(sinks: &[Arc<dyn ResultSink>], event: RoomEvent, db: &impl SessionDb) {
+    let found = match SessionId::new(event.session_id) {
+        Ok(session_id) => Session::find_by_session_id_readonly(session_id, db).await,
+        Err(e) => Err(e),
+    };
+    let session = match found {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Could not load registered authentication result: {}", e);
+            return;
+        }
+    };
+    let auth_result = match &session.auth_result {
+        Some(auth_result) => auth_result.clone(),
+        None => return,
+    };
+
+    for sink in sinks {
+        let sink = sink.clone();
+        let session = session.clone();
+        let auth_result = auth_result.clone();
+        spawn_tracked(async move {
+            if let Err(e) = sink.deliver(&session, &auth_result).await {
+                eprintln!("Could not deliver authentication result: {}", e);
+            }
+        });
+    }
+}
+
+/// Deliver every authentication result registered by this process to all of
+/// `sinks` until `shutdown` completes. Results registered before shutdown but
+/// not yet picked up are still delivered. Spawned by the fairings of the
+/// sinks; plugins not built on Rocket spawn it themselves.
+pub async fn dispatch(
+    sinks: Vec<Arc<dyn ResultSink>>,
+    db: impl SessionDb,
+    shutdown: impl Future<Output = ()>,
+) {
+    let mut events = events::subscribe();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            event = events::next_of_kind(&mut events, RoomEventKind::AuthResult) => match event {
+                Some(event) => deliver_all(&sinks, event, &db).await,
+                None => return,
+            },
+            _ = &mut shutdown => break,
+        }
+    }
+
+    loop {
+        match events.try_recv() {
+            Ok(event) if event.kind == RoomEventKind::AuthResult => {
+                deliver_all(&sinks, event, &db).await
+            }
+            Ok(_) | Err(TryRecvError::Lagged(_)) => continue,
+            Err(_) => return,
+        }
+    }
+}
+
+/// Fairing spawning a task that delivers every authentication result
+/// registered by this process to the sinks built from the configuration by
+/// `sinks`. Only results registered by this process are delivered, so with
+/// several instances every result is delivered once. On shutdown, pending
+/// deliveries are awaited by [`crate::shutdown::shutdown_fairing`]. Does
+/// nothing if there are no sinks.
+#[cfg(feature = "rocket")]
+pub(crate) fn sink_fairing(
+    name: &'static str,
+    sinks: fn(&Config) -> Result<Vec<Arc<dyn ResultSink>>, Error>,
+) -> impl Fairing {
+    AdHoc::on_liftoff(name, move |rocket| {
+        Box::pin(async move {
+            let config = CurrentConfig::from_rocket(rocket).expect("No configuration found");
+            let sinks = match sinks(&config) {
+                Ok(sinks) if !sinks.is_empty() => sinks,
+                Ok(_) => return,
+                Err(e) => {
+                    eprintln!("{} disabled: {}", name, e);
+                    return;
+                }
+            };
+
+            let db = match SessionDBConn::get_one(rocket).await {
+                Some(db) => db,
+                None => {
+                    eprintln!("{} disabled: no session database available", name);
+                    return;
+                }
+            };
+            spawn_tracked(dispatch(sinks, db, rocket.shutdown()));
+        })
+    })
+}
+
+/// Fairing delivering every authentication result to all sinks configured in
+/// `[[global.result_sinks]]`. Requires the [`Config`] to be managed and the
+/// [`SessionDBConn`] fairing to be attached.
+#[cfg(feature = "rocket")]
+pub fn result_sinks_fairing() -> impl Fairing {
+    sink_fairing("Result sinks", |config| {
+        config
+            .result_sinks()
+            .iter()
+            .map(|sink| sink.build(config))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RawResultSinkConfig, ResultSinkConfig};
+    use crate::config::ConfigValidation;
+
+    #[test]
+    fn test_result_sink_config() {
+        let raw: Vec<RawResultSinkConfig> = serde_yaml::from_str(
+            r#"
+            - type: webhook
+              url: https://host.example.com/results
+            - type: log
+            "#,
+        )
+        .unwrap();
+        let mut validation = ConfigValidation::default();
+        let sinks: Vec<_> = raw
+            .into_iter()
+            .filter_map(|raw| ResultSinkConfig::validate(raw, true, &mut validation))
+            .collect();
+        assert!(validation.finish().is_ok());
+        let kinds: Vec<_> = sinks.iter().map(ResultSinkConfig::kind).collect();
+        assert_eq!(kinds, ["webhook", "log"]);
+
+        let raw: RawResultSinkConfig =
+            serde_yaml::from_str("{ type: webhook, url: not a url }").unwrap();
+        let mut validation = ConfigValidation::default();
+        ResultSinkConfig::validate(raw, false, &mut validation);
+        match validation.finish() {
+            Err(crate::error::Error::Validation(problems)) => assert_eq!(problems.len(), 2),
+            _ => panic!("Expected validation problems"),
+        }
+    }
+}
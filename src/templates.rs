@@ -1,6 +1,7 @@
 use std::path::Path;
 
 use lazy_static;
+#[cfg(feature = "rocket")]
 use rocket::{
     response::{self, content, Responder},
     Request,
@@ -20,16 +21,18 @@ impl RenderedContent {
     }
 }
 
+#[cfg(feature = "rocket")]
 impl<'r> Responder<'r, 'static> for RenderedContent {
     fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
         let RenderedContent {
             content,
             render_type,
         } = self;
-        if render_type == RenderType::Json {
-            return content::RawJson(content).respond_to(req);
+        match render_type {
+            RenderType::Json => content::RawJson(content).respond_to(req),
+            RenderType::Text => content::RawText(content).respond_to(req),
+            RenderType::Html | RenderType::HtmlPage => content::RawHtml(content).respond_to(req),
         }
-        content::RawHtml(content).respond_to(req)
     }
 }
 
@@ -38,6 +41,7 @@ pub enum RenderType {
     Json,
     Html,
     HtmlPage,
+    Text,
 }
 
 // Includes template at runtime, if available, otherwise uses compile-time
@@ -77,6 +81,7 @@ lazy_static! {
         include_template!(tera, "login.html");
         include_template!(tera, "expired.html");
         include_template!(tera, "not_found.html");
+        include_template!(tera, "error.html");
 
         tera
     };
@@ -0,0 +1,6 @@
+pub mod fixtures;
+#[cfg(all(feature = "rocket", feature = "auth_during_comm"))]
+mod mock_core;
+
+#[cfg(all(feature = "rocket", feature = "auth_during_comm"))]
+pub use mock_core::MockCore;
@@ -0,0 +1,343 @@
+use std::{collections::HashMap, time::SystemTime};
+
+#[cfg(feature = "auth_during_comm")]
+use josekit::{
+    jwe::ECDH_ES,
+    jwk::alg::ec::{EcCurve, EcKeyPair},
+    jws::{alg::hmac::HmacJwsAlgorithm, ES256},
+};
+#[cfg(feature = "auth_during_comm")]
+use verder_helpen_jwt::sign_and_encrypt_auth_result;
+use verder_helpen_proto::{AuthResult, AuthStatus};
+
+#[cfg(all(feature = "rocket", feature = "auth_during_comm"))]
+use super::MockCore;
+#[cfg(feature = "auth_during_comm")]
+use crate::config::{AuthDuringCommConfig, Config};
+#[cfg(all(feature = "rocket", feature = "auth_during_comm"))]
+use crate::error::Error;
+use crate::{
+    auth_result::StoredAuthResult,
+    session::{Session, SessionState},
+    types::{AttrId, GuestToken, HostToken, RoomId, SessionDomain, SessionId},
+    util::random_string,
+};
+
+/// Builder of a valid [`GuestToken`], see [`guest_token`]
+#[derive(Debug, Clone)]
+pub struct GuestTokenBuilder {
+    token: GuestToken,
+}
+
+/// A guest token for purpose `test` in a random room, with a random session
+/// ID, to be adjusted before calling [`GuestTokenBuilder::build`]
+pub fn guest_token() -> GuestTokenBuilder {
+    GuestTokenBuilder {
+        token: GuestToken {
+            id: SessionId::new(random_string(32)).unwrap(),
+            domain: SessionDomain::Guest,
+            redirect_url: "https://example.com".to_owned(),
+            name: "Test Guest".to_owned(),
+            room_id: RoomId::new(random_string(32)).unwrap(),
+            instance: "verderhelpen.nl".to_owned(),
+            purpose: "test".to_owned(),
+        },
+    }
+}
+
+impl GuestTokenBuilder {
+    pub fn id(mut self, id: SessionId) -> Self {
+        self.token.id = id;
+        self
+    }
+
+    pub fn room_id(mut self, room_id: RoomId) -> Self {
+        self.token.room_id = room_id;
+        self
+    }
+
+    pub fn purpose(mut self, purpose: impl Into<String>) -> Self {
+        self.token.purpose = purpose.into();
+        self
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.token.name = name.into();
+        self
+    }
+
+    pub fn instance(mut self, instance: impl Into<String>) -> Self {
+        self.token.instance = instance.into();
+        self
+    }
+
+    pub fn redirect_url(mut self, redirect_url: impl Into<String>) -> Self {
+        self.token.redirect_url = redirect_url.into();
+        self
+    }
+
+    pub fn build(self) -> GuestToken {
+        self.token
+    }
+}
+
+/// A valid host token for the room and instance of `guest_token`, authorized
+/// for its guest
+pub fn host_token_for(guest_token: &GuestToken) -> HostToken {
+    HostToken {
+        id: random_string(32),
+        domain: SessionDomain::User,
+        room_id: guest_token.room_id.clone(),
+        instance: guest_token.instance.clone(),
+        expires_at: None,
+    }
+}
+
+/// Builder of a [`Session`], see [`session`]
+#[derive(Debug, Clone)]
+pub struct SessionBuilder {
+    session: Session,
+}
+
+/// A new session for `guest_token`, with a generated attribute ID, to be
+/// adjusted before calling [`SessionBuilder::build`]
+pub fn session(guest_token: GuestToken) -> SessionBuilder {
+    SessionBuilder {
+        session: Session::new(guest_token, None),
+    }
+}
+
+impl SessionBuilder {
+    pub fn attr_id(mut self, attr_id: AttrId) -> Self {
+        self.session.attr_id = attr_id;
+        self
+    }
+
+    pub fn state(mut self, state: SessionState) -> Self {
+        self.session.state = state;
+        self
+    }
+
+    /// Hold `auth_result`, as if it was registered
+    pub fn auth_result(mut self, auth_result: AuthResult) -> Self {
+        self.session.auth_result = Some(auth_result.into());
+        self
+    }
+
+    /// Pretend the session was created, and last active, at `created_at`
+    pub fn created_at(mut self, created_at: SystemTime) -> Self {
+        self.session.created_at = created_at;
+        self.session.last_activity = created_at;
+        self
+    }
+
+    pub fn build(self) -> Session {
+        self.session
+    }
+}
+
+/// Builder of an [`AuthResult`], see [`auth_result`]
+pub struct AuthResultBuilder {
+    auth_result: AuthResult,
+}
+
+/// A successful authentication result without attributes, to be adjusted
+/// before calling [`AuthResultBuilder::build`]
+pub fn auth_result() -> AuthResultBuilder {
+    AuthResultBuilder {
+        auth_result: AuthResult {
+            status: AuthStatus::Success,
+            attributes: None,
+            session_url: None,
+        },
+    }
+}
+
+impl AuthResultBuilder {
+    pub fn status(mut self, status: AuthStatus) -> Self {
+        self.auth_result.status = status;
+        self
+    }
+
+    /// Add attribute `key` with value `value`
+    pub fn attribute(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.auth_result
+            .attributes
+            .get_or_insert_with(HashMap::new)
+            .insert(key.into(), value.into());
+        self
+    }
+
+    pub fn session_url(mut self, session_url: impl Into<String>) -> Self {
+        self.auth_result.session_url = Some(session_url.into());
+        self
+    }
+
+    pub fn build(self) -> AuthResult {
+        self.auth_result
+    }
+
+    /// The result as stored with a session once registered
+    pub fn stored(self) -> StoredAuthResult {
+        self.auth_result.into()
+    }
+}
+
+/// Throwaway keys for everything a plugin signs, verifies, encrypts or
+/// decrypts, generated for a single test. Hands out a [`Config`] using them,
+/// and signs tokens and authentication results the way the communication
+/// platform and the core would.
+#[cfg(feature = "auth_during_comm")]
+#[derive(Clone)]
+pub struct TestKeys {
+    /// Secret of the HS256 guest and host tokens
+    platform_secret: String,
+    /// Secret of the HS256 widget parameters and start requests
+    core_secret: String,
+    /// DER of the ES256 key pair signing authentication results
+    result_signing_private: Vec<u8>,
+    result_signing_public: Vec<u8>,
+    /// DER of the ECDH-ES key pair encrypting authentication results
+    result_encryption_private: Vec<u8>,
+    result_encryption_public: Vec<u8>,
+}
+
+#[cfg(feature = "auth_during_comm")]
+impl TestKeys {
+    pub fn generate() -> Self {
+        let signing = ES256.generate_key_pair().unwrap();
+        let encryption = EcKeyPair::generate(EcCurve::P256).unwrap();
+        TestKeys {
+            platform_secret: random_string(32),
+            core_secret: random_string(32),
+            result_signing_private: signing.to_der_private_key(),
+            result_signing_public: signing.to_der_public_key(),
+            result_encryption_private: encryption.to_der_private_key(),
+            result_encryption_public: encryption.to_der_public_key(),
+        }
+    }
+
+    /// Configuration using these keys, with all URLs pointing to
+    /// `https://example.com`
+    pub fn config(&self) -> Config {
+        self.config_with_core_url("https://example.com")
+    }
+
+    /// Configuration using these keys and the core at `core_url`, e.g. that of
+    /// a [`MockCore`]
+    pub fn config_with_core_url(&self, core_url: &str) -> Config {
+        let hs256 = HmacJwsAlgorithm::Hs256;
+        let auth_during_comm_config = AuthDuringCommConfig::builder(
+            core_url,
+            "https://example.com",
+            Box::new(hs256.signer_from_bytes(&self.core_secret).unwrap()),
+            Box::new(hs256.signer_from_bytes(&self.core_secret).unwrap()),
+            "test",
+            Box::new(hs256.verifier_from_bytes(&self.platform_secret).unwrap()),
+            Box::new(hs256.verifier_from_bytes(&self.platform_secret).unwrap()),
+        )
+        .build()
+        .unwrap();
+        Config::builder(
+            "https://example.com",
+            Box::new(
+                ECDH_ES
+                    .decrypter_from_der(&self.result_encryption_private)
+                    .unwrap(),
+            ),
+            Box::new(
+                ES256
+                    .verifier_from_der(&self.result_signing_public)
+                    .unwrap(),
+            ),
+            auth_during_comm_config,
+        )
+        .build()
+        .unwrap()
+    }
+
+    /// `guest_token` signed as the communication platform issues it
+    pub fn sign_guest_token(&self, guest_token: &GuestToken) -> String {
+        guest_token.sign(self.platform_secret.as_bytes()).unwrap()
+    }
+
+    /// `host_token` signed as the communication platform issues it
+    pub fn sign_host_token(&self, host_token: &HostToken) -> String {
+        host_token.sign(self.platform_secret.as_bytes()).unwrap()
+    }
+
+    /// `auth_result` signed and encrypted as the core sends it
+    pub fn auth_result_jwe(&self, auth_result: &AuthResult) -> String {
+        sign_and_encrypt_auth_result(
+            auth_result,
+            &ES256.signer_from_der(&self.result_signing_private).unwrap(),
+            &ECDH_ES
+                .encrypter_from_der(&self.result_encryption_public)
+                .unwrap(),
+        )
+        .unwrap()
+    }
+
+    /// A [`MockCore`] accepting the start requests of the configuration of
+    /// these keys, and sending authentication results it can decrypt
+    #[cfg(feature = "rocket")]
+    pub async fn mock_core(&self) -> Result<MockCore, Error> {
+        MockCore::start(
+            Box::new(
+                HmacJwsAlgorithm::Hs256
+                    .verifier_from_bytes(&self.core_secret)
+                    .unwrap(),
+            ),
+            Box::new(ES256.signer_from_der(&self.result_signing_private).unwrap()),
+            Box::new(
+                ECDH_ES
+                    .encrypter_from_der(&self.result_encryption_public)
+                    .unwrap(),
+            ),
+        )
+        .await
+    }
+}
+
+#[cfg(all(test, feature = "auth_during_comm"))]
+mod tests {
+    use verder_helpen_proto::{AuthResult, AuthStatus};
+
+    use super::{guest_token, host_token_for, session, TestKeys};
+    use crate::{
+        auth_result::decrypt_and_verify,
+        guards::{verify_guest_token, verify_host_token},
+        session::SessionState,
+    };
+
+    #[test]
+    fn test_fixtures() {
+        let keys = TestKeys::generate();
+        let config = keys.config();
+
+        let guest = guest_token().purpose("age").name("Alice").build();
+        let jwt = keys.sign_guest_token(&guest);
+        let verified = verify_guest_token(config.auth_during_comm_config(), &jwt).unwrap();
+        assert_eq!(verified.claims.purpose, "age");
+        assert_eq!(verified.claims.room_id, guest.room_id);
+
+        let jwt = keys.sign_host_token(&host_token_for(&guest));
+        let verified = verify_host_token(config.auth_during_comm_config(), &jwt).unwrap();
+        assert!(verified.claims.authorize_guest(&guest).is_ok());
+
+        let auth_result = AuthResult {
+            status: AuthStatus::Success,
+            attributes: None,
+            session_url: None,
+        };
+        let jwe = keys.auth_result_jwe(&auth_result);
+        assert!(decrypt_and_verify(&jwe, &config).is_ok());
+        assert!(decrypt_and_verify(&jwe, &TestKeys::generate().config()).is_err());
+
+        let session = session(guest)
+            .state(SessionState::AuthCompleted)
+            .auth_result(auth_result)
+            .build();
+        assert!(session.auth_result.is_some());
+    }
+}
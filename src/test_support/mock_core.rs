@@ -0,0 +1,266 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use josekit::{
+    jwe::JweEncrypter,
+    jws::{JwsSigner, JwsVerifier},
+};
+use rocket::{fairing::AdHoc, figment::Figment, http::Status, serde::json::Json, Shutdown, State};
+use serde_json::{json, Value};
+use verder_helpen_jwt::sign_and_encrypt_auth_result;
+use verder_helpen_proto::{AuthResult, StartRequestAuthOnly};
+
+use crate::{error::Error, jwt::JwtError};
+
+/// What the mock core responds to start authentication requests with
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum StartResponse {
+    /// Accept the request, sending the guest to this client URL
+    ClientUrl(String),
+    /// Refuse the request with this status
+    Status(u16),
+}
+
+struct MockCoreState {
+    verifier: Box<dyn JwsVerifier>,
+    response: Mutex<StartResponse>,
+    requests: Mutex<Vec<StartRequestAuthOnly>>,
+    started: AtomicUsize,
+}
+
+#[rocket::post("/start", data = "<jws>")]
+fn start(jws: String, state: &State<Arc<MockCoreState>>) -> (Status, Json<Value>) {
+    let request = josekit::jwt::decode_with_verifier(&jws, state.verifier.as_ref())
+        .ok()
+        .and_then(|(payload, _)| payload.claim("request").cloned())
+        .and_then(|request| serde_json::from_value::<StartRequestAuthOnly>(request).ok());
+    let request = match request {
+        Some(request) => request,
+        None => {
+            return (
+                Status::BadRequest,
+                Json(json!({ "error": "Invalid request" })),
+            )
+        }
+    };
+
+    let response = state.response.lock().unwrap().clone();
+    match response {
+        StartResponse::ClientUrl(client_url) => {
+            state.requests.lock().unwrap().push(request);
+            let id = state.started.fetch_add(1, Ordering::SeqCst);
+            (
+                Status::Ok,
+                Json(json!({
+                    "client_url": client_url,
+                    "session_id": format!("mock-session-{}", id),
+                })),
+            )
+        }
+        StartResponse::Status(status) => (
+            Status::new(status),
+            Json(json!({ "error": "Refused by the mock core" })),
+        ),
+    }
+}
+
+/// Stand-in for the Verder Helpen core, for exercising the whole flow of a
+/// plugin in its tests. It listens on a random local port, accepts start
+/// authentication requests signed with the key of `verifier` at `/start`, and
+/// responds with a canned client URL. Authentication results are sent back
+/// with [`MockCore::complete`]. The server stops when the mock core is
+/// dropped.
+pub struct MockCore {
+    url: String,
+    state: Arc<MockCoreState>,
+    signer: Box<dyn JwsSigner>,
+    encrypter: Box<dyn JweEncrypter>,
+    shutdown: Shutdown,
+}
+
+impl MockCore {
+    /// Start a mock core verifying start requests with `verifier`, and signing
+    /// and encrypting authentication results with `signer` and `encrypter`.
+    /// Must be called from within a Tokio runtime.
+    pub async fn start(
+        verifier: Box<dyn JwsVerifier>,
+        signer: Box<dyn JwsSigner>,
+        encrypter: Box<dyn JweEncrypter>,
+    ) -> Result<MockCore, Error> {
+        let state = Arc::new(MockCoreState {
+            verifier,
+            response: Mutex::new(StartResponse::ClientUrl(
+                "https://core.example.com/auth".to_owned(),
+            )),
+            requests: Mutex::new(vec![]),
+            started: AtomicUsize::new(0),
+        });
+
+        let figment = Figment::from(rocket::Config::debug_default())
+            .merge(("address", "127.0.0.1"))
+            .merge(("port", 0))
+            .merge(("log_level", "off"));
+        let (port_sender, port_receiver) = tokio::sync::oneshot::channel();
+        let rocket = rocket::custom(figment)
+            .mount("/", rocket::routes![start])
+            .manage(state.clone())
+            .attach(AdHoc::on_liftoff("Mock core port", move |rocket| {
+                Box::pin(async move {
+                    let _ = port_sender.send(rocket.config().port);
+                })
+            }))
+            .ignite()
+            .await
+            .map_err(|e| Error::InternalServer(format!("Could not start mock core: {}", e)))?;
+        let shutdown = rocket.shutdown();
+        tokio::spawn(rocket.launch());
+        let port = port_receiver
+            .await
+            .map_err(|_| Error::InternalServer("Mock core did not start".to_owned()))?;
+
+        Ok(MockCore {
+            url: format!("http://127.0.0.1:{}", port),
+            state,
+            signer,
+            encrypter,
+            shutdown,
+        })
+    }
+
+    /// Base URL of the mock core, to be configured as the core URL
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Accept further start requests, sending guests to `client_url`
+    pub fn respond_with_client_url(&self, client_url: impl Into<String>) {
+        *self.state.response.lock().unwrap() = StartResponse::ClientUrl(client_url.into());
+    }
+
+    /// Refuse further start requests with `status`, e.g. to test how errors of
+    /// the core are handled
+    pub fn respond_with_status(&self, status: u16) {
+        *self.state.response.lock().unwrap() = StartResponse::Status(status);
+    }
+
+    /// The accepted start requests received since the last call, in order of
+    /// receipt
+    pub fn take_requests(&self) -> Vec<StartRequestAuthOnly> {
+        std::mem::take(&mut *self.state.requests.lock().unwrap())
+    }
+
+    /// `auth_result` signed and encrypted as the core sends it
+    pub fn auth_result_jwe(&self, auth_result: &AuthResult) -> Result<String, Error> {
+        let jwe = sign_and_encrypt_auth_result(
+            auth_result,
+            self.signer.as_ref(),
+            self.encrypter.as_ref(),
+        )
+        .map_err(JwtError::from)?;
+        Ok(jwe)
+    }
+
+    /// Send `auth_result` to the attribute URL of `request`, as the core does
+    /// once the guest finished authenticating. Returns the status the plugin
+    /// responded with.
+    pub async fn complete(
+        &self,
+        request: &StartRequestAuthOnly,
+        auth_result: &AuthResult,
+    ) -> Result<u16, Error> {
+        let attr_url = request
+            .attr_url
+            .as_deref()
+            .ok_or(Error::BadRequest("Start request without attribute URL"))?;
+        let response = reqwest::Client::new()
+            .post(attr_url)
+            .header(reqwest::header::CONTENT_TYPE, "application/jose")
+            .body(self.auth_result_jwe(auth_result)?)
+            .send()
+            .await
+            .map_err(|e| Error::InternalServer(format!("Could not send auth result: {}", e)))?;
+        Ok(response.status().as_u16())
+    }
+}
+
+impl Drop for MockCore {
+    fn drop(&mut self) {
+        self.shutdown.clone().notify();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use verder_helpen_proto::{AuthResult, AuthStatus, StartRequestAuthOnly};
+
+    use crate::{
+        auth_result::decrypt_and_verify,
+        core_client::{start_authentication, start_authentication_session},
+        test_support::fixtures::TestKeys,
+    };
+
+    fn request(attr_url: &str) -> StartRequestAuthOnly {
+        StartRequestAuthOnly {
+            purpose: "test".to_owned(),
+            auth_method: "irma".to_owned(),
+            comm_url: "https://example.com".to_owned(),
+            attr_url: Some(attr_url.to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_mock_core() {
+        tokio_test::block_on(async {
+            let keys = TestKeys::generate();
+            let mock = keys.mock_core().await.unwrap();
+            let config = keys.config_with_core_url(mock.url());
+
+            let attr_url = format!("{}/auth_result/unknown", mock.url());
+            let session = start_authentication_session(&config, request(&attr_url))
+                .await
+                .unwrap();
+            assert_eq!(
+                session.client_url.client_url,
+                "https://core.example.com/auth"
+            );
+            assert_eq!(session.core_session_id, "mock-session-0");
+            let requests = mock.take_requests();
+            assert_eq!(requests.len(), 1);
+            assert_eq!(requests[0].auth_method, "irma");
+
+            let auth_result = AuthResult {
+                status: AuthStatus::Success,
+                attributes: None,
+                session_url: None,
+            };
+            let jwe = mock.auth_result_jwe(&auth_result).unwrap();
+            assert!(matches!(
+                decrypt_and_verify(&jwe, &config).unwrap().status,
+                AuthStatus::Success
+            ));
+            // The mock core itself serves no attribute URLs
+            assert_eq!(
+                mock.complete(&requests[0], &auth_result).await.unwrap(),
+                404
+            );
+
+            mock.respond_with_status(403);
+            assert!(start_authentication_session(&config, request(&attr_url))
+                .await
+                .is_err());
+            assert!(mock.take_requests().is_empty());
+
+            mock.respond_with_client_url("https://core.example.com/other");
+            let client_url = start_authentication(&config, "test", "irma", &attr_url)
+                .await
+                .unwrap();
+            assert_eq!(client_url.client_url, "https://core.example.com/other");
+            let requests = mock.take_requests();
+            assert_eq!(requests[0].purpose, "test");
+            assert_eq!(requests[0].attr_url.as_deref(), Some(attr_url.as_str()));
+            assert_eq!(requests[0].comm_url, config.external_guest_url());
+        });
+    }
+}
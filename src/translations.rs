@@ -1,12 +1,25 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::Path};
 
-use rocket::{
-    request::{self, FromRequest, Request},
-    serde::Serialize,
+use figment::{
+    providers::{Format, Toml},
+    Figment,
 };
+#[cfg(feature = "rocket")]
+use rocket::request::{self, FromRequest, Request};
+use serde::Serialize;
 use unic_langid::{parser::parse_language_identifier, LanguageIdentifier};
 
-use crate::config::Config;
+#[cfg(feature = "rocket")]
+use crate::config::CurrentConfig;
+#[cfg(feature = "auth_during_comm")]
+use crate::{config::AuthDuringCommConfig, types::SessionDomain};
+use crate::{
+    config::{Config, LanguageTranslations},
+    error::Error,
+};
+
+/// Query parameter overriding the language selected from `Accept-Language`
+pub const LANGUAGE_PARAM: &str = "lang";
 
 #[derive(Serialize, Clone)]
 pub struct Translations {
@@ -14,7 +27,7 @@ pub struct Translations {
     pub translations: HashMap<String, String>,
 }
 
-impl<'r> Translations {
+impl Translations {
     pub fn get(&self, key: &str, fallback: &str) -> String {
         self.translations
             .get(key)
@@ -26,40 +39,61 @@ impl<'r> Translations {
         &self.translations
     }
 
-    pub fn from_request(req: &'r Request<'_>) -> Translations {
-        let config = req
-            .rocket()
-            .state::<Config>()
-            .expect("No configuration found");
+    /// Display name shown to users of the given platform instance and session
+    /// domain. Translations for `display_name_<domain>` and `display_name`
+    /// take precedence over the configured display names.
+    #[cfg(feature = "auth_during_comm")]
+    pub fn display_name(
+        &self,
+        config: &AuthDuringCommConfig,
+        instance: &str,
+        domain: &SessionDomain,
+    ) -> String {
+        self.translations
+            .get(&format!("display_name_{}", domain))
+            .or_else(|| self.translations.get("display_name"))
+            .map(String::as_str)
+            .unwrap_or_else(|| config.instance_display_name_for(instance, domain))
+            .to_owned()
+    }
 
-        // retrieve the accept langiage header
+    /// Translations for the default locale, for output that is not a
+    /// response to a request
+    pub fn for_default_locale(config: &Config) -> Translations {
+        Translations {
+            language: config.default_locale.clone(),
+            translations: config
+                .get_language_translations()
+                .get(&config.default_locale)
+                .expect("No translations specified for the default locale")
+                .clone(),
+        }
+    }
+
+    #[cfg(feature = "rocket")]
+    pub fn from_request(req: &Request<'_>) -> Translations {
+        let config = CurrentConfig::from_rocket(req.rocket()).expect("No configuration found");
+
+        // retrieve the language query parameter and the accept language header
+        let query_language = req.query_value::<&str>(LANGUAGE_PARAM).and_then(Result::ok);
         let raw_accept_language: Option<&str> = req.headers().get("accept-language").next();
 
-        // parse into normalized language identifiers
-        let accept_languages: Vec<_> = raw_accept_language
-            .map(|raw_accept_language| {
-                accept_language::parse(raw_accept_language)
-                    .iter()
-                    .filter_map(|al| parse_language_identifier(al.as_bytes()).ok())
-                    .collect()
-            })
-            .unwrap_or_default();
-
-        // retrieve translations keys and parse into normalized langiage identifiers
-        let keys: Vec<LanguageIdentifier> = config
-            .get_language_translations()
-            .keys()
-            .filter_map(|al| parse_language_identifier(al.as_bytes()).ok())
-            .collect();
-
-        // select the first matching language identifier
-        let first_lang = accept_languages.iter().find(|l| keys.contains(l));
-
-        // fallback to the configured fallback language
-        let lang = match first_lang {
-            Some(li) => li.language.as_str(),
-            None => &config.default_locale,
-        };
+        Self::for_request(&config, query_language, raw_accept_language)
+    }
+
+    /// Translations for a request with the given `lang` query parameter and
+    /// `Accept-Language` header, for use outside of Rocket
+    pub fn for_request(
+        config: &Config,
+        query_language: Option<&str>,
+        raw_accept_language: Option<&str>,
+    ) -> Translations {
+        let lang = select_language(
+            query_language,
+            raw_accept_language,
+            config.get_language_translations(),
+            &config.default_locale,
+        );
 
         Translations {
             language: lang.to_string(),
@@ -72,6 +106,91 @@ impl<'r> Translations {
     }
 }
 
+/// Select the language to use: the requested language if translations exist
+/// for it, else the first language from the `Accept-Language` header with
+/// translations, else the default locale
+fn select_language<'a>(
+    query_language: Option<&str>,
+    raw_accept_language: Option<&str>,
+    translations: &'a LanguageTranslations,
+    default_locale: &'a str,
+) -> &'a str {
+    // retrieve translations keys and parse into normalized langiage identifiers
+    let keys: Vec<(LanguageIdentifier, &str)> = translations
+        .keys()
+        .filter_map(|al| {
+            parse_language_identifier(al.as_bytes())
+                .ok()
+                .map(|li| (li, al.as_str()))
+        })
+        .collect();
+    let find = |language: &LanguageIdentifier| {
+        keys.iter()
+            .find(|(li, _)| li == language)
+            .map(|(_, key)| *key)
+    };
+
+    // an explicitly requested language takes precedence
+    if let Some(key) = query_language
+        .and_then(|ql| parse_language_identifier(ql.as_bytes()).ok())
+        .and_then(|li| find(&li))
+    {
+        return key;
+    }
+
+    // parse into normalized language identifiers
+    let accept_languages: Vec<_> = raw_accept_language
+        .map(|raw_accept_language| {
+            accept_language::parse(raw_accept_language)
+                .iter()
+                .filter_map(|al| parse_language_identifier(al.as_bytes()).ok())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    // select the first matching language identifier, falling back to the
+    // configured default language
+    accept_languages
+        .iter()
+        .find_map(find)
+        .unwrap_or(default_locale)
+}
+
+/// Load per-language string tables from `<locale>.toml` files in `dir`, each
+/// a flat table of keys to translated strings
+pub fn load_translations_dir(dir: &Path) -> Result<LanguageTranslations, Error> {
+    let entries = std::fs::read_dir(dir).map_err(|e| {
+        Error::Config(format!(
+            "Could not read translations directory {}: {}",
+            dir.display(),
+            e
+        ))
+    })?;
+
+    let mut translations = LanguageTranslations::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|e| Error::Config(format!("Could not read translations: {}", e)))?
+            .path();
+        if path.extension().and_then(|e| e.to_str()) != Some("toml") {
+            continue;
+        }
+        let locale = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(locale) => locale.to_string(),
+            None => continue,
+        };
+
+        let strings: HashMap<String, String> =
+            Figment::from(Toml::file(&path)).extract().map_err(|e| {
+                Error::Config(format!("Invalid translations in {}: {}", path.display(), e))
+            })?;
+        translations.insert(locale, strings);
+    }
+
+    Ok(translations)
+}
+
+#[cfg(feature = "rocket")]
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for Translations {
     type Error = ();
@@ -80,3 +199,34 @@ impl<'r> FromRequest<'r> for Translations {
         request::Outcome::Success(Self::from_request(req))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::select_language;
+    use crate::config::LanguageTranslations;
+
+    #[test]
+    fn test_select_language() {
+        let translations: LanguageTranslations = HashMap::from([
+            ("en".to_string(), HashMap::new()),
+            ("nl".to_string(), HashMap::new()),
+        ]);
+
+        assert_eq!(select_language(None, None, &translations, "en"), "en");
+        assert_eq!(
+            select_language(None, Some("nl-NL, nl;q=0.9, en;q=0.5"), &translations, "en"),
+            "nl"
+        );
+        assert_eq!(
+            select_language(Some("en"), Some("nl"), &translations, "nl"),
+            "en"
+        );
+        assert_eq!(
+            select_language(Some("de"), Some("de, nl;q=0.5"), &translations, "en"),
+            "nl"
+        );
+        assert_eq!(select_language(Some("fr"), None, &translations, "en"), "en");
+    }
+}
@@ -3,6 +3,10 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
+mod ids;
+
+pub use self::ids::{AttrId, RoomId, SessionId, MIN_ATTR_ID_LENGTH};
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StartRequest {
     pub purpose: String,
@@ -41,70 +45,461 @@ pub use platform_token::*;
 
 #[cfg(feature = "platform_token")]
 pub mod platform_token {
-    use core::str;
+    use core::{convert::TryFrom, fmt, str};
+    use std::time::{Duration, SystemTime};
 
-    use josekit::{jws::JwsVerifier, jwt::JwtPayloadValidator};
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use josekit::{
+        jws::{alg::hmac::HmacJwsAlgorithm, JwsHeader, JwsSigner, JwsVerifier},
+        jwt::JwtPayload,
+        JoseError,
+    };
     use serde::{de::DeserializeOwned, Deserialize, Serialize};
-    use strum_macros::{Display, EnumString};
+    use strum_macros::Display;
 
-    use crate::jwt::JwtError;
+    use super::{RoomId, SessionId};
+    use crate::{
+        audit::{self, AuditEvent, AuditEventKind},
+        error::Error,
+        jwt::JwtError,
+    };
 
-    #[derive(Deserialize, Debug, Serialize, Display, Clone, EnumString)]
-    #[strum(serialize_all = "snake_case")]
+    /// Domain a platform token was issued for. Domains introduced by new
+    /// communication platforms are kept as [`SessionDomain::Custom`], so
+    /// plugins can handle them without a new release of this crate.
+    #[derive(Deserialize, Debug, Serialize, Clone, PartialEq, Eq, Hash)]
+    #[serde(try_from = "String", into = "String")]
     pub enum SessionDomain {
-        #[serde(rename = "user")]
         User,
-        #[serde(rename = "guest")]
         Guest,
+        /// Any other domain, by its name. Never holds "user" or "guest", which
+        /// parse as the variants above.
+        Custom(String),
+    }
+
+    impl SessionDomain {
+        pub fn as_str(&self) -> &str {
+            match self {
+                SessionDomain::User => "user",
+                SessionDomain::Guest => "guest",
+                SessionDomain::Custom(domain) => domain,
+            }
+        }
+    }
+
+    impl fmt::Display for SessionDomain {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(self.as_str())
+        }
+    }
+
+    impl str::FromStr for SessionDomain {
+        type Err = strum::ParseError;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "user" => Ok(SessionDomain::User),
+                "guest" => Ok(SessionDomain::Guest),
+                "" => Err(strum::ParseError::VariantNotFound),
+                domain => Ok(SessionDomain::Custom(domain.to_owned())),
+            }
+        }
+    }
+
+    impl TryFrom<String> for SessionDomain {
+        type Error = strum::ParseError;
+
+        fn try_from(domain: String) -> Result<Self, Self::Error> {
+            domain.parse()
+        }
+    }
+
+    impl From<SessionDomain> for String {
+        fn from(domain: SessionDomain) -> Self {
+            match domain {
+                SessionDomain::Custom(domain) => domain,
+                domain => domain.as_str().to_owned(),
+            }
+        }
     }
 
-    #[derive(Deserialize, Debug)]
+    #[derive(Deserialize, Serialize, Debug, Clone)]
     pub struct HostToken {
         pub id: String,
         pub domain: SessionDomain,
         #[serde(rename = "roomId")]
-        pub room_id: String,
+        pub room_id: RoomId,
         pub instance: String,
+        /// Expiration time, taken from the `exp` claim of the JWT rather than
+        /// from its payload. Set once the token is verified.
+        #[serde(skip)]
+        pub expires_at: Option<SystemTime>,
+    }
+
+    /// Lifetime of the platform tokens signed by [`GuestToken::sign`] and
+    /// [`HostToken::sign`]
+    pub const SIGNED_TOKEN_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+    impl HostToken {
+        /// Sign the token with HS256 using `secret`, the way communication
+        /// platforms issue them. The token expires at `expires_at`, or after
+        /// [`SIGNED_TOKEN_LIFETIME`] if that is not set.
+        pub fn sign(&self, secret: &[u8]) -> Result<String, JwtError> {
+            self.sign_with(&HmacJwsAlgorithm::Hs256.signer_from_bytes(secret)?, None)
+        }
+
+        /// Sign the token with `signer`, for `audience` if any
+        pub fn sign_with(
+            &self,
+            signer: &dyn JwsSigner,
+            audience: Option<&str>,
+        ) -> Result<String, JwtError> {
+            let expires_at = self
+                .expires_at
+                .unwrap_or_else(|| SystemTime::now() + SIGNED_TOKEN_LIFETIME);
+            sign_platform_token(self, expires_at, audience, signer)
+        }
+
+        /// Whether the token was issued for the room `room_id` of the
+        /// platform instance `instance`. Room IDs are only unique within an
+        /// instance.
+        pub fn is_for_room(&self, room_id: &str, instance: &str) -> bool {
+            self.room_id == room_id && self.instance == instance
+        }
+
+        /// Fail with `Error::Forbidden` unless the token was issued for the
+        /// room `room_id` of the platform instance `instance`
+        pub fn authorize_room(&self, room_id: &str, instance: &str) -> Result<(), Error> {
+            if !self.is_for_room(room_id, instance) {
+                return Err(Error::Forbidden(
+                    "Host is not authorized for this room".to_owned(),
+                ));
+            }
+            Ok(())
+        }
+
+        /// Fail with `Error::Forbidden` unless the token belongs to a host in
+        /// the room and instance of the guest holding `guest_token`
+        pub fn authorize_guest(&self, guest_token: &GuestToken) -> Result<(), Error> {
+            if self.domain != SessionDomain::User {
+                return Err(Error::Forbidden(
+                    "Token does not belong to a host".to_owned(),
+                ));
+            }
+            if guest_token.room_id != self.room_id || guest_token.instance != self.instance {
+                return Err(Error::Forbidden(
+                    "Host is not authorized for this session".to_owned(),
+                ));
+            }
+            Ok(())
+        }
     }
 
     #[derive(Deserialize, Serialize, Debug, Clone)]
     pub struct GuestToken {
-        pub id: String,
+        pub id: SessionId,
         pub domain: SessionDomain,
         #[serde(rename = "redirectUrl")]
         pub redirect_url: String,
         pub name: String,
         #[serde(rename = "roomId")]
-        pub room_id: String,
+        pub room_id: RoomId,
         pub instance: String,
         pub purpose: String,
     }
 
+    impl GuestToken {
+        /// Sign the token with HS256 using `secret`, the way communication
+        /// platforms issue them. The token is valid for
+        /// [`SIGNED_TOKEN_LIFETIME`].
+        pub fn sign(&self, secret: &[u8]) -> Result<String, JwtError> {
+            self.sign_with(&HmacJwsAlgorithm::Hs256.signer_from_bytes(secret)?, None)
+        }
+
+        /// Sign the token with `signer`, for `audience` if any
+        pub fn sign_with(
+            &self,
+            signer: &dyn JwsSigner,
+            audience: Option<&str>,
+        ) -> Result<String, JwtError> {
+            let expires_at = SystemTime::now() + SIGNED_TOKEN_LIFETIME;
+            sign_platform_token(self, expires_at, audience, signer)
+        }
+    }
+
+    /// Reason a platform token was rejected
+    #[derive(Serialize, Debug, Display, Clone, Copy, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    #[strum(serialize_all = "snake_case")]
+    pub enum TokenFailureReason {
+        /// The signature did not match the configured secret
+        BadSignature,
+        /// The token is past its expiration time
+        Expired,
+        /// The token was issued for another audience
+        WrongAudience,
+        /// One of the other registered claims was rejected
+        InvalidClaims,
+        /// The token could not be decoded, or did not contain a valid payload
+        Malformed,
+    }
+
+    /// Claims a platform token must carry on top of a valid signature.
+    /// Registered claims that are present are always validated.
+    #[derive(Debug, Default, Clone)]
+    pub struct ClaimRequirements {
+        /// Reject tokens without an expiration time
+        pub require_exp: bool,
+        /// Reject tokens without an issue time, or issued in the future
+        pub require_iat: bool,
+        /// Reject tokens not issued for this audience
+        pub audience: Option<String>,
+        /// How far the clock of the issuer may run ahead of or behind ours.
+        /// Tokens are accepted this long after they expire, and when issued
+        /// this far in the future.
+        pub clock_skew: Duration,
+        /// Reject tokens issued longer ago than this, or without an issue
+        /// time
+        pub max_age: Option<Duration>,
+    }
+
+    /// Platform token payload together with the registered claims needed to
+    /// detect replays
+    #[derive(Debug)]
+    pub struct VerifiedToken<T> {
+        pub claims: T,
+        pub jwt_id: Option<String>,
+        /// Time until which the token is accepted: its expiration time plus
+        /// the allowed clock skew
+        pub expires_at: Option<std::time::SystemTime>,
+    }
+
     pub trait FromPlatformJwt: Sized + DeserializeOwned {
+        /// Token type used to tag verification failures
+        const TOKEN_TYPE: &'static str = "platform";
+
+        /// Check the claims in the payload, once the signature and the
+        /// registered claims are verified
+        fn validate(&self) -> Result<(), JwtError> {
+            Ok(())
+        }
+
+        /// Keep the expiration time of the verified token, for token types
+        /// exposing it
+        fn set_expires_at(&mut self, _expires_at: Option<SystemTime>) {}
+
         fn from_platform_jwt(jwt: &str, verifier: &dyn JwsVerifier) -> Result<Self, JwtError> {
-            from_platform_jwt_inner::<Self>(jwt, verifier, std::time::SystemTime::now())
+            Self::from_platform_jwt_with(jwt, verifier, &ClaimRequirements::default())
+        }
+
+        /// Verify and decode a platform token, enforcing `requirements`
+        fn from_platform_jwt_with(
+            jwt: &str,
+            verifier: &dyn JwsVerifier,
+            requirements: &ClaimRequirements,
+        ) -> Result<Self, JwtError> {
+            from_platform_jwt_inner::<Self>(
+                jwt,
+                verifier,
+                requirements,
+                std::time::SystemTime::now(),
+            )
         }
     }
 
-    pub(super) fn from_platform_jwt_inner<T: DeserializeOwned>(
+    pub(super) fn from_platform_jwt_inner<T: FromPlatformJwt>(
         jwt: &str,
         verifier: &dyn JwsVerifier,
+        requirements: &ClaimRequirements,
         time: std::time::SystemTime,
     ) -> Result<T, JwtError> {
-        let (payload, _) = josekit::jwt::decode_with_verifier(jwt, verifier)?;
-        let mut validator = JwtPayloadValidator::new();
-        validator.set_base_time(time);
-        validator.validate(&payload)?;
-        let claim = payload
-            .claim("payload")
-            .ok_or(JwtError::InvalidStructure("payload"))?;
-        let payload = serde_json::from_value(claim.clone())?;
-        Ok(payload)
+        verify_platform_token(jwt, verifier, requirements, time).map(|token| token.claims)
+    }
+
+    /// Verify and decode a platform token, keeping its registered claims
+    pub fn verify_platform_token<T: FromPlatformJwt>(
+        jwt: &str,
+        verifier: &dyn JwsVerifier,
+        requirements: &ClaimRequirements,
+        time: std::time::SystemTime,
+    ) -> Result<VerifiedToken<T>, JwtError> {
+        verify_platform_jwt(jwt, verifier, requirements, time)
+            .and_then(|mut token: VerifiedToken<T>| {
+                token
+                    .claims
+                    .validate()
+                    .map_err(|e| (e, TokenFailureReason::InvalidClaims))?;
+                token.claims.set_expires_at(token.expires_at);
+                // Replays must be detected for as long as the token is accepted
+                token.expires_at = token
+                    .expires_at
+                    .map(|expires_at| expires_at + requirements.clock_skew);
+                Ok(token)
+            })
+            .map_err(|(e, reason)| {
+                #[cfg(feature = "metrics")]
+                crate::metrics::token_verification_failed(T::TOKEN_TYPE, reason);
+                audit::record(
+                    AuditEvent::new(AuditEventKind::TokenVerificationFailed).detail(format!(
+                        "{} token: {}",
+                        T::TOKEN_TYPE,
+                        reason
+                    )),
+                );
+                e
+            })
+    }
+
+    /// Platform instance a platform token claims to be issued by, read
+    /// without verifying the token, to select the keys it is verified with.
+    /// Must not be relied on for anything else.
+    pub fn unverified_instance(jwt: &str) -> Option<String> {
+        let payload = URL_SAFE_NO_PAD.decode(jwt.split('.').nth(1)?).ok()?;
+        let payload: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+        payload
+            .get("payload")?
+            .get("instance")?
+            .as_str()
+            .map(str::to_owned)
+    }
+
+    /// Sign `claims` as the payload of a platform token, issued now and
+    /// expiring at `expires_at`
+    fn sign_platform_token<T: Serialize>(
+        claims: &T,
+        expires_at: SystemTime,
+        audience: Option<&str>,
+        signer: &dyn JwsSigner,
+    ) -> Result<String, JwtError> {
+        let mut header = JwsHeader::new();
+        header.set_token_type("JWT");
+        if let Some(kid) = signer.key_id() {
+            header.set_key_id(kid);
+        }
+
+        let mut payload = JwtPayload::new();
+        payload.set_claim("payload", Some(serde_json::to_value(claims)?))?;
+        payload.set_issued_at(&SystemTime::now());
+        payload.set_expires_at(&expires_at);
+        if let Some(audience) = audience {
+            payload.set_audience(vec![audience]);
+        }
+        Ok(josekit::jwt::encode_with_signer(&payload, &header, signer)?)
     }
 
-    impl FromPlatformJwt for GuestToken {}
+    pub(super) fn verify_platform_jwt<T: DeserializeOwned>(
+        jwt: &str,
+        verifier: &dyn JwsVerifier,
+        requirements: &ClaimRequirements,
+        time: std::time::SystemTime,
+    ) -> Result<VerifiedToken<T>, (JwtError, TokenFailureReason)> {
+        let (payload, _) = josekit::jwt::decode_with_verifier(jwt, verifier).map_err(|e| {
+            let reason = match e {
+                JoseError::InvalidSignature(_) => TokenFailureReason::BadSignature,
+                _ => TokenFailureReason::Malformed,
+            };
+            (JwtError::from(e), reason)
+        })?;
+        validate_platform_jwt(&payload, requirements, time)?;
+        let claim = payload.claim("payload").ok_or((
+            JwtError::InvalidStructure("payload"),
+            TokenFailureReason::Malformed,
+        ))?;
+        let claims = serde_json::from_value(claim.clone())
+            .map_err(|e| (JwtError::from(e), TokenFailureReason::Malformed))?;
+        Ok(VerifiedToken {
+            claims,
+            jwt_id: payload.jwt_id().map(str::to_owned),
+            expires_at: payload.expires_at(),
+        })
+    }
 
-    impl FromPlatformJwt for HostToken {}
+    /// Check the registered claims of a platform token at `time`, allowing
+    /// for the configured clock skew
+    fn validate_platform_jwt(
+        payload: &JwtPayload,
+        requirements: &ClaimRequirements,
+        time: std::time::SystemTime,
+    ) -> Result<(), (JwtError, TokenFailureReason)> {
+        let skew = requirements.clock_skew;
+        let expires_at = payload.expires_at();
+        let issued_at = payload.issued_at();
+        if requirements.require_exp && expires_at.is_none() {
+            return Err((
+                JwtError::InvalidStructure("exp"),
+                TokenFailureReason::InvalidClaims,
+            ));
+        }
+        if (requirements.require_iat || requirements.max_age.is_some()) && issued_at.is_none() {
+            return Err((
+                JwtError::InvalidStructure("iat"),
+                TokenFailureReason::InvalidClaims,
+            ));
+        }
+
+        if expires_at.is_some_and(|expires_at| expires_at + skew <= time) {
+            return Err((JwtError::InvalidClaim("exp"), TokenFailureReason::Expired));
+        }
+        if let (Some(max_age), Some(issued_at)) = (requirements.max_age, issued_at) {
+            if issued_at + max_age + skew <= time {
+                return Err((JwtError::InvalidClaim("iat"), TokenFailureReason::Expired));
+            }
+        }
+        if payload
+            .not_before()
+            .is_some_and(|not_before| not_before > time + skew)
+        {
+            return Err((
+                JwtError::InvalidClaim("nbf"),
+                TokenFailureReason::InvalidClaims,
+            ));
+        }
+        if requirements.require_iat && issued_at.is_some_and(|issued_at| issued_at > time + skew) {
+            return Err((
+                JwtError::InvalidClaim("iat"),
+                TokenFailureReason::InvalidClaims,
+            ));
+        }
+        if let Some(audience) = requirements.audience.as_deref() {
+            if !payload
+                .audience()
+                .is_some_and(|audiences| audiences.contains(&audience))
+            {
+                return Err((
+                    JwtError::InvalidClaim("aud"),
+                    TokenFailureReason::WrongAudience,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    impl FromPlatformJwt for GuestToken {
+        const TOKEN_TYPE: &'static str = "guest";
+    }
+
+    impl FromPlatformJwt for HostToken {
+        const TOKEN_TYPE: &'static str = "host";
+
+        /// Host tokens must identify the host and its instance, and must not
+        /// be issued to guests
+        fn validate(&self) -> Result<(), JwtError> {
+            if self.id.is_empty() {
+                return Err(JwtError::InvalidStructure("id"));
+            }
+            if self.instance.is_empty() {
+                return Err(JwtError::InvalidStructure("instance"));
+            }
+            if self.domain == SessionDomain::Guest {
+                return Err(JwtError::InvalidStructure("domain"));
+            }
+            Ok(())
+        }
+
+        fn set_expires_at(&mut self, expires_at: Option<SystemTime>) {
+            self.expires_at = expires_at;
+        }
+    }
 }
 
 #[cfg(test)]
@@ -161,6 +556,7 @@ mod tests {
         } = super::platform_token::from_platform_jwt_inner::<GuestToken>(
             GUEST_TOKEN,
             &guest_validator,
+            &Default::default(),
             std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1640000000),
         )
         .expect("Error verifying guest token");
@@ -176,6 +572,7 @@ mod tests {
             super::platform_token::from_platform_jwt_inner::<GuestToken>(
                 GUEST_TOKEN,
                 &guest_validator,
+                &Default::default(),
                 std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1660000000),
             )
             .is_err()
@@ -186,9 +583,11 @@ mod tests {
             domain,
             room_id,
             instance,
+            expires_at,
         } = super::platform_token::from_platform_jwt_inner::<HostToken>(
             HOST_TOKEN,
             &host_validator,
+            &Default::default(),
             std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1620000000),
         )
         .expect("Error verifying host token");
@@ -197,12 +596,305 @@ mod tests {
         assert!(matches!(domain, SessionDomain::User));
         assert_eq!(room_id, "16");
         assert_eq!(instance, "tweedegolf.nl");
+        assert_eq!(
+            expires_at,
+            Some(std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1620209905))
+        );
 
         assert!(super::platform_token::from_platform_jwt_inner::<HostToken>(
             HOST_TOKEN,
             &host_validator,
+            &Default::default(),
             std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1630000000),
         )
         .is_err());
     }
+
+    #[test]
+    #[cfg(feature = "platform_token")]
+    fn claim_requirements_test() {
+        use josekit::jwt::JwtPayload;
+
+        use super::platform_token::{ClaimRequirements, HostToken};
+
+        let signer = HmacJwsAlgorithm::Hs256
+            .signer_from_bytes(HOST_SECRET)
+            .unwrap();
+        let host_validator = HmacJwsAlgorithm::Hs256
+            .verifier_from_bytes(HOST_SECRET)
+            .unwrap();
+
+        let mut payload = JwtPayload::new();
+        payload
+            .set_claim(
+                "payload",
+                Some(serde_json::json!({
+                    "domain": "user",
+                    "id": "1",
+                    "instance": "tweedegolf.nl",
+                    "roomId": "16",
+                })),
+            )
+            .unwrap();
+        let token =
+            josekit::jwt::encode_with_signer(&payload, &josekit::jws::JwsHeader::new(), &signer)
+                .unwrap();
+
+        assert!(super::platform_token::from_platform_jwt_inner::<HostToken>(
+            &token,
+            &host_validator,
+            &Default::default(),
+            std::time::SystemTime::now(),
+        )
+        .is_ok());
+
+        let (_, reason) = super::platform_token::verify_platform_jwt::<HostToken>(
+            &token,
+            &host_validator,
+            &ClaimRequirements {
+                require_exp: true,
+                ..Default::default()
+            },
+            std::time::SystemTime::now(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            reason,
+            super::platform_token::TokenFailureReason::InvalidClaims
+        );
+
+        let now = std::time::SystemTime::now();
+        payload.set_audience(vec!["other-plugin"]);
+        payload.set_issued_at(&(now + std::time::Duration::from_secs(60)));
+        let token =
+            josekit::jwt::encode_with_signer(&payload, &josekit::jws::JwsHeader::new(), &signer)
+                .unwrap();
+
+        let (_, reason) = super::platform_token::verify_platform_jwt::<HostToken>(
+            &token,
+            &host_validator,
+            &ClaimRequirements {
+                audience: Some("comm-plugin".to_owned()),
+                ..Default::default()
+            },
+            now,
+        )
+        .unwrap_err();
+        assert_eq!(
+            reason,
+            super::platform_token::TokenFailureReason::WrongAudience
+        );
+
+        let (_, reason) = super::platform_token::verify_platform_jwt::<HostToken>(
+            &token,
+            &host_validator,
+            &ClaimRequirements {
+                require_iat: true,
+                audience: Some("other-plugin".to_owned()),
+                ..Default::default()
+            },
+            now,
+        )
+        .unwrap_err();
+        assert_eq!(
+            reason,
+            super::platform_token::TokenFailureReason::InvalidClaims
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "platform_token")]
+    fn clock_skew_test() {
+        use std::time::Duration;
+
+        use josekit::jwt::JwtPayload;
+
+        use super::platform_token::{ClaimRequirements, HostToken, TokenFailureReason};
+
+        let signer = HmacJwsAlgorithm::Hs256
+            .signer_from_bytes(HOST_SECRET)
+            .unwrap();
+        let host_validator = HmacJwsAlgorithm::Hs256
+            .verifier_from_bytes(HOST_SECRET)
+            .unwrap();
+
+        let now = std::time::SystemTime::now();
+        let mut payload = JwtPayload::new();
+        payload
+            .set_claim(
+                "payload",
+                Some(serde_json::json!({
+                    "domain": "user",
+                    "id": "1",
+                    "instance": "tweedegolf.nl",
+                    "roomId": "16",
+                })),
+            )
+            .unwrap();
+        payload.set_issued_at(&(now - Duration::from_secs(120)));
+        payload.set_expires_at(&(now - Duration::from_secs(10)));
+        let token =
+            josekit::jwt::encode_with_signer(&payload, &josekit::jws::JwsHeader::new(), &signer)
+                .unwrap();
+
+        let (_, reason) = super::platform_token::verify_platform_jwt::<HostToken>(
+            &token,
+            &host_validator,
+            &Default::default(),
+            now,
+        )
+        .unwrap_err();
+        assert_eq!(reason, TokenFailureReason::Expired);
+
+        let tolerant = ClaimRequirements {
+            clock_skew: Duration::from_secs(30),
+            ..Default::default()
+        };
+        let verified = super::platform_token::verify_platform_token::<HostToken>(
+            &token,
+            &host_validator,
+            &tolerant,
+            now,
+        )
+        .unwrap();
+        // Replays are detected until the end of the tolerance
+        assert!(verified.expires_at.unwrap() > now);
+
+        let (_, reason) = super::platform_token::verify_platform_jwt::<HostToken>(
+            &token,
+            &host_validator,
+            &ClaimRequirements {
+                max_age: Some(Duration::from_secs(60)),
+                ..tolerant
+            },
+            now,
+        )
+        .unwrap_err();
+        assert_eq!(reason, TokenFailureReason::Expired);
+    }
+
+    #[test]
+    #[cfg(feature = "platform_token")]
+    fn token_failure_reason_test() {
+        use super::platform_token::{GuestToken, TokenFailureReason};
+
+        let guest_validator = HmacJwsAlgorithm::Hs256
+            .verifier_from_bytes(GUEST_SECRET)
+            .unwrap();
+        let host_validator = HmacJwsAlgorithm::Hs256
+            .verifier_from_bytes(HOST_SECRET)
+            .unwrap();
+
+        let (_, reason) = super::platform_token::verify_platform_jwt::<GuestToken>(
+            GUEST_TOKEN,
+            &guest_validator,
+            &Default::default(),
+            std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1660000000),
+        )
+        .unwrap_err();
+        assert_eq!(reason, TokenFailureReason::Expired);
+
+        let (_, reason) = super::platform_token::verify_platform_jwt::<GuestToken>(
+            GUEST_TOKEN,
+            &host_validator,
+            &Default::default(),
+            std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1640000000),
+        )
+        .unwrap_err();
+        assert_eq!(reason, TokenFailureReason::BadSignature);
+
+        let (_, reason) = super::platform_token::verify_platform_jwt::<GuestToken>(
+            "not-a-token",
+            &guest_validator,
+            &Default::default(),
+            std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1640000000),
+        )
+        .unwrap_err();
+        assert_eq!(reason, TokenFailureReason::Malformed);
+    }
+
+    #[test]
+    #[cfg(feature = "platform_token")]
+    fn test_session_domain() {
+        use crate::types::SessionDomain;
+
+        let domain: SessionDomain = serde_json::from_str("\"guest\"").unwrap();
+        assert_eq!(domain, SessionDomain::Guest);
+        let domain: SessionDomain = serde_json::from_str("\"kiosk\"").unwrap();
+        assert_eq!(domain, SessionDomain::Custom("kiosk".to_owned()));
+        assert_eq!(serde_json::to_string(&domain).unwrap(), "\"kiosk\"");
+        assert_eq!(domain.to_string(), "kiosk");
+        assert_eq!(
+            "user".parse::<SessionDomain>().unwrap(),
+            SessionDomain::User
+        );
+        assert!("".parse::<SessionDomain>().is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "platform_token")]
+    fn test_host_token() {
+        use super::platform_token::{FromPlatformJwt, GuestToken, HostToken};
+        use crate::{
+            error::Error,
+            types::{RoomId, SessionDomain, SessionId},
+        };
+
+        let host_validator = HmacJwsAlgorithm::Hs256
+            .verifier_from_bytes(HOST_SECRET)
+            .unwrap();
+
+        let host = HostToken {
+            id: "1".to_owned(),
+            domain: SessionDomain::User,
+            room_id: RoomId::new("16").unwrap(),
+            instance: "tweedegolf.nl".to_owned(),
+            expires_at: None,
+        };
+        let jwt = host.sign(HOST_SECRET.as_bytes()).unwrap();
+        let verified = HostToken::from_platform_jwt(&jwt, &host_validator).unwrap();
+        assert_eq!(verified.room_id, host.room_id);
+        assert!(verified.expires_at.is_some());
+
+        // Guests can't pass off their token as a host token
+        let guest_as_host = HostToken {
+            domain: SessionDomain::Guest,
+            ..host.clone()
+        };
+        let jwt = guest_as_host.sign(HOST_SECRET.as_bytes()).unwrap();
+        assert!(HostToken::from_platform_jwt(&jwt, &host_validator).is_err());
+
+        assert!(host.authorize_room("16", "tweedegolf.nl").is_ok());
+        assert!(matches!(
+            host.authorize_room("17", "tweedegolf.nl"),
+            Err(Error::Forbidden(_))
+        ));
+        assert!(matches!(
+            host.authorize_room("16", "example.com"),
+            Err(Error::Forbidden(_))
+        ));
+
+        let mut guest = GuestToken {
+            id: SessionId::new("101").unwrap(),
+            domain: SessionDomain::Guest,
+            redirect_url: "https://tweedegolf.nl".to_owned(),
+            name: "Guest".to_owned(),
+            room_id: RoomId::new("16").unwrap(),
+            instance: "tweedegolf.nl".to_owned(),
+            purpose: "test".to_owned(),
+        };
+        assert!(host.authorize_guest(&guest).is_ok());
+        let guest_validator = HmacJwsAlgorithm::Hs256
+            .verifier_from_bytes(GUEST_SECRET)
+            .unwrap();
+        let jwt = guest.sign(GUEST_SECRET.as_bytes()).unwrap();
+        let verified = GuestToken::from_platform_jwt(&jwt, &guest_validator).unwrap();
+        assert_eq!(verified.id, guest.id);
+
+        guest.instance = "example.com".to_owned();
+        assert!(matches!(
+            host.authorize_guest(&guest),
+            Err(Error::Forbidden(_))
+        ));
+    }
 }
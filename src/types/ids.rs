@@ -0,0 +1,214 @@
+use std::{convert::TryFrom, fmt, ops::Deref, str::FromStr};
+
+use rand::{rngs::OsRng, Rng};
+#[cfg(feature = "rocket")]
+use rocket::request::FromParam;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// Maximum length in bytes of any identifier
+const MAX_ID_LENGTH: usize = 256;
+
+/// Minimum length of attribute IDs, which must be unguessable as they are the
+/// only thing authorizing the delivery of an authentication result
+pub const MIN_ATTR_ID_LENGTH: usize = 32;
+
+/// Minimum number of distinct characters in an attribute ID, refusing IDs
+/// such as "aaaa…" that are long enough but obviously not random
+const MIN_ATTR_ID_DISTINCT_CHARS: usize = 8;
+
+/// Characters of generated attribute IDs: the URL-safe base64 alphabet
+const ATTR_ID_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Length of generated attribute IDs, holding 258 bits of randomness
+const GENERATED_ATTR_ID_LENGTH: usize = 43;
+
+/// Room and session IDs are chosen by the communication platform, so anything
+/// printable of reasonable length is accepted
+fn is_valid_platform_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_ID_LENGTH && !id.chars().any(char::is_control)
+}
+
+/// Attribute IDs end up in URLs, and must be long and random
+fn is_valid_attr_id(id: &str) -> bool {
+    if id.len() < MIN_ATTR_ID_LENGTH
+        || id.len() > MAX_ID_LENGTH
+        || !id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return false;
+    }
+
+    let mut chars: Vec<char> = id.chars().collect();
+    chars.sort_unstable();
+    chars.dedup();
+    chars.len() >= MIN_ATTR_ID_DISTINCT_CHARS
+}
+
+/// Define a validated string identifier. The identifiers are stored as text,
+/// and read back from the session database without validating them again.
+macro_rules! string_id {
+    ($(#[$attr:meta])* $name:ident, $is_valid:path, $invalid:literal) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+        #[serde(try_from = "String", into = "String")]
+        #[cfg_attr(
+            feature = "sessions",
+            derive(postgres_types::ToSql, postgres_types::FromSql),
+            postgres(transparent)
+        )]
+        pub struct $name(String);
+
+        impl $name {
+            /// Validate `id`, failing with `Error::BadRequest` if it is not a
+            /// valid identifier of this kind
+            pub fn new(id: impl Into<String>) -> Result<Self, Error> {
+                let id = id.into();
+                if $is_valid(&id) {
+                    Ok($name(id))
+                } else {
+                    Err(Error::BadRequest($invalid))
+                }
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl Deref for $name {
+            type Target = str;
+
+            fn deref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = Error;
+
+            fn from_str(id: &str) -> Result<Self, Error> {
+                $name::new(id)
+            }
+        }
+
+        impl TryFrom<String> for $name {
+            type Error = Error;
+
+            fn try_from(id: String) -> Result<Self, Error> {
+                $name::new(id)
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(id: $name) -> Self {
+                id.0
+            }
+        }
+
+        impl PartialEq<str> for $name {
+            fn eq(&self, other: &str) -> bool {
+                self.0 == other
+            }
+        }
+
+        impl PartialEq<&str> for $name {
+            fn eq(&self, other: &&str) -> bool {
+                self.0 == *other
+            }
+        }
+
+        #[cfg(feature = "rocket")]
+        impl<'a> FromParam<'a> for $name {
+            type Error = Error;
+
+            fn from_param(param: &'a str) -> Result<Self, Error> {
+                $name::new(param)
+            }
+        }
+    };
+}
+
+string_id!(
+    /// ID of the room of a communication platform, shared by a host and the
+    /// guests they talk to
+    RoomId,
+    is_valid_platform_id,
+    "Invalid room ID"
+);
+
+string_id!(
+    /// ID of a session, taken from the ID in the guest token it was created
+    /// for
+    SessionId,
+    is_valid_platform_id,
+    "Invalid session ID"
+);
+
+string_id!(
+    /// Unguessable ID with which the core delivers the authentication result
+    /// of a session. Must be at least [`MIN_ATTR_ID_LENGTH`] URL-safe
+    /// characters.
+    AttrId,
+    is_valid_attr_id,
+    "Invalid attribute ID"
+);
+
+impl AttrId {
+    /// Generate an attribute ID from the random number generator of the
+    /// operating system. Use this rather than sequential IDs or UUIDs, which
+    /// would let anyone guessing them inject authentication results.
+    pub fn generate() -> Self {
+        loop {
+            let id: String = (0..GENERATED_ATTR_ID_LENGTH)
+                .map(|_| char::from(ATTR_ID_ALPHABET[OsRng.gen_range(0..ATTR_ID_ALPHABET.len())]))
+                .collect();
+            // Retrying is practically never needed, but keeps the result valid
+            if is_valid_attr_id(&id) {
+                return AttrId(id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AttrId, RoomId, SessionId};
+    use crate::util::random_string;
+
+    #[test]
+    fn test_ids() {
+        assert!(RoomId::new("16").is_ok());
+        assert!(RoomId::new("").is_err());
+        assert!(SessionId::new("101-1010\n").is_err());
+
+        let attr_id = random_string(32);
+        assert_eq!(AttrId::new(attr_id.clone()).unwrap(), attr_id.as_str());
+        assert!(AttrId::new("too short").is_err());
+        assert!(AttrId::new("a".repeat(32)).is_err());
+        assert!(AttrId::new(format!("{}/..", attr_id)).is_err());
+
+        let generated = AttrId::generate();
+        assert_eq!(generated.len(), 43);
+        assert!(AttrId::new(generated.as_str()).is_ok());
+        assert_ne!(generated, AttrId::generate());
+
+        let room_id: RoomId = serde_json::from_str("\"16\"").unwrap();
+        assert_eq!(serde_json::to_string(&room_id).unwrap(), "\"16\"");
+        assert!(serde_json::from_str::<AttrId>("\"short\"").is_err());
+    }
+}
@@ -1,5 +1,14 @@
+use std::{fmt::Debug, sync::RwLock};
+
+use josekit::jws::{alg::hmac::HmacJwsAlgorithm, JwsSigner};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 
+use crate::{error::Error, jwt::JwtError};
+
+lazy_static! {
+    static ref LOG_PSEUDONYMIZER: RwLock<Option<Pseudonymizer>> = RwLock::new(None);
+}
+
 /// Generate a random string for use as unique identification code
 pub fn random_string(len: usize) -> String {
     thread_rng()
@@ -8,3 +17,82 @@ pub fn random_string(len: usize) -> String {
         .map(char::from)
         .collect()
 }
+
+/// Characters used in join codes, leaving out those that are easily confused
+/// when typed over (0/O, 1/I/L)
+const JOIN_CODE_ALPHABET: &[u8] = b"23456789ABCDEFGHJKMNPQRSTUVWXYZ";
+
+/// Generate a random, human-typeable code for joining a session
+pub fn random_join_code(len: usize) -> String {
+    let mut rng = thread_rng();
+    (0..len)
+        .map(|_| char::from(JOIN_CODE_ALPHABET[rng.gen_range(0..JOIN_CODE_ALPHABET.len())]))
+        .collect()
+}
+
+/// Replaces identifiers with pseudonyms: the HMAC-SHA256 of the identifier
+/// under a configured salt. Pseudonyms are stable for a given salt, so
+/// sessions of the same room can still be related across exports and log
+/// lines, but can't be traced back to the identifiers without the salt.
+pub struct Pseudonymizer {
+    signer: Box<dyn JwsSigner>,
+}
+
+impl Clone for Pseudonymizer {
+    fn clone(&self) -> Self {
+        Pseudonymizer {
+            signer: self.signer.box_clone(),
+        }
+    }
+}
+
+impl Debug for Pseudonymizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pseudonymizer").finish_non_exhaustive()
+    }
+}
+
+impl Pseudonymizer {
+    pub fn from_salt(salt: &[u8]) -> Result<Self, Error> {
+        let signer = HmacJwsAlgorithm::Hs256
+            .signer_from_bytes(salt)
+            .map_err(JwtError::from)?;
+        Ok(Pseudonymizer {
+            signer: Box::new(signer),
+        })
+    }
+
+    /// Pseudonym of `id`, as 64 hexadecimal characters
+    pub fn pseudonym(&self, id: &str) -> Result<String, Error> {
+        let mac = self.signer.sign(id.as_bytes()).map_err(JwtError::from)?;
+        Ok(mac.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+}
+
+/// Pseudonymize the identifiers recorded in `tracing` spans with
+/// `pseudonymizer`, or leave them out if `None`. Installed from the configured
+/// `log_salt` by [`crate::logging::JsonLogFairing`].
+pub fn set_log_pseudonymizer(pseudonymizer: Option<Pseudonymizer>) {
+    *LOG_PSEUDONYMIZER.write().unwrap() = pseudonymizer;
+}
+
+/// Pseudonym of an identifier for use in `tracing` spans, so that a single
+/// session can be followed without logging the identifier itself. `None`
+/// unless a log pseudonymizer is installed.
+pub fn log_pseudonym(id: &str) -> Option<String> {
+    LOG_PSEUDONYMIZER
+        .read()
+        .unwrap()
+        .as_ref()
+        .and_then(|pseudonymizer| pseudonymizer.pseudonym(id).ok())
+}
+
+/// Report an error that can't be returned to a caller, through `tracing` if
+/// the `tracing` feature is enabled and on stderr otherwise
+#[cfg(feature = "sessions")]
+pub(crate) fn log_error(message: std::fmt::Arguments<'_>) {
+    #[cfg(feature = "tracing")]
+    tracing::error!("{}", message);
+    #[cfg(not(feature = "tracing"))]
+    eprintln!("{}", message);
+}
@@ -0,0 +1,150 @@
+#[cfg(feature = "rocket")]
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use josekit::jws::JwsSigner;
+#[cfg(feature = "rocket")]
+use rocket::fairing::Fairing;
+use serde::Serialize;
+
+#[cfg(feature = "rocket")]
+use crate::sinks::sink_fairing;
+use crate::{
+    auth_result::StoredAuthResult, error::Error, jwt::sign_result_notification, session::Session,
+    sinks::ResultSink,
+};
+
+/// Number of attempts at delivering a notification before giving up
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry, doubled for every next retry
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Timeout for a single delivery attempt
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Authentication result registered with a session, as sent to the webhook of
+/// the host system
+#[derive(Debug, Clone, Serialize)]
+pub struct ResultNotification {
+    pub room_id: String,
+    pub session_id: String,
+    pub purpose: String,
+    pub auth_result: StoredAuthResult,
+}
+
+impl ResultNotification {
+    /// Notification for `auth_result`, registered with `session`
+    pub fn new(session: &Session, auth_result: StoredAuthResult) -> Self {
+        ResultNotification {
+            room_id: session.guest_token.room_id.to_string(),
+            session_id: session.guest_token.id.to_string(),
+            purpose: session.guest_token.purpose.clone(),
+            auth_result,
+        }
+    }
+}
+
+/// Delay before retrying after failed attempt number `attempt`, counting from
+/// one
+fn retry_delay(attempt: u32) -> Duration {
+    INITIAL_RETRY_DELAY * (1 << (attempt - 1).min(16))
+}
+
+/// POST a signed notification to `url`, retrying failed attempts with
+/// exponential backoff. Any successful status counts as delivered.
+async fn post_with_retries(
+    client: &reqwest::Client,
+    url: &str,
+    signed: String,
+) -> Result<(), Error> {
+    let mut attempt = 1;
+    loop {
+        let result = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/jwt")
+            .timeout(REQUEST_TIMEOUT)
+            .body(signed.clone())
+            .send()
+            .await;
+        let error = match result {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => format!("webhook responded with status {}", response.status()),
+            Err(e) => e.to_string(),
+        };
+
+        if attempt >= MAX_ATTEMPTS {
+            return Err(Error::InternalServer(format!(
+                "Giving up on result notification after {} attempts: {}",
+                attempt, error
+            )));
+        }
+        eprintln!("Result notification failed, retrying: {}", error);
+        tokio::time::sleep(retry_delay(attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// Result sink POSTing notifications signed as JWT (`application/jwt`) to a
+/// webhook of the host system
+pub struct WebhookSink {
+    url: String,
+    signer: Box<dyn JwsSigner>,
+    client: reqwest::Client,
+}
+
+impl WebhookSink {
+    pub fn new(url: impl Into<String>, signer: Box<dyn JwsSigner>) -> Self {
+        WebhookSink {
+            url: url.into(),
+            signer,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl ResultSink for WebhookSink {
+    async fn deliver(
+        &self,
+        session: &Session,
+        auth_result: &StoredAuthResult,
+    ) -> Result<(), Error> {
+        let notification = ResultNotification::new(session, auth_result.clone());
+        let signed = sign_result_notification(&notification, self.signer.as_ref())?;
+        post_with_retries(&self.client, &self.url, signed).await
+    }
+}
+
+/// Fairing POSTing a signed notification to the configured
+/// `result_webhook_url` whenever an authentication result is registered. The
+/// notification is a JWT signed with the result signing key, or with the
+/// widget signing key if none is configured. Does nothing if no webhook is
+/// configured. Requires the [`crate::config::Config`] to be managed and the
+/// [`crate::session::SessionDBConn`] fairing to be attached.
+#[cfg(feature = "rocket")]
+pub fn webhook_fairing() -> impl Fairing {
+    sink_fairing("Result webhook", |config| {
+        let mut sinks: Vec<Arc<dyn ResultSink>> = vec![];
+        if let (Some(url), Some(signer)) =
+            (config.result_webhook_url(), config.result_webhook_signer())
+        {
+            sinks.push(Arc::new(WebhookSink::new(url, signer.box_clone())));
+        }
+        Ok(sinks)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::retry_delay;
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), Duration::from_secs(1));
+        assert_eq!(retry_delay(4), Duration::from_secs(8));
+    }
+}